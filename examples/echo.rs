@@ -32,6 +32,7 @@ use std::thread;
 
 const DISPLAY_WIDTH: f64 = 50.0;
 const DISPLAY_HEIGHT: f64 = 100.0;
+const ECHO_PORT: u16 = 7;
 
 #[derive(Clone)]
 struct LocalConfig
@@ -50,9 +51,9 @@ impl LocalConfig
 	}
 }
 
-fn rx_packet(event: &mut Event, effector: &mut Effector, expected_payload: &str)
+fn rx_packet(app: &user::AppComponent, event: &mut Event, effector: &mut Effector, expected_payload: &str) -> transport::Socket
 {
-	let (_, mut packet) = event.take_payload::<(internet::InternetInfo, common::Packet)>();
+	let (socket, mut packet) = app.recv(event);
 	let len = packet.len();
 	let data = packet.pop_bytes(len);
 	match str::from_utf8(data.as_slice()) {
@@ -60,6 +61,7 @@ fn rx_packet(event: &mut Event, effector: &mut Effector, expected_payload: &str)
 		Ok(text) => log_error!(effector, "received a bad packet: '{}'", text),
 		Err(mesg) => log_error!(effector, "received a bad packet: {}", mesg)
 	}
+	socket
 }
 
 fn handle_sender(app: &user::AppComponent, event: &mut Event, state: &SimState, effector: &mut Effector)
@@ -70,18 +72,19 @@ fn handle_sender(app: &user::AppComponent, event: &mut Event, state: &SimState,
 			effector.schedule_after_secs(event, app.data.id, 1.0);
 		},		
 		"timer" => {		
-			let info = internet::InternetInfo::new(internet::Protocol::Standard(internet::StandardProtocol::UDP), common::IPAddress::IPv4([10, 0, 0, 1]), common::IPAddress::IPv4([127, 0, 0, 2]));
-			let options = transport::SocketOptions::with_addr(common::IPAddress::IPv4([127, 0, 0, 2]));
+			let mut socket = app.open(transport::SocketKind::Datagram);
+			app.connect(effector, &mut socket, common::IPAddress::IPv4([10, 0, 0, 2]), ECHO_PORT);
+
 			let mut packet = common::Packet::new("packet", "#>1");
 			let payload = "hello".to_string();
 			packet.push_back_bytes(payload.as_bytes());
-			app.lower_out.send_payload_after_secs(effector, "send_down", 1.0, (info, options, packet));
+			app.send_after_secs(effector, &socket, 1.0, packet);
 
 			let sent = if state.contains(app.data.id, "num_sent") {state.get_int(app.data.id, "num_sent")} else {0};
 			effector.set_int("num_sent", sent+1);
 		},		
 		"send_up" => {
-			rx_packet(event, effector, "echoed hello");
+			rx_packet(app, event, effector, "echoed hello");
 
 			let recv = if state.contains(app.data.id, "num_recv") {state.get_int(app.data.id, "num_recv")} else {0};
 			effector.set_int("num_recv", recv+1);
@@ -107,20 +110,19 @@ fn handle_receiver(app: &user::AppComponent, event: &mut Event, state: &SimState
 {
 	match event.name.as_ref() {
 		"init 0" => {
-			log_info!(effector, "init");
+			let mut socket = app.open(transport::SocketKind::Datagram);
+			app.bind(effector, &mut socket, ECHO_PORT);
 		},		
 		"send_up" => {
-			rx_packet(event, effector, "hello");
+			let socket = rx_packet(app, event, effector, "hello");
 
 			let count = if state.contains(app.data.id, "num_recv") {state.get_int(app.data.id, "num_recv")} else {0};
 			effector.set_int("num_recv", count+1);
 		
-			let info = internet::InternetInfo::new(internet::Protocol::Standard(internet::StandardProtocol::UDP), common::IPAddress::IPv4([10, 0, 0, 2]), common::IPAddress::IPv4([127, 0, 0, 1]));
-			let options = transport::SocketOptions::with_addr(common::IPAddress::IPv4([127, 0, 0, 1]));
 			let mut packet = common::Packet::new("packet", "#>2");
 			let payload = "echoed hello".to_string();
 			packet.push_back_bytes(payload.as_bytes());
-			app.lower_out.send_payload_after_secs(effector, "send_down", 1.0, (info, options, packet));
+			app.send_after_secs(effector, &socket, 1.0, packet);
 		},		
 		_ => {
 			let cname = &(*state.components).get(app.data.id).name;
@@ -169,8 +171,8 @@ fn create_sim(local: LocalConfig, config: Config) -> Simulation
 	
 	let mut medium = physical::Medium80211Component::new(&mut sim, world_id);
 
-	let mut sender = devices::Endpoint::new("sender", &mut sim, world_id);
-	let mut receiver = devices::Endpoint::new("receiver", &mut sim, world_id);
+	let mut sender = devices::Endpoint::new("sender", [10, 0, 0, 1], [0x02, 0, 0, 0, 0, 1], &mut sim, world_id);
+	let mut receiver = devices::Endpoint::new("receiver", [10, 0, 0, 2], [0x02, 0, 0, 0, 0, 2], &mut sim, world_id);
 	sender.app.callback = Some(handle_sender);
 	receiver.app.callback = Some(handle_receiver);
 		
@@ -22,3 +22,16 @@ pub enum IPAddress
 	IPv4([u8; 4]),
 	IPv6([u8; 16]),
 }
+
+impl IPAddress
+{
+	/// Returns true for 0.0.0.0 and ::. Sockets bound to either of these accept traffic
+	/// for every local address (of both families).
+	pub fn is_unspecified(&self) -> bool
+	{
+		match *self {
+			IPAddress::IPv4(addr) => addr == [0; 4],
+			IPAddress::IPv6(addr) => addr == [0; 16],
+		}
+	}
+}
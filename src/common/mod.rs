@@ -18,8 +18,12 @@
 pub use self::checksum::*;
 pub use self::ip_address::*;
 pub use self::packet::*;
+pub use self::random::*;
+pub use self::settings::*;
 
 mod checksum;
 mod ip_address;
 mod packet;
+mod random;
+mod settings;
 
@@ -85,6 +85,24 @@ impl Packet
 		self.payload[index + self.offset]
 	}
 
+	/// Flips the bits in mask for the byte at index. Used by mediums to model bit errors.
+	pub fn corrupt(&mut self, index: usize, mask: u8)
+	{
+		self.payload[index + self.offset] ^= mask;
+	}
+
+	/// Returns the len bytes that were most recently popped off the front or None if fewer
+	/// bytes have been popped. Used to recover headers that have already been removed, e.g.
+	/// to quote them in ICMP errors.
+	pub fn popped_bytes(&self, len: usize) -> Option<Vec<u8>>
+	{
+		if len <= self.offset {
+			Some(self.payload.iter().skip(self.offset - len).take(len).cloned().collect())
+		} else {
+			None
+		}
+	}
+
 	/// Packet data in network endian byte order.
 	pub fn iter(&self) -> Skip<vec_deque::Iter<u8>>
 	{
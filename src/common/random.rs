@@ -0,0 +1,69 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+
+//! Small deterministic random number generator. Components that need random numbers
+//! should create one of these in "init 0" using the **seed** setting so that runs can be
+//! reproduced.
+use score::*;
+
+/// xorshift64* generator, see https://en.wikipedia.org/wiki/Xorshift.
+pub struct Rng
+{
+	state: u64,
+}
+
+impl Rng
+{
+	pub fn new(seed: u64) -> Self
+	{
+		// Zero is a fixed point for xorshift so we mix the seed using splitmix64.
+		let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+		z = z ^ (z >> 31);
+		Rng {state: if z == 0 {1} else {z}}
+	}
+
+	/// Returns a generator that gives each component a different sequence for the same seed.
+	pub fn with_component(seed: i64, id: ComponentID) -> Self
+	{
+		let name = format!("{}", id);
+		let hash = name.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));	// FNV-1a
+		Rng::new(seed as u64 ^ hash)
+	}
+
+	pub fn next_u64(&mut self) -> u64
+	{
+		self.state ^= self.state >> 12;
+		self.state ^= self.state << 25;
+		self.state ^= self.state >> 27;
+		self.state.wrapping_mul(0x2545F4914F6CDD1D)
+	}
+
+	/// Returns a number in [0.0, 1.0).
+	pub fn next_f64(&mut self) -> f64
+	{
+		(self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+	}
+
+	/// Returns a number in [min, max] (inclusive).
+	pub fn range(&mut self, min: i64, max: i64) -> i64
+	{
+		assert!(min <= max);
+		let span = (max - min) as u64 + 1;
+		min + (self.next_u64() % span) as i64
+	}
+}
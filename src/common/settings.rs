@@ -0,0 +1,50 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+
+//! Helpers for component settings. These are normally called from "init 0" and return
+//! the value from the store or, if the setting is missing, add the default to the store
+//! (so that the settings show up in GUIs) and return that.
+use score::*;
+
+pub fn int_setting(id: ComponentID, state: &SimState, effector: &mut Effector, name: &str, default: i64) -> i64
+{
+	if state.contains(id, name) {
+		state.get_int(id, name)
+	} else {
+		effector.set_int(name, default);
+		default
+	}
+}
+
+pub fn float_setting(id: ComponentID, state: &SimState, effector: &mut Effector, name: &str, default: f64) -> f64
+{
+	if state.contains(id, name) {
+		state.get_float(id, name)
+	} else {
+		effector.set_float(name, default);
+		default
+	}
+}
+
+pub fn string_setting(id: ComponentID, state: &SimState, effector: &mut Effector, name: &str, default: &str) -> String
+{
+	if state.contains(id, name) {
+		state.get_string(id, name)
+	} else {
+		effector.set_string(name, default);
+		default.to_string()
+	}
+}
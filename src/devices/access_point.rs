@@ -0,0 +1,96 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use link::*;
+use physical::*;
+use score::*;
+
+const START_X: f64 = 40.0;
+const START_Y: f64 = 5.0;
+const DY: f64 = 10.0;
+
+/// Network device that bridges an 802.11 BSS to a wired ethernet link. Frames from
+/// stations that aren't for another station in the BSS have their LLC header removed and
+/// are sent out the wired link as Ethernet II frames. Frames from the wired link get an
+/// LLC header and are sent to the station they are addressed to (or to every station for
+/// broadcasts). The AP itself has no IP addresses. Frames from both interfaces are
+/// captured by a single [`PcapngComponent`].
+pub struct AccessPoint
+{
+	pub name: String,
+	pub id: ComponentID,
+
+	pub mac: Mac80211Component,
+	pub llc: LlcComponent,
+	pub ethernet: EthernetMacComponent,
+
+	pub pcap: PcapngComponent,
+}
+
+impl AccessPoint
+{
+	/// mac_addr is used for the wireless interface and is also the BSSID.
+	pub fn new(name: &str, mac_addr: MacAddress, sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let id = sim.add_component(name, parent_id);
+
+		let mut mac = Mac80211Component::new(sim, id, mac_addr);
+		mac.mode = Mac80211Mode::AccessPoint;
+		let llc = LlcComponent::new(sim, id);
+		let ethernet = EthernetMacComponent::new(sim, id);
+		let pcap = PcapngComponent::new(sim, id);
+		AccessPoint {
+			name: name.to_string(),
+			id,
+			mac,
+			llc,
+			ethernet,
+
+			pcap,
+		}
+	}
+
+	pub fn start(mut self, sim: &mut Simulation, wireless: &mut Medium, wired: &mut Medium)
+	{
+		// Wire together the components.
+		self.llc.lower_out.connect_to(&self.mac.upper_in);
+		self.mac.upper_out.connect_to(&self.llc.lower_in);
+
+		self.llc.upper_out.connect_to(&self.ethernet.upper_in);
+		self.llc.ipv6_out.connect_to(&self.ethernet.upper_in);
+		self.ethernet.upper_out.connect_to(&self.llc.upper_in);
+
+		self.mac.pcap_out.connect_to(self.pcap.add_ieee80211("wlan0"));
+		self.ethernet.pcap_out.connect_to(self.pcap.add_ethernet("eth0"));
+		wireless.connect_radio(self.id, &mut self.mac.lower_out, &self.mac.lower_in, &self.mac.cca_in);
+		wired.connect(&mut self.ethernet.lower_out, &self.ethernet.lower_in);
+
+		// Spin up the threads.
+		self.mac.start();
+		self.llc.start();
+		self.ethernet.start();
+		self.pcap.start();
+
+		// Set our state.
+		let mut effector = Effector::new();
+		{
+		let (_, root) = sim.components.get_root();
+			effector.set_string("display-name", &self.name);
+			effector.set_float("display-location-x", START_X);
+			effector.set_float("display-location-y", START_Y + DY*(root.children.len()) as f64);
+		}
+		sim.apply(self.id, effector);
+	}
+}
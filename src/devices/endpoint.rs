@@ -28,7 +28,11 @@ const START_X: f64 = 25.0;
 const START_Y: f64 = 5.0;
 const DY: f64 = 10.0;
 
-/// Network device that can be used as a source or sink of traffic.
+/// Network device that can be used as a source or sink of traffic. Endpoints are dual-stack:
+/// IPv4 is configured manually and IPv6 uses a link-local address derived from the MAC
+/// address plus global addresses configured by SLAAC. The radio is in ad hoc mode unless
+/// mac.mode is changed to Station before start is called (see [`AccessPoint`]). Endpoints
+/// don't move unless they are created using with_mobility.
 pub struct Endpoint
 {
 	pub name: String,
@@ -36,71 +40,176 @@ pub struct Endpoint
 
 	pub app: AppComponent,
 	pub udp: UdpComponent,
-	pub ipv4: IPv4Component,	// TODO: should be InternetComponent
+	pub tcp: TcpComponent,
+	pub internet: UpperInternetComponent,
+	pub ipv4: IPv4Component,
+	pub icmp: IcmpComponent,
+	pub arp: ArpComponent,
+	pub ipv6: IPv6Component,
+	pub icmpv6: Icmpv6Component,
+	pub nd: NeighborDiscoveryComponent,
 	pub llc: LlcComponent,
 	pub mac: Mac80211Component,
 
 	pub pcap: PcapComponent,
+
+	/// Set for endpoints created using with_mobility.
+	pub mobility: Option<MobilityComponent>,
 }
 
 impl Endpoint
 {
-	pub fn new(name: &str, sim: &mut Simulation, parent_id: ComponentID) -> Self
+	pub fn new(name: &str, ipv4_addr: [u8; 4], mac_addr: MacAddress, sim: &mut Simulation, parent_id: ComponentID) -> Self
 	{
 		let id = sim.add_component(name, parent_id);
+		Endpoint::with_id(name, id, ipv4_addr, mac_addr, sim)
+	}
+
+	/// Like new except that the endpoint moves around using model. The endpoint's
+	/// "display-location-x" and "display-location-y" state is updated as it moves,
+	/// see [`MobilityComponent`].
+	pub fn with_mobility(name: &str, ipv4_addr: [u8; 4], mac_addr: MacAddress, model: Box<MobilityModel>, sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (id, data) = sim.add_active_component(name, parent_id);
+		let mut endpoint = Endpoint::with_id(name, id, ipv4_addr, mac_addr, sim);
+		endpoint.mobility = Some(MobilityComponent::new(data, model));
+		endpoint
+	}
 
-		let app = AppComponent::new(sim, id);
-		let udp = UdpComponent::new(sim, id);
-		let ipv4 = IPv4Component::new(sim, id);
+	fn with_id(name: &str, id: ComponentID, ipv4_addr: [u8; 4], mac_addr: MacAddress, sim: &mut Simulation) -> Self
+	{
+		let interface = IPv4Interface::new(ipv4_addr, [255, 255, 255, 0]);
+		let link_local = IPv6Interface::new(link_local_addr(&mac_addr), 64);
+		let mut app = AppComponent::new(sim, id);
+		app.interfaces.push(interface);
+		app.ipv6_interfaces.push(link_local);
+		let mut udp = UdpComponent::new(sim, id);
+		udp.add_upper();
+		let tcp = TcpComponent::new(sim, id);
+		let internet = UpperInternetComponent::new(sim, id);
+		let mut ipv4 = IPv4Component::new(sim, id);
+		ipv4.add_interface(interface);
+		let icmp = IcmpComponent::new(sim, id, ipv4_addr);
+		let arp = ArpComponent::new(sim, id, ipv4_addr, mac_addr);
+		let mut ipv6 = IPv6Component::new(sim, id);
+		ipv6.add_interface(link_local);
+		let icmpv6 = Icmpv6Component::new(sim, id);
+		let nd = NeighborDiscoveryComponent::new(sim, id, mac_addr);
 		let llc = LlcComponent::new(sim, id);
-		let mac = Mac80211Component::new(sim, id);
+		let mac = Mac80211Component::new(sim, id, mac_addr);
 		let pcap = PcapComponent::new(sim, id);
 		Endpoint {
 			name: name.to_string(),
 			id,
 			app,
 			udp,
+			tcp,
+			internet,
 			ipv4,
+			icmp,
+			arp,
+			ipv6,
+			icmpv6,
+			nd,
 			llc,
 			mac,
 
 			pcap,
+			mobility: None,
 		}
 	}
 
-	pub fn start(mut self, sim: &mut Simulation, medium: &mut Medium80211Component)	// TODO: use a trait for the medium
+	pub fn start(mut self, sim: &mut Simulation, medium: &mut Medium)
 	{
 		// Wire together the components.
-		self.app.lower_out.connect_to(&self.udp.upper_in);
-		self.udp.upper_out.connect_to(&self.app.lower_in);
+		self.app.lower_out.connect_to(&self.udp.upper_ins[0]);
+		self.udp.upper_outs[0].connect_to(&self.app.lower_in);
+		self.udp.error_out.connect_to(&self.icmp.error_in);
+
+		self.udp.lower_out.connect_to(&self.internet.upper_in);
+		self.internet.upper_out.connect_to(&self.udp.lower_in);
+
+		self.app.tcp_out.connect_to(&self.tcp.upper_in);
+		self.tcp.upper_out.connect_to(&self.app.tcp_in);
+
+		self.tcp.lower_out.connect_to(&self.internet.upper_in);
+		self.ipv4.tcp_out.connect_to(&self.tcp.lower_in);
+		self.ipv6.tcp_out.connect_to(&self.tcp.lower_in);
+		self.icmp.tcp_out.connect_to(&self.tcp.icmp_in);
+		self.icmpv6.tcp_out.connect_to(&self.tcp.icmp_in);
 
-		self.udp.lower_out.connect_to(&self.ipv4.upper_in);
-		self.ipv4.upper_out.connect_to(&self.udp.lower_in);
+		self.internet.lower_ipv4_out.connect_to(&self.ipv4.upper_in);
+		self.internet.lower_ipv6_out.connect_to(&self.ipv6.upper_in);
+		self.ipv4.upper_out.connect_to(&self.internet.lower_in);
+		self.ipv6.upper_out.connect_to(&self.internet.lower_in);
 
-		self.ipv4.lower_out.connect_to(&self.llc.upper_in);
-		self.llc.upper_out.connect_to(&self.ipv4.lower_in);
+		self.app.icmp_out.connect_to(&self.icmp.upper_in);
+		self.icmp.upper_out.connect_to(&self.app.icmp_in);
+		self.icmp.udp_out.connect_to(&self.udp.icmp_in);
+		self.udp.icmp_outs[0].connect_to(&self.app.icmp_in);
+
+		self.icmp.lower_out.connect_to(&self.ipv4.upper_in);
+		self.ipv4.icmp_out.connect_to(&self.icmp.lower_in);
+		self.ipv4.error_out.connect_to(&self.icmp.error_in);
+		self.ipv4.forward_out.connect_to(&self.icmp.forward_in);
+		self.icmp.icmpv6_out.connect_to(&self.icmpv6.error_in);
+
+		self.app.icmpv6_out.connect_to(&self.icmpv6.upper_in);
+		self.icmpv6.upper_out.connect_to(&self.app.icmpv6_in);
+		self.icmpv6.udp_out.connect_to(&self.udp.icmp_in);
+
+		self.icmpv6.lower_out.connect_to(&self.ipv6.upper_in);
+		self.ipv6.icmp_out.connect_to(&self.icmpv6.lower_in);
+		self.ipv6.error_out.connect_to(&self.icmpv6.error_in);
+		self.ipv6.config_out.connect_to(&self.app.config_in);
+
+		self.ipv4.lower_outs[0].connect_to(&self.arp.upper_in);
+		self.arp.upper_out.connect_to(&self.ipv4.lower_ins[0]);
+
+		self.arp.lower_out.connect_to(&self.llc.upper_in);
+		self.llc.upper_out.connect_to(&self.arp.lower_in);
+
+		self.ipv6.lower_outs[0].connect_to(&self.nd.upper_in);
+		self.nd.upper_out.connect_to(&self.ipv6.lower_ins[0]);
+		self.nd.config_out.connect_to(&self.ipv6.config_ins[0]);
+
+		self.nd.lower_out.connect_to(&self.llc.upper_in);
+		self.llc.ipv6_out.connect_to(&self.nd.lower_in);
 
 		self.llc.lower_out.connect_to(&self.mac.upper_in);
 		self.mac.upper_out.connect_to(&self.llc.lower_in);
 
 		self.mac.pcap_out.connect_to(&self.pcap.ieee80211_in);
-		medium.connect(&mut self.mac.lower_out, &self.mac.lower_in);
+		medium.connect_radio(self.id, &mut self.mac.lower_out, &self.mac.lower_in, &self.mac.cca_in);
 		
 		// Spin up the threads.
 		self.app.start();
 		self.udp.start();
+		self.tcp.start();
+		self.internet.start();
 		self.ipv4.start();
+		self.icmp.start();
+		self.arp.start();
+		self.ipv6.start();
+		self.icmpv6.start();
+		self.nd.start();
 		self.llc.start();
 		self.mac.start();
 		self.pcap.start();
+		let mobile = self.mobility.is_some();
+		if let Some(mobility) = self.mobility.take() {
+			mobility.start();
+		}
 		
 		// Set our state.
 		let mut effector = Effector::new();
 		{
 		let (_, root) = sim.components.get_root();
 			effector.set_string("display-name", &self.name);
-			effector.set_float("display-location-x", START_X);
-			effector.set_float("display-location-y", START_Y + DY*(root.children.len()) as f64);
+			if !mobile {		// otherwise MobilityComponent sets the location
+				effector.set_float("display-location-x", START_X);
+				effector.set_float("display-location-y", START_Y + DY*(root.children.len()) as f64);
+			}
 		}
 		sim.apply(self.id, effector);
 	}
@@ -15,7 +15,11 @@
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 
 //! This module exposes pre-defined networking devices.
+pub use self::access_point::*;
 pub use self::endpoint::*;
+pub use self::router::*;
 
+mod access_point;
 mod endpoint;
+mod router;
 
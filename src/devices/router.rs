@@ -0,0 +1,168 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use internet::*;
+use link::*;
+use physical::*;
+use score::*;
+
+const START_X: f64 = 50.0;
+const START_Y: f64 = 5.0;
+const DY: f64 = 10.0;
+
+/// The components below ARP for one of a router's interfaces.
+pub enum RouterLink
+{
+	Wireless {llc: LlcComponent, mac: Mac80211Component},
+	Wired {mac: EthernetMacComponent},
+}
+
+pub struct RouterInterface
+{
+	pub arp: ArpComponent,
+	pub link: RouterLink,
+}
+
+/// Network device that forwards IPv4 packets between its interfaces. Each interface
+/// is a child component named "if0", "if1", etc. Per-interface counters are maintained
+/// by the ipv4 component (e.g. "rx_packets_0"). Frames from every interface are captured
+/// by a single [`PcapngComponent`] (using the interface names).
+pub struct Router
+{
+	pub name: String,
+	pub id: ComponentID,
+
+	pub ipv4: IPv4Component,
+	pub icmp: IcmpComponent,
+	pub interfaces: Vec<RouterInterface>,
+	pub pcap: PcapngComponent,
+}
+
+impl Router
+{
+	pub fn new(name: &str, sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let id = sim.add_component(name, parent_id);
+
+		let mut ipv4 = IPv4Component::new(sim, id);
+		ipv4.forwarding = true;
+
+		let icmp = IcmpComponent::new(sim, id, [0, 0, 0, 0]);	// set when the first interface is added
+		let pcap = PcapngComponent::new(sim, id);
+		Router {
+			name: name.to_string(),
+			id,
+			ipv4,
+			icmp,
+			interfaces: Vec::new(),
+			pcap,
+		}
+	}
+
+	/// Adds an 802.11 interface and returns its index. Static routes that use the
+	/// interface can be added to ipv4.routes.
+	pub fn add_wireless_interface(&mut self, sim: &mut Simulation, interface: IPv4Interface, mac_addr: MacAddress, medium: &mut Medium80211Component) -> usize
+	{
+		let (index, if_id) = self.add_interface(sim, interface);
+
+		let arp = ArpComponent::new(sim, if_id, interface.addr, mac_addr);
+		let mut llc = LlcComponent::new(sim, if_id);
+		let mut mac = Mac80211Component::new(sim, if_id, mac_addr);
+
+		llc.lower_out.connect_to(&mac.upper_in);
+		mac.upper_out.connect_to(&llc.lower_in);
+		mac.pcap_out.connect_to(self.pcap.add_ieee80211(&format!("if{}", index)));
+		medium.connect_radio(self.id, &mut mac.lower_out, &mac.lower_in, &mac.cca_in);
+
+		self.connect_arp(arp, index, &llc.upper_in, RouterLink::Wireless {llc, mac}, |link| match *link {
+			RouterLink::Wireless {ref mut llc, ..} => &mut llc.upper_out,
+			_ => unreachable!()
+		})
+	}
+
+	/// Adds a wired ethernet interface and returns its index.
+	pub fn add_wired_interface(&mut self, sim: &mut Simulation, interface: IPv4Interface, mac_addr: MacAddress, medium: &mut Medium) -> usize
+	{
+		let (index, if_id) = self.add_interface(sim, interface);
+
+		let arp = ArpComponent::new(sim, if_id, interface.addr, mac_addr);
+		let mut mac = EthernetMacComponent::new(sim, if_id);
+		mac.pcap_out.connect_to(self.pcap.add_ethernet(&format!("if{}", index)));
+		medium.connect(&mut mac.lower_out, &mac.lower_in);
+
+		self.connect_arp(arp, index, &mac.upper_in, RouterLink::Wired {mac}, |link| match *link {
+			RouterLink::Wired {ref mut mac} => &mut mac.upper_out,
+			_ => unreachable!()
+		})
+	}
+
+	pub fn start(mut self, sim: &mut Simulation)
+	{
+		// Wire together the components.
+		self.icmp.lower_out.connect_to(&self.ipv4.upper_in);
+		self.ipv4.icmp_out.connect_to(&self.icmp.lower_in);
+		self.ipv4.error_out.connect_to(&self.icmp.error_in);
+		self.ipv4.forward_out.connect_to(&self.icmp.forward_in);
+
+		// Spin up the threads.
+		self.ipv4.start();
+		self.icmp.start();
+		self.pcap.start();
+		for interface in self.interfaces.drain(..) {
+			interface.arp.start();
+			match interface.link {
+				RouterLink::Wireless {llc, mac} => {
+					llc.start();
+					mac.start();
+				},
+				RouterLink::Wired {mac} => mac.start(),
+			}
+		}
+
+		// Set our state.
+		let mut effector = Effector::new();
+		{
+		let (_, root) = sim.components.get_root();
+			effector.set_string("display-name", &self.name);
+			effector.set_float("display-location-x", START_X);
+			effector.set_float("display-location-y", START_Y + DY*(root.children.len()) as f64);
+		}
+		sim.apply(self.id, effector);
+	}
+
+	fn add_interface(&mut self, sim: &mut Simulation, interface: IPv4Interface) -> (usize, ComponentID)
+	{
+		let index = self.ipv4.add_interface(interface);
+		if index == 0 {
+			self.icmp.ipv4_addr = interface.addr;
+		}
+
+		let if_id = sim.add_component(&format!("if{}", index), self.id);
+		(index, if_id)
+	}
+
+	fn connect_arp<F>(&mut self, mut arp: ArpComponent, index: usize, link_in: &InPort<(LinkInfo, Packet)>, mut link: RouterLink, link_out: F) -> usize
+		where F: Fn(&mut RouterLink) -> &mut OutPort<(LinkInfo, Packet)>
+	{
+		self.ipv4.lower_outs[index].connect_to(&arp.upper_in);
+		arp.upper_out.connect_to(&self.ipv4.lower_ins[index]);
+
+		arp.lower_out.connect_to(link_in);
+		link_out(&mut link).connect_to(&arp.lower_in);
+
+		self.interfaces.push(RouterInterface {arp, link});
+		index
+	}
+}
@@ -0,0 +1,243 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::ipv4::*;
+use std::collections::HashMap;
+
+/// Splits a packet (without an IPv4 header) into fragments that, with their headers,
+/// fit within mtu bytes. Each fragment is returned with the header that should be
+/// pushed onto it.
+pub fn fragment_ipv4(header: &IPv4Header, packet: &Packet, mtu: usize) -> Vec<(IPv4Header, Packet)>
+{
+	assert!(mtu >= 28, "mtu {} is too small", mtu);
+
+	let data: Vec<u8> = packet.iter().cloned().collect();
+	let max_len = (mtu - 20) & !7;			// all but the last fragment must be a multiple of 8 bytes
+	let count = (data.len() + max_len - 1)/max_len;
+	let base_offset = 8*header.fragment_offset as usize;	// the packet may itself be a fragment
+
+	let mut result = Vec::with_capacity(count);
+	for i in 0..count {
+		let start = i*max_len;
+		let end = if start + max_len < data.len() {start + max_len} else {data.len()};
+		let suffix = format!("{{{} of {}}}", i+1, count);
+
+		let mut fragment = Packet::new(&(packet.name().to_string() + &suffix), &(packet.id().to_string() + &suffix));
+		fragment.push_back_bytes(&data[start..end]);
+
+		let mut fheader = header.clone();
+		fheader.fragment_offset = ((base_offset + start)/8) as u16;
+		fheader.more_fragments = i+1 < count || header.more_fragments;
+		result.push((fheader, fragment));
+	}
+
+	result
+}
+
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+struct FragmentKey
+{
+	src_addr: [u8; 4],
+	dst_addr: [u8; 4],
+	protocol: u8,
+	identification: u16,
+}
+
+struct FragmentBuffer
+{
+	header: IPv4Header,		// from the first fragment if we've seen it
+	name: String,
+	id: String,
+	data: Vec<u8>,
+	received: Vec<bool>,
+	total_len: Option<usize>,
+	expires: f64,
+}
+
+/// Collects IPv4 fragments until all of a datagram has arrived. Overlapping fragments
+/// are trimmed so that bytes which have already arrived are not overwritten (this is
+/// what BSD does and it defeats the classic overlapping fragment attacks).
+pub struct IPv4Reassembler
+{
+	buffers: HashMap<FragmentKey, FragmentBuffer>,
+}
+
+impl IPv4Reassembler
+{
+	pub fn new() -> Self
+	{
+		IPv4Reassembler {buffers: HashMap::new()}
+	}
+
+	/// Number of datagrams which have not been completely re-assembled.
+	pub fn len(&self) -> usize
+	{
+		self.buffers.len()
+	}
+
+	/// Header and packet should be for a fragment that has just been popped. Returns
+	/// the re-assembled datagram once all of its fragments have arrived.
+	pub fn add(&mut self, header: IPv4Header, packet: Packet, expires: f64) -> Result<Option<(IPv4Header, Packet)>, String>
+	{
+		let key = FragmentKey {
+			src_addr: header.src_addr,
+			dst_addr: header.dst_addr,
+			protocol: header.protocol.as_u8(),
+			identification: header.identification};
+		let offset = 8*header.fragment_offset as usize;
+		let end = offset + packet.len();
+		if end > 65535 - 20 {
+			self.buffers.remove(&key);
+			return Err(format!("fragment for {:?} ends at {} which is too large", header.src_addr, end))
+		}
+		if header.more_fragments && packet.len() % 8 != 0 {
+			return Err(format!("non-final fragment for {:?} has length {}", header.src_addr, packet.len()))
+		}
+
+		let complete = {
+			let buffer = self.buffers.entry(key).or_insert_with(|| FragmentBuffer {
+				header: header.clone(),
+				name: base_name(packet.name()),
+				id: base_name(packet.id()),
+				data: Vec::new(),
+				received: Vec::new(),
+				total_len: None,
+				expires});
+
+			if !header.more_fragments {
+				match buffer.total_len {
+					Some(len) if len != end => return Err(format!("last fragment for {:?} ends at {} but an earlier one ended at {}", header.src_addr, end, len)),
+					_ => buffer.total_len = Some(end)
+				}
+			}
+			if offset == 0 {
+				buffer.header = header.clone();
+			}
+
+			if buffer.data.len() < end {
+				buffer.data.resize(end, 0);
+				buffer.received.resize(end, false);
+			}
+			for (i, &b) in packet.iter().enumerate() {
+				if !buffer.received[offset + i] {
+					buffer.data[offset + i] = b;
+					buffer.received[offset + i] = true;
+				}
+			}
+
+			match buffer.total_len {
+				Some(len) => buffer.received.len() == len && buffer.received.iter().all(|&r| r),
+				None => false
+			}
+		};
+
+		if complete {
+			let buffer = self.buffers.remove(&key).unwrap();
+			let mut header = buffer.header;
+			header.more_fragments = false;
+			header.fragment_offset = 0;
+
+			let mut packet = Packet::new(&buffer.name, &buffer.id);
+			packet.push_back_bytes(&buffer.data);
+			Ok(Some((header, packet)))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Removes datagrams that have expired. For datagrams whose first fragment arrived
+	/// this returns the first fragment's header and its payload (which is what ICMP
+	/// Time Exceeded messages need).
+	pub fn expire(&mut self, time: f64) -> Vec<(IPv4Header, Packet)>
+	{
+		let keys: Vec<FragmentKey> = self.buffers.iter().filter(|&(_, b)| b.expires <= time).map(|(k, _)| *k).collect();
+
+		let mut result = Vec::new();
+		for key in keys {
+			let buffer = self.buffers.remove(&key).unwrap();
+			if buffer.header.fragment_offset == 0 {
+				let len = buffer.received.iter().take_while(|&&r| r).count();
+				let mut packet = Packet::new(&buffer.name, &buffer.id);
+				packet.push_back_bytes(&buffer.data[0..len]);
+				result.push((buffer.header, packet));
+			}
+		}
+		result
+	}
+}
+
+// "ICMP Ping{1 of 4}" => "ICMP Ping"
+fn base_name(name: &str) -> String
+{
+	match name.rfind('{') {
+		Some(i) if name.ends_with('}') => name[0..i].to_string(),
+		_ => name.to_string()
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+    #[cfg(test)]
+	use internet::protocol::*;
+    #[cfg(test)]
+	use transport::*;
+
+    #[test]
+    fn fragment_and_reassemble()
+	{
+		let mut packet = Packet::new("big packet", "#>1.1");
+		let payload: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+		packet.push_back_bytes(&payload);
+
+		let options = SocketOptions::with_addr(IPAddress::IPv4([10, 0, 0, 2]));
+		let mut header = IPv4Header::new(Protocol::Custom(200), [10, 0, 0, 1], [10, 0, 0, 2], &options);
+		header.identification = 7;
+
+		let fragments = fragment_ipv4(&header, &packet, 1500);
+		assert_eq!(3, fragments.len());
+		assert_eq!("big packet{1 of 3}", fragments[0].1.name());
+		assert_eq!(185, fragments[1].0.fragment_offset);
+		assert!(!fragments[2].0.more_fragments);
+
+		// Deliver them out of order and duplicate one of them.
+		let mut reassembler = IPv4Reassembler::new();
+		let mut result = None;
+		for &i in [2, 0, 2, 1].iter() {
+			let (ref fheader, ref fpacket) = fragments[i];
+			match reassembler.add(fheader.clone(), fpacket.clone(), 30.0) {
+				Ok(Some(r)) => result = Some(r),
+				Ok(None) => (),
+				Err(mesg) => assert!(false, "IPv4Reassembler::add failed: {}", mesg)
+			}
+		}
+
+		match result {
+			Some((rheader, mut rpacket)) => {
+				assert_eq!(0, rheader.fragment_offset);
+				assert!(!rheader.more_fragments);
+				assert_eq!("big packet", rpacket.name());
+				assert_eq!("#>1.1", rpacket.id());
+
+				let len = rpacket.len();
+				assert_eq!(payload, rpacket.pop_bytes(len));
+			}
+			None => assert!(false, "packet wasn't re-assembled")
+		}
+		assert_eq!(0, reassembler.len());
+    }
+}
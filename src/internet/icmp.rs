@@ -0,0 +1,438 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::internet::*;
+use internet::ipv4::*;
+use internet::protocol::*;
+use score::*;
+use std::thread;
+use transport::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unreachable
+{
+	Network,
+	Host,
+	Protocol,
+	Port,
+
+	/// The packet was too large and DF was set. Includes the next hop MTU (see RFC 1191).
+	FragmentationNeeded(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeExceeded
+{
+	/// TTL hit zero while the packet was being forwarded. Traceroute relies on this.
+	Transit,
+
+	/// Not all of the fragments arrived in time.
+	Reassembly,
+}
+
+/// In memory version of the ICMPv4 header. For errors the packet payload is the
+/// offending IPv4 header plus the first eight bytes of its payload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IcmpMessage		// see RFC 792
+{
+	EchoReply {identifier: u16, sequence: u16},
+	DestinationUnreachable(Unreachable),
+	EchoRequest {identifier: u16, sequence: u16},
+	TimeExceeded(TimeExceeded),
+}
+
+impl IcmpMessage
+{
+	/// Errors report a problem with a datagram. Everything else is a query.
+	pub fn is_error(self) -> bool
+	{
+		match self {
+			IcmpMessage::DestinationUnreachable(_) => true,
+			IcmpMessage::TimeExceeded(_) => true,
+			_ => false
+		}
+	}
+
+	/// Returns a name suitable for use with [`Packet`].
+	pub fn name(self) -> &'static str
+	{
+		match self {
+			IcmpMessage::EchoReply {..} => "ICMP Echo Reply",
+			IcmpMessage::DestinationUnreachable(_) => "ICMP Destination Unreachable",
+			IcmpMessage::EchoRequest {..} => "ICMP Echo Request",
+			IcmpMessage::TimeExceeded(_) => "ICMP Time Exceeded",
+		}
+	}
+
+	/// Adds an ICMP header to the packet.
+	pub fn push(&self, packet: &mut Packet)
+	{
+		let (kind, code, rest) = match *self {
+			IcmpMessage::EchoReply {identifier, sequence} => (0, 0, (identifier as u32) << 16 | sequence as u32),
+			IcmpMessage::DestinationUnreachable(reason) => match reason {
+				Unreachable::Network => (3, 0, 0),
+				Unreachable::Host => (3, 1, 0),
+				Unreachable::Protocol => (3, 2, 0),
+				Unreachable::Port => (3, 3, 0),
+				Unreachable::FragmentationNeeded(mtu) => (3, 4, mtu as u32),
+			},
+			IcmpMessage::EchoRequest {identifier, sequence} => (8, 0, (identifier as u32) << 16 | sequence as u32),
+			IcmpMessage::TimeExceeded(TimeExceeded::Transit) => (11, 0, 0),
+			IcmpMessage::TimeExceeded(TimeExceeded::Reassembly) => (11, 1, 0),
+		};
+
+		let mut header = Header::with_capacity(8);
+		header.push8(kind);
+		header.push8(code);
+		header.push16(0);			// checksum (this is set for real after we've pushed the rest of the header)
+		header.push32(rest);
+
+		let crc = header.start_checksum(0);
+		let crc = packet.finish_checksum(packet.len(), crc);
+		header.data[2] = (crc >> 8) as u8;
+		header.data[3] = (crc & 0xFF) as u8;
+
+		packet.push_header(&header);
+	}
+
+	/// Removes an ICMP header from the packet.
+	pub fn pop(packet: &mut Packet) -> Result<IcmpMessage, String>
+	{
+		if packet.len() < 8 {
+			return Err(format!("ICMP packet should be at least 8 bytes but is {}", packet.len()))
+		}
+
+		let crc = packet.checksum(packet.len());
+		if crc != 0 {
+			return Err("Checksum error".to_string())
+		}
+
+		let kind = packet.pop8();
+		let code = packet.pop8();
+		let _ = packet.pop16();		// checksum
+		let rest = packet.pop32();
+
+		let identifier = (rest >> 16) as u16;
+		let sequence = (rest & 0xFFFF) as u16;
+		match (kind, code) {
+			(0, 0) => Ok(IcmpMessage::EchoReply {identifier, sequence}),
+			(3, 0) => Ok(IcmpMessage::DestinationUnreachable(Unreachable::Network)),
+			(3, 1) => Ok(IcmpMessage::DestinationUnreachable(Unreachable::Host)),
+			(3, 2) => Ok(IcmpMessage::DestinationUnreachable(Unreachable::Protocol)),
+			(3, 3) => Ok(IcmpMessage::DestinationUnreachable(Unreachable::Port)),
+			(3, 4) => Ok(IcmpMessage::DestinationUnreachable(Unreachable::FragmentationNeeded(sequence))),
+			(8, 0) => Ok(IcmpMessage::EchoRequest {identifier, sequence}),
+			(11, 0) => Ok(IcmpMessage::TimeExceeded(TimeExceeded::Transit)),
+			(11, 1) => Ok(IcmpMessage::TimeExceeded(TimeExceeded::Reassembly)),
+			_ => Err(format!("ICMP type {} code {} isn't supported", kind, code))
+		}
+	}
+}
+
+/// Handles ICMPv4 for a device: answers echo requests, sends errors generated by the
+/// internet and transport layers, and delivers errors that arrive back up to the
+/// transport that sent the offending datagram.
+pub struct IcmpComponent
+{
+	data: ThreadData,
+
+	/// Source address used for the errors this device generates (other than errors for
+	/// datagrams it was forwarding).
+	pub ipv4_addr: [u8; 4],
+
+	/// Listens for "send_down" events. Used by apps to send echo requests.
+	pub upper_in: InPort<(InternetInfo, IcmpMessage, Packet)>,
+	pub upper_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Listens for "send_error" (send an error to the datagram's source) and "local_error"
+	/// (the datagram originated here so deliver the error directly) events. The info and
+	/// packet are for the offending datagram. For IPv4 "send_error" events the packet should
+	/// start with the datagram's IPv4 header as it was received (this is what the error
+	/// quotes). Otherwise the packet should start with its transport header.
+	pub error_in: InPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Listens for "forward_error" events. These are like "send_error" except that they're
+	/// for datagrams a router couldn't forward so the error is sent from the address of the
+	/// interface the datagram arrived on.
+	pub forward_in: InPort<(InternetInfo, IcmpMessage, [u8; 4], Packet)>,
+
+	/// Errors for UDP datagrams are sent here using "icmp_error" events.
+	pub udp_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Errors for TCP segments are sent here using "icmp_error" events.
+	pub tcp_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// "send_error" events for IPv6 datagrams are forwarded here, i.e. to [`Icmpv6Component`].
+	pub icmpv6_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Listens for "send_up" events.
+	pub lower_in: InPort<(InternetInfo, Packet)>,
+	pub lower_out: OutPort<(InternetInfo, SocketOptions, Packet)>,
+}
+
+impl IcmpComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID, ipv4_addr: [u8; 4]) -> Self
+	{
+		let (id, data) = sim.add_active_component("ICMP", parent_id);
+		IcmpComponent {
+			data: data,
+			ipv4_addr,
+
+			upper_in: InPort::with_port_name(id, "upper_in"),
+			error_in: InPort::with_port_name(id, "error_in"),
+			forward_in: InPort::with_port_name(id, "forward_in"),
+			lower_out: OutPort::new(),
+
+			lower_in: InPort::with_port_name(id, "lower_in"),
+			upper_out: OutPort::new(),
+			udp_out: OutPort::new(),
+			tcp_out: OutPort::new(),
+			icmpv6_out: OutPort::new(),
+		}
+	}
+
+	pub fn start(self)
+	{
+		let mut next_id = 0;
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					effector.set_int("errors_sent", 0);
+				},
+				"send_down" => {
+					let (info, message, packet) = event.take_payload::<(InternetInfo, IcmpMessage, Packet)>();
+					self.send_message(&mut effector, info, message, packet);
+				},
+				"send_error" => {
+					let (info, message, packet) = event.take_payload::<(InternetInfo, IcmpMessage, Packet)>();
+					if let IPAddress::IPv6(_) = info.src_addr {
+						self.icmpv6_out.send_payload(&mut effector, &event.name, (info, message, packet));
+					} else {
+						let src_addr = self.ipv4_addr;
+						self.send_error(&mut effector, &state, &mut next_id, info, message, src_addr, packet);
+					}
+				},
+				"forward_error" => {
+					let (info, message, src_addr, packet) = event.take_payload::<(InternetInfo, IcmpMessage, [u8; 4], Packet)>();
+					self.send_error(&mut effector, &state, &mut next_id, info, message, src_addr, packet);
+				},
+				"local_error" => {
+					let (info, message, packet) = event.take_payload::<(InternetInfo, IcmpMessage, Packet)>();
+					self.deliver_error(&mut effector, info, message, packet);
+				},
+				"send_up" => {
+					let (info, mut packet) = event.take_payload::<(InternetInfo, Packet)>();
+					match IcmpMessage::pop(&mut packet) {
+						Ok(IcmpMessage::EchoRequest {identifier, sequence}) => {
+							let len = packet.len();
+							let mut reply = Packet::new("ICMP Echo Reply", &(packet.id().to_string() + "r"));
+							reply.push_back_bytes(&packet.pop_bytes(len));
+
+							let rinfo = InternetInfo::new(ICMP, info.dst_addr, info.src_addr);
+							self.send_message(&mut effector, rinfo, IcmpMessage::EchoReply {identifier, sequence}, reply);
+						},
+						Ok(message) if message.is_error() => {
+							match IPv4Header::pop(&mut packet) {
+								Ok(header) => {
+									let oinfo = InternetInfo::new(header.protocol, IPAddress::IPv4(header.src_addr), IPAddress::IPv4(header.dst_addr));
+									self.deliver_error(&mut effector, oinfo, message, packet);
+								},
+								Err(mesg) => log_warning!(effector, "{} had a bad IPv4 header: {}", message.name(), mesg)
+							}
+						},
+						Ok(message) => {
+							self.upper_out.send_payload(&mut effector, "send_up", (info, message, packet));
+						},
+						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
+					}
+				}
+			);
+		});
+	}
+
+	fn send_message(&self, effector: &mut Effector, info: InternetInfo, message: IcmpMessage, mut packet: Packet)
+	{
+		message.push(&mut packet);
+		let options = SocketOptions::with_addr(info.dst_addr);
+		self.lower_out.send_payload(effector, "send_down", (info, options, packet));
+	}
+
+	fn send_error(&self, effector: &mut Effector, state: &SimState, next_id: &mut i32, info: InternetInfo, message: IcmpMessage, src_addr: [u8; 4], packet: Packet)
+	{
+		if should_send_error(&info, &packet) {
+			*next_id += 1;
+			let mut error = Packet::new(message.name(), &format!("#>{}.{}", self.data.id, next_id));
+			error.push_back_bytes(&offending_bytes(&packet));
+
+			let reply = InternetInfo::new(ICMP, IPAddress::IPv4(src_addr), info.src_addr);
+			self.send_message(effector, reply, message, error);
+
+			let count = state.get_int(self.data.id, "errors_sent");
+			effector.set_int("errors_sent", count + 1);
+		}
+	}
+
+	fn deliver_error(&self, effector: &mut Effector, info: InternetInfo, message: IcmpMessage, packet: Packet)
+	{
+		match info.protocol {
+			Protocol::Standard(StandardProtocol::TCP) => self.tcp_out.send_payload(effector, "icmp_error", (info, message, packet)),
+			Protocol::Standard(StandardProtocol::UDP) => self.udp_out.send_payload(effector, "icmp_error", (info, message, packet)),
+			_ => log_info!(effector, "ignoring {:?} for {:?}", message, info.protocol)
+		}
+	}
+}
+
+const ICMP: Protocol = Protocol::Standard(StandardProtocol::ICMP);
+
+// See RFC 1122 section 3.2.2. The packet starts with the IPv4 header.
+fn should_send_error(info: &InternetInfo, packet: &Packet) -> bool
+{
+	if let Protocol::Standard(StandardProtocol::ICMP) = info.protocol {
+		let len = header_len(packet);
+		if packet.len() > len {
+			match packet.get(len) {
+				3 | 4 | 5 | 11 | 12 => return false,	// never send errors about errors
+				_ => ()
+			}
+		}
+	}
+
+	if packet.len() >= 8 {
+		let offset = ((packet.get(6) as u16) << 8 | packet.get(7) as u16) & 0x1FFF;
+		if offset != 0 {						// only the first fragment gets errors
+			return false
+		}
+	}
+
+	// Errors are only sent for datagrams that were sent to, and came from, a single host.
+	match (info.src_addr, info.dst_addr) {
+		(IPAddress::IPv4(src), IPAddress::IPv4(dst)) => !is_group(src) && src != [0, 0, 0, 0] && src[0] != 127 && !is_group(dst),
+		_ => true
+	}
+}
+
+// Returns true for the limited broadcast address, multicast, and class E addresses.
+fn is_group(addr: [u8; 4]) -> bool
+{
+	addr[0] >= 224
+}
+
+// Returns the original IPv4 header plus the first 8 bytes of the offending datagram's
+// payload, see RFC 792.
+fn offending_bytes(packet: &Packet) -> Vec<u8>
+{
+	let len = header_len(packet) + 8;
+	packet.iter().take(len).cloned().collect()
+}
+
+fn header_len(packet: &Packet) -> usize
+{
+	if packet.len() > 0 {4*(packet.get(0) & 0x0F) as usize} else {0}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn icmp_header_pushing()
+	{
+		let messages = [
+			IcmpMessage::EchoRequest {identifier: 7, sequence: 3},
+			IcmpMessage::DestinationUnreachable(Unreachable::FragmentationNeeded(576)),
+			IcmpMessage::TimeExceeded(TimeExceeded::Transit),
+		];
+		for &message1 in messages.iter() {
+			let mut packet = Packet::new("test packet", "1");
+			packet.push_back_bytes("hello".as_bytes());		// odd length exercises the checksum padding
+			message1.push(&mut packet);
+
+			match IcmpMessage::pop(&mut packet) {
+				Ok(message2) => assert_eq!(message1, message2),
+				Err(mesg) => assert!(false, "IcmpMessage::pop failed: ".to_string() + &mesg)
+			}
+			assert_eq!(5, packet.len());
+		}
+    }
+
+    #[test]
+    fn quoting()
+	{
+		let mut options = SocketOptions::with_addr(IPAddress::IPv4([10, 0, 1, 2]));
+		options.ttl = 1;
+		options.dont_fragment = true;
+		let mut header = IPv4Header::new(Protocol::Standard(StandardProtocol::UDP), [10, 0, 0, 2], [10, 0, 1, 2], &options);
+		header.identification = 0x1234;
+
+		let mut packet = Packet::new("datagram", "1");
+		packet.push_back_bytes(&[0, 53, 0, 53, 0, 20, 0, 0, 1, 2, 3, 4]);
+		header.push(&mut packet);
+		let original: Vec<u8> = packet.iter().cloned().collect();
+
+		// The original header is quoted as is (TTL, identification, flags, and total length).
+		let info = InternetInfo::new(header.protocol, IPAddress::IPv4(header.src_addr), IPAddress::IPv4(header.dst_addr));
+		assert!(should_send_error(&info, &packet));
+		assert_eq!(offending_bytes(&packet), &original[..28]);
+
+		// Errors are sent for the first fragment but not later ones.
+		let mut fragment = Packet::new("fragment", "3");
+		fragment.push_back_bytes(&[0; 16]);
+		let mut fheader = IPv4Header::new(Protocol::Standard(StandardProtocol::UDP), [10, 0, 0, 2], [10, 0, 1, 2], &options);
+		fheader.dont_fragment = false;
+		fheader.more_fragments = true;
+		fheader.push(&mut fragment);
+		assert!(should_send_error(&info, &fragment));
+
+		let mut fragment = Packet::new("fragment", "4");
+		fragment.push_back_bytes(&[0; 16]);
+		fheader.fragment_offset = 185;
+		fheader.push(&mut fragment);
+		assert!(!should_send_error(&info, &fragment));
+
+		// Or for datagrams from broadcast, multicast, unspecified, or loopback addresses (or to groups).
+		for &src in [[255, 255, 255, 255], [224, 0, 0, 1], [0, 0, 0, 0], [127, 0, 0, 1]].iter() {
+			let ginfo = InternetInfo::new(info.protocol, IPAddress::IPv4(src), info.dst_addr);
+			assert!(!should_send_error(&ginfo, &packet), "sent an error to {:?}", src);
+		}
+		let ginfo = InternetInfo::new(info.protocol, info.src_addr, IPAddress::IPv4([239, 1, 2, 3]));
+		assert!(!should_send_error(&ginfo, &packet));
+
+		// Or for ICMP errors.
+		let mut packet = Packet::new("error", "2");
+		packet.push_back_bytes(&[0; 8]);
+		IcmpMessage::TimeExceeded(TimeExceeded::Transit).push(&mut packet);
+		let header = IPv4Header::new(ICMP, [10, 0, 0, 2], [10, 0, 1, 2], &options);
+		header.push(&mut packet);
+		let info = InternetInfo::new(ICMP, IPAddress::IPv4(header.src_addr), IPAddress::IPv4(header.dst_addr));
+		assert!(!should_send_error(&info, &packet));
+
+		for &kind in [4, 5, 12].iter() {
+			let mut packet = Packet::new("error", "5");
+			packet.push_back_bytes(&[kind, 0, 0, 0, 0, 0, 0, 0]);
+			header.push(&mut packet);
+			assert!(!should_send_error(&info, &packet), "sent an error for type {}", kind);
+		}
+
+		// Other ICMP messages (e.g. echo requests) are fine.
+		let mut packet = Packet::new("echo", "6");
+		IcmpMessage::EchoRequest {identifier: 1, sequence: 1}.push(&mut packet);
+		header.push(&mut packet);
+		assert!(should_send_error(&info, &packet));
+	}
+}
@@ -0,0 +1,614 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::icmp::*;
+use internet::internet::*;
+use internet::ipv6::*;
+use internet::protocol::*;
+use link::*;
+use score::*;
+use std::thread;
+use transport::*;
+
+/// Prefix Information option from a Router Advertisement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrefixInfo
+{
+	pub prefix: [u8; 16],
+	pub prefix_len: u8,
+
+	/// Addresses with the prefix can be reached without going through a router.
+	pub on_link: bool,
+
+	/// The prefix can be used for stateless address autoconfiguration.
+	pub autonomous: bool,
+
+	/// Lifetimes are in seconds.
+	pub valid_lifetime: u32,
+	pub preferred_lifetime: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RouterAdvertisement
+{
+	/// Hop limit hosts should use. Zero means unspecified.
+	pub hop_limit: u8,
+
+	/// Number of seconds the sender can be used as a default router. Zero means it shouldn't be.
+	pub lifetime: u16,
+
+	/// Milliseconds. Zero means unspecified.
+	pub reachable_time: u32,
+	pub retrans_timer: u32,
+
+	pub source_mac: Option<MacAddress>,
+	pub mtu: Option<u32>,
+	pub prefix: Option<PrefixInfo>,
+}
+
+/// In memory version of the ICMPv6 header plus the Neighbor Discovery options we support.
+/// For errors the packet payload is the offending IPv6 header plus as much of its payload
+/// as will fit into the minimum MTU.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Icmpv6Message		// see RFC 4443 and RFC 4861
+{
+	/// Protocol and FragmentationNeeded can't be used: use ParameterProblem and PacketTooBig instead.
+	DestinationUnreachable(Unreachable),
+	PacketTooBig(u32),
+	TimeExceeded(TimeExceeded),
+	ParameterProblem {code: u8, pointer: u32},
+	EchoRequest {identifier: u16, sequence: u16},
+	EchoReply {identifier: u16, sequence: u16},
+	RouterSolicitation {source_mac: Option<MacAddress>},
+	RouterAdvertisement(RouterAdvertisement),
+	NeighborSolicitation {target: [u8; 16], source_mac: Option<MacAddress>},
+	NeighborAdvertisement {target: [u8; 16], router: bool, solicited: bool, override_entry: bool, target_mac: Option<MacAddress>},
+}
+
+impl Icmpv6Message
+{
+	/// Converts an error generated by the internet or transport layers into its ICMPv6 equivalent.
+	/// Pointer is the offset of the offending datagram's last Next Header field (it's used
+	/// for Protocol errors).
+	pub fn with_error(message: IcmpMessage, pointer: u32) -> Self
+	{
+		match message {
+			IcmpMessage::DestinationUnreachable(Unreachable::Protocol) => Icmpv6Message::ParameterProblem {code: 1, pointer},	// unrecognized next header
+			IcmpMessage::DestinationUnreachable(Unreachable::FragmentationNeeded(mtu)) => Icmpv6Message::PacketTooBig(mtu as u32),
+			IcmpMessage::DestinationUnreachable(reason) => Icmpv6Message::DestinationUnreachable(reason),
+			IcmpMessage::TimeExceeded(reason) => Icmpv6Message::TimeExceeded(reason),
+			_ => panic!("{:?} isn't an error", message)
+		}
+	}
+
+	/// Converts errors into the form [`UdpComponent`] and [`TcpComponent`] understand.
+	pub fn to_error(self) -> Option<IcmpMessage>
+	{
+		match self {
+			Icmpv6Message::DestinationUnreachable(reason) => Some(IcmpMessage::DestinationUnreachable(reason)),
+			Icmpv6Message::PacketTooBig(mtu) => Some(IcmpMessage::DestinationUnreachable(Unreachable::FragmentationNeeded(if mtu > 0xFFFF {0xFFFF} else {mtu as u16}))),
+			Icmpv6Message::TimeExceeded(reason) => Some(IcmpMessage::TimeExceeded(reason)),
+			Icmpv6Message::ParameterProblem {code: 1, ..} => Some(IcmpMessage::DestinationUnreachable(Unreachable::Protocol)),
+			_ => None
+		}
+	}
+
+	/// Errors report a problem with a datagram. Everything else is informational.
+	pub fn is_error(self) -> bool
+	{
+		match self {
+			Icmpv6Message::DestinationUnreachable(_) => true,
+			Icmpv6Message::PacketTooBig(_) => true,
+			Icmpv6Message::TimeExceeded(_) => true,
+			Icmpv6Message::ParameterProblem {..} => true,
+			_ => false
+		}
+	}
+
+	/// Returns a name suitable for use with [`Packet`].
+	pub fn name(self) -> &'static str
+	{
+		match self {
+			Icmpv6Message::DestinationUnreachable(_) => "ICMPv6 Destination Unreachable",
+			Icmpv6Message::PacketTooBig(_) => "ICMPv6 Packet Too Big",
+			Icmpv6Message::TimeExceeded(_) => "ICMPv6 Time Exceeded",
+			Icmpv6Message::ParameterProblem {..} => "ICMPv6 Parameter Problem",
+			Icmpv6Message::EchoRequest {..} => "ICMPv6 Echo Request",
+			Icmpv6Message::EchoReply {..} => "ICMPv6 Echo Reply",
+			Icmpv6Message::RouterSolicitation {..} => "Router Solicitation",
+			Icmpv6Message::RouterAdvertisement(_) => "Router Advertisement",
+			Icmpv6Message::NeighborSolicitation {..} => "Neighbor Solicitation",
+			Icmpv6Message::NeighborAdvertisement {..} => "Neighbor Advertisement",
+		}
+	}
+
+	/// Adds an ICMPv6 header to the packet. The info is needed for the pseudo-header checksum.
+	pub fn push(&self, packet: &mut Packet, info: &InternetInfo)
+	{
+		let mut header = Header::with_capacity(8);
+		let (kind, code, rest) = match *self {
+			Icmpv6Message::DestinationUnreachable(reason) => match reason {
+				Unreachable::Network => (1, 0, 0),
+				Unreachable::Host => (1, 3, 0),
+				Unreachable::Port => (1, 4, 0),
+				_ => panic!("{:?} can't be used with ICMPv6 Destination Unreachable", reason)
+			},
+			Icmpv6Message::PacketTooBig(mtu) => (2, 0, mtu),
+			Icmpv6Message::TimeExceeded(TimeExceeded::Transit) => (3, 0, 0),
+			Icmpv6Message::TimeExceeded(TimeExceeded::Reassembly) => (3, 1, 0),
+			Icmpv6Message::ParameterProblem {code, pointer} => (4, code, pointer),
+			Icmpv6Message::EchoRequest {identifier, sequence} => (128, 0, (identifier as u32) << 16 | sequence as u32),
+			Icmpv6Message::EchoReply {identifier, sequence} => (129, 0, (identifier as u32) << 16 | sequence as u32),
+			Icmpv6Message::RouterSolicitation {..} => (133, 0, 0),
+			Icmpv6Message::RouterAdvertisement(ref ra) => (134, 0, (ra.hop_limit as u32) << 24 | ra.lifetime as u32),
+			Icmpv6Message::NeighborSolicitation {..} => (135, 0, 0),
+			Icmpv6Message::NeighborAdvertisement {router, solicited, override_entry, ..} =>
+				(136, 0, (router as u32) << 31 | (solicited as u32) << 30 | (override_entry as u32) << 29),
+		};
+		header.push8(kind);
+		header.push8(code);
+		header.push16(0);			// checksum (this is set for real after we've pushed the rest of the header)
+		header.push32(rest);
+
+		match *self {
+			Icmpv6Message::RouterSolicitation {source_mac} => push_mac_option(&mut header, 1, source_mac),
+			Icmpv6Message::RouterAdvertisement(ref ra) => {
+				header.push32(ra.reachable_time);
+				header.push32(ra.retrans_timer);
+				push_mac_option(&mut header, 1, ra.source_mac);
+				if let Some(mtu) = ra.mtu {
+					header.push8(5);	// MTU option
+					header.push8(1);
+					header.push16(0);
+					header.push32(mtu);
+				}
+				if let Some(ref prefix) = ra.prefix {
+					header.push8(3);	// Prefix Information option
+					header.push8(4);
+					header.push8(prefix.prefix_len);
+					header.push8((prefix.on_link as u8) << 7 | (prefix.autonomous as u8) << 6);
+					header.push32(prefix.valid_lifetime);
+					header.push32(prefix.preferred_lifetime);
+					header.push32(0);
+					header.push_bytes(&prefix.prefix);
+				}
+			},
+			Icmpv6Message::NeighborSolicitation {target, source_mac} => {
+				header.push_bytes(&target);
+				push_mac_option(&mut header, 1, source_mac);
+			},
+			Icmpv6Message::NeighborAdvertisement {target, target_mac, ..} => {
+				header.push_bytes(&target);
+				push_mac_option(&mut header, 2, target_mac);
+			},
+			_ => ()
+		}
+
+		let crc = pseudo_header_checksum(header.data.len() + packet.len(), info);
+		let crc = header.start_checksum(crc);
+		let crc = packet.finish_checksum(packet.len(), crc);
+		header.data[2] = (crc >> 8) as u8;
+		header.data[3] = (crc & 0xFF) as u8;
+
+		packet.push_header(&header);
+	}
+
+	/// Removes an ICMPv6 header (and, for Neighbor Discovery messages, its options) from the packet.
+	pub fn pop(packet: &mut Packet, info: &InternetInfo) -> Result<Icmpv6Message, String>
+	{
+		if packet.len() < 8 {
+			return Err(format!("ICMPv6 packet should be at least 8 bytes but is {}", packet.len()))
+		}
+
+		let crc = pseudo_header_checksum(packet.len(), info);
+		let crc = packet.finish_checksum(packet.len(), crc);
+		if crc != 0 {
+			return Err("Checksum error".to_string())
+		}
+
+		let kind = packet.pop8();
+		let code = packet.pop8();
+		let _ = packet.pop16();		// checksum
+		let rest = packet.pop32();
+
+		let identifier = (rest >> 16) as u16;
+		let sequence = (rest & 0xFFFF) as u16;
+		match (kind, code) {
+			(1, 0) => Ok(Icmpv6Message::DestinationUnreachable(Unreachable::Network)),
+			(1, 3) => Ok(Icmpv6Message::DestinationUnreachable(Unreachable::Host)),
+			(1, 4) => Ok(Icmpv6Message::DestinationUnreachable(Unreachable::Port)),
+			(2, 0) => Ok(Icmpv6Message::PacketTooBig(rest)),
+			(3, 0) => Ok(Icmpv6Message::TimeExceeded(TimeExceeded::Transit)),
+			(3, 1) => Ok(Icmpv6Message::TimeExceeded(TimeExceeded::Reassembly)),
+			(4, _) => Ok(Icmpv6Message::ParameterProblem {code, pointer: rest}),
+			(128, 0) => Ok(Icmpv6Message::EchoRequest {identifier, sequence}),
+			(129, 0) => Ok(Icmpv6Message::EchoReply {identifier, sequence}),
+			(133, 0) => {
+				let options = try!(pop_options(packet));
+				Ok(Icmpv6Message::RouterSolicitation {source_mac: options.source_mac})
+			},
+			(134, 0) => {
+				if packet.len() < 8 {
+					return Err("Router Advertisement is truncated".to_string())
+				}
+				let reachable_time = packet.pop32();
+				let retrans_timer = packet.pop32();
+				let options = try!(pop_options(packet));
+				Ok(Icmpv6Message::RouterAdvertisement(RouterAdvertisement {
+					hop_limit: (rest >> 24) as u8,
+					lifetime: sequence,
+					reachable_time,
+					retrans_timer,
+					source_mac: options.source_mac,
+					mtu: options.mtu,
+					prefix: options.prefix,
+				}))
+			},
+			(135, 0) => {
+				let target = try!(pop_target(packet));
+				let options = try!(pop_options(packet));
+				Ok(Icmpv6Message::NeighborSolicitation {target, source_mac: options.source_mac})
+			},
+			(136, 0) => {
+				let target = try!(pop_target(packet));
+				let options = try!(pop_options(packet));
+				Ok(Icmpv6Message::NeighborAdvertisement {
+					target,
+					router: rest & 0x8000_0000 != 0,
+					solicited: rest & 0x4000_0000 != 0,
+					override_entry: rest & 0x2000_0000 != 0,
+					target_mac: options.target_mac,
+				})
+			},
+			_ => Err(format!("ICMPv6 type {} code {} isn't supported", kind, code))
+		}
+	}
+}
+
+fn push_mac_option(header: &mut Header, kind: u8, mac: Option<MacAddress>)
+{
+	if let Some(mac) = mac {
+		header.push8(kind);			// Source (1) or Target (2) Link-Layer Address option
+		header.push8(1);			// length in 8 byte units
+		header.push_bytes(&mac);
+	}
+}
+
+struct NdOptions
+{
+	source_mac: Option<MacAddress>,
+	target_mac: Option<MacAddress>,
+	mtu: Option<u32>,
+	prefix: Option<PrefixInfo>,
+}
+
+fn pop_target(packet: &mut Packet) -> Result<[u8; 16], String>
+{
+	if packet.len() < 16 {
+		return Err("Neighbor Discovery target address is truncated".to_string())
+	}
+
+	let mut target = [0; 16];
+	for b in target.iter_mut() {
+		*b = packet.pop8();
+	}
+	Ok(target)
+}
+
+// Unknown options are skipped (see RFC 4861 section 4.6).
+fn pop_options(packet: &mut Packet) -> Result<NdOptions, String>
+{
+	let mut options = NdOptions {source_mac: None, target_mac: None, mtu: None, prefix: None};
+	while packet.len() >= 2 {
+		let kind = packet.pop8();
+		let len = 8*(packet.pop8() as usize);
+		if len == 0 {
+			return Err(format!("Neighbor Discovery option {} has zero length", kind))
+		}
+		if len - 2 > packet.len() {
+			return Err(format!("Neighbor Discovery option {} has length {} but only {} bytes are left", kind, len, packet.len() + 2))
+		}
+
+		let mut data = packet.pop_bytes(len - 2);
+		match (kind, len) {
+			(1, 8) => options.source_mac = Some([data[0], data[1], data[2], data[3], data[4], data[5]]),
+			(2, 8) => options.target_mac = Some([data[0], data[1], data[2], data[3], data[4], data[5]]),
+			(3, 32) => {
+				let mut prefix = [0; 16];
+				prefix.copy_from_slice(&data.split_off(14));
+				options.prefix = Some(PrefixInfo {
+					prefix,
+					prefix_len: data[0],
+					on_link: data[1] & 0x80 != 0,
+					autonomous: data[1] & 0x40 != 0,
+					valid_lifetime: to_u32(&data[2..6]),
+					preferred_lifetime: to_u32(&data[6..10]),
+				});
+			},
+			(5, 8) => options.mtu = Some(to_u32(&data[2..6])),
+			_ => ()
+		}
+	}
+	Ok(options)
+}
+
+fn to_u32(data: &[u8]) -> u32
+{
+	(data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+/// Handles ICMPv6 echo and errors for a device. Neighbor Discovery messages are handled
+/// by [`NeighborDiscoveryComponent`] and never make it up here. Errors are sent using the
+/// offending datagram's destination as the source address.
+pub struct Icmpv6Component
+{
+	data: ThreadData,
+
+	/// Listens for "send_down" events. Used by apps to send echo requests.
+	pub upper_in: InPort<(InternetInfo, Icmpv6Message, Packet)>,
+	pub upper_out: OutPort<(InternetInfo, Icmpv6Message, Packet)>,
+
+	/// Listens for "send_error" and "local_error" events. These work the same way as
+	/// they do for [`IcmpComponent`]: "send_error" packets start with the datagram's IPv6
+	/// header (and extension headers) as it was received.
+	pub error_in: InPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Errors for UDP datagrams are sent here using "icmp_error" events.
+	pub udp_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Errors for TCP segments are sent here using "icmp_error" events.
+	pub tcp_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Listens for "send_up" events.
+	pub lower_in: InPort<(InternetInfo, Packet)>,
+	pub lower_out: OutPort<(InternetInfo, SocketOptions, Packet)>,
+}
+
+impl Icmpv6Component
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (id, data) = sim.add_active_component("ICMPv6", parent_id);
+		Icmpv6Component {
+			data: data,
+
+			upper_in: InPort::with_port_name(id, "upper_in"),
+			error_in: InPort::with_port_name(id, "error_in"),
+			lower_out: OutPort::new(),
+
+			lower_in: InPort::with_port_name(id, "lower_in"),
+			upper_out: OutPort::new(),
+			udp_out: OutPort::new(),
+			tcp_out: OutPort::new(),
+		}
+	}
+
+	pub fn start(self)
+	{
+		let mut next_id = 0;
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					effector.set_int("errors_sent", 0);
+				},
+				"send_down" => {
+					let (info, message, packet) = event.take_payload::<(InternetInfo, Icmpv6Message, Packet)>();
+					self.send_message(&mut effector, info, message, packet);
+				},
+				"send_error" => {
+					let (info, message, packet) = event.take_payload::<(InternetInfo, IcmpMessage, Packet)>();
+					if should_send_error(&info, &packet) {
+						let (pointer, _) = next_header_offsets(&packet);
+						let message = Icmpv6Message::with_error(message, pointer as u32);
+						next_id += 1;
+						let mut error = Packet::new(message.name(), &format!("#>{}.{}", self.data.id, next_id));
+						error.push_back_bytes(&offending_bytes(&packet));
+
+						let reply = InternetInfo::new(ICMPV6, info.dst_addr, info.src_addr);
+						self.send_message(&mut effector, reply, message, error);
+
+						let count = state.get_int(self.data.id, "errors_sent");
+						effector.set_int("errors_sent", count + 1);
+					}
+				},
+				"local_error" => {
+					let (info, message, packet) = event.take_payload::<(InternetInfo, IcmpMessage, Packet)>();
+					self.deliver_error(&mut effector, info, message, packet);
+				},
+				"send_up" => {
+					let (info, mut packet) = event.take_payload::<(InternetInfo, Packet)>();
+					match Icmpv6Message::pop(&mut packet, &info) {
+						Ok(Icmpv6Message::EchoRequest {identifier, sequence}) => {
+							let len = packet.len();
+							let mut reply = Packet::new("ICMPv6 Echo Reply", &(packet.id().to_string() + "r"));
+							reply.push_back_bytes(&packet.pop_bytes(len));
+
+							let rinfo = InternetInfo::new(ICMPV6, info.dst_addr, info.src_addr);
+							self.send_message(&mut effector, rinfo, Icmpv6Message::EchoReply {identifier, sequence}, reply);
+						},
+						Ok(message) if message.is_error() => {
+							match IPv6Header::pop(&mut packet) {
+								Ok(header) => {
+									let oinfo = InternetInfo::new(header.protocol, IPAddress::IPv6(header.src_addr), IPAddress::IPv6(header.dst_addr));
+									match message.to_error() {
+										Some(error) => self.deliver_error(&mut effector, oinfo, error, packet),
+										None => log_info!(effector, "ignoring {:?}", message)
+									}
+								},
+								Err(mesg) => log_warning!(effector, "{} had a bad IPv6 header: {}", message.name(), mesg)
+							}
+						},
+						Ok(message) => {
+							self.upper_out.send_payload(&mut effector, "send_up", (info, message, packet));
+						},
+						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
+					}
+				}
+			);
+		});
+	}
+
+	fn send_message(&self, effector: &mut Effector, info: InternetInfo, message: Icmpv6Message, mut packet: Packet)
+	{
+		message.push(&mut packet, &info);
+		let options = SocketOptions::with_addr(info.dst_addr);
+		self.lower_out.send_payload(effector, "send_down", (info, options, packet));
+	}
+
+	fn deliver_error(&self, effector: &mut Effector, info: InternetInfo, message: IcmpMessage, packet: Packet)
+	{
+		match info.protocol {
+			Protocol::Standard(StandardProtocol::TCP) => self.tcp_out.send_payload(effector, "icmp_error", (info, message, packet)),
+			Protocol::Standard(StandardProtocol::UDP) => self.udp_out.send_payload(effector, "icmp_error", (info, message, packet)),
+			_ => log_info!(effector, "ignoring {:?} for {:?}", message, info.protocol)
+		}
+	}
+}
+
+const ICMPV6: Protocol = Protocol::Standard(StandardProtocol::IPV6_ICMP);
+
+// Errors must fit into the minimum IPv6 MTU (1280) along with their own IPv6 and ICMPv6 headers.
+const MAX_OFFENDING_BYTES: usize = 1280 - 40 - 8;
+
+// See RFC 4443 section 2.4. The packet starts with the IPv6 header.
+fn should_send_error(info: &InternetInfo, packet: &Packet) -> bool
+{
+	if let Protocol::Standard(StandardProtocol::IPV6_ICMP) = info.protocol {
+		let (_, offset) = next_header_offsets(packet);
+		if packet.len() > offset && packet.get(offset) < 128 {	// never send errors about errors
+			return false
+		}
+	}
+
+	match info.dst_addr {
+		IPAddress::IPv6(addr) => !is_ipv6_multicast(addr),
+		IPAddress::IPv4(_) => panic!("Icmpv6Component can't send errors for IPv4 datagrams")
+	}
+}
+
+// Returns the original IPv6 header plus as much of the offending datagram as will fit.
+fn offending_bytes(packet: &Packet) -> Vec<u8>
+{
+	packet.iter().take(MAX_OFFENDING_BYTES).cloned().collect()
+}
+
+// Walks the extension header chain of a packet starting with an IPv6 header. Returns the
+// offset of the last Next Header field (i.e. the one naming the upper layer protocol)
+// and the offset of the upper layer header.
+fn next_header_offsets(packet: &Packet) -> (usize, usize)
+{
+	let mut field = 6;
+	let mut offset = 40;
+	while packet.len() > field && is_extension(Protocol::from_u8(packet.get(field))) && packet.len() >= offset + 2 {
+		field = offset;
+		offset += 8*(packet.get(offset + 1) as usize + 1);
+	}
+	(field, offset)
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn icmpv6_header_pushing()
+	{
+		let src = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+		let dst = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+		let info = InternetInfo::new(ICMPV6, IPAddress::IPv6(src), IPAddress::IPv6(dst));
+
+		let prefix = PrefixInfo {
+			prefix: [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+			prefix_len: 64,
+			on_link: true,
+			autonomous: true,
+			valid_lifetime: 86400,
+			preferred_lifetime: 14400,
+		};
+		let messages = [
+			Icmpv6Message::EchoRequest {identifier: 7, sequence: 3},
+			Icmpv6Message::PacketTooBig(1280),
+			Icmpv6Message::RouterAdvertisement(RouterAdvertisement {
+				hop_limit: 64,
+				lifetime: 1800,
+				reachable_time: 0,
+				retrans_timer: 0,
+				source_mac: Some([0x02, 0, 0, 0, 0, 1]),
+				mtu: Some(1500),
+				prefix: Some(prefix),
+			}),
+			Icmpv6Message::NeighborSolicitation {target: src, source_mac: Some([0x02, 0, 0, 0, 0, 2])},
+			Icmpv6Message::NeighborAdvertisement {target: src, router: false, solicited: true, override_entry: true, target_mac: Some([0x02, 0, 0, 0, 0, 1])},
+		];
+		for &message1 in messages.iter() {
+			let mut packet = Packet::new("test packet", "1");
+			if !message1.name().contains("Neighbor") && !message1.name().contains("Router") {
+				packet.push_back_bytes("hello".as_bytes());		// odd length exercises the checksum padding
+			}
+			message1.push(&mut packet, &info);
+
+			match Icmpv6Message::pop(&mut packet, &info) {
+				Ok(message2) => assert_eq!(message1, message2),
+				Err(mesg) => assert!(false, "Icmpv6Message::pop failed: ".to_string() + &mesg)
+			}
+		}
+    }
+
+    #[test]
+    fn quoting()
+	{
+		let src = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+		let dst = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+		let mut options = SocketOptions::with_addr(IPAddress::IPv6(dst));
+		options.ttl = 7;
+		let mut header = IPv6Header::new(Protocol::Standard(StandardProtocol::EXPERIMENTAL1), src, dst, &options);
+		header.flow_label = 0x12345;
+		header.extensions.push(IPv6Extension::new(Protocol::Standard(StandardProtocol::HOPOPT), vec![1, 4, 0, 0, 0, 0]));
+		header.extensions.push(IPv6Extension::new(Protocol::Standard(StandardProtocol::IPV6_OPTS), vec![1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+
+		let mut packet = Packet::new("datagram", "1");
+		packet.push_back_bytes(&[0xAA; 2000]);
+		header.push(&mut packet);
+		let original: Vec<u8> = packet.iter().cloned().collect();
+
+		// The original header is quoted (hop limit, flow label, extensions, etc).
+		let info = InternetInfo::new(header.protocol, IPAddress::IPv6(src), IPAddress::IPv6(dst));
+		assert!(should_send_error(&info, &packet));
+		let quoted = offending_bytes(&packet);
+		assert_eq!(1280 - 40 - 8, quoted.len());
+		assert_eq!(&original[..quoted.len()], &quoted[..]);
+
+		// The pointer is to the Next Header field of the last extension header.
+		assert_eq!((48, 64), next_header_offsets(&packet));
+		match Icmpv6Message::with_error(IcmpMessage::DestinationUnreachable(Unreachable::Protocol), 48) {
+			Icmpv6Message::ParameterProblem {code: 1, pointer: 48} => (),
+			message => panic!("expected a parameter problem not {:?}", message)
+		}
+
+		// Without extensions it's the IPv6 header's Next Header field.
+		let mut packet = Packet::new("error", "2");
+		packet.push_back_bytes(&[0; 8]);
+		let info = InternetInfo::new(ICMPV6, IPAddress::IPv6(src), IPAddress::IPv6(dst));
+		Icmpv6Message::TimeExceeded(TimeExceeded::Transit).push(&mut packet, &info);
+		IPv6Header::with_internet(&info, &options).push(&mut packet);
+		assert_eq!((6, 40), next_header_offsets(&packet));
+
+		// Errors aren't sent for errors.
+		assert!(!should_send_error(&info, &packet));
+	}
+}
@@ -14,10 +14,14 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 use common::*;
+use internet::fragments::*;
+use internet::icmp::*;
 use internet::internet::*;
 use internet::protocol::*;
+use internet::routing::*;
 use link::*;
 use score::*;
+use std::collections::HashMap;
 //use std::str;
 use std::thread;
 use std::u16;
@@ -33,6 +37,7 @@ pub enum ECN
 }
 
 /// In memory version of the IPv4 header. When serialized to a [`Packet`] it's packed according to the spec.
+#[derive(Clone)]
 pub struct IPv4Header
 {
 	/// TCP, UDP, IGMP, OSPF, etc.
@@ -60,7 +65,7 @@ pub struct IPv4Header
 	// Set for fragmented packets (except for the very last fragment).
 	pub more_fragments: bool,
 	
-	/// The offset (in bytes) of the fragment within the original packet.
+	/// The offset (in 8 byte units) of the fragment within the original packet.
 	pub fragment_offset: u16,
 	
 	/// Used to re-assemble fragmented packets.
@@ -68,13 +73,13 @@ pub struct IPv4Header
 }
 
 // See the DSCP section of https://wireless.wiki.kernel.org/en/developers/documentation/mac80211/queues
-fn qos_to_dscp(qos: QoS) -> u8
+pub fn qos_to_dscp(qos: QoS) -> u8
 {
 	match qos {
-		QoS::Background => 0b001 << 2,	// TODO: lower two bits are ECN
-		QoS::BestEffort => 0b000 << 2,
-		QoS::Video      => 0b100 << 2,
-		QoS::Voice      => 0b110 << 2,
+		QoS::Background => 0b001 << 3,	// class selectors, see RFC 2474
+		QoS::BestEffort => 0b000 << 3,
+		QoS::Video      => 0b100 << 3,
+		QoS::Voice      => 0b110 << 3,
 	}
 }
 
@@ -121,7 +126,7 @@ impl IPv4Header
 					IPAddress::IPv6(_) => panic!("InternetInfo has mixed IPv4 and IPv6 addresses")
 				}
 			},
-			IPAddress::IPv6(_) => panic!("IPv6 isn't supported yet")
+			IPAddress::IPv6(_) => panic!("IPv4Header requires IPv4 addresses (use IPv6Header for IPv6)")
 		}
 	}
 
@@ -144,8 +149,8 @@ impl IPv4Header
 
 		header.push16(self.identification);	// identification
 
-		assert!(self.fragment_offset < 8192);	// flags + frag offset
-		let hw = (self.more_fragments as u16) << 15 | (self.dont_fragment as u16) << 14 | self.fragment_offset;
+		assert!(self.fragment_offset < 8192);	// flags (reserved, DF, MF) + frag offset
+		let hw = (self.dont_fragment as u16) << 14 | (self.more_fragments as u16) << 13 | self.fragment_offset;
 		//println!("pushed {}", hw);
 		header.push16(hw);
 	
@@ -202,16 +207,19 @@ impl IPv4Header
 		};
 
 		let total_length = packet.pop16() as usize;
-		if total_length != in_len {
+		if total_length > in_len {
 			return Err(format!("IPv4Header.total_length should be {} but is {}", in_len, total_length))
 		}
+		for _ in total_length..in_len {
+			let _ = packet.pop_back8();		// ethernet padding
+		}
 
 		let identification = packet.pop16();
 
 		let hw = packet.pop16();
-		let more_fragments = hw & 0x8000 != 0;
+		let reserved = hw & 0x8000 != 0;
 		let dont_fragment = hw & 0x4000 != 0;
-		let reserved = hw & 0x2000 != 0;
+		let more_fragments = hw & 0x2000 != 0;
 		let fragment_offset = hw & 0x1FFF;
 		if reserved {
 			return Err(format!("IPv4Header.flags has bit 0 set"))
@@ -246,17 +254,47 @@ impl IPv4Header
 
 /// Pushes an IPv4Header onto packets moving down the network stack.
 /// Pops off an IPv4Header header for packets moving up the stack.
+/// Each interface has its own pair of lower ports and outgoing packets use the interface
+/// chosen by the routing table. Packets too large for the interface's MTU are fragmented
+/// and fragments are re-assembled before they are sent up. ICMP packets are sent to
+/// icmp_out and TCP packets to tcp_out instead of upper_out and problems are reported using error_out. If forwarding
+/// is enabled packets addressed to other devices are routed out the appropriate interface.
+/// There is one component setting:
+/// - **reassembly_timeout** is the number of seconds to wait for missing fragments. Defaults to 30.0.
 pub struct IPv4Component
 {
 	data: ThreadData,
 
+	/// Use add_interface to add these.
+	pub interfaces: Vec<IPv4Interface>,
+
+	/// Static routes. Routes for each interface's subnet are added when the component starts.
+	pub routes: RoutingTable,
+
+	/// If set then this acts as a router. Defaults to false.
+	pub forwarding: bool,
+
 	/// Listens for "send_down" events.
 	pub upper_in: InPort<(InternetInfo, SocketOptions, Packet)>,	
 	pub upper_out: OutPort<(InternetInfo, Packet)>,
+	pub icmp_out: OutPort<(InternetInfo, Packet)>,
+	pub tcp_out: OutPort<(InternetInfo, Packet)>,
+
+	/// Sends "send_error" and "local_error" events to [`IcmpComponent`]. "send_error" packets
+	/// start with the offending datagram's IPv4 header.
+	pub error_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
 
-	/// Listens for "send_up" events.
-	pub lower_in: InPort<(LinkInfo, Packet)>,
-	pub lower_out: OutPort<(IPv4Header, Packet)>,
+	/// Sends "forward_error" events to [`IcmpComponent`] for datagrams that couldn't be
+	/// forwarded. The address is that of the interface the datagram arrived on and the
+	/// packet starts with the datagram's IPv4 header.
+	pub forward_out: OutPort<(InternetInfo, IcmpMessage, [u8; 4], Packet)>,
+
+	/// Listens for "send_up" events. There is one of these for each interface.
+	pub lower_ins: Vec<InPort<(LinkInfo, Packet)>>,
+
+	/// The address is the next hop, i.e. the gateway or, if the destination is on the
+	/// same subnet, the destination. There is one of these for each interface.
+	pub lower_outs: Vec<OutPort<(IPv4Header, [u8; 4], Packet)>>,
 }
 
 impl IPv4Component
@@ -266,40 +304,258 @@ impl IPv4Component
 		let (id, data) = sim.add_active_component("IPv4", parent_id);
 		IPv4Component {
 			data: data,
+			interfaces: Vec::new(),
+			routes: RoutingTable::new(),
+			forwarding: false,
 
 			upper_in: InPort::with_port_name(id, "upper_in"),
-			lower_out: OutPort::new(),
+			lower_outs: Vec::new(),
 
-			lower_in: InPort::with_port_name(id, "lower_in"),
+			lower_ins: Vec::new(),
 			upper_out: OutPort::new(),
+			icmp_out: OutPort::new(),
+			tcp_out: OutPort::new(),
+			error_out: OutPort::new(),
+			forward_out: OutPort::new(),
 		}
 	}
+
+	/// Returns the index of the new interface (which is also the index of its lower ports).
+	pub fn add_interface(&mut self, interface: IPv4Interface) -> usize
+	{
+		let index = self.interfaces.len();
+		self.interfaces.push(interface);
+		self.lower_ins.push(InPort::with_port_name(self.data.id, &format!("lower_in_{}", index)));
+		self.lower_outs.push(OutPort::new());
+		index
+	}
 	
-	pub fn start(self)
+	pub fn start(mut self)
 	{		
+		for i in 0..self.interfaces.len() {
+			let interface = self.interfaces[i];
+			self.routes.add_connected(&interface, i);
+		}
+
+		let mut reassembly_timeout = 30.0;
+		let mut identifications: HashMap<[u8; 4], u16> = HashMap::new();
+		let mut reassembler = IPv4Reassembler::new();
+
 		thread::spawn(move || {
 			process_events!(self.data, event, state, effector,
 				"init 0" => {
+					reassembly_timeout = float_setting(self.data.id, &state, &mut effector, "reassembly_timeout", reassembly_timeout);
+					effector.set_string("routes", &format!("{:?}", self.routes));
+					for i in 0..self.interfaces.len() {
+						effector.set_int(&format!("rx_packets_{}", i), 0);
+						effector.set_int(&format!("tx_packets_{}", i), 0);
+					}
+					if self.forwarding {
+						effector.set_int("forwarded", 0);
+						effector.set_int("ttl_exceeded", 0);
+					}
 				},
 				"send_down" => {
-					let (iinfo, options, mut packet) = event.take_payload::<(InternetInfo, SocketOptions, Packet)>();
-					let header = IPv4Header::with_internet(&iinfo, &options);
-					header.push(&mut packet);
-					self.lower_out.send_payload(&mut effector, &event.name, (header, packet));
+					let (iinfo, options, packet) = event.take_payload::<(InternetInfo, SocketOptions, Packet)>();
+					let mut header = IPv4Header::with_internet(&iinfo, &options);
+
+					// Identification only matters for fragments but Linux and BSD set it for
+					// all packets so we do too.
+					let identification = identifications.entry(header.src_addr).or_insert(0);
+					header.identification = *identification;
+					*identification = identification.wrapping_add(1);
+
+					self.route(&mut effector, &state, header, packet, None);
 				},
 				"send_up" => {
+					assert!(!event.port_name.is_empty());
+					let ingress = self.lower_ins.iter().position(|p| p.target_port == event.port_name).expect("send_up should arrive on a lower_in port");
+					self.increment(&mut effector, &state, &format!("rx_packets_{}", ingress));
+
 					let (_, mut packet) = event.take_payload::<(LinkInfo, Packet)>();
 					match IPv4Header::pop(&mut packet) {
 						Ok(header) => {
-							let iinfo = InternetInfo::new(header.protocol, IPAddress::IPv4(header.src_addr), IPAddress::IPv4(header.dst_addr));
-							self.upper_out.send_payload(&mut effector, &event.name, (iinfo, packet));
+							if !self.is_local(header.dst_addr) {
+								if self.forwarding {
+									self.forward(&mut effector, &state, header, packet, ingress);
+								} else {
+									log_debug!(effector, "ignoring {} (not addressed to us)", packet.name());
+								}
+
+							} else if header.more_fragments || header.fragment_offset > 0 {
+								let num_pending = reassembler.len();
+								match reassembler.add(header, packet, state.time + reassembly_timeout) {
+									Ok(Some((header, packet))) => {
+										// Keep the header in the packet so that upper layers can quote it.
+										let mut packet = with_header(&header, &packet);
+										let _ = packet.pop_bytes(20);
+										self.send_up(&mut effector, &header, packet)
+									},
+									Ok(None) => {
+										if reassembler.len() > num_pending {
+											let event = Event::new("reassembly_timeout");
+											effector.schedule_after_secs(event, self.data.id, reassembly_timeout);
+										}
+									},
+									Err(mesg) => log_warning!(effector, "reassembly failed: {}", mesg)
+								}
+							} else {
+								self.send_up(&mut effector, &header, packet);
+							}
 						},
 						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
 					}
+				},
+				"reassembly_timeout" => {
+					for (header, packet) in reassembler.expire(state.time) {
+						log_info!(effector, "dropping {} from {:?} (fragment reassembly timed out)", packet.name(), header.src_addr);
+
+						let info = InternetInfo::new(header.protocol, IPAddress::IPv4(header.src_addr), IPAddress::IPv4(header.dst_addr));
+						let message = IcmpMessage::TimeExceeded(TimeExceeded::Reassembly);
+						self.error_out.send_payload(&mut effector, "send_error", (info, message, with_header(&header, &packet)));
+					}
 				}
 			);
 		});
 	}
+
+	/// Returns true if packets sent to addr should be delivered to this device.
+	fn is_local(&self, addr: [u8; 4]) -> bool
+	{
+		addr == [255, 255, 255, 255] || self.interfaces.iter().any(|i| i.addr == addr || i.broadcast_addr() == addr)
+	}
+
+	fn forward(&self, effector: &mut Effector, state: &SimState, mut header: IPv4Header, packet: Packet, ingress: usize)
+	{
+		if header.ttl <= 1 {
+			log_info!(effector, "dropping {} (TTL expired)", packet.name());
+			self.increment(effector, state, "ttl_exceeded");
+
+			let message = IcmpMessage::TimeExceeded(TimeExceeded::Transit);
+			self.forward_error(effector, &header, message, &packet, ingress);
+
+		} else {
+			header.ttl -= 1;		// the checksum is recomputed when the header is pushed
+			self.increment(effector, state, "forwarded");
+			self.route(effector, state, header, packet, Some(ingress));
+		}
+	}
+
+	// Ingress is the interface forwarded packets arrived on. Forwarded packets report errors
+	// to the packet's source. Otherwise the packet originated with us so errors are delivered
+	// locally.
+	fn route(&self, effector: &mut Effector, state: &SimState, header: IPv4Header, packet: Packet, ingress: Option<usize>)
+	{
+		if header.dst_addr == [255, 255, 255, 255] {
+			if ingress.is_none() {
+				for i in 0..self.interfaces.len() {		// limited broadcasts go out every interface
+					self.send_down(effector, state, header.clone(), packet.clone(), i, header.dst_addr, ingress);
+				}
+			}
+
+		} else if let Some(route) = self.routes.lookup(header.dst_addr) {
+			let mut next_hop = route.next_hop(header.dst_addr);
+			if next_hop == self.interfaces[route.interface].broadcast_addr() {
+				next_hop = [255, 255, 255, 255];	// so that ARP knows to use the broadcast MAC address
+			}
+			self.send_down(effector, state, header.clone(), packet, route.interface, next_hop, ingress);
+
+		} else {
+			log_info!(effector, "dropping {} (no route to {:?})", packet.name(), header.dst_addr);
+			self.increment(effector, state, "num_dropped");
+
+			let message = IcmpMessage::DestinationUnreachable(Unreachable::Network);
+			self.routing_error(effector, &header, message, packet, ingress);
+		}
+	}
+
+	fn send_down(&self, effector: &mut Effector, state: &SimState, header: IPv4Header, mut packet: Packet, interface: usize, next_hop: [u8; 4], ingress: Option<usize>)
+	{
+		let mtu = self.interfaces[interface].mtu;
+		let port = &self.lower_outs[interface];
+		if 20 + packet.len() <= mtu {
+			header.push(&mut packet);
+			port.send_payload(effector, "send_down", (header, next_hop, packet));
+			self.increment(effector, state, &format!("tx_packets_{}", interface));
+
+		} else if header.dont_fragment {
+			log_info!(effector, "dropping {} ({} bytes is larger than the {} byte MTU and DF is set)", packet.name(), 20 + packet.len(), mtu);
+			self.increment(effector, state, "num_dropped");
+
+			let message = IcmpMessage::DestinationUnreachable(Unreachable::FragmentationNeeded(mtu as u16));
+			self.routing_error(effector, &header, message, packet, ingress);
+
+		} else {
+			let fragments = fragment_ipv4(&header, &packet, mtu);
+			self.increment_by(effector, state, &format!("tx_packets_{}", interface), fragments.len() as i64);
+			for (fheader, mut fragment) in fragments {
+				fheader.push(&mut fragment);
+				port.send_payload(effector, "send_down", (fheader, next_hop, fragment));
+			}
+		}
+	}
+
+	fn send_up(&self, effector: &mut Effector, header: &IPv4Header, packet: Packet)
+	{
+		let iinfo = InternetInfo::new(header.protocol, IPAddress::IPv4(header.src_addr), IPAddress::IPv4(header.dst_addr));
+		match header.protocol {
+			Protocol::Standard(StandardProtocol::ICMP) => self.icmp_out.send_payload(effector, "send_up", (iinfo, packet)),
+			Protocol::Standard(StandardProtocol::TCP) => self.tcp_out.send_payload(effector, "send_up", (iinfo, packet)),
+			Protocol::Standard(StandardProtocol::UDP) => self.upper_out.send_payload(effector, "send_up", (iinfo, packet)),
+			_ => {
+				let message = IcmpMessage::DestinationUnreachable(Unreachable::Protocol);
+				self.error_out.send_payload(effector, "send_error", (iinfo, message, with_header(header, &packet)));
+			}
+		}
+	}
+
+	// Errors for packets we're forwarding go to their source. Otherwise the packet originated
+	// with us so the error is delivered locally.
+	fn routing_error(&self, effector: &mut Effector, header: &IPv4Header, message: IcmpMessage, packet: Packet, ingress: Option<usize>)
+	{
+		match ingress {
+			Some(ingress) => {
+				let mut received = header.clone();
+				received.ttl += 1;		// undo the decrement from forward
+				self.forward_error(effector, &received, message, &packet, ingress);
+			},
+			None => {
+				let info = InternetInfo::new(header.protocol, IPAddress::IPv4(header.src_addr), IPAddress::IPv4(header.dst_addr));
+				self.error_out.send_payload(effector, "local_error", (info, message, packet));
+			}
+		}
+	}
+
+	// Header should be the header as it was received.
+	fn forward_error(&self, effector: &mut Effector, header: &IPv4Header, message: IcmpMessage, packet: &Packet, ingress: usize)
+	{
+		let info = InternetInfo::new(header.protocol, IPAddress::IPv4(header.src_addr), IPAddress::IPv4(header.dst_addr));
+		let addr = self.interfaces[ingress].addr;
+		self.forward_out.send_payload(effector, "forward_error", (info, message, addr, with_header(header, packet)));
+	}
+
+	fn increment(&self, effector: &mut Effector, state: &SimState, name: &str)
+	{
+		self.increment_by(effector, state, name, 1);
+	}
+
+	// Note that state isn't updated until after the event is processed so this should
+	// only be called once per counter per event.
+	fn increment_by(&self, effector: &mut Effector, state: &SimState, name: &str, delta: i64)
+	{
+		let count = if state.contains(self.data.id, name) {state.get_int(self.data.id, name)} else {0};
+		effector.set_int(name, count + delta);
+	}
+}
+
+// Returns a copy of the packet with the header pushed onto it.
+fn with_header(header: &IPv4Header, packet: &Packet) -> Packet
+{
+	let bytes: Vec<u8> = packet.iter().cloned().collect();
+	let mut result = Packet::new(packet.name(), packet.id());
+	result.push_back_bytes(&bytes);
+	header.push(&mut result);
+	result
 }
 
 mod tests
@@ -352,4 +608,32 @@ mod tests
 			Err(mesg) => assert!(false, format!("IPv4Header::pop_payload failed: {}", mesg))
 		}
     }
+
+    #[test]
+    fn fragment_flags()
+	{
+		let options = SocketOptions::with_addr(IPAddress::IPv4([10, 0, 0, 2]));
+		let mut header1 = IPv4Header::new(EXPERIMENTAL1, [10, 0, 0, 1], [10, 0, 0, 2], &options);
+		header1.more_fragments = true;
+		header1.fragment_offset = 185;		// 1480 bytes
+
+		// On the wire the flags are reserved (0x8000), DF (0x4000), and MF (0x2000).
+		let mut packet = Packet::new("fragment", "1");
+		header1.push(&mut packet);
+		assert_eq!(packet.get(6), 0x20);
+		assert_eq!(packet.get(7), 185);
+
+		let header2 = IPv4Header::pop(&mut packet).unwrap();
+		assert!(header2.more_fragments);
+		assert!(!header2.dont_fragment);
+		assert_eq!(header2.fragment_offset, 185);
+
+		header1.more_fragments = false;
+		header1.dont_fragment = true;
+		header1.fragment_offset = 0;
+		let mut packet = Packet::new("whole", "2");
+		header1.push(&mut packet);
+		assert_eq!(packet.get(6), 0x40);
+		assert_eq!(packet.get(7), 0);
+	}
 }
@@ -0,0 +1,546 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::icmp::*;
+use internet::internet::*;
+use internet::ipv4::*;
+use internet::neighbor_discovery::*;
+use internet::protocol::*;
+use internet::routing::*;
+use link::*;
+use score::*;
+use std::thread;
+use std::u16;
+use transport::*;
+
+/// An IPv6 extension header, e.g. Hop-by-Hop Options or Fragment. The next header and
+/// length fields are filled in when the header is pushed.
+#[derive(Clone, Debug)]
+pub struct IPv6Extension
+{
+	/// HOPOPT, IPV6_ROUTE, IPV6_FRAG, or IPV6_OPTS.
+	pub kind: Protocol,
+
+	/// Everything after the length field. This must be 6 bytes plus a multiple of 8.
+	pub data: Vec<u8>,
+}
+
+impl IPv6Extension
+{
+	pub fn new(kind: Protocol, data: Vec<u8>) -> Self
+	{
+		assert!(is_extension(kind), "{:?} isn't an IPv6 extension header", kind);
+		assert!(data.len() >= 6 && (data.len() - 6) % 8 == 0, "extension data must be 6 bytes plus a multiple of 8 not {}", data.len());
+		IPv6Extension {kind, data}
+	}
+}
+
+/// In memory version of the IPv6 header and its extension headers. When serialized to a
+/// [`Packet`] it's packed according to RFC 8200.
+#[derive(Clone, Debug)]
+pub struct IPv6Header
+{
+	/// The upper layer protocol, i.e. the next header after all the extension headers.
+	pub protocol: Protocol,
+
+	/// The sender of the packet.
+	pub src_addr: [u8; 16],
+	
+	/// The destination of the packet.
+	pub dst_addr: [u8; 16],
+
+	/// Differentiated services field. Same as for IPv4.
+	pub dscp: u8,
+	
+	/// Explicit congestion notification field.
+	pub ecn: ECN,
+
+	/// Identifies packets that should be handled the same way by routers. Only the low 20 bits are used.
+	pub flow_label: u32,
+
+	/// Maximum number of hops the packet is allowed to travel.
+	pub hop_limit: u8,
+
+	/// Extension headers in the order they appear in the packet.
+	pub extensions: Vec<IPv6Extension>,
+}
+
+// See https://en.wikipedia.org/wiki/IPv6_packet
+impl IPv6Header
+{
+	pub fn new(protocol: Protocol, src_addr: [u8; 16], dst_addr: [u8; 16], options: &SocketOptions) -> Self
+	{	
+		assert!(protocol.is_valid(), "{:?} isn't a valid protocol", protocol);
+
+		IPv6Header {
+			protocol,
+			src_addr,
+			dst_addr,
+			dscp: qos_to_dscp(options.qos),
+			ecn: ECN::NotCapable,
+			flow_label: 0,
+			hop_limit: options.ttl,
+			extensions: Vec::new(),
+		}
+	}
+
+	pub fn with_internet(info: &InternetInfo, options: &SocketOptions) -> IPv6Header
+	{	
+		match info.dst_addr {
+			IPAddress::IPv6(dst_addr) => {
+				match info.src_addr {
+					IPAddress::IPv6(src_addr) => IPv6Header::new(info.protocol, src_addr, dst_addr, options),
+					IPAddress::IPv4(_) => panic!("InternetInfo has mixed IPv4 and IPv6 addresses")
+				}
+			},
+			IPAddress::IPv4(_) => panic!("IPv6Header requires IPv6 addresses")
+		}
+	}
+
+	/// Number of bytes the header will take up once it's pushed.
+	pub fn len(&self) -> usize
+	{
+		40 + self.extensions.iter().map(|e| 2 + e.data.len()).sum::<usize>()
+	}
+
+	/// Adds the IPv6 header and its extension headers to the packet.
+	pub fn push(&self, packet: &mut Packet)
+	{
+		// Extension headers are chained using their next header fields so we push them
+		// starting with the one closest to the payload.
+		let mut next_header = self.protocol.as_u8();
+		for extension in self.extensions.iter().rev() {
+			let mut header = Header::with_capacity(2 + extension.data.len());
+			header.push8(next_header);
+			header.push8(((extension.data.len() - 6)/8) as u8);	// length in 8 byte units not counting the first 8 bytes
+			header.push_bytes(&extension.data);
+			packet.push_header(&header);
+
+			next_header = extension.kind.as_u8();
+		}
+
+		let payload_len = packet.len();
+		assert!(payload_len <= u16::MAX as usize);	// we don't support jumbograms
+		let mut header = Header::with_capacity(40);
+
+		assert!(self.dscp < 64);
+		assert!(self.flow_label < 1 << 20);
+		let traffic_class = (self.dscp << 2 | (self.ecn as u8)) as u32;
+		header.push32(6 << 28 | traffic_class << 20 | self.flow_label);	// version + traffic class + flow label
+
+		header.push16(payload_len as u16);	// payload length (includes extension headers)
+		header.push8(next_header);
+		header.push8(self.hop_limit);
+		header.push_bytes(&self.src_addr);
+		header.push_bytes(&self.dst_addr);
+
+		packet.push_header(&header);
+	}
+
+	/// Removes an IPv6 header and its extension headers from the packet.
+	pub fn pop(packet: &mut Packet) -> Result<IPv6Header, String>
+	{
+		let in_len = packet.len();
+		if in_len < 40 {
+			return Err(format!("IPv6 packets should be at least 40 bytes not {}", in_len))
+		}
+
+		let w = packet.pop32();
+		let version = w >> 28;
+		if version != 6 {				// there's no header checksum so this is our main sanity check
+			return Err(format!("IPv6Header.version should be 6 not {}", version))
+		}
+
+		let traffic_class = (w >> 20 & 0xFF) as u8;
+		let dscp = traffic_class >> 2;
+		let ecn = match traffic_class & 0x3 {
+			0 => ECN::NotCapable,
+			1 => ECN::Capable0,
+			2 => ECN::Capable1,
+			3 => ECN::Congested,
+			_ => panic!("should never get a value larger than 3 from 2 bits")
+		};
+		let flow_label = w & 0xFFFFF;
+
+		let payload_len = packet.pop16() as usize;
+		if 40 + payload_len > in_len {
+			return Err(format!("IPv6Header.payload_length should be at most {} but is {}", in_len - 40, payload_len))
+		}
+
+		let mut next_header = Protocol::from_u8(packet.pop8());
+		let hop_limit = packet.pop8();
+		let src_addr = pop_addr(packet);
+		let dst_addr = pop_addr(packet);
+
+		for _ in payload_len..packet.len() {
+			let _ = packet.pop_back8();		// ethernet padding
+		}
+
+		let mut extensions = Vec::new();
+		while is_extension(next_header) {
+			if packet.len() < 8 {
+				return Err(format!("IPv6 {:?} extension header is truncated", next_header))
+			}
+			let kind = next_header;
+			next_header = Protocol::from_u8(packet.pop8());
+
+			let len = 8*(packet.pop8() as usize) + 6;
+			if len > packet.len() {
+				return Err(format!("IPv6 {:?} extension header has length {} but only {} bytes are left", kind, len, packet.len()))
+			}
+			extensions.push(IPv6Extension {kind, data: packet.pop_bytes(len)});
+		}
+		if let Protocol::Standard(StandardProtocol::RESERVED) = next_header {
+			return Err(format!("IPv6Header.next_header is using the RESERVED protocol"))
+		}
+
+		let header = IPv6Header {
+			protocol: next_header,
+			src_addr,
+			dst_addr,
+			dscp,
+			ecn,
+			flow_label,
+			hop_limit,
+			extensions,
+		};
+		Ok(header)
+	}
+}
+
+/// Returns true for the extension header types we know how to chain through.
+pub fn is_extension(protocol: Protocol) -> bool
+{
+	match protocol {
+		Protocol::Standard(StandardProtocol::HOPOPT) => true,
+		Protocol::Standard(StandardProtocol::IPV6_ROUTE) => true,
+		Protocol::Standard(StandardProtocol::IPV6_FRAG) => true,
+		Protocol::Standard(StandardProtocol::IPV6_OPTS) => true,
+		_ => false
+	}
+}
+
+/// Returns the bytes of the IPv6 header (including extension headers) that was popped off
+/// packet. Used by upper layers to quote the header in ICMPv6 errors.
+pub fn popped_ipv6_header(packet: &Packet) -> Option<Vec<u8>>
+{
+	// Extension headers are a multiple of 8 bytes so we look for a header whose payload
+	// length covers exactly the rest of the packet.
+	let mut len = 40;
+	while let Some(bytes) = packet.popped_bytes(len) {
+		let payload_len = (bytes[4] as usize) << 8 | bytes[5] as usize;
+		if bytes[0] >> 4 == 6 && payload_len == len - 40 + packet.len() {
+			return Some(bytes)
+		}
+		len += 8;
+	}
+	None
+}
+
+/// Returns true for ff00::/8 addresses.
+pub fn is_ipv6_multicast(addr: [u8; 16]) -> bool
+{
+	addr[0] == 0xFF
+}
+
+fn pop_addr(packet: &mut Packet) -> [u8; 16]
+{
+	let mut addr = [0; 16];
+	for b in addr.iter_mut() {
+		*b = packet.pop8();
+	}
+	addr
+}
+
+/// Pushes an IPv6Header onto packets moving down the network stack.
+/// Pops off an IPv6Header header for packets moving up the stack.
+/// Each interface has its own pair of lower ports. Packets for an interface's subnet are
+/// sent directly to their destination, multicast packets go out every interface, and
+/// everything else is sent to the default router. IPv6 routers don't fragment packets
+/// and we don't fragment locally either so packets too large for the MTU are dropped.
+/// ICMPv6 packets are sent to icmp_out and TCP packets to tcp_out instead of upper_out
+/// and problems are reported using error_out. Addresses and the default router can also
+/// be configured at runtime by [`NeighborDiscoveryComponent`] using "configured" events.
+/// Per-interface packet counts are maintained like they are for [`IPv4Component`].
+pub struct IPv6Component
+{
+	data: ThreadData,
+
+	/// Use add_interface to add these.
+	pub interfaces: Vec<IPv6Interface>,
+
+	/// Address and interface index of the router used for off-link destinations.
+	pub default_router: Option<([u8; 16], usize)>,
+
+	// Addresses configured using SLAAC along with the index of their interface.
+	autoconfigured: Vec<(usize, IPv6Interface)>,
+
+	/// Listens for "send_down" events.
+	pub upper_in: InPort<(InternetInfo, SocketOptions, Packet)>,	
+	pub upper_out: OutPort<(InternetInfo, Packet)>,
+	pub icmp_out: OutPort<(InternetInfo, Packet)>,
+	pub tcp_out: OutPort<(InternetInfo, Packet)>,
+
+	/// Sends "send_error" and "local_error" events to [`Icmpv6Component`].
+	pub error_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Listens for "configured" events. There is one of these for each interface.
+	pub config_ins: Vec<InPort<AutoConfig>>,
+
+	/// "configured" events are forwarded here so that apps know which addresses they can use.
+	pub config_out: OutPort<AutoConfig>,
+
+	/// Listens for "send_up" events. There is one of these for each interface.
+	pub lower_ins: Vec<InPort<(LinkInfo, Packet)>>,
+
+	/// The address is the next hop, i.e. the router or, if the destination is on the
+	/// same subnet, the destination. There is one of these for each interface.
+	pub lower_outs: Vec<OutPort<(IPv6Header, [u8; 16], Packet)>>,
+}
+
+impl IPv6Component
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (id, data) = sim.add_active_component("IPv6", parent_id);
+		IPv6Component {
+			data: data,
+			interfaces: Vec::new(),
+			default_router: None,
+			autoconfigured: Vec::new(),
+
+			upper_in: InPort::with_port_name(id, "upper_in"),
+			lower_outs: Vec::new(),
+
+			lower_ins: Vec::new(),
+			upper_out: OutPort::new(),
+			icmp_out: OutPort::new(),
+			tcp_out: OutPort::new(),
+			error_out: OutPort::new(),
+			config_ins: Vec::new(),
+			config_out: OutPort::new(),
+		}
+	}
+
+	/// Returns the index of the new interface (which is also the index of its lower ports).
+	pub fn add_interface(&mut self, interface: IPv6Interface) -> usize
+	{
+		let index = self.interfaces.len();
+		self.interfaces.push(interface);
+		self.lower_ins.push(InPort::with_port_name(self.data.id, &format!("lower_in_{}", index)));
+		self.lower_outs.push(OutPort::new());
+		self.config_ins.push(InPort::with_port_name(self.data.id, &format!("config_in_{}", index)));
+		index
+	}
+	
+	pub fn start(mut self)
+	{		
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					for i in 0..self.interfaces.len() {
+						effector.set_int(&format!("rx_packets_{}", i), 0);
+						effector.set_int(&format!("tx_packets_{}", i), 0);
+					}
+				},
+				"configured" => {
+					assert!(!event.port_name.is_empty());
+					let i = self.config_ins.iter().position(|p| p.target_port == event.port_name).expect("event should have come from a config_in");
+					let config = event.take_payload::<AutoConfig>();
+					match config {
+						AutoConfig::Address(interface) => {
+							self.autoconfigured.push((i, interface));
+							let addrs: Vec<String> = self.autoconfigured.iter().map(|&(_, a)| format!("{:?}", a.addr)).collect();
+							effector.set_string("autoconfigured", &addrs.join(", "));
+						},
+						AutoConfig::Router(addr) => if self.default_router.is_none() {
+							self.default_router = Some((addr, i));
+						}
+					}
+					self.config_out.send_payload(&mut effector, &event.name, config);
+				},
+				"send_down" => {
+					let (iinfo, options, packet) = event.take_payload::<(InternetInfo, SocketOptions, Packet)>();
+					let header = IPv6Header::with_internet(&iinfo, &options);
+					self.route(&mut effector, &state, header, packet);
+				},
+				"send_up" => {
+					assert!(!event.port_name.is_empty());
+					if let Some(i) = self.lower_ins.iter().position(|p| p.target_port == event.port_name) {
+						self.increment(&mut effector, &state, &format!("rx_packets_{}", i));
+					}
+
+					let (_, mut packet) = event.take_payload::<(LinkInfo, Packet)>();
+					match IPv6Header::pop(&mut packet) {
+						Ok(header) => {
+							if !self.is_local(header.dst_addr) {
+								log_debug!(effector, "ignoring {} (not addressed to us)", packet.name());
+							} else if header.extensions.iter().any(|e| e.kind.as_u8() == StandardProtocol::IPV6_FRAG as u8) {
+								log_info!(effector, "dropping {} (fragment reassembly isn't supported)", packet.name());
+							} else {
+								self.send_up(&mut effector, &header, packet);
+							}
+						},
+						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
+					}
+				}
+			);
+		});
+	}
+
+	/// Returns true if packets sent to addr should be delivered to this device.
+	fn is_local(&self, addr: [u8; 16]) -> bool
+	{
+		is_ipv6_multicast(addr) || self.interfaces.iter().any(|i| i.addr == addr) || self.autoconfigured.iter().any(|&(_, i)| i.addr == addr)
+	}
+
+	// Returns the index of the interface whose subnet includes addr.
+	fn on_link(&self, addr: [u8; 16]) -> Option<usize>
+	{
+		self.interfaces.iter().position(|i| i.is_local(addr))
+			.or(self.autoconfigured.iter().find(|&&(_, i)| i.is_local(addr)).map(|&(index, _)| index))
+	}
+
+	fn route(&self, effector: &mut Effector, state: &SimState, header: IPv6Header, packet: Packet)
+	{
+		if is_ipv6_multicast(header.dst_addr) {
+			for i in 0..self.interfaces.len() {
+				self.send_down(effector, state, header.clone(), packet.clone(), i, header.dst_addr);
+			}
+
+		} else if let Some(i) = self.on_link(header.dst_addr) {
+			let next_hop = header.dst_addr;
+			self.send_down(effector, state, header, packet, i, next_hop);
+
+		} else if let Some((router, i)) = self.default_router {
+			self.send_down(effector, state, header, packet, i, router);
+
+		} else {
+			log_info!(effector, "dropping {} (no route to {:?})", packet.name(), header.dst_addr);
+			self.increment(effector, state, "num_dropped");
+
+			let info = InternetInfo::new(header.protocol, IPAddress::IPv6(header.src_addr), IPAddress::IPv6(header.dst_addr));
+			let message = IcmpMessage::DestinationUnreachable(Unreachable::Network);
+			self.error_out.send_payload(effector, "local_error", (info, message, packet));
+		}
+	}
+
+	fn send_down(&self, effector: &mut Effector, state: &SimState, header: IPv6Header, mut packet: Packet, interface: usize, next_hop: [u8; 16])
+	{
+		let mtu = self.interfaces[interface].mtu;
+		if header.len() + packet.len() <= mtu {
+			header.push(&mut packet);
+			self.lower_outs[interface].send_payload(effector, "send_down", (header, next_hop, packet));
+			self.increment(effector, state, &format!("tx_packets_{}", interface));
+		} else {
+			log_info!(effector, "dropping {} ({} bytes is larger than the {} byte MTU)", packet.name(), header.len() + packet.len(), mtu);
+			self.increment(effector, state, "num_dropped");
+
+			let info = InternetInfo::new(header.protocol, IPAddress::IPv6(header.src_addr), IPAddress::IPv6(header.dst_addr));
+			let message = IcmpMessage::DestinationUnreachable(Unreachable::FragmentationNeeded(mtu as u16));
+			self.error_out.send_payload(effector, "local_error", (info, message, packet));
+		}
+	}
+
+	fn send_up(&self, effector: &mut Effector, header: &IPv6Header, packet: Packet)
+	{
+		let iinfo = InternetInfo::new(header.protocol, IPAddress::IPv6(header.src_addr), IPAddress::IPv6(header.dst_addr));
+		match header.protocol {
+			Protocol::Standard(StandardProtocol::IPV6_ICMP) => self.icmp_out.send_payload(effector, "send_up", (iinfo, packet)),
+			Protocol::Standard(StandardProtocol::TCP) => self.tcp_out.send_payload(effector, "send_up", (iinfo, packet)),
+			Protocol::Standard(StandardProtocol::UDP) => self.upper_out.send_payload(effector, "send_up", (iinfo, packet)),
+			_ => {
+				// Errors quote the header as it was received.
+				match popped_ipv6_header(&packet) {
+					Some(mut bytes) => {
+						bytes.extend(packet.iter());
+						let mut original = Packet::new(packet.name(), packet.id());
+						original.push_back_bytes(&bytes);
+
+						let message = IcmpMessage::DestinationUnreachable(Unreachable::Protocol);
+						self.error_out.send_payload(effector, "send_error", (iinfo, message, original));
+					},
+					None => log_warning!(effector, "can't send parameter problem for {} (its IPv6 header is missing)", packet.name())
+				}
+			}
+		}
+	}
+
+	// Note that state isn't updated until after the event is processed so this should
+	// only be called once per counter per event.
+	fn increment(&self, effector: &mut Effector, state: &SimState, name: &str)
+	{
+		let count = if state.contains(self.data.id, name) {state.get_int(self.data.id, name)} else {0};
+		effector.set_int(name, count + 1);
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+    #[cfg(test)]
+	use std::str;
+
+    #[test]
+    fn ipv6_header_pushing()
+	{
+		let mut packet = Packet::new("test packet", "1");
+		let payload = "hello world".to_string();
+		packet.push_back_bytes(payload.as_bytes());
+
+		let src_ip = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+		let dst_ip = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+		let options = SocketOptions::with_addr(IPAddress::IPv6(dst_ip));
+		let mut header1 = IPv6Header::new(Protocol::Standard(StandardProtocol::EXPERIMENTAL1), src_ip, dst_ip, &options);
+		header1.flow_label = 0x12345;
+		header1.extensions.push(IPv6Extension::new(Protocol::Standard(StandardProtocol::HOPOPT), vec![1, 4, 0, 0, 0, 0]));
+		header1.extensions.push(IPv6Extension::new(Protocol::Standard(StandardProtocol::IPV6_OPTS), vec![1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+		header1.push(&mut packet);
+		assert_eq!(header1.len() + payload.len(), packet.len());
+
+		match IPv6Header::pop(&mut packet) {
+			Ok(header2) => {
+				assert_eq!(header1.dscp, header2.dscp);
+				assert_eq!(header1.ecn, header2.ecn);
+				assert_eq!(header1.flow_label, header2.flow_label);
+				assert_eq!(header1.hop_limit, header2.hop_limit);
+				assert_eq!(header1.protocol.as_u8(), header2.protocol.as_u8());
+				assert_eq!(header1.src_addr, header2.src_addr);
+				assert_eq!(header1.dst_addr, header2.dst_addr);
+
+				assert_eq!(2, header2.extensions.len());
+				assert_eq!(StandardProtocol::HOPOPT as u8, header2.extensions[0].kind.as_u8());
+				assert_eq!(StandardProtocol::IPV6_OPTS as u8, header2.extensions[1].kind.as_u8());
+				assert_eq!(header1.extensions[1].data, header2.extensions[1].data);
+			}
+			Err(mesg) => assert!(false, "IPv6Header::pop failed: ".to_string() + &mesg)
+		}
+
+		let popped = popped_ipv6_header(&packet).expect("header should have been popped");
+		let mut expected = Packet::new("expected", "1");
+		expected.push_back_bytes(payload.as_bytes());
+		header1.push(&mut expected);
+		assert_eq!(expected.iter().cloned().collect::<Vec<u8>>(), popped);
+
+		let len = packet.len();
+		let data = packet.pop_bytes(len);
+		match str::from_utf8(data.as_slice()) {
+			Ok(text) => assert_eq!(payload, text),
+			Err(mesg) => assert!(false, format!("IPv6Header::pop payload failed: {}", mesg))
+		}
+    }
+}
@@ -15,13 +15,25 @@
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 
 //! This is the layer responsible for routing frames towards an endpoint.
+mod fragments;
+mod icmp;
+mod icmpv6;
 mod internet;
 mod ipv4;
+mod ipv6;
+mod neighbor_discovery;
 mod protocol;
+mod routing;
 mod upper_internet;
 
+pub use self::fragments::*;
+pub use self::icmp::*;
+pub use self::icmpv6::*;
 pub use self::internet::*;
 pub use self::ipv4::*;		
+pub use self::ipv6::*;
+pub use self::neighbor_discovery::*;
 pub use self::protocol::*;
+pub use self::routing::*;
 pub use self::upper_internet::*;
 
@@ -0,0 +1,476 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::icmpv6::*;
+use internet::internet::*;
+use internet::ipv6::*;
+use internet::protocol::*;
+use internet::routing::*;
+use link::*;
+use score::*;
+use std::collections::HashMap;
+use std::thread;
+use transport::*;
+
+/// Sent with "configured" events when stateless address autoconfiguration (RFC 4862)
+/// learns something about the link.
+#[derive(Clone, Copy, Debug)]
+pub enum AutoConfig
+{
+	/// A global address formed from an advertised prefix.
+	Address(IPv6Interface),
+
+	/// A router that can be used as the default router.
+	Router([u8; 16]),
+}
+
+/// All-nodes multicast address (ff02::1).
+pub const ALL_NODES: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+/// All-routers multicast address (ff02::2).
+pub const ALL_ROUTERS: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+/// Returns the modified EUI-64 interface identifier for a MAC address (see RFC 4291 appendix A).
+pub fn interface_id(mac_addr: &MacAddress) -> [u8; 8]
+{
+	[mac_addr[0] ^ 0x02, mac_addr[1], mac_addr[2], 0xff, 0xfe, mac_addr[3], mac_addr[4], mac_addr[5]]
+}
+
+/// Returns an address formed from the first 64 bits of prefix and the interface id of mac_addr.
+pub fn slaac_addr(prefix: &[u8; 16], mac_addr: &MacAddress) -> [u8; 16]
+{
+	let mut addr = [0; 16];
+	addr[..8].copy_from_slice(&prefix[..8]);
+	addr[8..].copy_from_slice(&interface_id(mac_addr));
+	addr
+}
+
+/// Returns the fe80::/64 address for mac_addr.
+pub fn link_local_addr(mac_addr: &MacAddress) -> [u8; 16]
+{
+	slaac_addr(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], mac_addr)
+}
+
+/// Returns the solicited-node multicast address (ff02::1:ffXX:XXXX) for addr.
+pub fn solicited_node_addr(addr: &[u8; 16]) -> [u8; 16]
+{
+	[0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, addr[13], addr[14], addr[15]]
+}
+
+/// Returns the MAC address IPv6 multicast packets are sent to (see RFC 2464 section 7).
+pub fn multicast_mac(addr: &[u8; 16]) -> MacAddress
+{
+	[0x33, 0x33, addr[12], addr[13], addr[14], addr[15]]
+}
+
+struct NeighborEntry
+{
+	mac_addr: MacAddress,
+	expires: f64,
+}
+
+/// Implements IPv6 Neighbor Discovery (RFC 4861) for one interface. Sits between
+/// [`IPv6Component`] and [`LlcComponent`] in the same way that [`ArpComponent`] does for
+/// IPv4: it resolves next hop addresses into MAC addresses using Neighbor Solicitations,
+/// answers solicitations for its own addresses, and intercepts Neighbor Discovery messages
+/// so that they don't go up the stack. Hosts send Router Solicitations when they start
+/// and use prefixes from Router Advertisements to configure global addresses which are
+/// reported using "configured" events. If prefix is set the component acts as a router
+/// and advertises the prefix instead. Duplicate address detection isn't performed.
+/// There are several component settings:
+/// - **queue_length** is the maximum number of packets queued for an unresolved address. Defaults to 3.
+/// - **retries** is the number of solicitations sent before queued packets are dropped. Defaults to 3.
+/// - **retry_delay** is the number of seconds to wait for an advertisement. Defaults to 1.0.
+/// - **reachable_time** is the number of seconds a cache entry remains valid. Defaults to 30.0.
+/// - **router_solicitations** is the number of Router Solicitations hosts send. Defaults to 3.
+/// - **router_solicitation_interval** is the number of seconds between Router Solicitations. Defaults to 4.0.
+/// - **router_advertisement_interval** is the number of seconds between unsolicited Router Advertisements. Defaults to 200.0.
+pub struct NeighborDiscoveryComponent
+{
+	data: ThreadData,
+
+	/// Hardware address of the interface.
+	pub mac_addr: MacAddress,
+
+	/// The interface's fe80::/64 address. This is derived from mac_addr.
+	pub link_local: [u8; 16],
+
+	/// Prefix (and its length) to advertise. Set this for routers.
+	pub prefix: Option<([u8; 16], u8)>,
+
+	/// Listens for "send_down" events. The address is the next hop.
+	pub upper_in: InPort<(IPv6Header, [u8; 16], Packet)>,
+	pub upper_out: OutPort<(LinkInfo, Packet)>,
+
+	/// Sends "configured" events to [`IPv6Component`].
+	pub config_out: OutPort<AutoConfig>,
+
+	/// Listens for "send_up" events.
+	pub lower_in: InPort<(LinkInfo, Packet)>,
+	pub lower_out: OutPort<(LinkInfo, Packet)>,
+}
+
+impl NeighborDiscoveryComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID, mac_addr: MacAddress) -> Self
+	{
+		let (id, data) = sim.add_active_component("ND", parent_id);
+		NeighborDiscoveryComponent {
+			data: data,
+			mac_addr,
+			link_local: link_local_addr(&mac_addr),
+			prefix: None,
+
+			upper_in: InPort::with_port_name(id, "upper_in"),
+			lower_out: OutPort::new(),
+			config_out: OutPort::new(),
+
+			lower_in: InPort::with_port_name(id, "lower_in"),
+			upper_out: OutPort::new(),
+		}
+	}
+
+	pub fn start(self)
+	{
+		let mut cache: HashMap<[u8; 16], NeighborEntry> = HashMap::new();
+		let mut pending: HashMap<[u8; 16], Vec<Packet>> = HashMap::new();
+		let mut attempts: HashMap<[u8; 16], i64> = HashMap::new();
+		let mut addrs = vec![self.link_local];
+		let mut solicitations_left = 0;
+		let mut queue_length = 3;
+		let mut retries = 3;
+		let mut retry_delay = 1.0;
+		let mut reachable_time = 30.0;
+		let mut router_solicitations = 3;
+		let mut router_solicitation_interval = 4.0;
+		let mut router_advertisement_interval = 200.0;
+		let mut next_id = 0;
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					queue_length = int_setting(self.data.id, &state, &mut effector, "queue_length", queue_length);
+					retries = int_setting(self.data.id, &state, &mut effector, "retries", retries);
+					retry_delay = float_setting(self.data.id, &state, &mut effector, "retry_delay", retry_delay);
+					reachable_time = float_setting(self.data.id, &state, &mut effector, "reachable_time", reachable_time);
+					router_solicitations = int_setting(self.data.id, &state, &mut effector, "router_solicitations", router_solicitations);
+					router_solicitation_interval = float_setting(self.data.id, &state, &mut effector, "router_solicitation_interval", router_solicitation_interval);
+					router_advertisement_interval = float_setting(self.data.id, &state, &mut effector, "router_advertisement_interval", router_advertisement_interval);
+					effector.set_int("solicitations_sent", 0);
+					effector.set_int("advertisements_sent", 0);
+
+					if self.prefix.is_some() {
+						let event = Event::new("advertise");
+						effector.schedule_immediately(event, self.data.id);
+					} else {
+						solicitations_left = router_solicitations;
+						let event = Event::new("solicit_router");
+						effector.schedule_immediately(event, self.data.id);
+					}
+				},
+				"solicit_router" => {
+					if solicitations_left > 0 {
+						solicitations_left -= 1;
+						let message = Icmpv6Message::RouterSolicitation {source_mac: Some(self.mac_addr)};
+						self.send_nd(&mut effector, message, ALL_ROUTERS, &multicast_mac(&ALL_ROUTERS), &mut next_id);
+						self.increment(&mut effector, &state, "solicitations_sent");
+
+						let event = Event::new("solicit_router");
+						effector.schedule_after_secs(event, self.data.id, router_solicitation_interval);
+					}
+				},
+				"advertise" => {
+					self.advertise(&mut effector, &state, &mut next_id);
+
+					let event = Event::new("advertise");
+					effector.schedule_after_secs(event, self.data.id, router_advertisement_interval);
+				},
+				"send_down" => {
+					let (_, next_hop, packet) = event.take_payload::<(IPv6Header, [u8; 16], Packet)>();
+					if is_ipv6_multicast(next_hop) {
+						self.send_ipv6(&mut effector, &multicast_mac(&next_hop), packet);
+
+					} else if let Some(mac_addr) = lookup(&cache, next_hop, state.time) {
+						self.send_ipv6(&mut effector, &mac_addr, packet);
+
+					} else {
+						let queue = pending.entry(next_hop).or_insert(Vec::new());
+						if queue.len() as i64 >= queue_length {
+							let old = queue.remove(0);
+							log_info!(effector, "dropping {} (too many packets waiting on {:?})", old.name(), next_hop);
+						}
+						queue.push(packet);
+
+						if !attempts.contains_key(&next_hop) {
+							attempts.insert(next_hop, 1);
+							self.solicit_neighbor(&mut effector, &state, next_hop, retry_delay, &mut next_id);
+						}
+					}
+				},
+				"resend_solicitation" => {
+					let target = event.take_payload::<[u8; 16]>();
+					if let Some(count) = attempts.get(&target).cloned() {
+						if count < retries {
+							attempts.insert(target, count + 1);
+							self.solicit_neighbor(&mut effector, &state, target, retry_delay, &mut next_id);
+						} else {
+							if let Some(queue) = pending.remove(&target) {
+								log_info!(effector, "dropping {} packets ({:?} didn't advertise)", queue.len(), target);
+							}
+							attempts.remove(&target);
+						}
+					}
+				},
+				"send_up" => {
+					let (linfo, packet) = event.take_payload::<(LinkInfo, Packet)>();
+					if linfo.dst_addr != self.mac_addr && linfo.dst_addr != BROADCAST_MAC && linfo.dst_addr[..2] != [0x33, 0x33] {
+						log_debug!(effector, "ignoring {} (sent to {:?})", packet.name(), linfo.dst_addr);
+
+					} else if let Some((header, message)) = pop_nd(&packet) {
+						let src_addr = header.src_addr;
+						let mut learned = None;
+						match message {
+							Icmpv6Message::RouterSolicitation {source_mac} => {
+								if self.prefix.is_some() {
+									learned = source_mac;
+									self.advertise(&mut effector, &state, &mut next_id);
+								}
+							},
+							Icmpv6Message::RouterAdvertisement(ra) => {
+								if self.prefix.is_none() {
+									learned = ra.source_mac;
+									solicitations_left = 0;
+									if let Some(prefix) = ra.prefix {
+										let addr = slaac_addr(&prefix.prefix, &self.mac_addr);
+										if prefix.autonomous && prefix.prefix_len == 64 && !addrs.contains(&addr) {
+											log_info!(effector, "configured {:?} using SLAAC", addr);
+											addrs.push(addr);
+
+											let interface = IPv6Interface::with_mtu(addr, prefix.prefix_len, advertised_mtu(&ra));
+											self.config_out.send_payload(&mut effector, "configured", AutoConfig::Address(interface));
+										}
+									}
+									if ra.lifetime > 0 {
+										self.config_out.send_payload(&mut effector, "configured", AutoConfig::Router(src_addr));
+									}
+								}
+							},
+							Icmpv6Message::NeighborSolicitation {target, source_mac} => {
+								if addrs.contains(&target) {
+									learned = source_mac;
+									let (dst_addr, solicited) = if src_addr == [0; 16] {(ALL_NODES, false)} else {(src_addr, true)};
+									let reply = Icmpv6Message::NeighborAdvertisement {
+										target,
+										router: self.prefix.is_some(),
+										solicited,
+										override_entry: true,
+										target_mac: Some(self.mac_addr),
+									};
+									let dst_mac = source_mac.unwrap_or(linfo.src_addr);
+									self.send_nd(&mut effector, reply, dst_addr, &dst_mac, &mut next_id);
+									self.increment(&mut effector, &state, "advertisements_sent");
+								}
+							},
+							Icmpv6Message::NeighborAdvertisement {target, target_mac, ..} => {
+								let mac_addr = target_mac.unwrap_or(linfo.src_addr);
+								if cache.contains_key(&target) || attempts.contains_key(&target) {
+									cache.insert(target, NeighborEntry {mac_addr, expires: state.time + reachable_time});
+									effector.set_int("cache_size", cache.len() as i64);
+
+									if let Some(queue) = pending.remove(&target) {
+										for p in queue {
+											self.send_ipv6(&mut effector, &mac_addr, p);
+										}
+									}
+									attempts.remove(&target);
+								}
+							},
+							_ => ()
+						}
+
+						// RFC 4861 section 7.2.3: solicitations create (or update) an entry for their sender.
+						if let Some(mac_addr) = learned {
+							if src_addr != [0; 16] {
+								cache.insert(src_addr, NeighborEntry {mac_addr, expires: state.time + reachable_time});
+								effector.set_int("cache_size", cache.len() as i64);
+							}
+						}
+
+					} else {
+						self.upper_out.send_payload(&mut effector, &event.name, (linfo, packet));
+					}
+				}
+			);
+		});
+	}
+
+	fn solicit_neighbor(&self, effector: &mut Effector, state: &SimState, target: [u8; 16], retry_delay: f64, next_id: &mut i64)
+	{
+		let message = Icmpv6Message::NeighborSolicitation {target, source_mac: Some(self.mac_addr)};
+		let dst_addr = solicited_node_addr(&target);
+		self.send_nd(effector, message, dst_addr, &multicast_mac(&dst_addr), next_id);
+		self.increment(effector, state, "solicitations_sent");
+
+		let event = Event::with_payload("resend_solicitation", target);
+		effector.schedule_after_secs(event, self.data.id, retry_delay);
+	}
+
+	// Solicited advertisements are multicast too which RFC 4861 section 6.2.6 allows.
+	fn advertise(&self, effector: &mut Effector, state: &SimState, next_id: &mut i64)
+	{
+		let (prefix, prefix_len) = self.prefix.expect("only routers advertise");
+		let ra = RouterAdvertisement {
+			hop_limit: 64,
+			lifetime: 1800,
+			reachable_time: 0,
+			retrans_timer: 0,
+			source_mac: Some(self.mac_addr),
+			mtu: None,
+			prefix: Some(PrefixInfo {
+				prefix,
+				prefix_len,
+				on_link: true,
+				autonomous: true,
+				valid_lifetime: 2592000,
+				preferred_lifetime: 604800,
+			}),
+		};
+		self.send_nd(effector, Icmpv6Message::RouterAdvertisement(ra), ALL_NODES, &multicast_mac(&ALL_NODES), next_id);
+		self.increment(effector, state, "advertisements_sent");
+	}
+
+	fn send_nd(&self, effector: &mut Effector, message: Icmpv6Message, dst_addr: [u8; 16], dst_mac: &MacAddress, next_id: &mut i64)
+	{
+		*next_id += 1;
+		let mut packet = Packet::new(message.name(), &format!("#>{}.{}", self.data.id, next_id));
+
+		let info = InternetInfo::new(ICMPV6, IPAddress::IPv6(self.link_local), IPAddress::IPv6(dst_addr));
+		message.push(&mut packet, &info);
+
+		let mut options = SocketOptions::with_addr(info.dst_addr);
+		options.ttl = 255;		// receivers drop ND messages that may have been forwarded
+		let header = IPv6Header::with_internet(&info, &options);
+		header.push(&mut packet);
+
+		let linfo = LinkInfo::new(EtherType::IPv6, &self.mac_addr, dst_mac);
+		self.lower_out.send_payload(effector, "send_down", (linfo, packet));
+	}
+
+	fn send_ipv6(&self, effector: &mut Effector, dst_addr: &MacAddress, packet: Packet)
+	{
+		let linfo = LinkInfo::new(EtherType::IPv6, &self.mac_addr, dst_addr);
+		self.lower_out.send_payload(effector, "send_down", (linfo, packet));
+	}
+
+	// Note that state isn't updated until after the event is processed so this should
+	// only be called once per counter per event.
+	fn increment(&self, effector: &mut Effector, state: &SimState, name: &str)
+	{
+		let count = state.get_int(self.data.id, name);
+		effector.set_int(name, count + 1);
+	}
+}
+
+const ICMPV6: Protocol = Protocol::Standard(StandardProtocol::IPV6_ICMP);
+
+// Returns the IPv6 header and message if packet is a Neighbor Discovery message.
+fn pop_nd(packet: &Packet) -> Option<(IPv6Header, Icmpv6Message)>
+{
+	let mut packet = packet.clone();
+	match IPv6Header::pop(&mut packet) {
+		Ok(ref header) if header.protocol.as_u8() == ICMPV6.as_u8() && header.hop_limit == 255 && packet.len() > 0 && packet.get(0) >= 133 && packet.get(0) <= 137 => {
+			let info = InternetInfo::new(ICMPV6, IPAddress::IPv6(header.src_addr), IPAddress::IPv6(header.dst_addr));
+			Icmpv6Message::pop(&mut packet, &info).ok().map(|m| (header.clone(), m))
+		},
+		_ => None
+	}
+}
+
+// MTU options smaller than the IPv6 minimum are ignored, see RFC 4861 section 6.3.4.
+fn advertised_mtu(ra: &RouterAdvertisement) -> usize
+{
+	ra.mtu.map(|m| m as usize).filter(|&m| m >= 1280).unwrap_or(1500)
+}
+
+fn lookup(cache: &HashMap<[u8; 16], NeighborEntry>, addr: [u8; 16], time: f64) -> Option<MacAddress>
+{
+	match cache.get(&addr) {
+		Some(entry) if entry.expires > time => Some(entry.mac_addr),
+		_ => None
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn slaac_addresses()
+	{
+		let mac_addr = [0x00, 0x1b, 0x63, 0x84, 0x45, 0xe6];
+		assert_eq!([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x02, 0x1b, 0x63, 0xff, 0xfe, 0x84, 0x45, 0xe6], link_local_addr(&mac_addr));
+
+		let prefix = [0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0xAA, 0xAA, 0, 0, 0, 0, 0, 0];
+		let addr = slaac_addr(&prefix, &mac_addr);
+		assert_eq!([0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0x02, 0x1b, 0x63, 0xff, 0xfe, 0x84, 0x45, 0xe6], addr);
+
+		assert_eq!([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0x84, 0x45, 0xe6], solicited_node_addr(&addr));
+		assert_eq!([0x33, 0x33, 0xff, 0x84, 0x45, 0xe6], multicast_mac(&solicited_node_addr(&addr)));
+    }
+
+    #[test]
+    fn advertised_mtus()
+	{
+		let router = link_local_addr(&[0x00, 0x1b, 0x63, 0x84, 0x45, 0xe6]);
+		let info = InternetInfo::new(ICMPV6, IPAddress::IPv6(router), IPAddress::IPv6(ALL_NODES));
+		let mut options = SocketOptions::with_addr(info.dst_addr);
+		options.ttl = 255;
+
+		for &(mtu, expected) in [(None, 1500), (Some(1400), 1400), (Some(1280), 1280), (Some(1000), 1500), (Some(0), 1500)].iter() {
+			let ra = RouterAdvertisement {
+				hop_limit: 64,
+				lifetime: 1800,
+				reachable_time: 0,
+				retrans_timer: 0,
+				source_mac: None,
+				mtu,
+				prefix: Some(PrefixInfo {
+					prefix: [0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+					prefix_len: 64,
+					on_link: true,
+					autonomous: true,
+					valid_lifetime: 2592000,
+					preferred_lifetime: 604800,
+				}),
+			};
+			let mut packet = Packet::new("ra", "1");
+			Icmpv6Message::RouterAdvertisement(ra).push(&mut packet, &info);
+			IPv6Header::with_internet(&info, &options).push(&mut packet);
+
+			match pop_nd(&packet) {
+				Some((_, Icmpv6Message::RouterAdvertisement(ra))) => {
+					assert_eq!(mtu, ra.mtu);
+					let mtu = advertised_mtu(&ra);
+					assert_eq!(expected, mtu);
+					let interface = IPv6Interface::with_mtu(ra.prefix.unwrap().prefix, 64, mtu);
+					assert_eq!(expected, interface.mtu);
+				},
+				_ => panic!("expected a router advertisement for {:?}", mtu)
+			}
+		}
+    }
+}
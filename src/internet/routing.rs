@@ -0,0 +1,248 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use std::fmt;
+
+/// Configuration for one of the links an [`IPv4Component`] is attached to.
+#[derive(Clone, Copy, Debug)]
+pub struct IPv4Interface
+{
+	pub addr: [u8; 4],
+	pub netmask: [u8; 4],
+
+	/// Largest packet (including the IPv4 header) the link can carry. Defaults to 1500.
+	pub mtu: usize,
+}
+
+impl IPv4Interface
+{
+	pub fn new(addr: [u8; 4], netmask: [u8; 4]) -> Self
+	{
+		IPv4Interface {addr, netmask, mtu: 1500}
+	}
+
+	pub fn with_mtu(addr: [u8; 4], netmask: [u8; 4], mtu: usize) -> Self
+	{
+		IPv4Interface {addr, netmask, mtu}
+	}
+
+	/// Returns true if addr is on the same subnet as this interface.
+	pub fn is_local(&self, addr: [u8; 4]) -> bool
+	{
+		to_u32(addr) & to_u32(self.netmask) == to_u32(self.addr) & to_u32(self.netmask)
+	}
+
+	/// Address used to send to every device on the interface's subnet.
+	pub fn broadcast_addr(&self) -> [u8; 4]
+	{
+		from_u32(to_u32(self.addr) | !to_u32(self.netmask))
+	}
+}
+
+/// Configuration for one of the links an [`IPv6Component`] is attached to.
+#[derive(Clone, Copy, Debug)]
+pub struct IPv6Interface
+{
+	pub addr: [u8; 16],
+
+	/// Number of leading bits in addr that identify the link's subnet. Normally 64.
+	pub prefix_len: u8,
+
+	/// Largest packet (including the IPv6 headers) the link can carry. Defaults to 1500.
+	pub mtu: usize,
+}
+
+impl IPv6Interface
+{
+	pub fn new(addr: [u8; 16], prefix_len: u8) -> Self
+	{
+		IPv6Interface {addr, prefix_len, mtu: 1500}
+	}
+
+	pub fn with_mtu(addr: [u8; 16], prefix_len: u8, mtu: usize) -> Self
+	{
+		assert!(mtu >= 1280, "IPv6 requires an MTU of at least 1280 bytes");
+		IPv6Interface {addr, prefix_len, mtu}
+	}
+
+	/// Returns true if addr is on the same subnet as this interface.
+	pub fn is_local(&self, addr: [u8; 16]) -> bool
+	{
+		let mut bits = self.prefix_len as usize;
+		for i in 0..16 {
+			let mask = if bits >= 8 {0xFF} else {!(0xFFu8 >> bits)};
+			if addr[i] & mask != self.addr[i] & mask {
+				return false;
+			}
+			bits = if bits >= 8 {bits - 8} else {0};
+		}
+		true
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Route
+{
+	pub destination: [u8; 4],
+	pub netmask: [u8; 4],
+
+	/// If this is missing then the destination is directly reachable using the interface.
+	pub gateway: Option<[u8; 4]>,
+
+	/// Index of the interface within the [`IPv4Component`].
+	pub interface: usize,
+
+	/// Used to break ties between routes with the same prefix length. Lower is better.
+	pub metric: u32,
+}
+
+impl Route
+{
+	pub fn new(destination: [u8; 4], netmask: [u8; 4], gateway: Option<[u8; 4]>, interface: usize, metric: u32) -> Self
+	{
+		Route {destination, netmask, gateway, interface, metric}
+	}
+
+	pub fn matches(&self, addr: [u8; 4]) -> bool
+	{
+		to_u32(addr) & to_u32(self.netmask) == to_u32(self.destination) & to_u32(self.netmask)
+	}
+
+	pub fn prefix_len(&self) -> u32
+	{
+		to_u32(self.netmask).count_ones()
+	}
+
+	/// Returns the gateway or, for directly connected routes, addr.
+	pub fn next_hop(&self, addr: [u8; 4]) -> [u8; 4]
+	{
+		self.gateway.unwrap_or(addr)
+	}
+}
+
+/// IPv4 forwarding table using longest prefix match.
+#[derive(Clone)]
+pub struct RoutingTable
+{
+	routes: Vec<Route>,
+}
+
+impl RoutingTable
+{
+	pub fn new() -> Self
+	{
+		RoutingTable {routes: Vec::new()}
+	}
+
+	pub fn add(&mut self, route: Route)
+	{
+		self.routes.push(route);
+	}
+
+	/// Adds a route for the interface's subnet.
+	pub fn add_connected(&mut self, interface: &IPv4Interface, index: usize)
+	{
+		let destination = from_u32(to_u32(interface.addr) & to_u32(interface.netmask));
+		self.add(Route::new(destination, interface.netmask, None, index, 0));
+	}
+
+	/// Adds a 0.0.0.0/0 route.
+	pub fn add_default(&mut self, gateway: [u8; 4], interface: usize, metric: u32)
+	{
+		self.add(Route::new([0, 0, 0, 0], [0, 0, 0, 0], Some(gateway), interface, metric));
+	}
+
+	/// Removes all routes using the interface.
+	pub fn remove_interface(&mut self, interface: usize)
+	{
+		self.routes.retain(|r| r.interface != interface);
+	}
+
+	/// Returns the route with the longest prefix matching addr. Ties are broken with the metric.
+	pub fn lookup(&self, addr: [u8; 4]) -> Option<&Route>
+	{
+		let mut best: Option<&Route> = None;
+		for route in self.routes.iter().filter(|r| r.matches(addr)) {
+			best = match best {
+				Some(b) if b.prefix_len() > route.prefix_len() => Some(b),
+				Some(b) if b.prefix_len() == route.prefix_len() && b.metric <= route.metric => Some(b),
+				_ => Some(route)
+			};
+		}
+		best
+	}
+
+	pub fn routes(&self) -> &Vec<Route>
+	{
+		&self.routes
+	}
+}
+
+impl fmt::Debug for RoutingTable
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		for route in self.routes.iter() {
+			let d = route.destination;
+			let gateway = match route.gateway {
+				Some(g) => format!("{}.{}.{}.{}", g[0], g[1], g[2], g[3]),
+				None => "*".to_string(),
+			};
+			try!(write!(f, "{}.{}.{}.{}/{} via {} if {} metric {}\n", d[0], d[1], d[2], d[3], route.prefix_len(), gateway, route.interface, route.metric));
+		}
+		Ok(())
+    }
+}
+
+fn to_u32(addr: [u8; 4]) -> u32
+{
+	(addr[0] as u32) << 24 | (addr[1] as u32) << 16 | (addr[2] as u32) << 8 | addr[3] as u32
+}
+
+fn from_u32(addr: u32) -> [u8; 4]
+{
+	[(addr >> 24) as u8, (addr >> 16 & 0xFF) as u8, (addr >> 8 & 0xFF) as u8, (addr & 0xFF) as u8]
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn longest_prefix_match()
+	{
+		let mut table = RoutingTable::new();
+		table.add_connected(&IPv4Interface::new([10, 0, 0, 1], [255, 255, 255, 0]), 0);
+		table.add_connected(&IPv4Interface::new([10, 0, 1, 1], [255, 255, 255, 0]), 1);
+		table.add(Route::new([10, 0, 0, 0], [255, 0, 0, 0], Some([10, 0, 1, 254]), 1, 0));
+		table.add_default([10, 0, 0, 254], 0, 10);
+		table.add_default([10, 0, 1, 253], 1, 5);
+
+		let route = table.lookup([10, 0, 0, 7]).unwrap();
+		assert_eq!(0, route.interface);
+		assert_eq!([10, 0, 0, 7], route.next_hop([10, 0, 0, 7]));
+
+		let route = table.lookup([10, 9, 9, 9]).unwrap();
+		assert_eq!(Some([10, 0, 1, 254]), route.gateway);
+
+		let route = table.lookup([192, 168, 0, 1]).unwrap();
+		assert_eq!(Some([10, 0, 1, 253]), route.gateway);	// lower metric wins
+
+		table.remove_interface(1);
+		let route = table.lookup([10, 9, 9, 9]).unwrap();
+		assert_eq!(Some([10, 0, 0, 254]), route.gateway);
+    }
+}
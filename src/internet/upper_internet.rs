@@ -14,16 +14,15 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 use common::*;
-use internet::ipv4::*;
 use internet::internet::*;
-// use internet::protocol_numbers::*;
 use score::*;
-// use std::str;
 use std::thread;
 use transport::*;
 
-/// Handles dispatching downward packets to either [`IPv4Component`] or [`IPv6Component`].
-/// For upward packets this will (normally) send an event to [`LowerTransportComponent`].
+/// Handles dispatching downward packets to either [`IPv4Component`] or [`IPv6Component`]
+/// using the address family of the destination. Upward packets from either component are
+/// sent (normally) to [`UdpComponent`] so that dual-stack devices can use one transport
+/// component for both families.
 pub struct UpperInternetComponent
 {
 	data: ThreadData,
@@ -31,23 +30,24 @@ pub struct UpperInternetComponent
 	/// Listens for "send_down" events.
 	pub upper_in: InPort<(InternetInfo, SocketOptions, Packet)>,
 	pub upper_out: OutPort<(InternetInfo, Packet)>,
-	// TODO: IPv6
 
-	/// Listens for "send_up" events.
+	/// Listens for "send_up" events from either IP component.
 	pub lower_in: InPort<(InternetInfo, Packet)>,
-	pub lower_ipv4_out: OutPort<(IPv4Header, Packet)>,
+	pub lower_ipv4_out: OutPort<(InternetInfo, SocketOptions, Packet)>,
+	pub lower_ipv6_out: OutPort<(InternetInfo, SocketOptions, Packet)>,
 }
 
 impl UpperInternetComponent
 {
 	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
 	{
-		let (id, data) = sim.add_active_component("IPv4", parent_id);
+		let (id, data) = sim.add_active_component("UpperInternet", parent_id);
 		UpperInternetComponent {
 			data: data,
 
 			upper_in: InPort::with_port_name(id, "upper_in"),
 			lower_ipv4_out: OutPort::new(),
+			lower_ipv6_out: OutPort::new(),
 
 			lower_in: InPort::with_port_name(id, "lower_in"),
 			upper_out: OutPort::new(),
@@ -62,8 +62,10 @@ impl UpperInternetComponent
 				},
 				"send_down" => {
 					let (iinfo, options, packet) = event.take_payload::<(InternetInfo, SocketOptions, Packet)>();
-					let header = IPv4Header::with_internet(&iinfo, &options);
-					self.lower_ipv4_out.send_payload(&mut effector, &event.name, (header, packet));
+					match iinfo.dst_addr {
+						IPAddress::IPv4(_) => self.lower_ipv4_out.send_payload(&mut effector, &event.name, (iinfo, options, packet)),
+						IPAddress::IPv6(_) => self.lower_ipv6_out.send_payload(&mut effector, &event.name, (iinfo, options, packet)),
+					}
 				},
 				"send_up" => {
 					let (info, packet) = event.take_payload::<(InternetInfo, Packet)>();
@@ -0,0 +1,347 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::*;
+use link::ether_type::*;
+use link::link::*;
+use score::*;
+use std::collections::HashMap;
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpOperation
+{
+	Request = 1,
+	Reply = 2,
+}
+
+/// In memory representation of an ARP packet (Ethernet hardware and IPv4 protocol addresses only).
+pub struct ArpHeader	// see RFC 826
+{
+	pub operation: ArpOperation,
+
+	/// Sender hardware address.
+	pub sha: MacAddress,
+
+	/// Sender protocol address.
+	pub spa: [u8; 4],
+
+	/// Target hardware address. Ignored for requests.
+	pub tha: MacAddress,
+
+	/// Target protocol address.
+	pub tpa: [u8; 4],
+}
+
+impl ArpHeader
+{
+	pub fn request(sha: &MacAddress, spa: [u8; 4], tpa: [u8; 4]) -> Self
+	{
+		ArpHeader {operation: ArpOperation::Request, sha: *sha, spa, tha: [0, 0, 0, 0, 0, 0], tpa}
+	}
+
+	pub fn reply(sha: &MacAddress, spa: [u8; 4], tha: &MacAddress, tpa: [u8; 4]) -> Self
+	{
+		ArpHeader {operation: ArpOperation::Reply, sha: *sha, spa, tha: *tha, tpa}
+	}
+
+	/// Gratuitous ARPs are requests for our own address. They are used to announce
+	/// ourselves and to update stale entries in other devices' caches.
+	pub fn gratuitous(sha: &MacAddress, spa: [u8; 4]) -> Self
+	{
+		ArpHeader::request(sha, spa, spa)
+	}
+
+	/// Adds an ARP header to the packet.
+	pub fn push(&self, packet: &mut Packet)
+	{
+		let mut header = Header::with_capacity(28);
+
+		header.push16(1);							// hardware type (Ethernet)
+		header.push16(EtherType::IPv4.as_u16());	// protocol type
+		header.push8(6);							// hardware address length
+		header.push8(4);							// protocol address length
+		header.push16(self.operation as u16);
+
+		header.push_bytes(&self.sha);
+		header.push_bytes(&self.spa);
+		header.push_bytes(&self.tha);
+		header.push_bytes(&self.tpa);
+
+		packet.push_header(&header);
+	}
+
+	/// Removes an ARP header from the packet.
+	pub fn pop(packet: &mut Packet) -> Result<ArpHeader, String>
+	{
+		if packet.len() < 28 {
+			return Err(format!("ARP packet should be at least 28 bytes but is {}", packet.len()))
+		}
+
+		let htype = packet.pop16();
+		if htype != 1 {
+			return Err(format!("ArpHeader.htype should be 1 not {}", htype))
+		}
+
+		let ptype = packet.pop16();
+		if ptype != EtherType::IPv4.as_u16() {
+			return Err(format!("ArpHeader.ptype should be 0x0800 not {:#06X}", ptype))
+		}
+
+		let hlen = packet.pop8();
+		let plen = packet.pop8();
+		if hlen != 6 || plen != 4 {
+			return Err(format!("ArpHeader lengths should be 6 and 4 not {} and {}", hlen, plen))
+		}
+
+		let operation = match packet.pop16() {
+			1 => ArpOperation::Request,
+			2 => ArpOperation::Reply,
+			op => return Err(format!("ArpHeader.oper {} isn't supported", op))
+		};
+
+		let sha = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
+		let spa = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
+		let tha = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
+		let tpa = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
+
+		Ok(ArpHeader {operation, sha, spa, tha, tpa})
+	}
+}
+
+struct ArpEntry
+{
+	mac_addr: MacAddress,
+	expires: f64,
+}
+
+/// Resolves IPv4 addresses into MAC addresses. Sits between [`IPv4Component`] and
+/// [`LlcComponent`]. Packets for addresses that are not in the cache are queued
+/// while a request is outstanding. There are several component settings:
+/// - **queue_length** is the maximum number of packets queued for an unresolved address. Defaults to 3.
+/// - **retries** is the number of requests sent before queued packets are dropped. Defaults to 3.
+/// - **retry_delay** is the number of seconds to wait for a reply. Defaults to 1.0.
+/// - **timeout** is the number of seconds a cache entry remains valid. Defaults to 60.0.
+pub struct ArpComponent
+{
+	data: ThreadData,
+
+	/// Address of the interface this component resolves addresses for.
+	pub ipv4_addr: [u8; 4],
+
+	/// Hardware address of the interface.
+	pub mac_addr: MacAddress,
+
+	/// Listens for "send_down" events. The address is the next hop.
+	pub upper_in: InPort<(IPv4Header, [u8; 4], Packet)>,
+	pub upper_out: OutPort<(LinkInfo, Packet)>,
+
+	/// Listens for "send_up" events.
+	pub lower_in: InPort<(LinkInfo, Packet)>,
+	pub lower_out: OutPort<(LinkInfo, Packet)>,
+}
+
+impl ArpComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID, ipv4_addr: [u8; 4], mac_addr: MacAddress) -> Self
+	{
+		let (id, data) = sim.add_active_component("ARP", parent_id);
+		ArpComponent {
+			data: data,
+			ipv4_addr,
+			mac_addr,
+
+			upper_in: InPort::with_port_name(id, "upper_in"),
+			lower_out: OutPort::new(),
+
+			lower_in: InPort::with_port_name(id, "lower_in"),
+			upper_out: OutPort::new(),
+		}
+	}
+
+	pub fn start(self)
+	{
+		let mut cache: HashMap<[u8; 4], ArpEntry> = HashMap::new();
+		let mut pending: HashMap<[u8; 4], Vec<Packet>> = HashMap::new();
+		let mut attempts: HashMap<[u8; 4], i64> = HashMap::new();
+		let mut queue_length = 3;
+		let mut retries = 3;
+		let mut retry_delay = 1.0;
+		let mut timeout = 60.0;
+		let mut next_id = 0;
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					queue_length = int_setting(self.data.id, &state, &mut effector, "queue_length", queue_length);
+					retries = int_setting(self.data.id, &state, &mut effector, "retries", retries);
+					retry_delay = float_setting(self.data.id, &state, &mut effector, "retry_delay", retry_delay);
+					timeout = float_setting(self.data.id, &state, &mut effector, "timeout", timeout);
+					effector.set_int("requests_sent", 0);
+					effector.set_int("replies_sent", 0);
+
+					let header = ArpHeader::gratuitous(&self.mac_addr, self.ipv4_addr);
+					self.send_arp(&mut effector, &header, &BROADCAST_MAC, &mut next_id);
+				},
+				"send_down" => {
+					let (ipv4, dst_addr, packet) = event.take_payload::<(IPv4Header, [u8; 4], Packet)>();
+					if ipv4.dst_addr == [255, 255, 255, 255] || dst_addr == [255, 255, 255, 255] {
+						self.send_ipv4(&mut effector, &BROADCAST_MAC, packet);
+
+					} else if let Some(mac_addr) = lookup(&cache, dst_addr, state.time) {
+						self.send_ipv4(&mut effector, &mac_addr, packet);
+
+					} else {
+						let queue = pending.entry(dst_addr).or_insert(Vec::new());
+						if queue.len() as i64 >= queue_length {
+							let old = queue.remove(0);
+							log_info!(effector, "dropping {} (too many packets waiting on {:?})", old.name(), dst_addr);
+						}
+						queue.push(packet);
+
+						if !attempts.contains_key(&dst_addr) {
+							attempts.insert(dst_addr, 1);
+							self.send_request(&mut effector, &state, dst_addr, retry_delay, &mut next_id);
+						}
+					}
+				},
+				"resend_request" => {
+					let dst_addr = event.take_payload::<[u8; 4]>();
+					if let Some(count) = attempts.get(&dst_addr).cloned() {
+						if count < retries {
+							attempts.insert(dst_addr, count + 1);
+							self.send_request(&mut effector, &state, dst_addr, retry_delay, &mut next_id);
+						} else {
+							if let Some(queue) = pending.remove(&dst_addr) {
+								log_info!(effector, "dropping {} packets ({:?} didn't reply)", queue.len(), dst_addr);
+							}
+							attempts.remove(&dst_addr);
+						}
+					}
+				},
+				"send_up" => {
+					let (linfo, mut packet) = event.take_payload::<(LinkInfo, Packet)>();
+					if linfo.dst_addr != self.mac_addr && linfo.dst_addr != BROADCAST_MAC {
+						log_debug!(effector, "ignoring {} (sent to {:?})", packet.name(), linfo.dst_addr);
+
+					} else if let EtherType::ARP = linfo.ether_type {
+						match ArpHeader::pop(&mut packet) {
+							Ok(header) => {
+								// RFC 826 says to update existing entries before checking the target
+								// and to only add new entries if we are the target.
+								let merged = cache.contains_key(&header.spa);
+								if merged || header.tpa == self.ipv4_addr {
+									cache.insert(header.spa, ArpEntry {mac_addr: header.sha, expires: state.time + timeout});
+									effector.set_int("cache_size", cache.len() as i64);
+
+									if let Some(queue) = pending.remove(&header.spa) {
+										for p in queue {
+											self.send_ipv4(&mut effector, &header.sha, p);
+										}
+									}
+									attempts.remove(&header.spa);
+								}
+
+								if header.tpa == self.ipv4_addr && header.operation == ArpOperation::Request && header.spa != self.ipv4_addr {
+									let reply = ArpHeader::reply(&self.mac_addr, self.ipv4_addr, &header.sha, header.spa);
+									self.send_arp(&mut effector, &reply, &header.sha, &mut next_id);
+
+									let count = state.get_int(self.data.id, "replies_sent");
+									effector.set_int("replies_sent", count + 1);
+								}
+							},
+							Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
+						}
+
+					} else {
+						self.upper_out.send_payload(&mut effector, &event.name, (linfo, packet));
+					}
+				}
+			);
+		});
+	}
+
+	fn send_request(&self, effector: &mut Effector, state: &SimState, dst_addr: [u8; 4], retry_delay: f64, next_id: &mut i64)
+	{
+		let header = ArpHeader::request(&self.mac_addr, self.ipv4_addr, dst_addr);
+		self.send_arp(effector, &header, &BROADCAST_MAC, next_id);
+
+		let count = state.get_int(self.data.id, "requests_sent");
+		effector.set_int("requests_sent", count + 1);
+
+		let event = Event::with_payload("resend_request", dst_addr);
+		effector.schedule_after_secs(event, self.data.id, retry_delay);
+	}
+
+	fn send_arp(&self, effector: &mut Effector, header: &ArpHeader, dst_addr: &MacAddress, next_id: &mut i64)
+	{
+		let name = match header.operation {
+			ArpOperation::Request if header.spa == header.tpa => "Gratuitous ARP",
+			ArpOperation::Request => "ARP Request",
+			ArpOperation::Reply => "ARP Reply",
+		};
+		*next_id += 1;
+		let mut packet = Packet::new(name, &format!("#>{}.{}", self.data.id, next_id));
+		header.push(&mut packet);
+
+		let linfo = LinkInfo::new(EtherType::ARP, &self.mac_addr, dst_addr);
+		self.lower_out.send_payload(effector, "send_down", (linfo, packet));
+	}
+
+	fn send_ipv4(&self, effector: &mut Effector, dst_addr: &MacAddress, packet: Packet)
+	{
+		let linfo = LinkInfo::new(EtherType::IPv4, &self.mac_addr, dst_addr);
+		self.lower_out.send_payload(effector, "send_down", (linfo, packet));
+	}
+}
+
+fn lookup(cache: &HashMap<[u8; 4], ArpEntry>, addr: [u8; 4], time: f64) -> Option<MacAddress>
+{
+	match cache.get(&addr) {
+		Some(entry) if entry.expires > time => Some(entry.mac_addr),
+		_ => None
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn arp_header_pushing()
+	{
+		let mut packet = Packet::new("test packet", "1");
+
+		let sha = [0x02, 0, 0, 0, 0, 1];
+		let header1 = ArpHeader::request(&sha, [10, 0, 0, 1], [10, 0, 0, 2]);
+		header1.push(&mut packet);
+		assert_eq!(28, packet.len());
+
+		match ArpHeader::pop(&mut packet) {
+			Ok(header2) => {
+				assert_eq!(header1.operation, header2.operation);
+				assert_eq!(header1.sha, header2.sha);
+				assert_eq!(header1.spa, header2.spa);
+				assert_eq!(header1.tha, header2.tha);
+				assert_eq!(header1.tpa, header2.tpa);
+			}
+			Err(mesg) => assert!(false, "ArpHeader::pop failed: ".to_string() + &mesg)
+		}
+
+		assert!(packet.is_empty())
+    }
+}
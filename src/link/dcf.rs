@@ -0,0 +1,423 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use link::link::*;
+use link::frame80211::*;
+use std::cmp;
+use std::collections::VecDeque;
+
+// Unless otherwise indicated all references are to the 2016 version of the 802.11 spec.
+
+/// Values from [`Mac80211Component`]'s settings. Times are in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct DcfSettings
+{
+	pub slot_time: f64,
+	pub sifs: f64,
+	pub aifsn: i64,
+	pub cw_min: i64,
+	pub cw_max: i64,
+	pub retry_limit: i64,
+	pub ack_timeout: f64,
+	pub queue_length: i64,
+
+	/// Maximum duration of a burst of frames, zero means one frame per channel access.
+	pub txop_limit: f64,
+}
+
+impl DcfSettings
+{
+	/// Arbitration interframe space, see 10.3.2.3.6. Plain DCF uses an AIFSN of two
+	/// which makes this DIFS.
+	pub fn aifs(&self) -> f64
+	{
+		self.sifs + (self.aifsn as f64)*self.slot_time
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DcfState
+{
+	/// Nothing to send and no backoff pending.
+	Idle,
+
+	/// Waiting for the medium to be idle for AIFS and then counting down the backoff.
+	Contending,
+
+	/// Waiting SIFS before sending the next frame of a TXOP.
+	Bursting,
+
+	/// A frame was handed to the medium but the medium hasn't reported when it will finish.
+	Transmitting,
+
+	/// A unicast frame was sent and we're waiting for its ACK.
+	AwaitingAck,
+}
+
+/// Things a [`Dcf`] asks [`Mac80211Component`] to do.
+#[derive(Debug)]
+pub enum DcfAction
+{
+	/// Send the frame to the medium.
+	Transmit(Packet),
+
+	/// The frame at the head of the queue was acknowledged (or was a group frame and was sent).
+	Sent,
+
+	/// The frame is going to be sent again.
+	Retry,
+
+	/// The frame was dropped because it hit the retry limit.
+	Dropped(Packet),
+}
+
+/// Distributed Coordination Function (see 10.3): carrier sensing with AIFS, binary
+/// exponential backoff in slot units that is frozen while the medium is busy, ACKs for
+/// unicast frames, and retransmissions up to a retry limit. A backoff is also done after
+/// each frame is sent (post-backoff) so that a station with a full queue can't hog the
+/// medium. If txop_limit is set then, once the medium has been won, frames are sent
+/// back to back (separated by SIFS) until the limit would be exceeded. [`Edca`] runs
+/// one of these per access category and calls timeout when timer expires.
+pub struct Dcf
+{
+	pub state: DcfState,
+
+	/// Absolute time at which timeout should be called.
+	pub timer: Option<f64>,
+
+	/// Time at which the medium becomes idle.
+	pub busy_until: f64,
+
+	settings: DcfSettings,
+	rng: Rng,
+	queue: VecDeque<(Ieee80211Frame, Packet)>,
+	cw: i64,
+	backoff: i64,
+	countdown_start: Option<f64>,
+	retries: i64,
+	txop_start: Option<f64>,
+	exchange_start: f64,
+}
+
+impl Dcf
+{
+	pub fn new(settings: DcfSettings, rng: Rng) -> Self
+	{
+		Dcf {
+			state: DcfState::Idle,
+			timer: None,
+			busy_until: 0.0,
+			settings,
+			rng,
+			queue: VecDeque::new(),
+			cw: settings.cw_min,
+			backoff: 0,
+			countdown_start: None,
+			retries: 0,
+			txop_start: None,
+			exchange_start: 0.0,
+		}
+	}
+
+	pub fn queue_len(&self) -> usize
+	{
+		self.queue.len()
+	}
+
+	/// Adds a frame to the transmit queue. The payload shouldn't include the MAC header
+	/// (and is empty for management frames). Returns false if the queue is full.
+	pub fn enqueue(&mut self, frame: Ieee80211Frame, payload: Packet, now: f64, out: &mut Vec<DcfAction>) -> bool
+	{
+		if self.queue.len() as i64 >= self.settings.queue_length {
+			return false
+		}
+
+		self.queue.push_back((frame, payload));
+		if self.state == DcfState::Idle {
+			// If the medium is busy we have to back off, see 10.3.4.3.
+			if now < self.busy_until {
+				self.backoff = self.draw_backoff();
+			}
+			self.contend(now, out);
+		}
+		true
+	}
+
+	/// Another station started transmitting.
+	pub fn medium_busy(&mut self, until: f64, now: f64, out: &mut Vec<DcfAction>)
+	{
+		if self.state == DcfState::Contending {
+			self.freeze(now);
+		}
+		if until > self.busy_until {
+			self.busy_until = until;
+		}
+		if self.state == DcfState::Contending {
+			self.contend(now, out);
+		}
+	}
+
+	/// The medium reported when our own transmission (data or ACK) will finish.
+	pub fn transmitting(&mut self, until: f64, now: f64, out: &mut Vec<DcfAction>)
+	{
+		if until > self.busy_until {
+			self.busy_until = until;
+		}
+
+		if self.state == DcfState::Transmitting {
+			let unicast = self.queue.front().map(|&(ref f, _)| !is_group(&f.ra())).unwrap_or(false);
+			if unicast {
+				self.state = DcfState::AwaitingAck;
+				self.timer = Some(until + self.settings.ack_timeout);
+			} else {
+				self.succeeded(now, out);
+			}
+		} else if self.state == DcfState::Contending {
+			self.freeze(now);
+			self.contend(now, out);
+		}
+	}
+
+	/// An ACK addressed to us arrived.
+	pub fn ack_received(&mut self, now: f64, out: &mut Vec<DcfAction>)
+	{
+		if self.state == DcfState::AwaitingAck {
+			self.succeeded(now, out);
+		}
+	}
+
+	pub fn timeout(&mut self, now: f64, out: &mut Vec<DcfAction>)
+	{
+		self.timer = None;
+		match self.state {
+			DcfState::Contending => self.contend(now, out),
+			DcfState::Bursting => {
+				if now + TIMER_SLOP < self.busy_until {
+					self.txop_start = None;
+					self.backoff = self.draw_backoff();
+					self.contend(now, out);
+				} else {
+					self.transmit(now, out);
+				}
+			},
+			DcfState::AwaitingAck => self.failed(now, out),
+			_ => ()
+		}
+	}
+
+	/// The frame we just transmitted lost to a higher priority access category on this
+	/// station that finished its backoff in the same slot. This is handled like a
+	/// collision on the medium, see 10.22.2.4.
+	pub fn internal_collision(&mut self, now: f64, out: &mut Vec<DcfAction>)
+	{
+		assert!(self.state == DcfState::Transmitting);
+		if now > self.busy_until {
+			self.busy_until = now;
+		}
+		self.failed(now, out);
+	}
+
+	/// The frame we just transmitted couldn't be sent because another access category
+	/// on this station is using the radio. The frame stays at the head of the queue and
+	/// a new backoff is started.
+	pub fn defer(&mut self, now: f64, out: &mut Vec<DcfAction>)
+	{
+		assert!(self.state == DcfState::Transmitting);
+		if now > self.busy_until {
+			self.busy_until = now;
+		}
+		self.txop_start = None;
+		self.backoff = self.draw_backoff();
+		self.contend(now, out);
+	}
+
+	// See 10.3.4.4 and 10.3.3.
+	fn failed(&mut self, now: f64, out: &mut Vec<DcfAction>)
+	{
+		self.txop_start = None;
+		self.retries += 1;
+		if self.retries >= self.settings.retry_limit {
+			let (_, payload) = self.queue.pop_front().unwrap();
+			out.push(DcfAction::Dropped(payload));
+			self.retries = 0;
+			self.cw = self.settings.cw_min;
+		} else {
+			self.cw = cmp::min(2*self.cw + 1, self.settings.cw_max);
+			out.push(DcfAction::Retry);
+		}
+		self.backoff = self.draw_backoff();
+		self.contend(now, out);
+	}
+
+	fn succeeded(&mut self, now: f64, out: &mut Vec<DcfAction>)
+	{
+		let _ = self.queue.pop_front();
+		out.push(DcfAction::Sent);
+
+		self.retries = 0;
+		self.cw = self.settings.cw_min;
+
+		// Keep the TXOP going if another exchange like the last one will fit, see 10.22.2.8.
+		if let Some(start) = self.txop_start {
+			let exchange = now - self.exchange_start;
+			if !self.queue.is_empty() && now + self.settings.sifs + exchange <= start + self.settings.txop_limit {
+				self.state = DcfState::Bursting;
+				self.timer = Some(now + self.settings.sifs);
+				return
+			}
+		}
+
+		self.txop_start = None;
+		self.backoff = self.draw_backoff();
+		self.contend(now, out);
+	}
+
+	// Counts down the backoff while the medium has been idle for AIFS and transmits
+	// once it hits zero.
+	fn contend(&mut self, now: f64, out: &mut Vec<DcfAction>)
+	{
+		if self.backoff == 0 && self.queue.is_empty() {
+			self.enter_idle();
+			return
+		}
+
+		let idle_at = self.busy_until + self.settings.aifs();
+		if now + TIMER_SLOP < idle_at {
+			self.state = DcfState::Contending;
+			self.countdown_start = None;
+			self.timer = Some(idle_at);
+			return
+		}
+
+		self.freeze(now);
+		if self.backoff == 0 {
+			if self.queue.is_empty() {
+				self.enter_idle();
+			} else {
+				self.transmit(now, out);
+			}
+		} else {
+			self.state = DcfState::Contending;
+			self.countdown_start = Some(now);
+			self.timer = Some(now + (self.backoff as f64)*self.settings.slot_time);
+		}
+	}
+
+	// Decrements the backoff by the number of idle slots that have elapsed.
+	fn freeze(&mut self, now: f64)
+	{
+		if let Some(start) = self.countdown_start {
+			let elapsed = ((now - start)/self.settings.slot_time + TIMER_SLOP).floor() as i64;
+			self.backoff -= cmp::min(elapsed, self.backoff);
+			self.countdown_start = None;
+		}
+	}
+
+	fn transmit(&mut self, now: f64, out: &mut Vec<DcfAction>)
+	{
+		let &(ref frame, ref payload) = self.queue.front().unwrap();
+		let mut frame = frame.clone();
+		frame.set_retry(self.retries > 0);
+
+		let mut packet = payload.clone();
+		frame.push(&mut packet);
+		out.push(DcfAction::Transmit(packet));
+
+		self.state = DcfState::Transmitting;
+		self.countdown_start = None;
+		self.timer = None;
+		self.exchange_start = now;
+		if self.txop_start.is_none() && self.settings.txop_limit > 0.0 {
+			self.txop_start = Some(now);
+		}
+	}
+
+	fn enter_idle(&mut self)
+	{
+		self.state = DcfState::Idle;
+		self.countdown_start = None;
+		self.timer = None;
+	}
+
+	fn draw_backoff(&mut self) -> i64
+	{
+		self.rng.range(0, self.cw)
+	}
+}
+
+/// Timers are considered to have expired if they are within this amount of the current time.
+pub const TIMER_SLOP: f64 = 1.0e-9;
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[cfg(test)]
+	fn settings() -> DcfSettings
+	{
+		DcfSettings {slot_time: 0.000_009, sifs: 0.000_016, aifsn: 2, cw_min: 15, cw_max: 1023, retry_limit: 3, ack_timeout: 0.000_1, queue_length: 10, txop_limit: 0.0}
+	}
+
+    #[test]
+    fn retries_and_backoff()
+	{
+		let mut dcf = Dcf::new(settings(), Rng::new(1));
+		let mut out = Vec::new();
+		let frame = Ieee80211Frame::Data(Mac80211DataFrame::new(&[2, 0, 0, 0, 0, 1], &[2, 0, 0, 0, 0, 2], 0));
+
+		// The medium has been idle for a long time so the first frame goes out immediately.
+		let now = 1.0;
+		assert!(dcf.enqueue(frame.clone(), Packet::new("data", "1"), now, &mut out));
+		assert_eq!(DcfState::Transmitting, dcf.state);
+		assert_eq!(1, out.len());
+		out.clear();
+
+		dcf.transmitting(now + 0.000_05, now, &mut out);
+		assert_eq!(DcfState::AwaitingAck, dcf.state);
+
+		// No ACKs arrive so we keep retrying (with a backoff) until the retry limit is hit.
+		let mut transmits = 1;
+		let mut now = dcf.timer.unwrap();
+		while let Some(time) = dcf.timer {
+			now = time;
+			dcf.timeout(now, &mut out);
+			for action in out.drain(..) {
+				match action {
+					DcfAction::Transmit(_) => {
+						transmits += 1;
+						assert!(now >= dcf.busy_until + settings().aifs() - TIMER_SLOP);
+					},
+					DcfAction::Dropped(_) => assert_eq!(3, transmits),
+					_ => ()
+				}
+			}
+			if dcf.state == DcfState::Transmitting {
+				let end = now + 0.000_05;
+				dcf.transmitting(end, now, &mut out);
+			}
+		}
+		assert_eq!(3, transmits);
+		assert_eq!(0, dcf.queue_len());
+
+		// A frame that arrives while the medium is busy has to wait for it to go idle.
+		dcf.medium_busy(now + 0.001, now, &mut out);
+		assert!(dcf.enqueue(frame, Packet::new("data", "2"), now, &mut out));
+		assert_eq!(DcfState::Contending, dcf.state);
+		assert!(out.is_empty());
+		assert!(dcf.timer.unwrap() >= now + 0.001 + settings().aifs() - TIMER_SLOP);
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use link::dcf::*;
+use link::ether_type::*;
+use link::frame80211::*;
+use transport::*;
+
+// Unless otherwise indicated all references are to the 2016 version of the 802.11 spec.
+
+/// EDCA access categories, see 10.22.2. These are ordered by priority and can be used
+/// as indexes.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum AccessCategory
+{
+	Background = 0,
+	BestEffort = 1,
+	Video = 2,
+	Voice = 3,
+}
+
+/// All the access categories from lowest to highest priority.
+pub const ACCESS_CATEGORIES: [AccessCategory; 4] = [AccessCategory::Background, AccessCategory::BestEffort, AccessCategory::Video, AccessCategory::Voice];
+
+impl AccessCategory
+{
+	/// Maps a TID (i.e. an 802.1D user priority) to an access category, see Table 10-1.
+	pub fn from_tid(tid: u8) -> Self
+	{
+		match tid & 0x7 {
+			1 | 2 => AccessCategory::Background,
+			0 | 3 => AccessCategory::BestEffort,
+			4 | 5 => AccessCategory::Video,
+			_     => AccessCategory::Voice,
+		}
+	}
+
+	pub fn from_qos(qos: QoS) -> Self
+	{
+		match qos {
+			QoS::Background => AccessCategory::Background,
+			QoS::BestEffort => AccessCategory::BestEffort,
+			QoS::Video      => AccessCategory::Video,
+			QoS::Voice      => AccessCategory::Voice,
+		}
+	}
+
+	/// Used to prefix the names of per access category settings and counters.
+	pub fn abbrev(self) -> &'static str
+	{
+		match self {
+			AccessCategory::Background => "bk",
+			AccessCategory::BestEffort => "be",
+			AccessCategory::Video      => "vi",
+			AccessCategory::Voice      => "vo",
+		}
+	}
+
+	/// Returns (aifsn, cw_min, cw_max, txop_limit) for an OFDM PHY, see Table 9-137.
+	pub fn default_parameters(self) -> (i64, i64, i64, f64)
+	{
+		match self {
+			AccessCategory::Background => (7, 15, 1023, 0.0),
+			AccessCategory::BestEffort => (3, 15, 1023, 0.0),
+			AccessCategory::Video      => (2, 7, 15, 0.003_008),
+			AccessCategory::Voice      => (2, 3, 7, 0.001_504),
+		}
+	}
+}
+
+/// Returns the TID for a DSCP using the mapping from RFC 8325 section 4.3 except that
+/// CS7 is treated like CS6 and multimedia conferencing uses UP 5. Codepoints that aren't
+/// listed there are best effort.
+pub fn dscp_to_tid(dscp: u8) -> u8
+{
+	match dscp {
+		48 | 56           => 7,	// CS6 and CS7 (network control)
+		46 | 44           => 6,	// EF and VOICE-ADMIT (telephony)
+		34 | 36 | 38 | 40 => 5,	// AF4x (multimedia conferencing) and CS5 (signaling)
+		24 | 26 | 28 | 30 => 4,	// CS3 (broadcast video) and AF3x (multimedia streaming)
+		32                => 4,	// CS4 (real-time interactive)
+		18 | 20 | 22      => 3,	// AF2x (low-latency data)
+		8                 => 1,	// CS1 (low-priority data)
+		_                 => 0,	// DF, CS2 (OAM), AF1x (high-throughput data), etc
+	}
+}
+
+/// Returns the TID for a frame body (i.e. an LLC header followed by the payload). IP
+/// datagrams use their DSCP and everything else is best effort.
+pub fn classify(packet: &Packet) -> u8
+{
+	if packet.len() < 10 {
+		return 0
+	}
+
+	let ether_type = (packet.get(6) as u16) << 8 | packet.get(7) as u16;
+	if ether_type == EtherType::IPv4.as_u16() {
+		dscp_to_tid(packet.get(9) >> 2)
+
+	} else if ether_type == EtherType::IPv6.as_u16() {
+		let traffic_class = (packet.get(8) & 0x0F) << 4 | packet.get(9) >> 4;
+		dscp_to_tid(traffic_class >> 2)
+
+	} else {
+		0
+	}
+}
+
+/// Enhanced Distributed Channel Access (see 10.22.2): a [`Dcf`] for each access category
+/// with its own queue and parameters. If more than one access category finishes its
+/// backoff in the same slot the highest priority one transmits and the others act as if
+/// there was a collision. Only one access category can use the radio at a time so
+/// categories that win while another is busy with a frame exchange back off again.
+pub struct Edca
+{
+	/// Absolute time at which timeout should be called.
+	pub timer: Option<f64>,
+
+	dcfs: Vec<Dcf>,
+	slot_time: f64,
+	active: Option<(AccessCategory, f64)>,	// category using the radio and when it started
+}
+
+impl Edca
+{
+	/// Settings are indexed by access category.
+	pub fn new(settings: &[DcfSettings; 4], mut rng: Rng) -> Self
+	{
+		Edca {
+			timer: None,
+			dcfs: settings.iter().map(|s| Dcf::new(*s, Rng::new(rng.next_u64()))).collect(),
+			slot_time: settings[0].slot_time,
+			active: None,
+		}
+	}
+
+	pub fn dcf(&self, ac: AccessCategory) -> &Dcf
+	{
+		&self.dcfs[ac as usize]
+	}
+
+	/// Adds a frame to the access category's queue. Returns false if the queue is full.
+	pub fn enqueue(&mut self, ac: AccessCategory, frame: Ieee80211Frame, payload: Packet, now: f64, out: &mut Vec<(AccessCategory, DcfAction)>) -> bool
+	{
+		let mut actions = Vec::new();
+		let queued = self.dcfs[ac as usize].enqueue(frame, payload, now, &mut actions);
+		self.resolve(ac, now, actions, out);
+		self.update_timer();
+		queued
+	}
+
+	/// Another station started transmitting.
+	pub fn medium_busy(&mut self, until: f64, now: f64, out: &mut Vec<(AccessCategory, DcfAction)>)
+	{
+		self.run(now, out, |dcf, actions| dcf.medium_busy(until, now, actions));
+	}
+
+	/// The medium reported when our own transmission (data or ACK) will finish.
+	pub fn transmitting(&mut self, until: f64, now: f64, out: &mut Vec<(AccessCategory, DcfAction)>)
+	{
+		self.run(now, out, |dcf, actions| dcf.transmitting(until, now, actions));
+	}
+
+	/// An ACK addressed to us arrived.
+	pub fn ack_received(&mut self, now: f64, out: &mut Vec<(AccessCategory, DcfAction)>)
+	{
+		if let Some((ac, _)) = self.active {
+			let mut actions = Vec::new();
+			self.dcfs[ac as usize].ack_received(now, &mut actions);
+			self.resolve(ac, now, actions, out);
+			self.update_timer();
+		}
+	}
+
+	pub fn timeout(&mut self, now: f64, out: &mut Vec<(AccessCategory, DcfAction)>)
+	{
+		self.run(now, out, |dcf, actions| {
+			let expired = match dcf.timer {
+				Some(time) => time <= now + TIMER_SLOP,
+				None => false
+			};
+			if expired {
+				dcf.timeout(now, actions);
+			}
+		});
+	}
+
+	// Calls f for each access category, highest priority first so that it wins internal
+	// collisions.
+	fn run<F>(&mut self, now: f64, out: &mut Vec<(AccessCategory, DcfAction)>, f: F)
+		where F: Fn(&mut Dcf, &mut Vec<DcfAction>)
+	{
+		for &ac in ACCESS_CATEGORIES.iter().rev() {
+			let mut actions = Vec::new();
+			f(&mut self.dcfs[ac as usize], &mut actions);
+			self.resolve(ac, now, actions, out);
+		}
+		self.update_timer();
+	}
+
+	fn resolve(&mut self, ac: AccessCategory, now: f64, actions: Vec<DcfAction>, out: &mut Vec<(AccessCategory, DcfAction)>)
+	{
+		let mut pending = actions;
+		while !pending.is_empty() {
+			let mut more = Vec::new();
+			for action in pending {
+				match action {
+					DcfAction::Transmit(packet) => match self.active {
+						Some((winner, start)) if winner != ac => {
+							let dcf = &mut self.dcfs[ac as usize];
+							if now < start + self.slot_time {
+								dcf.internal_collision(now, &mut more);
+							} else {
+								dcf.defer(now, &mut more);
+							}
+						},
+						_ => {
+							if self.active.is_none() {
+								self.active = Some((ac, now));
+							}
+							out.push((ac, DcfAction::Transmit(packet)));
+						}
+					},
+					action => out.push((ac, action)),
+				}
+			}
+			pending = more;
+		}
+
+		if let Some((winner, _)) = self.active {
+			match self.dcfs[winner as usize].state {
+				DcfState::Transmitting | DcfState::AwaitingAck | DcfState::Bursting => (),
+				_ => self.active = None,
+			}
+		}
+	}
+
+	fn update_timer(&mut self)
+	{
+		self.timer = self.dcfs.iter().filter_map(|d| d.timer).fold(None, |min, t| match min {
+			Some(m) if m <= t => Some(m),
+			_ => Some(t)
+		});
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+    #[cfg(test)]
+	use link::llc::*;
+    #[cfg(test)]
+	use internet::*;
+
+    #[test]
+    fn internal_collisions()
+	{
+		let mut settings = [DcfSettings {slot_time: 0.000_009, sifs: 0.000_016, aifsn: 2, cw_min: 15, cw_max: 1023, retry_limit: 7, ack_timeout: 0.000_1, queue_length: 10, txop_limit: 0.0}; 4];
+		for &ac in ACCESS_CATEGORIES.iter() {
+			let (aifsn, cw_min, cw_max, txop_limit) = ac.default_parameters();
+			settings[ac as usize].aifsn = aifsn;
+			settings[ac as usize].cw_min = cw_min;
+			settings[ac as usize].cw_max = cw_max;
+			settings[ac as usize].txop_limit = txop_limit;
+		}
+		let mut edca = Edca::new(&settings, Rng::new(1));
+		let mut out = Vec::new();
+		let frame = Ieee80211Frame::Data(Mac80211DataFrame::new(&[2, 0, 0, 0, 0, 1], &[2, 0, 0, 0, 0, 2], 0));
+
+		// The medium has been idle for a long time so voice goes out immediately and best
+		// effort, which also had no backoff, loses the internal collision.
+		let now = 1.0;
+		assert!(edca.enqueue(AccessCategory::Voice, frame.clone(), Packet::new("voice", "1"), now, &mut out));
+		assert!(edca.enqueue(AccessCategory::BestEffort, frame, Packet::new("data", "2"), now, &mut out));
+		assert_eq!(2, out.len());
+		match out[0] {
+			(AccessCategory::Voice, DcfAction::Transmit(_)) => (),
+			ref action => panic!("expected a voice transmit not {:?}", action)
+		}
+		match out[1] {
+			(AccessCategory::BestEffort, DcfAction::Retry) => (),
+			ref action => panic!("expected a best effort retry not {:?}", action)
+		}
+		assert_eq!(DcfState::Contending, edca.dcf(AccessCategory::BestEffort).state);
+		out.clear();
+
+		// Once the voice frame is acknowledged best effort gets its turn.
+		edca.transmitting(now + 0.000_05, now, &mut out);
+		assert_eq!(DcfState::AwaitingAck, edca.dcf(AccessCategory::Voice).state);
+		edca.ack_received(now + 0.000_08, &mut out);
+		match out[0] {
+			(AccessCategory::Voice, DcfAction::Sent) => (),
+			ref action => panic!("expected voice to be sent not {:?}", action)
+		}
+		out.clear();
+
+		let mut now = now + 0.000_08;
+		while out.is_empty() {
+			now = edca.timer.unwrap();
+			edca.timeout(now, &mut out);
+		}
+		match out[0] {
+			(AccessCategory::BestEffort, DcfAction::Transmit(_)) => (),
+			ref action => panic!("expected a best effort transmit not {:?}", action)
+		}
+		assert!(now >= edca.dcf(AccessCategory::BestEffort).busy_until + settings[1].aifs() - TIMER_SLOP);
+    }
+
+    #[test]
+    fn classification()
+	{
+		let mut options = SocketOptions::with_addr(IPAddress::IPv4([10, 0, 0, 1]));
+		options.qos = QoS::Voice;
+		let mut packet = Packet::new("voice", "1");
+		IPv4Header::new(Protocol::Standard(StandardProtocol::UDP), [10, 0, 0, 1], [10, 0, 0, 2], &options).push(&mut packet);
+		LlcHeader::with_ipv4().push(&mut packet);
+		assert_eq!(AccessCategory::Voice, AccessCategory::from_tid(classify(&packet)));
+
+		options.qos = QoS::Video;
+		let mut packet = Packet::new("video", "2");
+		IPv6Header::new(Protocol::Standard(StandardProtocol::UDP), [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2], &options).push(&mut packet);
+		LlcHeader::with_ether_type(EtherType::IPv6).push(&mut packet);
+		assert_eq!(AccessCategory::Video, AccessCategory::from_tid(classify(&packet)));
+
+		let mut packet = Packet::new("arp", "3");
+		packet.push_back_bytes(&[0; 28]);
+		LlcHeader::with_ether_type(EtherType::ARP).push(&mut packet);
+		assert_eq!(AccessCategory::BestEffort, AccessCategory::from_tid(classify(&packet)));
+
+		for &qos in [QoS::Background, QoS::BestEffort, QoS::Video, QoS::Voice].iter() {
+			assert_eq!(AccessCategory::from_qos(qos), AccessCategory::from_tid(dscp_to_tid(qos_to_dscp(qos))));
+		}
+
+		assert_eq!(6, dscp_to_tid(46));		// EF
+		assert_eq!(AccessCategory::Voice, AccessCategory::from_tid(dscp_to_tid(46)));
+		assert_eq!(5, dscp_to_tid(34));		// AF41
+		assert_eq!(AccessCategory::Video, AccessCategory::from_tid(dscp_to_tid(34)));
+		assert_eq!(3, dscp_to_tid(18));		// AF21
+		assert_eq!(0, dscp_to_tid(10));		// AF11
+		assert_eq!(7, dscp_to_tid(56));		// CS7
+		assert_eq!(0, dscp_to_tid(16));		// CS2
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use link::ether_type::*;
+use link::link::*;
+use link::link_helpers::*;
+use link::llc::*;
+use score::*;
+use std::thread;
+
+/// Ethernet frames without the FCS must be at least this large.
+const MIN_FRAME_SIZE: usize = 60;
+
+/// Ethernet II uses the type field to identify the payload. 802.3 uses the field as
+/// a length and relies on an LLC header to identify the payload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EthernetFormat
+{
+	EthernetII,
+	Ieee8023,
+}
+
+/// In memory representation of a wired ethernet header.
+pub struct EthernetFrame
+{
+	pub dst_addr: MacAddress,
+	pub src_addr: MacAddress,
+
+	/// For 802.3 frames this is the ether_type from the LLC header.
+	pub ether_type: EtherType,
+
+	pub format: EthernetFormat,
+}
+
+impl EthernetFrame
+{
+	pub fn new(format: EthernetFormat, linfo: &LinkInfo) -> Self
+	{
+		EthernetFrame {
+			dst_addr: linfo.dst_addr,
+			src_addr: linfo.src_addr,
+			ether_type: linfo.ether_type,
+			format,
+		}
+	}
+
+	/// Adds an ethernet header, padding, and FCS to the packet.
+	pub fn push(&self, packet: &mut Packet)
+	{
+		let type_or_len = match self.format {
+			EthernetFormat::EthernetII => self.ether_type.as_u16(),
+			EthernetFormat::Ieee8023 => {
+				let llc = LlcHeader::with_ether_type(self.ether_type);
+				llc.push(packet);
+
+				let len = packet.len();
+				assert!(EtherType::EthernetLength(len as u16).is_valid(), "802.3 payload is too large: {} bytes", len);
+				len as u16
+			}
+		};
+
+		let mut header = Header::with_capacity(14);
+		header.push_bytes(&self.dst_addr);
+		header.push_bytes(&self.src_addr);
+		header.push16(type_or_len);
+		packet.push_header(&header);
+
+		if packet.len() < MIN_FRAME_SIZE {
+			let padding = vec![0; MIN_FRAME_SIZE - packet.len()];
+			packet.push_back_bytes(&padding);
+		}
+
+		let crc = crc32(packet);		// FCS (which is always little endian)
+		let fcs = [(crc & 0xFF) as u8, (crc >> 8 & 0xFF) as u8, (crc >> 16 & 0xFF) as u8, (crc >> 24 & 0xFF) as u8];
+		packet.push_back_bytes(&fcs);
+	}
+
+	/// Removes an ethernet header and FCS from the packet. For 802.3 frames the padding
+	/// is removed too. Ethernet II frames don't have a length so upper layers are
+	/// responsible for stripping padding.
+	pub fn pop(packet: &mut Packet) -> Result<EthernetFrame, String>
+	{
+		if packet.len() < MIN_FRAME_SIZE + 4 {
+			return Err(format!("Frame should be at least {} bytes but is {}", MIN_FRAME_SIZE + 4, packet.len()))
+		}
+
+		let crc = reverse32(!crc32(packet));	// see Mac80211DataFrame::pop
+		if crc != 0xC704DD7B {
+			return Err("Checksum error".to_string())
+		}
+
+		let dst_addr = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
+		let src_addr = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
+		let type_or_len = packet.pop16();
+
+		for _ in 0..4 {
+			let _ = packet.pop_back8();	// fcs
+		}
+
+		match EtherType::from_u16(type_or_len) {
+			EtherType::EthernetLength(len) => {
+				let len = len as usize;
+				if len > packet.len() {
+					return Err(format!("EthernetFrame.length is {} but only {} bytes are left", len, packet.len()))
+				}
+				while packet.len() > len {
+					let _ = packet.pop_back8();
+				}
+
+				let llc = try!(LlcHeader::pop(packet));
+				Ok(EthernetFrame {dst_addr, src_addr, ether_type: llc.ether_type, format: EthernetFormat::Ieee8023})
+			},
+			ether_type if ether_type.is_valid() => {
+				Ok(EthernetFrame {dst_addr, src_addr, ether_type, format: EthernetFormat::EthernetII})
+			},
+			ether_type => Err(format!("ether_type {:?} isn't valid", ether_type))
+		}
+	}
+}
+
+/// Medium Access Control for wired ethernet. Unlike [`Mac80211Component`] this talks
+/// directly to [`ArpComponent`]: Ethernet II frames don't use an LLC header and 802.3
+/// frames push their own.
+pub struct EthernetMacComponent
+{
+	data: ThreadData,
+
+	/// Defaults to Ethernet II.
+	pub format: EthernetFormat,
+
+	/// Listens for "send_down" events.
+	pub upper_in: InPort<(LinkInfo, Packet)>,
+	pub upper_out: OutPort<(LinkInfo, Packet)>,
+
+	/// Listens for "send_up" events.
+	pub lower_in: InPort<Packet>,
+	pub lower_out: OutPort<(ComponentID, Packet)>,
+
+	/// Sends "send_ethernet" events for every frame sent and "recv_ethernet" events for every
+	/// frame received, see [`PcapngComponent`].
+	pub pcap_out: OutPort<Packet>,
+}
+
+impl EthernetMacComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (id, data) = sim.add_active_component("EthernetMac", parent_id);
+		EthernetMacComponent {
+			data: data,
+			format: EthernetFormat::EthernetII,
+
+			upper_in: InPort::with_port_name(id, "upper_in"),
+			lower_out: OutPort::new(),
+
+			lower_in: InPort::with_port_name(id, "lower_in"),
+			upper_out: OutPort::new(),
+
+			pcap_out: OutPort::new(),
+		}
+	}
+
+	pub fn start(self)
+	{
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+				},
+				"send_down" => {
+					let (linfo, mut packet) = event.take_payload::<(LinkInfo, Packet)>();
+					let header = EthernetFrame::new(self.format, &linfo);
+					header.push(&mut packet);
+					self.pcap_out.send_payload(&mut effector, "send_ethernet", packet.clone());
+					self.lower_out.send_payload(&mut effector, &event.name, (self.data.id, packet));
+				},
+				"send_up" => {
+					let mut packet = event.take_payload::<Packet>();
+					self.pcap_out.send_payload(&mut effector, "recv_ethernet", packet.clone());
+					match EthernetFrame::pop(&mut packet) {
+						Ok(header) => {
+							let linfo = LinkInfo::new(header.ether_type, &header.src_addr, &header.dst_addr);
+							self.upper_out.send_payload(&mut effector, &event.name, (linfo, packet));
+						},
+						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
+					}
+				}
+			);
+		});
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn ethernet_frame_pushing()
+	{
+		for &format in [EthernetFormat::EthernetII, EthernetFormat::Ieee8023].iter() {
+			let mut packet = Packet::new("test packet", "1");
+			packet.push_back_bytes("hello world".as_bytes());
+
+			let linfo = LinkInfo::new(EtherType::IPv4, &[0x02, 0, 0, 0, 0, 1], &[0x02, 0, 0, 0, 0, 2]);
+			let header1 = EthernetFrame::new(format, &linfo);
+			header1.push(&mut packet);
+			assert_eq!(MIN_FRAME_SIZE + 4, packet.len());
+
+			match EthernetFrame::pop(&mut packet) {
+				Ok(header2) => {
+					assert_eq!(header1.dst_addr, header2.dst_addr);
+					assert_eq!(header1.src_addr, header2.src_addr);
+					assert_eq!(header1.ether_type.as_u16(), header2.ether_type.as_u16());
+					assert_eq!(format, header2.format);
+				}
+				Err(mesg) => assert!(false, "EthernetFrame::pop failed: ".to_string() + &mesg)
+			}
+
+			if format == EthernetFormat::Ieee8023 {
+				assert_eq!(11, packet.len());
+			}
+		}
+    }
+}
@@ -0,0 +1,1013 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use link::link::*;
+use link::link_helpers::*;
+use std::u8;
+
+// Unless otherwise indicated all references are to the 2016 version of "Part 11: Wireless LAN Medium Access Control (MAC) and Physical Layer (PHY) Specifications"
+// (i.e. the 802.11 spec). Note that, apart from frame control, multi-byte fields are little endian.
+
+/// Capability Information bits, see 9.4.1.4.
+pub const CAPABILITY_ESS: u16 = 0x0001;
+pub const CAPABILITY_IBSS: u16 = 0x0002;
+pub const CAPABILITY_PRIVACY: u16 = 0x0010;
+pub const CAPABILITY_SHORT_SLOT_TIME: u16 = 0x0400;
+
+/// The OFDM rates in 500 kb/s units. Basic rates (which all stations in the BSS must support)
+/// have the high bit set. See 9.4.2.3.
+pub const OFDM_RATES: [u8; 8] = [0x8C, 0x12, 0x98, 0x24, 0xB0, 0x48, 0x60, 0x6C];
+
+/// Status code for a successful authentication or association, see 9.4.1.9.
+pub const STATUS_SUCCESS: u16 = 0;
+
+/// Reason code used when a station leaves the BSS, see 9.4.1.7.
+pub const REASON_LEAVING: u16 = 3;
+
+/// Size in bytes of ACK and CTS frames (including the FCS).
+pub const ACK_LEN: usize = 14;
+
+/// In memory representation of a MAC 802.11 data header.
+#[derive(Clone, Copy, Debug)]
+pub struct Mac80211DataFrame	// see 9.3.2
+{
+	/// Microseconds the medium is reserved for after this frame (i.e. for the ACK), see 9.2.5.
+	pub duration: u16,
+
+	/// Address the frame originated at.
+	pub sa: MacAddress,
+
+	/// Address the frame is being sent from. Often the same as sa.
+	pub ta: MacAddress,
+
+	/// Address the frame is being forwarded to. Often the same as da.
+	pub ra: MacAddress,
+
+	/// Address the frame is being routed to.
+	pub da: MacAddress,
+
+	/// AP address.
+	pub bssid: MacAddress,
+
+	/// Sequence number set by the TA.
+	pub seq_num: u16,
+
+	/// Set if this is a retransmission.
+	pub retry: bool,
+
+	/// Set for frames going from a station to an AP.
+	pub to_ds: bool,
+
+	/// Set for frames going from an AP to a station.
+	pub from_ds: bool,
+
+	/// Set for QoS data frames (which have a QoS control field).
+	pub qos: bool,
+
+	/// Traffic identifier (i.e. user priority), see [`AccessCategory`].
+	pub tid: u8,
+
+	// TODO: Lots of stuff missing, e.g. fragmentation, power management, and rate control.
+}
+
+impl Mac80211DataFrame
+{
+	pub fn new(src_addr: &MacAddress, dst_addr: &MacAddress, seq_num: u16) -> Self
+	{
+		let sa = *src_addr;
+		let da = *dst_addr;
+		let bssid = [0, 0, 0, 0, 0, 0];
+
+		Mac80211DataFrame {
+			duration: 0,
+			sa,
+			ta: sa,
+			ra: da,
+			da,
+			bssid,
+			seq_num,
+			retry: false,
+			to_ds: false,
+			from_ds: false,
+			qos: true,
+			tid: 0,
+		}
+	}
+
+	/// Adds an 802.11 ethernet header to the packet.
+	pub fn push(&self, packet: &mut Packet)
+	{
+		let mut header = Header::with_capacity(32);
+
+		let subtype = if self.qos {0b1000} else {0b0000};
+		let flags = (self.to_ds as u8) | (self.from_ds as u8) << 1 | (self.retry as u8) << 3;
+		header.push16(frame_control(DATA_TYPE, subtype, flags));
+
+		push16_le(&mut header, self.duration);	// duration/ID, see 9.2.4.2
+
+		header.push_bytes(&self.ra);		// addresses, see Table 9-26
+		header.push_bytes(&self.ta);
+		match (self.to_ds, self.from_ds) {
+			(false, false) => header.push_bytes(&self.bssid),
+			(false, true)  => header.push_bytes(&self.sa),
+			(true, _)      => header.push_bytes(&self.da),
+		}
+
+		push16_le(&mut header, self.seq_num << 4);	// sequence control, see 9.2.4.4.1
+
+		if self.to_ds && self.from_ds {
+			header.push_bytes(&self.sa);
+		}
+
+		if self.qos {
+			let ack_policy = if is_group(&self.da) {0b01} else {0b00};	// no ack or normal ack, see 9.2.4.5.4
+			push16_le(&mut header, ack_policy << 5 | (self.tid & 0x0F) as u16);	// QoS control, see 9.2.4.5.1
+		}
+
+		packet.push_header(&header);
+
+		// Note that 802.3 has a minimum frame body size but 802.11 does not.
+		push_fcs(packet);
+	}
+
+	/// Removes an 802.11 ethernet header from the packet.
+	pub fn pop(packet: &mut Packet) -> Result<Mac80211DataFrame, String>
+	{
+		try!(pop_fcs(packet, 28));
+
+		let (kind, subtype, flags) = try!(pop_frame_control(packet));
+		if kind != DATA_TYPE || (subtype != 0b0000 && subtype != 0b1000) {
+			return Err(format!("type {} subtype {} isn't a data frame", kind, subtype))
+		}
+		let to_ds = flags & 0b0001 != 0;
+		let from_ds = flags & 0b0010 != 0;
+		let retry = flags & 0b1000 != 0;
+		let qos = subtype == 0b1000;
+
+		let min_len = 22 + (if to_ds && from_ds {6} else {0}) + (if qos {2} else {0});
+		if packet.len() < min_len {
+			return Err(format!("data frame header should be at least {} bytes not {}", min_len, packet.len()))
+		}
+
+		let duration = pop16_le(packet);
+		let addr1 = pop_addr(packet);
+		let addr2 = pop_addr(packet);
+		let addr3 = pop_addr(packet);
+		let sn = pop16_le(packet);
+		let addr4 = if to_ds && from_ds {pop_addr(packet)} else {[0; 6]};
+		let tid = if qos {(pop16_le(packet) & 0x0F) as u8} else {0};
+
+		let (da, sa, bssid) = match (to_ds, from_ds) {
+			(false, false) => (addr1, addr2, addr3),
+			(false, true)  => (addr1, addr3, addr2),
+			(true, false)  => (addr3, addr2, addr1),
+			(true, true)   => (addr3, addr4, [0; 6]),
+		};
+
+		Ok(Mac80211DataFrame {
+			duration,
+			sa,
+			ta: addr2,
+			ra: addr1,
+			da,
+			bssid,
+			seq_num: sn >> 4,
+			retry,
+			to_ds,
+			from_ds,
+			qos,
+			tid,
+		})
+	}
+}
+
+/// In memory representation of an 802.11 ACK frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Mac80211AckFrame	// see 9.3.1.4
+{
+	/// The TA of the frame being acknowledged.
+	pub ra: MacAddress,
+}
+
+impl Mac80211AckFrame
+{
+	pub fn new(ra: &MacAddress) -> Self
+	{
+		Mac80211AckFrame {ra: *ra}
+	}
+
+	/// Adds an ACK frame to the packet (which should be empty).
+	pub fn push(&self, packet: &mut Packet)
+	{
+		let mut header = Header::with_capacity(10);
+		header.push16(frame_control(CONTROL_TYPE, ACK_SUBTYPE, 0));
+		push16_le(&mut header, 0);			// duration (there's no fragmentation so this is always zero)
+		header.push_bytes(&self.ra);
+
+		packet.push_header(&header);
+		push_fcs(packet);
+	}
+
+	/// Removes an ACK frame from the packet.
+	pub fn pop(packet: &mut Packet) -> Result<Mac80211AckFrame, String>
+	{
+		try!(pop_fcs(packet, 14));
+		try!(pop_control(packet, ACK_SUBTYPE, 10));
+		let _duration = pop16_le(packet);
+		let ra = pop_addr(packet);
+
+		Ok(Mac80211AckFrame {ra})
+	}
+}
+
+/// In memory representation of an 802.11 RTS frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Mac80211RtsFrame	// see 9.3.1.2
+{
+	/// Microseconds needed for the rest of the exchange (CTS, data, and ACK).
+	pub duration: u16,
+
+	pub ra: MacAddress,
+	pub ta: MacAddress,
+}
+
+impl Mac80211RtsFrame
+{
+	pub fn new(ra: &MacAddress, ta: &MacAddress, duration: u16) -> Self
+	{
+		Mac80211RtsFrame {duration, ra: *ra, ta: *ta}
+	}
+
+	/// Adds an RTS frame to the packet (which should be empty).
+	pub fn push(&self, packet: &mut Packet)
+	{
+		let mut header = Header::with_capacity(16);
+		header.push16(frame_control(CONTROL_TYPE, RTS_SUBTYPE, 0));
+		push16_le(&mut header, self.duration);
+		header.push_bytes(&self.ra);
+		header.push_bytes(&self.ta);
+
+		packet.push_header(&header);
+		push_fcs(packet);
+	}
+
+	/// Removes an RTS frame from the packet.
+	pub fn pop(packet: &mut Packet) -> Result<Mac80211RtsFrame, String>
+	{
+		try!(pop_fcs(packet, 20));
+		try!(pop_control(packet, RTS_SUBTYPE, 16));
+		let duration = pop16_le(packet);
+		let ra = pop_addr(packet);
+		let ta = pop_addr(packet);
+
+		Ok(Mac80211RtsFrame {duration, ra, ta})
+	}
+}
+
+/// In memory representation of an 802.11 CTS frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Mac80211CtsFrame	// see 9.3.1.3
+{
+	/// Microseconds needed for the rest of the exchange (data and ACK).
+	pub duration: u16,
+
+	/// The TA of the RTS being answered.
+	pub ra: MacAddress,
+}
+
+impl Mac80211CtsFrame
+{
+	pub fn new(ra: &MacAddress, duration: u16) -> Self
+	{
+		Mac80211CtsFrame {duration, ra: *ra}
+	}
+
+	/// Adds a CTS frame to the packet (which should be empty).
+	pub fn push(&self, packet: &mut Packet)
+	{
+		let mut header = Header::with_capacity(10);
+		header.push16(frame_control(CONTROL_TYPE, CTS_SUBTYPE, 0));
+		push16_le(&mut header, self.duration);
+		header.push_bytes(&self.ra);
+
+		packet.push_header(&header);
+		push_fcs(packet);
+	}
+
+	/// Removes a CTS frame from the packet.
+	pub fn pop(packet: &mut Packet) -> Result<Mac80211CtsFrame, String>
+	{
+		try!(pop_fcs(packet, 14));
+		try!(pop_control(packet, CTS_SUBTYPE, 10));
+		let duration = pop16_le(packet);
+		let ra = pop_addr(packet);
+
+		Ok(Mac80211CtsFrame {duration, ra})
+	}
+}
+
+/// In memory representation of the header shared by all 802.11 management frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mac80211ManagementHeader	// see 9.3.3.2
+{
+	/// Microseconds the medium is reserved for after this frame, see 9.2.5.
+	pub duration: u16,
+
+	pub da: MacAddress,
+	pub sa: MacAddress,
+	pub bssid: MacAddress,
+
+	/// Sequence number set by the SA.
+	pub seq_num: u16,
+
+	/// Set if this is a retransmission.
+	pub retry: bool,
+}
+
+impl Mac80211ManagementHeader
+{
+	pub fn new(src_addr: &MacAddress, dst_addr: &MacAddress, bssid: &MacAddress, seq_num: u16) -> Self
+	{
+		Mac80211ManagementHeader {
+			duration: 0,
+			da: *dst_addr,
+			sa: *src_addr,
+			bssid: *bssid,
+			seq_num,
+			retry: false,
+		}
+	}
+
+	fn push(&self, subtype: u8, header: &mut Header)
+	{
+		header.push16(frame_control(MANAGEMENT_TYPE, subtype, (self.retry as u8) << 3));
+		push16_le(header, self.duration);
+		header.push_bytes(&self.da);
+		header.push_bytes(&self.sa);
+		header.push_bytes(&self.bssid);
+		push16_le(header, self.seq_num << 4);
+	}
+
+	fn pop(packet: &mut Packet, flags: u8) -> Result<Mac80211ManagementHeader, String>
+	{
+		if packet.len() < 22 {
+			return Err(format!("management frame header should be 22 bytes not {}", packet.len()))
+		}
+
+		let duration = pop16_le(packet);
+		let da = pop_addr(packet);
+		let sa = pop_addr(packet);
+		let bssid = pop_addr(packet);
+		let sn = pop16_le(packet);
+
+		Ok(Mac80211ManagementHeader {duration, da, sa, bssid, seq_num: sn >> 4, retry: flags & 0b1000 != 0})
+	}
+}
+
+/// Body of beacon and probe response frames, see 9.3.3.3 and 9.3.3.11.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Beacon
+{
+	/// The AP's TSF timer in microseconds.
+	pub timestamp: u64,
+
+	/// Time between beacons in TUs (1024 microseconds).
+	pub beacon_interval: u16,
+
+	/// See CAPABILITY_ESS, etc.
+	pub capability: u16,
+
+	pub ssid: String,
+
+	/// Supported rates, see OFDM_RATES.
+	pub rates: Vec<u8>,
+
+	/// From the DS Parameter Set element.
+	pub channel: u8,
+}
+
+/// Body of probe request frames, see 9.3.3.10.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeRequest
+{
+	/// An empty SSID matches any network.
+	pub ssid: String,
+	pub rates: Vec<u8>,
+}
+
+/// Body of authentication frames, see 9.3.3.12.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Authentication
+{
+	/// Zero for Open System.
+	pub algorithm: u16,
+
+	/// Transaction sequence number, starts at one.
+	pub seq: u16,
+
+	/// See STATUS_SUCCESS.
+	pub status: u16,
+}
+
+/// Body of association request frames, see 9.3.3.6.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssociationRequest
+{
+	pub capability: u16,
+
+	/// How often the station wakes up to listen to beacons, in beacon intervals.
+	pub listen_interval: u16,
+
+	pub ssid: String,
+	pub rates: Vec<u8>,
+}
+
+/// Body of association response frames, see 9.3.3.7.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssociationResponse
+{
+	pub capability: u16,
+
+	/// See STATUS_SUCCESS.
+	pub status: u16,
+
+	/// Association ID (1 to 2007) assigned by the AP.
+	pub aid: u16,
+
+	pub rates: Vec<u8>,
+}
+
+/// All of the 802.11 frames we support.
+#[derive(Clone, Debug)]
+pub enum Ieee80211Frame
+{
+	/// Data and QoS data frames. The frame body is left in the packet.
+	Data(Mac80211DataFrame),
+	Ack(Mac80211AckFrame),
+	Rts(Mac80211RtsFrame),
+	Cts(Mac80211CtsFrame),
+	Beacon(Mac80211ManagementHeader, Beacon),
+	ProbeRequest(Mac80211ManagementHeader, ProbeRequest),
+	ProbeResponse(Mac80211ManagementHeader, Beacon),
+	Authentication(Mac80211ManagementHeader, Authentication),
+	AssociationRequest(Mac80211ManagementHeader, AssociationRequest),
+	AssociationResponse(Mac80211ManagementHeader, AssociationResponse),
+
+	/// Includes the reason code.
+	Deauthentication(Mac80211ManagementHeader, u16),
+
+	/// Includes the reason code.
+	Disassociation(Mac80211ManagementHeader, u16),
+}
+
+impl Ieee80211Frame
+{
+	pub fn name(&self) -> &'static str
+	{
+		match *self {
+			Ieee80211Frame::Data(ref header) => if header.qos {"QoS Data"} else {"Data"},
+			Ieee80211Frame::Ack(_) => "ACK",
+			Ieee80211Frame::Rts(_) => "RTS",
+			Ieee80211Frame::Cts(_) => "CTS",
+			Ieee80211Frame::Beacon(_, _) => "Beacon",
+			Ieee80211Frame::ProbeRequest(_, _) => "Probe Request",
+			Ieee80211Frame::ProbeResponse(_, _) => "Probe Response",
+			Ieee80211Frame::Authentication(_, _) => "Authentication",
+			Ieee80211Frame::AssociationRequest(_, _) => "Association Request",
+			Ieee80211Frame::AssociationResponse(_, _) => "Association Response",
+			Ieee80211Frame::Deauthentication(_, _) => "Deauthentication",
+			Ieee80211Frame::Disassociation(_, _) => "Disassociation",
+		}
+	}
+
+	/// Returns the address of the station that should process the frame.
+	pub fn ra(&self) -> MacAddress
+	{
+		match *self {
+			Ieee80211Frame::Data(ref header) => header.ra,
+			Ieee80211Frame::Ack(ref frame) => frame.ra,
+			Ieee80211Frame::Rts(ref frame) => frame.ra,
+			Ieee80211Frame::Cts(ref frame) => frame.ra,
+			Ieee80211Frame::Beacon(ref header, _) => header.da,
+			Ieee80211Frame::ProbeRequest(ref header, _) => header.da,
+			Ieee80211Frame::ProbeResponse(ref header, _) => header.da,
+			Ieee80211Frame::Authentication(ref header, _) => header.da,
+			Ieee80211Frame::AssociationRequest(ref header, _) => header.da,
+			Ieee80211Frame::AssociationResponse(ref header, _) => header.da,
+			Ieee80211Frame::Deauthentication(ref header, _) => header.da,
+			Ieee80211Frame::Disassociation(ref header, _) => header.da,
+		}
+	}
+
+	/// Microseconds the medium is reserved for after this frame, used by other radios to set
+	/// their NAV.
+	pub fn duration(&self) -> u16
+	{
+		match *self {
+			Ieee80211Frame::Data(ref header) => header.duration,
+			Ieee80211Frame::Ack(_) => 0,
+			Ieee80211Frame::Rts(ref frame) => frame.duration,
+			Ieee80211Frame::Cts(ref frame) => frame.duration,
+			Ieee80211Frame::Beacon(ref header, _) => header.duration,
+			Ieee80211Frame::ProbeRequest(ref header, _) => header.duration,
+			Ieee80211Frame::ProbeResponse(ref header, _) => header.duration,
+			Ieee80211Frame::Authentication(ref header, _) => header.duration,
+			Ieee80211Frame::AssociationRequest(ref header, _) => header.duration,
+			Ieee80211Frame::AssociationResponse(ref header, _) => header.duration,
+			Ieee80211Frame::Deauthentication(ref header, _) => header.duration,
+			Ieee80211Frame::Disassociation(ref header, _) => header.duration,
+		}
+	}
+
+	/// Sets the duration field (ACKs always use zero because we don't fragment).
+	pub fn set_duration(&mut self, duration: u16)
+	{
+		match *self {
+			Ieee80211Frame::Data(ref mut header) => header.duration = duration,
+			Ieee80211Frame::Ack(_) => (),
+			Ieee80211Frame::Rts(ref mut frame) => frame.duration = duration,
+			Ieee80211Frame::Cts(ref mut frame) => frame.duration = duration,
+			Ieee80211Frame::Beacon(ref mut header, _) => header.duration = duration,
+			Ieee80211Frame::ProbeRequest(ref mut header, _) => header.duration = duration,
+			Ieee80211Frame::ProbeResponse(ref mut header, _) => header.duration = duration,
+			Ieee80211Frame::Authentication(ref mut header, _) => header.duration = duration,
+			Ieee80211Frame::AssociationRequest(ref mut header, _) => header.duration = duration,
+			Ieee80211Frame::AssociationResponse(ref mut header, _) => header.duration = duration,
+			Ieee80211Frame::Deauthentication(ref mut header, _) => header.duration = duration,
+			Ieee80211Frame::Disassociation(ref mut header, _) => header.duration = duration,
+		}
+	}
+
+	/// Sets the retry flag (which control frames don't have).
+	pub fn set_retry(&mut self, retry: bool)
+	{
+		match *self {
+			Ieee80211Frame::Data(ref mut header) => header.retry = retry,
+			Ieee80211Frame::Ack(_) | Ieee80211Frame::Rts(_) | Ieee80211Frame::Cts(_) => (),
+			Ieee80211Frame::Beacon(ref mut header, _) => header.retry = retry,
+			Ieee80211Frame::ProbeRequest(ref mut header, _) => header.retry = retry,
+			Ieee80211Frame::ProbeResponse(ref mut header, _) => header.retry = retry,
+			Ieee80211Frame::Authentication(ref mut header, _) => header.retry = retry,
+			Ieee80211Frame::AssociationRequest(ref mut header, _) => header.retry = retry,
+			Ieee80211Frame::AssociationResponse(ref mut header, _) => header.retry = retry,
+			Ieee80211Frame::Deauthentication(ref mut header, _) => header.retry = retry,
+			Ieee80211Frame::Disassociation(ref mut header, _) => header.retry = retry,
+		}
+	}
+
+	/// Adds the frame to the packet. For data frames the packet should contain the
+	/// frame body, otherwise it should be empty.
+	pub fn push(&self, packet: &mut Packet)
+	{
+		match *self {
+			Ieee80211Frame::Data(ref header) => header.push(packet),
+			Ieee80211Frame::Ack(ref frame) => frame.push(packet),
+			Ieee80211Frame::Rts(ref frame) => frame.push(packet),
+			Ieee80211Frame::Cts(ref frame) => frame.push(packet),
+			Ieee80211Frame::Beacon(ref header, ref body) => push_management(packet, header, BEACON_SUBTYPE, |h| push_beacon(h, body)),
+			Ieee80211Frame::ProbeRequest(ref header, ref body) => push_management(packet, header, PROBE_REQUEST_SUBTYPE, |h| {
+				push_ssid(h, &body.ssid);
+				push_rates(h, &body.rates);
+			}),
+			Ieee80211Frame::ProbeResponse(ref header, ref body) => push_management(packet, header, PROBE_RESPONSE_SUBTYPE, |h| push_beacon(h, body)),
+			Ieee80211Frame::Authentication(ref header, ref body) => push_management(packet, header, AUTHENTICATION_SUBTYPE, |h| {
+				push16_le(h, body.algorithm);
+				push16_le(h, body.seq);
+				push16_le(h, body.status);
+			}),
+			Ieee80211Frame::AssociationRequest(ref header, ref body) => push_management(packet, header, ASSOCIATION_REQUEST_SUBTYPE, |h| {
+				push16_le(h, body.capability);
+				push16_le(h, body.listen_interval);
+				push_ssid(h, &body.ssid);
+				push_rates(h, &body.rates);
+			}),
+			Ieee80211Frame::AssociationResponse(ref header, ref body) => push_management(packet, header, ASSOCIATION_RESPONSE_SUBTYPE, |h| {
+				push16_le(h, body.capability);
+				push16_le(h, body.status);
+				push16_le(h, body.aid | 0xC000);	// the top two bits are always set, see 9.4.1.8
+				push_rates(h, &body.rates);
+			}),
+			Ieee80211Frame::Deauthentication(ref header, reason) => push_management(packet, header, DEAUTHENTICATION_SUBTYPE, |h| push16_le(h, reason)),
+			Ieee80211Frame::Disassociation(ref header, reason) => push_management(packet, header, DISASSOCIATION_SUBTYPE, |h| push16_le(h, reason)),
+		}
+	}
+
+	/// Removes a frame from the packet. For data frames the frame body is left in the packet.
+	pub fn pop(packet: &mut Packet) -> Result<Ieee80211Frame, String>
+	{
+		if packet.len() < 14 {
+			return Err(format!("frames should be at least 14 bytes not {}", packet.len()))
+		}
+
+		let b = packet.get(0);
+		let kind = b >> 2 & 0b11;
+		let subtype = b >> 4;
+		match kind {
+			DATA_TYPE => Mac80211DataFrame::pop(packet).map(Ieee80211Frame::Data),
+			CONTROL_TYPE => match subtype {
+				ACK_SUBTYPE => Mac80211AckFrame::pop(packet).map(Ieee80211Frame::Ack),
+				RTS_SUBTYPE => Mac80211RtsFrame::pop(packet).map(Ieee80211Frame::Rts),
+				CTS_SUBTYPE => Mac80211CtsFrame::pop(packet).map(Ieee80211Frame::Cts),
+				_ => Err(format!("control subtype {} isn't supported", subtype))
+			},
+			MANAGEMENT_TYPE => Ieee80211Frame::pop_management(packet),
+			_ => Err(format!("frame type {} is reserved", kind))
+		}
+	}
+
+	fn pop_management(packet: &mut Packet) -> Result<Ieee80211Frame, String>
+	{
+		try!(pop_fcs(packet, 28));
+		let (_, subtype, flags) = try!(pop_frame_control(packet));
+		let header = try!(Mac80211ManagementHeader::pop(packet, flags));
+
+		match subtype {
+			BEACON_SUBTYPE => pop_beacon(packet).map(|body| Ieee80211Frame::Beacon(header, body)),
+			PROBE_RESPONSE_SUBTYPE => pop_beacon(packet).map(|body| Ieee80211Frame::ProbeResponse(header, body)),
+			PROBE_REQUEST_SUBTYPE => {
+				let elements = try!(pop_elements(packet));
+				Ok(Ieee80211Frame::ProbeRequest(header, ProbeRequest {
+					ssid: try!(find_ssid(&elements)),
+					rates: find_rates(&elements),
+				}))
+			},
+			AUTHENTICATION_SUBTYPE => {
+				try!(check_fixed(packet, 6));
+				let algorithm = pop16_le(packet);
+				let seq = pop16_le(packet);
+				let status = pop16_le(packet);
+				Ok(Ieee80211Frame::Authentication(header, Authentication {algorithm, seq, status}))
+			},
+			ASSOCIATION_REQUEST_SUBTYPE => {
+				try!(check_fixed(packet, 4));
+				let capability = pop16_le(packet);
+				let listen_interval = pop16_le(packet);
+				let elements = try!(pop_elements(packet));
+				Ok(Ieee80211Frame::AssociationRequest(header, AssociationRequest {
+					capability,
+					listen_interval,
+					ssid: try!(find_ssid(&elements)),
+					rates: find_rates(&elements),
+				}))
+			},
+			ASSOCIATION_RESPONSE_SUBTYPE => {
+				try!(check_fixed(packet, 6));
+				let capability = pop16_le(packet);
+				let status = pop16_le(packet);
+				let aid = pop16_le(packet) & 0x3FFF;
+				let elements = try!(pop_elements(packet));
+				Ok(Ieee80211Frame::AssociationResponse(header, AssociationResponse {
+					capability,
+					status,
+					aid,
+					rates: find_rates(&elements),
+				}))
+			},
+			DEAUTHENTICATION_SUBTYPE => {
+				try!(check_fixed(packet, 2));
+				Ok(Ieee80211Frame::Deauthentication(header, pop16_le(packet)))
+			},
+			DISASSOCIATION_SUBTYPE => {
+				try!(check_fixed(packet, 2));
+				Ok(Ieee80211Frame::Disassociation(header, pop16_le(packet)))
+			},
+			_ => Err(format!("management subtype {} isn't supported", subtype))
+		}
+	}
+}
+
+/// Returns true for broadcast and multicast addresses.
+pub fn is_group(addr: &MacAddress) -> bool
+{
+	addr[0] & 0x01 != 0
+}
+
+/// Returns true if the frame ends with a valid FCS.
+pub fn valid_fcs(packet: &Packet) -> bool
+{
+	// When sending the crc includes everything but the crc itself (the FCS field).
+	// When receiving the crc includes the FCS and, because of the magic of modulo
+	// arithmetic, a valid frame's crc will always be 0xC704DD7B.
+	packet.len() > 4 && reverse32(!crc32(packet)) == 0xC704DD7B
+}
+
+// Frame types and subtypes, see Table 9-1.
+const MANAGEMENT_TYPE: u8 = 0b00;
+const CONTROL_TYPE: u8 = 0b01;
+const DATA_TYPE: u8 = 0b10;
+
+const ASSOCIATION_REQUEST_SUBTYPE: u8 = 0b0000;
+const ASSOCIATION_RESPONSE_SUBTYPE: u8 = 0b0001;
+const PROBE_REQUEST_SUBTYPE: u8 = 0b0100;
+const PROBE_RESPONSE_SUBTYPE: u8 = 0b0101;
+const BEACON_SUBTYPE: u8 = 0b1000;
+const DISASSOCIATION_SUBTYPE: u8 = 0b1010;
+const AUTHENTICATION_SUBTYPE: u8 = 0b1011;
+const DEAUTHENTICATION_SUBTYPE: u8 = 0b1100;
+
+const RTS_SUBTYPE: u8 = 0b1011;
+const CTS_SUBTYPE: u8 = 0b1100;
+const ACK_SUBTYPE: u8 = 0b1101;
+
+// Element IDs, see Table 9-77.
+const SSID_ELEMENT: u8 = 0;
+const SUPPORTED_RATES_ELEMENT: u8 = 1;
+const DS_PARAMETER_SET_ELEMENT: u8 = 3;
+
+// Frame control is written so that the first byte has the protocol version, type, and
+// subtype and the second byte has the flags, see 9.2.4.1. Note that B0 is the low bit in
+// the first byte.
+fn frame_control(kind: u8, subtype: u8, flags: u8) -> u16
+{
+	((subtype << 4 | kind << 2) as u16) << 8 | flags as u16
+}
+
+// Returns (type, subtype, flags).
+fn pop_frame_control(packet: &mut Packet) -> Result<(u8, u8, u8), String>
+{
+	let b0 = packet.pop8();
+	let flags = packet.pop8();
+	if b0 & 0b11 != 0 {				// 9.2.4.1.2
+		return Err("Version isn't zero".to_string())
+	}
+	Ok((b0 >> 2 & 0b11, b0 >> 4, flags))
+}
+
+fn pop_control(packet: &mut Packet, subtype: u8, len: usize) -> Result<(), String>
+{
+	let (k, s, _) = try!(pop_frame_control(packet));
+	if k != CONTROL_TYPE || s != subtype {
+		return Err(format!("type {} subtype {} isn't control subtype {}", k, s, subtype))
+	}
+	if packet.len() != len - 2 {
+		return Err(format!("control frame should be {} bytes not {}", len, packet.len() + 2))
+	}
+	Ok(())
+}
+
+fn push_management<F>(packet: &mut Packet, mheader: &Mac80211ManagementHeader, subtype: u8, push_body: F)
+	where F: Fn(&mut Header)
+{
+	assert!(packet.len() == 0, "management frames don't have a payload");
+
+	let mut header = Header::with_capacity(64);
+	mheader.push(subtype, &mut header);
+	push_body(&mut header);
+
+	packet.push_header(&header);
+	push_fcs(packet);
+}
+
+fn push_beacon(header: &mut Header, body: &Beacon)
+{
+	header.push32(((body.timestamp & 0xFFFF_FFFF) as u32).swap_bytes());
+	header.push32(((body.timestamp >> 32) as u32).swap_bytes());
+	push16_le(header, body.beacon_interval);
+	push16_le(header, body.capability);
+	push_ssid(header, &body.ssid);
+	push_rates(header, &body.rates);
+	push_element(header, DS_PARAMETER_SET_ELEMENT, &[body.channel]);
+}
+
+fn pop_beacon(packet: &mut Packet) -> Result<Beacon, String>
+{
+	try!(check_fixed(packet, 12));
+	let low = packet.pop32().swap_bytes() as u64;
+	let high = packet.pop32().swap_bytes() as u64;
+	let beacon_interval = pop16_le(packet);
+	let capability = pop16_le(packet);
+	let elements = try!(pop_elements(packet));
+
+	let channel = match elements.iter().find(|e| e.0 == DS_PARAMETER_SET_ELEMENT) {
+		Some(&(_, ref data)) if data.len() == 1 => data[0],
+		_ => 0
+	};
+
+	Ok(Beacon {
+		timestamp: high << 32 | low,
+		beacon_interval,
+		capability,
+		ssid: try!(find_ssid(&elements)),
+		rates: find_rates(&elements),
+		channel,
+	})
+}
+
+fn push_ssid(header: &mut Header, ssid: &str)
+{
+	assert!(ssid.len() <= 32, "SSIDs can be at most 32 bytes");
+	push_element(header, SSID_ELEMENT, ssid.as_bytes());
+}
+
+fn push_rates(header: &mut Header, rates: &[u8])
+{
+	assert!(rates.len() <= 8, "extended supported rates aren't supported");
+	push_element(header, SUPPORTED_RATES_ELEMENT, rates);
+}
+
+// See 9.4.2.1.
+fn push_element(header: &mut Header, id: u8, data: &[u8])
+{
+	assert!(data.len() <= u8::MAX as usize);
+	header.push8(id);
+	header.push8(data.len() as u8);
+	header.push_bytes(data);
+}
+
+// Unknown elements are returned as well so that callers can ignore them.
+fn pop_elements(packet: &mut Packet) -> Result<Vec<(u8, Vec<u8>)>, String>
+{
+	let mut elements = Vec::new();
+	while packet.len() > 0 {
+		if packet.len() < 2 {
+			return Err("truncated element".to_string())
+		}
+		let id = packet.pop8();
+		let len = packet.pop8() as usize;
+		if packet.len() < len {
+			return Err(format!("element {} should have {} bytes but only {} are left", id, len, packet.len()))
+		}
+		elements.push((id, packet.pop_bytes(len)));
+	}
+	Ok(elements)
+}
+
+fn find_ssid(elements: &Vec<(u8, Vec<u8>)>) -> Result<String, String>
+{
+	match elements.iter().find(|e| e.0 == SSID_ELEMENT) {
+		Some(&(_, ref data)) => Ok(String::from_utf8_lossy(data).into_owned()),
+		None => Err("missing the SSID element".to_string())
+	}
+}
+
+fn find_rates(elements: &Vec<(u8, Vec<u8>)>) -> Vec<u8>
+{
+	match elements.iter().find(|e| e.0 == SUPPORTED_RATES_ELEMENT) {
+		Some(&(_, ref data)) => data.clone(),
+		None => Vec::new()
+	}
+}
+
+fn check_fixed(packet: &Packet, len: usize) -> Result<(), String>
+{
+	if packet.len() < len {
+		return Err(format!("fixed fields should be {} bytes not {}", len, packet.len()))
+	}
+	Ok(())
+}
+
+fn push16_le(header: &mut Header, data: u16)
+{
+	header.push16(data.swap_bytes());
+}
+
+fn pop16_le(packet: &mut Packet) -> u16
+{
+	packet.pop16().swap_bytes()
+}
+
+fn pop_addr(packet: &mut Packet) -> MacAddress
+{
+	[packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()]
+}
+
+fn push_fcs(packet: &mut Packet)
+{
+	let crc = crc32(packet);		// FCS (which is always little endian)
+	let fcs = [(crc & 0xFF) as u8, (crc >> 8 & 0xFF) as u8, (crc >> 16 & 0xFF) as u8, (crc >> 24 & 0xFF) as u8];
+	packet.push_back_bytes(&fcs);
+}
+
+// Checks and removes the FCS.
+fn pop_fcs(packet: &mut Packet, min_len: usize) -> Result<(), String>
+{
+	if packet.len() < min_len {
+		return Err(format!("frame should be at least {} bytes not {}", min_len, packet.len()))
+	}
+
+	if !valid_fcs(packet) {
+		return Err("Checksum error".to_string())
+	}
+
+	for _ in 0..4 {
+		let _ = packet.pop_back8();
+	}
+	Ok(())
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[cfg(test)]
+	fn round_trip(frame: &Ieee80211Frame) -> Ieee80211Frame
+	{
+		let mut packet = Packet::new("frame", "1");
+		frame.push(&mut packet);
+		let result = Ieee80211Frame::pop(&mut packet).unwrap();
+		assert_eq!(0, packet.len());
+		assert_eq!(frame.name(), result.name());
+		result
+	}
+
+    #[test]
+    fn frame_pushing()
+	{
+		let ap = [2, 0, 0, 0, 0, 1];
+		let sta = [2, 0, 0, 0, 0, 2];
+		let server = [2, 0, 0, 0, 0, 3];
+
+		// Data frames going to the AP use the third address for the destination.
+		let mut header = Mac80211DataFrame::new(&sta, &server, 100);
+		header.ra = ap;
+		header.bssid = ap;
+		header.to_ds = true;
+		header.retry = true;
+		header.tid = 6;
+		header.duration = 44;
+		let mut packet = Packet::new("data", "2");
+		packet.push_back_bytes(b"hello");
+		header.push(&mut packet);
+		assert_eq!(0b1000_10_00, packet.get(0));
+		assert_eq!(0b0000_1001, packet.get(1));
+		match Ieee80211Frame::pop(&mut packet).unwrap() {
+			Ieee80211Frame::Data(result) => {
+				assert_eq!(sta, result.sa);
+				assert_eq!(server, result.da);
+				assert_eq!(ap, result.ra);
+				assert_eq!(ap, result.bssid);
+				assert_eq!(100, result.seq_num);
+				assert_eq!(6, result.tid);
+				assert_eq!(44, result.duration);
+				assert!(result.retry);
+			},
+			frame => panic!("expected a data frame not {}", frame.name())
+		}
+		assert_eq!(5, packet.len());
+
+		// ACK and CTS frames have the same length.
+		match round_trip(&Ieee80211Frame::Cts(Mac80211CtsFrame::new(&sta, 300))) {
+			Ieee80211Frame::Cts(frame) => assert_eq!(300, frame.duration),
+			frame => panic!("expected a CTS not {}", frame.name())
+		}
+		round_trip(&Ieee80211Frame::Ack(Mac80211AckFrame::new(&sta)));
+		round_trip(&Ieee80211Frame::Rts(Mac80211RtsFrame::new(&ap, &sta, 400)));
+
+		let mheader = Mac80211ManagementHeader::new(&ap, &BROADCAST_MAC, &ap, 7);
+		let beacon = Beacon {
+			timestamp: 0x1_0000_0002,
+			beacon_interval: 100,
+			capability: CAPABILITY_ESS | CAPABILITY_SHORT_SLOT_TIME,
+			ssid: "simulated".to_string(),
+			rates: OFDM_RATES.to_vec(),
+			channel: 36,
+		};
+		match round_trip(&Ieee80211Frame::Beacon(mheader, beacon.clone())) {
+			Ieee80211Frame::Beacon(h, b) => {
+				assert_eq!(mheader, h);
+				assert_eq!(beacon, b);
+			},
+			frame => panic!("expected a beacon not {}", frame.name())
+		}
+
+		let mheader = Mac80211ManagementHeader::new(&sta, &ap, &ap, 8);
+		let request = AssociationRequest {capability: CAPABILITY_ESS, listen_interval: 10, ssid: "simulated".to_string(), rates: OFDM_RATES.to_vec()};
+		match round_trip(&Ieee80211Frame::AssociationRequest(mheader, request.clone())) {
+			Ieee80211Frame::AssociationRequest(_, r) => assert_eq!(request, r),
+			frame => panic!("expected an association request not {}", frame.name())
+		}
+
+		let response = AssociationResponse {capability: CAPABILITY_ESS, status: STATUS_SUCCESS, aid: 1, rates: OFDM_RATES.to_vec()};
+		match round_trip(&Ieee80211Frame::AssociationResponse(mheader, response.clone())) {
+			Ieee80211Frame::AssociationResponse(_, r) => assert_eq!(response, r),
+			frame => panic!("expected an association response not {}", frame.name())
+		}
+
+		let auth = Authentication {algorithm: 0, seq: 1, status: STATUS_SUCCESS};
+		match round_trip(&Ieee80211Frame::Authentication(mheader, auth)) {
+			Ieee80211Frame::Authentication(_, a) => assert_eq!(auth, a),
+			frame => panic!("expected an authentication not {}", frame.name())
+		}
+
+		round_trip(&Ieee80211Frame::ProbeRequest(mheader, ProbeRequest {ssid: "".to_string(), rates: OFDM_RATES.to_vec()}));
+		match round_trip(&Ieee80211Frame::Deauthentication(mheader, REASON_LEAVING)) {
+			Ieee80211Frame::Deauthentication(_, reason) => assert_eq!(REASON_LEAVING, reason),
+			frame => panic!("expected a deauthentication not {}", frame.name())
+		}
+		round_trip(&Ieee80211Frame::Disassociation(mheader, REASON_LEAVING));
+    }
+}
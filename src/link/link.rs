@@ -17,6 +17,9 @@ use link::ether_type::*;
 
 pub type MacAddress = [u8; 6];
 
+/// Destination address used for frames that should be delivered to every device on the link.
+pub const BROADCAST_MAC: MacAddress = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
 /// This, and and [`Packet`], are the types used to communicate between
 /// the internet and link layers.
 pub struct LinkInfo
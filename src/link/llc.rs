@@ -14,7 +14,6 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 use common::*;
-use internet::*;
 use link::ether_type::*;
 use link::link::*;
 use score::*;
@@ -37,6 +36,11 @@ impl LlcHeader
 		LlcHeader {ether_type: EtherType::IPv4}
 	}
 
+	pub fn with_ether_type(ether_type: EtherType) -> Self
+	{
+		LlcHeader {ether_type}
+	}
+
 	/// Adds an LLC header to the packet.
 	pub fn push(&self, packet: &mut Packet)
 	{
@@ -98,14 +102,16 @@ impl LlcHeader
 	}
 }
 
-/// Component that pushes and pops a Logical Link Control header.
+/// Component that pushes and pops a Logical Link Control header. Upward IPv6 frames are
+/// sent to ipv6_out (normally [`NeighborDiscoveryComponent`]) and everything else to upper_out.
 pub struct LlcComponent
 {
 	data: ThreadData,
 
 	/// Listens for "send_down" events.
-	pub upper_in: InPort<(IPv4Header, Packet)>,	
+	pub upper_in: InPort<(LinkInfo, Packet)>,	
 	pub upper_out: OutPort<(LinkInfo, Packet)>,
+	pub ipv6_out: OutPort<(LinkInfo, Packet)>,
 
 	/// Listens for "send_up" events.
 	pub lower_in: InPort<(MacAddress, MacAddress, Packet)>,
@@ -125,6 +131,7 @@ impl LlcComponent
 
 			lower_in: InPort::with_port_name(id, "lower_in"),
 			upper_out: OutPort::new(),
+			ipv6_out: OutPort::new(),
 		}
 	}
 	
@@ -135,20 +142,20 @@ impl LlcComponent
 				"init 0" => {
 				},
 				"send_down" => {
-					let (ipv4, mut packet) = event.take_payload::<(IPv4Header, Packet)>();
-					let header = LlcHeader::with_ipv4();
+					let (linfo, mut packet) = event.take_payload::<(LinkInfo, Packet)>();
+					let header = LlcHeader::with_ether_type(linfo.ether_type);
 					header.push(&mut packet);
-
-               		let src_addr = [0, 0, ipv4.src_addr[0], ipv4.src_addr[1], ipv4.src_addr[2], ipv4.src_addr[3]];	// TODO: need to use an ARP table
-               		let dst_addr = [0, 0, ipv4.dst_addr[0], ipv4.dst_addr[1], ipv4.dst_addr[2], ipv4.dst_addr[3]];
-					self.lower_out.send_payload(&mut effector, &event.name, (src_addr, dst_addr, packet));
+					self.lower_out.send_payload(&mut effector, &event.name, (linfo.src_addr, linfo.dst_addr, packet));
 				},
 				"send_up" => {
 					let (src_addr, dst_addr, mut packet) = event.take_payload::<(MacAddress, MacAddress, Packet)>();
 					match LlcHeader::pop(&mut packet) {
 						Ok(header) => {
 							let linfo = LinkInfo::new(header.ether_type, &src_addr, &dst_addr);
-							self.upper_out.send_payload(&mut effector, &event.name, (linfo, packet));
+							match header.ether_type {
+								EtherType::IPv6 => self.ipv6_out.send_payload(&mut effector, &event.name, (linfo, packet)),
+								_ => self.upper_out.send_payload(&mut effector, &event.name, (linfo, packet)),
+							}
 						},
 						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
 					}
@@ -17,9 +17,15 @@ use common::*;
 //use internet::*;
 // use internet::protocol_numbers::*;
 // use internet::upper_internet::*;
+use link::dcf::*;
+use link::edca::*;
+use link::frame80211::*;
 use link::link::*;
-use link::link_helpers::*;
+use link::mlme::*;
+use physical::*;
+// use link::link_helpers::*;
 use score::*;
+use std::collections::{HashMap, VecDeque};
 // use std::str;
 use std::thread;
 // use std::u16;
@@ -28,151 +34,102 @@ use std::thread;
 // Unless otherwise indicated all references are to the 2016 version of "Part 11: Wireless LAN Medium Access Control (MAC) and Physical Layer (PHY) Specifications"
 // (i.e. the 802.11 spec).
 
-/// In memory representation of a MAC 802.11 data header.
-pub struct Mac80211DataFrame	// see 9.3.2
-{
-	/// Address the frame originated at.
-	pub sa: MacAddress,
-
-	/// Address the frame is being sent from. Often the same as sa.
-	pub ta: MacAddress,
-
-	/// Address the frame is being forwarded to. Often the same as da.
-	pub ra: MacAddress,
-
-	/// Address the frame is being routed to.
-	pub da: MacAddress,
-
-	/// AP address.
-	pub bssid: MacAddress,
-
-	/// Sequence number set by the TA.
-	pub seq_num: u16,
-
-	// TODO: Lots of stuff missing, e.g. qos, fragmentation, retries, power management, and rate control.
-}
-
-impl Mac80211DataFrame
-{
-	pub fn new(src_addr: &MacAddress, dst_addr: &MacAddress, seq_num: u16) -> Self
-	{
-		let sa = *src_addr;
-		let ta = *dst_addr;
-		let bssid = [0, 0, 0, 0, 0, 0];
-
-		Mac80211DataFrame {
-			sa,
-			ta,
-			ra: sa,
-			da: ta,
-			bssid,
-			seq_num,
-		}
-	}
-
-	/// Adds an 802.11 ethernet header to the packet.
-	pub fn push(&self, packet: &mut Packet)
-	{
-		let mut header = Header::with_capacity(30);
-
-		let hw = 0b1000_10_00_00000000;	// frame control, see 9.2.4.1, note that B0 is the low bit in the first byte
-		header.push16(hw);
-
-		let hw = 0;						// duration/ID, see 9.2.4.2
-		header.push16(hw);
-
-		for &b in self.da.iter() {		// address 1, see 9.3.2.1
-			header.push8(b);
-		}
-
-		for &b in self.sa.iter() {		// address 2
-			header.push8(b);
-		}
-
-		for &b in self.bssid.iter() {	// address 3
-			header.push8(b);
-		}
-
-		let hw = self.seq_num << 4;		// sequence control, see 9.2.4.4.1
-		header.push16(hw);
-
-		let hw = 0b0_11_0_0000_00000000;// QoS control, see 9.2.4.5.1
-		header.push16(hw);
-
-		packet.push_header(&header);
-
-		// Note that 802.3 has a minimum frame body size but 802.11 does not.
-
-		let crc = crc32(packet);		// FCS (which is always little endian)
-		let fcs = [(crc & 0xFF) as u8, (crc >> 8 & 0xFF) as u8, (crc >> 16 & 0xFF) as u8, (crc >> 24 & 0xFF) as u8];
-		packet.push_back_bytes(&fcs);
-	}
-
-	/// Removes an 802.11 ethernet header from the packet.
-	pub fn pop(packet: &mut Packet) -> Result<Mac80211DataFrame, String>
-	{
-		// When sending the crc includes everything but the crc itself (the FCS field).
-		// When receiving the crc includes the FCS and, because of the magic of modulo
-		// arithmetic, a valid frame's crc will always be 0xC704DD7B.
-		let crc = reverse32(!crc32(packet));
-		if crc != 0xC704DD7B {
-			return Err("Checksum error".to_string())
-		}
-
-		let frame_control = packet.pop16();
-		if frame_control & 0b11 != 0 {			// 9.2.4.1.2
-			return Err("Version isn't zero".to_string())
-		}
-		let _duration = packet.pop16();
-
-		let addr1 = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
-		let addr2 = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
-		let addr3 = [packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8(), packet.pop8()];
-
-		let sn = packet.pop16();
-		let _qos = packet.pop16();
-
-		let _ = packet.pop_back8();	// fcs (we used this when we computed the crc)
-		let _ = packet.pop_back8();
-		let _ = packet.pop_back8();
-		let _ = packet.pop_back8();
-
-		Ok(Mac80211DataFrame {
-			sa: addr2,
-			ta: addr2,
-			ra: addr1,
-			da: addr1,
-			bssid: addr3,
-			seq_num: sn,
-		})
-	}
-}
-
-// TODO: Need an enum to encapsulate the various frame types
-
-/// Medium Access Control for 802.11 wireless radios.
+/// Medium Access Control for 802.11 wireless radios. Frames are sent using EDCA (see
+/// [`Edca`]): each frame is assigned a TID from its DSCP (see [`classify`]) and queued on
+/// the matching access category so that voice and video get to the medium before best
+/// effort and background traffic. Unicast frames are acknowledged after SIFS and
+/// retransmitted if the ACK doesn't arrive, and retransmitted duplicates are detected
+/// using the sequence number. Unicast frames reserve the medium for their ACK using the
+/// duration field and other radios honor that reservation (the NAV, see 10.3.2.4).
+///
+/// The mode controls how the radio joins a network (see [`Mlme`]). Ad hoc radios talk
+/// directly to each other. APs send beacons, answer probe requests, let stations
+/// authenticate and associate, relay frames between the stations in their BSS, and pass
+/// everything else up (e.g. to a bridge). Stations scan for an AP, associate with it, and
+/// then send all their frames through it (frames sent before then are queued).
+///
+/// There are several component settings:
+/// - **slot_time** is the backoff slot duration in seconds. Defaults to 0.000_009.
+/// - **sifs** is the short interframe space in seconds. Defaults to 0.000_016.
+/// - **retry_limit** is the number of transmission attempts before a frame is dropped. Defaults to 7.
+/// - **ack_timeout** is the number of seconds to wait for an ACK after a transmission ends.
+/// Defaults to sifs plus slot_time plus the time needed to send an ACK.
+/// - **standard**, **mcs**, **bandwidth**, **spatial_streams**, and **short_gi** select the
+/// PHY and the rate frames are sent at, see [`phy_settings`]. The medium uses these to
+/// compute airtimes.
+/// - **queue_length** is the maximum number of frames waiting to be sent on each access category. Defaults to 50.
+/// - **seed** is used for the backoff random number generator. Defaults to 1.
+/// - **bk_aifsn**, **be_aifsn**, **vi_aifsn**, and **vo_aifsn** are the number of slots
+/// after SIFS each access category waits for. Default to 7, 3, 2, and 2.
+/// - **bk_cw_min**, **be_cw_min**, **vi_cw_min**, and **vo_cw_min** are the initial
+/// contention windows. Default to 15, 15, 7, and 3.
+/// - **bk_cw_max**, **be_cw_max**, **vi_cw_max**, and **vo_cw_max** are the largest
+/// contention windows. Default to 1023, 1023, 15, and 7.
+/// - **bk_txop_limit**, **be_txop_limit**, **vi_txop_limit**, and **vo_txop_limit** are
+/// the maximum seconds an access category can keep sending once it wins the medium. Default
+/// to 0, 0, 0.003_008, and 0.001_504.
+/// - **ssid** is the network name APs advertise and stations look for (an empty SSID
+/// means any network). Defaults to "score".
+/// - **beacon_interval** is the time between beacons in TUs (1024 microseconds). Defaults to 100.
+/// - **channel** is advertised in beacons. Defaults to 36.
+/// - **scan_time** is the number of seconds stations listen for APs. Defaults to 0.25.
+/// - **active_scan** if 1 stations send probe requests when they scan. Defaults to 1.
+/// - **auth_timeout** is the number of seconds stations wait for authentication and association
+/// responses. Defaults to 0.1.
 pub struct Mac80211Component
 {
 	data: ThreadData,
 
+	/// Address used to decide which frames to acknowledge.
+	pub mac_addr: MacAddress,
+
+	/// Defaults to AdHoc.
+	pub mode: Mac80211Mode,
+
 	/// Listens for "send_down" events.
 	pub upper_in: InPort<(MacAddress, MacAddress, Packet)>,	
 	pub upper_out: OutPort<(MacAddress, MacAddress, Packet)>,
 
 	/// Listens for "send_up" events.
-	pub lower_in: InPort<Packet>,
+	pub lower_in: InPort<(RadioInfo, Packet)>,
 	pub lower_out: OutPort<(ComponentID, Packet)>,
 
-	pub pcap_out: OutPort<Packet>,
+	/// Listens for "medium_busy" and "transmitting" events, see [`Medium`].
+	pub cca_in: InPort<f64>,
+
+	/// Sends "send_ieee80211" events for every frame sent and "recv_ieee80211" events for
+	/// frames addressed to this station. Frames the radio heard that were for other stations
+	/// or had a bad FCS are sent using "monitor_ieee80211" events, see [`PcapComponent`].
+	pub pcap_out: OutPort<(RadioInfo, Packet)>,
+}
+
+// State that is created in "init 0" and used by the helper methods.
+struct MacState
+{
+	edca: Edca,
+	mlme: Mlme,
+	edca_scheduled: Option<f64>,
+	mlme_scheduled: Option<f64>,
+	last_seq: HashMap<(MacAddress, u8), u16>,
+	sns: [u16; 16],							// sequence numbers are per TID, see 10.3.2.14.2
+	pending: VecDeque<(MacAddress, MacAddress, Packet)>,	// frames waiting for association
+	queue_length: usize,
+	sifs: f64,
+	ack_duration: u16,						// microseconds needed for SIFS and an ACK
+	tx_info: RadioInfo,
+	counters: Counters,
+	next_id: i64,							// for the ids of frames we generate
 }
 
 impl Mac80211Component
 {
-	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID, mac_addr: MacAddress) -> Self
 	{
 		let (id, data) = sim.add_active_component("Mac80211", parent_id);
 		Mac80211Component {
 			data: data,
+			mac_addr,
+			mode: Mac80211Mode::AdHoc,
 
 			upper_in: InPort::with_port_name(id, "upper_in"),
 			lower_out: OutPort::new(),
@@ -180,33 +137,183 @@ impl Mac80211Component
 			lower_in: InPort::with_port_name(id, "lower_in"),
 			upper_out: OutPort::new(),
 
+			cca_in: InPort::with_port_name(id, "cca_in"),
 			pcap_out: OutPort::new(),
 		}
 	}
 	
 	pub fn start(self)
 	{		
+		let mut settings = [DcfSettings {
+			slot_time: 0.000_009,
+			sifs: 0.000_016,
+			aifsn: 2,
+			cw_min: 15,
+			cw_max: 1023,
+			retry_limit: 7,
+			ack_timeout: 0.000_1,
+			queue_length: 50,
+			txop_limit: 0.0,
+		}; 4];
+		let mut mlme_settings = MlmeSettings {
+			ssid: "score".to_string(),
+			beacon_interval: 100,
+			channel: 36,
+			scan_time: 0.25,
+			active_scan: true,
+			auth_timeout: 0.1,
+		};
+		let mut mac: Option<MacState> = None;
+
 		thread::spawn(move || {
 			process_events!(self.data, event, state, effector,
 				"init 0" => {
-					effector.set_int("sn", 0);
+					let slot_time = float_setting(self.data.id, &state, &mut effector, "slot_time", settings[0].slot_time);
+					let sifs = float_setting(self.data.id, &state, &mut effector, "sifs", settings[0].sifs);
+					let retry_limit = int_setting(self.data.id, &state, &mut effector, "retry_limit", settings[0].retry_limit);
+					let phy = phy_settings(self.data.id, &state, &mut effector);
+					let ack_time = phy.control_rate().airtime(ACK_LEN);
+					let ack_timeout = float_setting(self.data.id, &state, &mut effector, "ack_timeout", sifs + slot_time + ack_time);
+					let queue_length = int_setting(self.data.id, &state, &mut effector, "queue_length", settings[0].queue_length);
+					for &ac in ACCESS_CATEGORIES.iter() {
+						let (aifsn, cw_min, cw_max, txop_limit) = ac.default_parameters();
+						let s = &mut settings[ac as usize];
+						s.slot_time = slot_time;
+						s.sifs = sifs;
+						s.retry_limit = retry_limit;
+						s.ack_timeout = ack_timeout;
+						s.queue_length = queue_length;
+						s.aifsn = int_setting(self.data.id, &state, &mut effector, &format!("{}_aifsn", ac.abbrev()), aifsn);
+						s.cw_min = int_setting(self.data.id, &state, &mut effector, &format!("{}_cw_min", ac.abbrev()), cw_min);
+						s.cw_max = int_setting(self.data.id, &state, &mut effector, &format!("{}_cw_max", ac.abbrev()), cw_max);
+						s.txop_limit = float_setting(self.data.id, &state, &mut effector, &format!("{}_txop_limit", ac.abbrev()), txop_limit);
+					}
+					let seed = int_setting(self.data.id, &state, &mut effector, "seed", 1);
+
+					mlme_settings.ssid = string_setting(self.data.id, &state, &mut effector, "ssid", &mlme_settings.ssid);
+					mlme_settings.beacon_interval = int_setting(self.data.id, &state, &mut effector, "beacon_interval", mlme_settings.beacon_interval as i64) as u16;
+					mlme_settings.channel = int_setting(self.data.id, &state, &mut effector, "channel", mlme_settings.channel as i64) as u8;
+					mlme_settings.scan_time = float_setting(self.data.id, &state, &mut effector, "scan_time", mlme_settings.scan_time);
+					mlme_settings.active_scan = int_setting(self.data.id, &state, &mut effector, "active_scan", 1) != 0;
+					mlme_settings.auth_timeout = float_setting(self.data.id, &state, &mut effector, "auth_timeout", mlme_settings.auth_timeout);
+
+					let mut m = MacState {
+						edca: Edca::new(&settings, Rng::with_component(seed, self.data.id)),
+						mlme: Mlme::new(self.mode, self.mac_addr, mlme_settings.clone()),
+						edca_scheduled: None,
+						mlme_scheduled: None,
+						last_seq: HashMap::new(),
+						sns: [0; 16],
+						pending: VecDeque::new(),
+						queue_length: queue_length as usize,
+						sifs,
+						ack_duration: ((sifs + ack_time)*1_000_000.0).ceil() as u16,
+						tx_info: RadioInfo::with_tx(phy, channel_frequency(mlme_settings.channel)),
+						counters: Counters {sent: [0; 4], retries: 0, dropped: [0; 4], duplicates: 0, associations: 0},
+						next_id: 0,
+					};
+					m.counters.save(&mut effector);
+
+					let mut out = Vec::new();
+					m.mlme.start(state.time, &mut out);
+					self.apply_mlme(&mut effector, &state, &mut m, out);
+					mac = Some(m);
 				},
 				"send_down" => {
-					let sn = state.get_int(self.data.id, "sn");
-					effector.set_int("num_recv", (sn+1) % 4096);	// sequence number is 12 bits so modulo 4096
-
-					let (src_addr, dst_addr, mut packet) = event.take_payload::<(MacAddress, MacAddress, Packet)>();
-					let header = Mac80211DataFrame::new(&src_addr, &dst_addr, sn as u16);
-					header.push(&mut packet);
-					self.pcap_out.send_payload(&mut effector, "send_ieee80211", packet.clone());
-					self.lower_out.send_payload(&mut effector, &event.name, (self.data.id, packet));
+					let (src_addr, dst_addr, packet) = event.take_payload::<(MacAddress, MacAddress, Packet)>();
+					let mac = mac.as_mut().unwrap();
+					self.send_data(&mut effector, &state, mac, src_addr, dst_addr, packet);
+				},
+				"medium_busy" => {
+					let until = event.take_payload::<f64>();
+					let mac = mac.as_mut().unwrap();
+					let mut out = Vec::new();
+					mac.edca.medium_busy(until, state.time, &mut out);
+					self.apply_edca(&mut effector, &state, mac, out);
+				},
+				"transmitting" => {
+					let until = event.take_payload::<f64>();
+					let mac = mac.as_mut().unwrap();
+					let mut out = Vec::new();
+					mac.edca.transmitting(until, state.time, &mut out);
+					self.apply_edca(&mut effector, &state, mac, out);
+				},
+				"edca_timer" => {
+					let mac = mac.as_mut().unwrap();
+					if expired(mac.edca.timer, state.time) {
+						mac.edca_scheduled = None;
+						let mut out = Vec::new();
+						mac.edca.timeout(state.time, &mut out);
+						self.apply_edca(&mut effector, &state, mac, out);
+					}
+				},
+				"mlme_timer" => {
+					let mac = mac.as_mut().unwrap();
+					if expired(mac.mlme.timer, state.time) {
+						mac.mlme_scheduled = None;
+						let mut out = Vec::new();
+						mac.mlme.timeout(state.time, &mut out);
+						self.apply_mlme(&mut effector, &state, mac, out);
+					}
+				},
+				"send_ack" => {
+					let ra = event.take_payload::<MacAddress>();
+					let mac = mac.as_mut().unwrap();
+					mac.next_id += 1;
+					let mut packet = Packet::new("ACK", &format!("#>{}.{}", self.data.id, mac.next_id));
+					Mac80211AckFrame::new(&ra).push(&mut packet);
+
+					let info = RadioInfo::with_tx(mac.tx_info.phy.control_rate(), mac.tx_info.frequency);
+					self.pcap_out.send_payload(&mut effector, "send_ieee80211", (info, packet.clone()));
+					self.lower_out.send_payload(&mut effector, "send_down", (self.data.id, packet));
 				},
 				"send_up" => {
-					let mut packet = event.take_payload::<Packet>();
-					self.pcap_out.send_payload(&mut effector, "send_ieee80211", packet.clone());
-					match Mac80211DataFrame::pop(&mut packet) {
-						Ok(header) => {
-							self.upper_out.send_payload(&mut effector, &event.name, (header.sa, header.da, packet));
+					let (mut info, mut packet) = event.take_payload::<(RadioInfo, Packet)>();
+					info.bad_fcs = !valid_fcs(&packet);
+					let captured = packet.clone();
+
+					let mac = mac.as_mut().unwrap();
+					let result = Ieee80211Frame::pop(&mut packet);
+					let for_us = match result {
+						Ok(ref frame) => frame.ra() == self.mac_addr || is_group(&frame.ra()),
+						Err(_) => false,
+					};
+					let name = if for_us {"recv_ieee80211"} else {"monitor_ieee80211"};
+					self.pcap_out.send_payload(&mut effector, name, (info, captured));
+
+					if let Ok(ref frame) = result {
+						// Frames for other radios reserve the medium, see 10.3.2.4.
+						if frame.ra() != self.mac_addr && frame.duration() > 0 {
+							let mut out = Vec::new();
+							mac.edca.medium_busy(state.time + frame.duration() as f64/1_000_000.0, state.time, &mut out);
+							self.apply_edca(&mut effector, &state, mac, out);
+						}
+					}
+					match result {
+						Ok(Ieee80211Frame::Ack(ack)) => if ack.ra == self.mac_addr {
+							let mut out = Vec::new();
+							mac.edca.ack_received(state.time, &mut out);
+							self.apply_edca(&mut effector, &state, mac, out);
+						},
+						Ok(Ieee80211Frame::Data(header)) => {
+							if header.ra == self.mac_addr {
+								self.send_ack(&mut effector, mac, &header.ta);
+							}
+							self.received_data(&mut effector, &state, mac, header, packet);
+						},
+						Ok(Ieee80211Frame::Rts(_)) => (),
+						Ok(Ieee80211Frame::Cts(_)) => (),
+						Ok(frame) => {
+							let ra = frame.ra();
+							if ra == self.mac_addr {
+								let sa = frame_sa(&frame);
+								self.send_ack(&mut effector, mac, &sa);
+							}
+							if ra == self.mac_addr || is_group(&ra) {
+								let mut out = Vec::new();
+								mac.mlme.received(frame, state.time, &mut out);
+								self.apply_mlme(&mut effector, &state, mac, out);
+							}
 						},
 						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
 					}
@@ -214,4 +321,233 @@ impl Mac80211Component
 			);
 		});
 	}
+
+	fn send_data(&self, effector: &mut Effector, state: &SimState, mac: &mut MacState, src_addr: MacAddress, dst_addr: MacAddress, packet: Packet)
+	{
+		let tid = classify(&packet);
+		let ac = AccessCategory::from_tid(tid);
+
+		// Frames use the address layout for their direction, see Table 9-26.
+		let bssid = mac.mlme.bssid();
+		match self.mode {
+			Mac80211Mode::AdHoc => (),
+			Mac80211Mode::Station => if bssid.is_none() {
+				if mac.pending.len() < mac.queue_length {
+					mac.pending.push_back((src_addr, dst_addr, packet));
+				} else {
+					log_info!(effector, "dropping {} (not associated)", packet.name());
+					mac.counters.dropped[ac as usize] += 1;
+					mac.counters.save(effector);
+				}
+				return
+			},
+			Mac80211Mode::AccessPoint => if !is_group(&dst_addr) && !mac.mlme.is_associated(&dst_addr) {
+				log_debug!(effector, "dropping {} ({:?} isn't in the BSS)", packet.name(), dst_addr);
+				return
+			}
+		}
+
+		let mut header = Mac80211DataFrame::new(&src_addr, &dst_addr, mac.sns[tid as usize]);
+		header.tid = tid;
+		mac.sns[tid as usize] = (mac.sns[tid as usize] + 1) % 4096;	// sequence number is 12 bits so modulo 4096
+		if let Some(bssid) = bssid {
+			header.bssid = bssid;
+			header.ta = self.mac_addr;
+			if self.mode == Mac80211Mode::Station {
+				header.to_ds = true;
+				header.ra = bssid;
+			} else {
+				header.from_ds = true;
+			}
+		}
+		if !is_group(&header.ra) {
+			header.duration = mac.ack_duration;
+		}
+
+		let mut out = Vec::new();
+		if !mac.edca.enqueue(ac, Ieee80211Frame::Data(header), packet.clone(), state.time, &mut out) {
+			log_info!(effector, "dropping {} (queue is full)", packet.name());
+			mac.counters.dropped[ac as usize] += 1;
+		}
+		self.apply_edca(effector, state, mac, out);
+	}
+
+	fn received_data(&self, effector: &mut Effector, state: &SimState, mac: &mut MacState, header: Mac80211DataFrame, packet: Packet)
+	{
+		if header.ra != self.mac_addr && !is_group(&header.ra) {
+			return
+		}
+		if header.sa == self.mac_addr {
+			return		// one of our group frames relayed back to us by the AP
+		}
+
+		// See 10.3.2.14.3.
+		let key = (header.ta, header.tid);
+		let duplicate = header.retry && mac.last_seq.get(&key) == Some(&header.seq_num);
+		if !is_group(&header.ra) {
+			mac.last_seq.insert(key, header.seq_num);
+		}
+		if duplicate {
+			log_debug!(effector, "dropping {} (duplicate)", packet.name());
+			mac.counters.duplicates += 1;
+			mac.counters.save(effector);
+			return
+		}
+
+		match self.mode {
+			Mac80211Mode::AdHoc => self.upper_out.send_payload(effector, "send_up", (header.sa, header.da, packet)),
+			Mac80211Mode::Station => {
+				if header.from_ds && Some(header.bssid) == mac.mlme.bssid() {
+					self.upper_out.send_payload(effector, "send_up", (header.sa, header.da, packet));
+				} else {
+					log_debug!(effector, "dropping {} (not from our BSS)", packet.name());
+				}
+			},
+			Mac80211Mode::AccessPoint => {
+				if !header.to_ds || header.bssid != self.mac_addr {
+					log_debug!(effector, "dropping {} (not for our BSS)", packet.name());
+
+				} else if !mac.mlme.is_associated(&header.ta) {
+					log_info!(effector, "dropping {} ({:?} isn't associated)", packet.name(), header.ta);
+					let mut out = Vec::new();
+					mac.mlme.not_associated(&header.ta, &mut out);
+					self.apply_mlme(effector, state, mac, out);
+
+				} else if is_group(&header.da) {
+					self.upper_out.send_payload(effector, "send_up", (header.sa, header.da, packet.clone()));
+					self.send_data(effector, state, mac, header.sa, header.da, packet);
+
+				} else if header.da != self.mac_addr && mac.mlme.is_associated(&header.da) {
+					self.send_data(effector, state, mac, header.sa, header.da, packet);
+
+				} else {
+					self.upper_out.send_payload(effector, "send_up", (header.sa, header.da, packet));
+				}
+			}
+		}
+	}
+
+	fn send_ack(&self, effector: &mut Effector, mac: &MacState, ra: &MacAddress)
+	{
+		let event = Event::with_payload("send_ack", *ra);
+		effector.schedule_after_secs(event, self.data.id, mac.sifs);
+	}
+
+	fn apply_edca(&self, effector: &mut Effector, state: &SimState, mac: &mut MacState, out: Vec<(AccessCategory, DcfAction)>)
+	{
+		for (ac, action) in out {
+			match action {
+				DcfAction::Transmit(packet) => {
+					self.pcap_out.send_payload(effector, "send_ieee80211", (mac.tx_info, packet.clone()));
+					self.lower_out.send_payload(effector, "send_down", (self.data.id, packet));
+				},
+				DcfAction::Sent => mac.counters.sent[ac as usize] += 1,
+				DcfAction::Retry => mac.counters.retries += 1,
+				DcfAction::Dropped(packet) => {
+					log_info!(effector, "dropping {} (retry limit reached)", packet.name());
+					mac.counters.dropped[ac as usize] += 1;
+				}
+			}
+		}
+		mac.counters.save(effector);
+		self.schedule(effector, state, "edca_timer", mac.edca.timer, &mut mac.edca_scheduled);
+	}
+
+	fn apply_mlme(&self, effector: &mut Effector, state: &SimState, mac: &mut MacState, out: Vec<MlmeAction>)
+	{
+		for action in out {
+			match action {
+				MlmeAction::Send(mut frame) => {
+					if !is_group(&frame.ra()) {
+						frame.set_duration(mac.ack_duration);
+					}
+
+					// Management frames use AC_VO, see 10.2.4.2.
+					mac.next_id += 1;
+					let packet = Packet::new(frame.name(), &format!("#>{}.{}", self.data.id, mac.next_id));
+					let mut actions = Vec::new();
+					if !mac.edca.enqueue(AccessCategory::Voice, frame, packet, state.time, &mut actions) {
+						log_info!(effector, "dropping management frame (queue is full)");
+					}
+					self.apply_edca(effector, state, mac, actions);
+				},
+				MlmeAction::Associated(addr) => {
+					log_info!(effector, "associated with {:?}", addr);
+					mac.counters.associations += 1;
+					mac.counters.save(effector);
+					while let Some((src_addr, dst_addr, packet)) = mac.pending.pop_front() {
+						self.send_data(effector, state, mac, src_addr, dst_addr, packet);
+					}
+				},
+				MlmeAction::Disassociated(addr) => {
+					log_info!(effector, "disassociated from {:?}", addr);
+					mac.counters.associations -= 1;
+					mac.counters.save(effector);
+				}
+			}
+		}
+		self.schedule(effector, state, "mlme_timer", mac.mlme.timer, &mut mac.mlme_scheduled);
+	}
+
+	fn schedule(&self, effector: &mut Effector, state: &SimState, name: &str, timer: Option<f64>, scheduled: &mut Option<f64>)
+	{
+		if timer.is_some() && timer != *scheduled {
+			let time = timer.unwrap();
+			let event = Event::new(name);
+			effector.schedule_after_secs(event, self.data.id, if time > state.time {time - state.time} else {0.0});
+			*scheduled = timer;
+		}
+	}
+}
+
+fn expired(timer: Option<f64>, now: f64) -> bool
+{
+	match timer {
+		Some(time) => time <= now + TIMER_SLOP,
+		None => false
+	}
+}
+
+// Management frames are acknowledged using the SA.
+fn frame_sa(frame: &Ieee80211Frame) -> MacAddress
+{
+	match *frame {
+		Ieee80211Frame::Beacon(ref header, _) => header.sa,
+		Ieee80211Frame::ProbeRequest(ref header, _) => header.sa,
+		Ieee80211Frame::ProbeResponse(ref header, _) => header.sa,
+		Ieee80211Frame::Authentication(ref header, _) => header.sa,
+		Ieee80211Frame::AssociationRequest(ref header, _) => header.sa,
+		Ieee80211Frame::AssociationResponse(ref header, _) => header.sa,
+		Ieee80211Frame::Deauthentication(ref header, _) => header.sa,
+		Ieee80211Frame::Disassociation(ref header, _) => header.sa,
+		Ieee80211Frame::Data(ref header) => header.ta,
+		Ieee80211Frame::Ack(_) | Ieee80211Frame::Rts(_) | Ieee80211Frame::Cts(_) => BROADCAST_MAC,
+	}
+}
+
+// Store updates aren't applied until the event finishes so we track these locally.
+// Per access category counters are indexed by AccessCategory.
+struct Counters
+{
+	sent: [i64; 4],
+	retries: i64,
+	dropped: [i64; 4],
+	duplicates: i64,
+	associations: i64,
+}
+
+impl Counters
+{
+	fn save(&self, effector: &mut Effector)
+	{
+		effector.set_int("frames_sent", self.sent.iter().sum());
+		effector.set_int("retries", self.retries);
+		effector.set_int("frames_dropped", self.dropped.iter().sum());
+		effector.set_int("duplicates", self.duplicates);
+		effector.set_int("associations", self.associations);
+		for &ac in ACCESS_CATEGORIES.iter() {
+			effector.set_int(&format!("{}_frames_sent", ac.abbrev()), self.sent[ac as usize]);
+			effector.set_int(&format!("{}_frames_dropped", ac.abbrev()), self.dropped[ac as usize]);
+		}
+	}
 }
@@ -0,0 +1,472 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use link::frame80211::*;
+use link::link::*;
+use std::collections::HashMap;
+
+// Unless otherwise indicated all references are to the 2016 version of the 802.11 spec.
+
+/// How a [`Mac80211Component`] participates in a network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mac80211Mode
+{
+	/// Talks directly to any other ad hoc radio without a BSS.
+	AdHoc,
+
+	/// Sends beacons and lets stations associate, see 11.1.
+	AccessPoint,
+
+	/// Scans for an AP with the right SSID and associates with it, see 11.1.4 and 11.3.
+	Station,
+}
+
+/// Values from [`Mac80211Component`]'s settings. Times are in seconds.
+#[derive(Clone, Debug)]
+pub struct MlmeSettings
+{
+	/// An empty SSID means that stations will join any network.
+	pub ssid: String,
+
+	/// In TUs (1024 microseconds).
+	pub beacon_interval: u16,
+
+	pub channel: u8,
+
+	/// How long stations listen for beacons and probe responses.
+	pub scan_time: f64,
+
+	/// If set stations send a probe request when they start scanning.
+	pub active_scan: bool,
+
+	/// How long stations wait for authentication and association responses.
+	pub auth_timeout: f64,
+}
+
+impl MlmeSettings
+{
+	pub fn beacon_secs(&self) -> f64
+	{
+		(self.beacon_interval as f64)*0.001_024
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StationState
+{
+	Scanning,
+	Authenticating(MacAddress),
+	Associating(MacAddress),
+	Associated(MacAddress),
+}
+
+/// Things an [`Mlme`] asks [`Mac80211Component`] to do.
+#[derive(Debug)]
+pub enum MlmeAction
+{
+	/// Queue the management frame for transmission.
+	Send(Ieee80211Frame),
+
+	/// We joined the BSS or, for APs, a station joined our BSS.
+	Associated(MacAddress),
+
+	/// We left the BSS or, for APs, a station left our BSS.
+	Disassociated(MacAddress),
+}
+
+// Status and reason codes, see 9.4.1.9 and 9.4.1.7.
+const STATUS_FAILURE: u16 = 1;
+const STATUS_UNSUPPORTED_ALGORITHM: u16 = 13;
+const REASON_NOT_AUTHENTICATED: u16 = 6;
+const REASON_NOT_ASSOCIATED: u16 = 7;
+
+const OPEN_SYSTEM: u16 = 0;
+
+/// MAC sublayer management entity: handles beaconing, scanning, authentication (open
+/// system only), and association. APs keep track of the stations in their BSS and stations
+/// track the AP they are associated with. The component calls timeout when timer expires.
+pub struct Mlme
+{
+	pub mode: Mac80211Mode,
+	pub state: StationState,
+
+	/// Absolute time at which timeout should be called.
+	pub timer: Option<f64>,
+
+	mac_addr: MacAddress,
+	settings: MlmeSettings,
+	seq_num: u16,
+	start_time: f64,
+	candidates: Vec<(MacAddress, String)>,	// (bssid, ssid) heard while scanning
+	stations: HashMap<MacAddress, u16>,		// authenticated stations and their AIDs (zero until they associate)
+	next_aid: u16,
+}
+
+impl Mlme
+{
+	pub fn new(mode: Mac80211Mode, mac_addr: MacAddress, settings: MlmeSettings) -> Self
+	{
+		Mlme {
+			mode,
+			state: StationState::Scanning,
+			timer: None,
+			mac_addr,
+			settings,
+			seq_num: 0,
+			start_time: 0.0,
+			candidates: Vec::new(),
+			stations: HashMap::new(),
+			next_aid: 1,
+		}
+	}
+
+	/// Called once when the simulation starts.
+	pub fn start(&mut self, now: f64, out: &mut Vec<MlmeAction>)
+	{
+		self.start_time = now;
+		match self.mode {
+			Mac80211Mode::AdHoc => (),
+			Mac80211Mode::AccessPoint => self.send_beacon(now, out),
+			Mac80211Mode::Station => self.scan(now, out),
+		}
+	}
+
+	/// The BSS we're using, if any.
+	pub fn bssid(&self) -> Option<MacAddress>
+	{
+		match self.mode {
+			Mac80211Mode::AdHoc => None,
+			Mac80211Mode::AccessPoint => Some(self.mac_addr),
+			Mac80211Mode::Station => match self.state {
+				StationState::Associated(bssid) => Some(bssid),
+				_ => None
+			}
+		}
+	}
+
+	/// For APs returns true if the station is in our BSS.
+	pub fn is_associated(&self, addr: &MacAddress) -> bool
+	{
+		self.stations.get(addr).map(|&aid| aid != 0).unwrap_or(false)
+	}
+
+	/// A station that isn't associated sent us a data frame, see 11.3.3.
+	pub fn not_associated(&mut self, addr: &MacAddress, out: &mut Vec<MlmeAction>)
+	{
+		let reason = if self.stations.contains_key(addr) {REASON_NOT_ASSOCIATED} else {REASON_NOT_AUTHENTICATED};
+		let header = self.header(addr);
+		out.push(MlmeAction::Send(Ieee80211Frame::Deauthentication(header, reason)));
+	}
+
+	pub fn timeout(&mut self, now: f64, out: &mut Vec<MlmeAction>)
+	{
+		self.timer = None;
+		match self.mode {
+			Mac80211Mode::AdHoc => (),
+			Mac80211Mode::AccessPoint => self.send_beacon(now, out),
+			Mac80211Mode::Station => match self.state {
+				StationState::Scanning => {
+					// TODO: once we model signal strength we should pick the strongest AP.
+					if let Some(&(bssid, _)) = self.candidates.first() {
+						self.state = StationState::Authenticating(bssid);
+						let header = self.header(&bssid);
+						let body = Authentication {algorithm: OPEN_SYSTEM, seq: 1, status: STATUS_SUCCESS};
+						out.push(MlmeAction::Send(Ieee80211Frame::Authentication(header, body)));
+						self.timer = Some(now + self.settings.auth_timeout);
+					} else {
+						self.scan(now, out);
+					}
+				},
+				StationState::Authenticating(_) | StationState::Associating(_) => self.scan(now, out),
+				StationState::Associated(_) => (),
+			}
+		}
+	}
+
+	/// A management frame addressed to us (or to everyone) arrived.
+	pub fn received(&mut self, frame: Ieee80211Frame, now: f64, out: &mut Vec<MlmeAction>)
+	{
+		match self.mode {
+			Mac80211Mode::AdHoc => (),
+			Mac80211Mode::AccessPoint => self.ap_received(frame, out),
+			Mac80211Mode::Station => self.sta_received(frame, now, out),
+		}
+	}
+
+	fn ap_received(&mut self, frame: Ieee80211Frame, out: &mut Vec<MlmeAction>)
+	{
+		match frame {
+			Ieee80211Frame::ProbeRequest(header, body) => {
+				if self.ssid_matches(&body.ssid) {
+					let response = self.header(&header.sa);
+					out.push(MlmeAction::Send(Ieee80211Frame::ProbeResponse(response, self.beacon_body())));
+				}
+			},
+			Ieee80211Frame::Authentication(header, body) => {
+				if body.seq == 1 {
+					let status = if body.algorithm == OPEN_SYSTEM {
+						self.stations.entry(header.sa).or_insert(0);
+						STATUS_SUCCESS
+					} else {
+						STATUS_UNSUPPORTED_ALGORITHM
+					};
+					let response = self.header(&header.sa);
+					let body = Authentication {algorithm: body.algorithm, seq: 2, status};
+					out.push(MlmeAction::Send(Ieee80211Frame::Authentication(response, body)));
+				}
+			},
+			Ieee80211Frame::AssociationRequest(header, body) => {
+				if !self.stations.contains_key(&header.sa) {
+					self.not_associated(&header.sa, out);
+					return
+				}
+
+				let response = self.header(&header.sa);
+				if self.ssid_matches(&body.ssid) {
+					let (aid, new) = match self.stations.get(&header.sa) {
+						Some(&aid) if aid != 0 => (aid, false),	// station re-sent its request
+						_ => {
+							let aid = self.next_aid;
+							self.next_aid = if aid == 2007 {1} else {aid + 1};
+							(aid, true)
+						}
+					};
+					self.stations.insert(header.sa, aid);
+					let body = AssociationResponse {capability: CAPABILITY_ESS | CAPABILITY_SHORT_SLOT_TIME, status: STATUS_SUCCESS, aid, rates: OFDM_RATES.to_vec()};
+					out.push(MlmeAction::Send(Ieee80211Frame::AssociationResponse(response, body)));
+					if new {
+						out.push(MlmeAction::Associated(header.sa));
+					}
+				} else {
+					let body = AssociationResponse {capability: CAPABILITY_ESS, status: STATUS_FAILURE, aid: 0, rates: OFDM_RATES.to_vec()};
+					out.push(MlmeAction::Send(Ieee80211Frame::AssociationResponse(response, body)));
+				}
+			},
+			Ieee80211Frame::Deauthentication(header, _) => {
+				if let Some(aid) = self.stations.remove(&header.sa) {
+					if aid != 0 {
+						out.push(MlmeAction::Disassociated(header.sa));
+					}
+				}
+			},
+			Ieee80211Frame::Disassociation(header, _) => {
+				if let Some(aid) = self.stations.get_mut(&header.sa) {
+					if *aid != 0 {
+						*aid = 0;
+						out.push(MlmeAction::Disassociated(header.sa));
+					}
+				}
+			},
+			_ => ()
+		}
+	}
+
+	fn sta_received(&mut self, frame: Ieee80211Frame, now: f64, out: &mut Vec<MlmeAction>)
+	{
+		match (self.state, frame) {
+			(StationState::Scanning, Ieee80211Frame::Beacon(header, body)) |
+			(StationState::Scanning, Ieee80211Frame::ProbeResponse(header, body)) => {
+				if body.capability & CAPABILITY_ESS != 0 && self.ssid_matches(&body.ssid) && !self.candidates.iter().any(|c| c.0 == header.bssid) {
+					self.candidates.push((header.bssid, body.ssid));
+				}
+			},
+			(StationState::Authenticating(bssid), Ieee80211Frame::Authentication(header, body)) => {
+				if header.sa == bssid && body.seq == 2 {
+					if body.status == STATUS_SUCCESS {
+						self.state = StationState::Associating(bssid);
+						let ssid = self.candidates.iter().find(|c| c.0 == bssid).map(|c| c.1.clone()).unwrap_or(self.settings.ssid.clone());
+						let request = self.header(&bssid);
+						let body = AssociationRequest {capability: CAPABILITY_ESS | CAPABILITY_SHORT_SLOT_TIME, listen_interval: 10, ssid, rates: OFDM_RATES.to_vec()};
+						out.push(MlmeAction::Send(Ieee80211Frame::AssociationRequest(request, body)));
+						self.timer = Some(now + self.settings.auth_timeout);
+					} else {
+						self.scan(now, out);
+					}
+				}
+			},
+			(StationState::Associating(bssid), Ieee80211Frame::AssociationResponse(header, body)) => {
+				if header.sa == bssid {
+					if body.status == STATUS_SUCCESS {
+						self.state = StationState::Associated(bssid);
+						self.timer = None;
+						out.push(MlmeAction::Associated(bssid));
+					} else {
+						self.scan(now, out);
+					}
+				}
+			},
+			(StationState::Associated(bssid), Ieee80211Frame::Deauthentication(header, _)) |
+			(StationState::Associated(bssid), Ieee80211Frame::Disassociation(header, _)) => {
+				if header.sa == bssid {
+					out.push(MlmeAction::Disassociated(bssid));
+					self.scan(now, out);
+				}
+			},
+			(StationState::Authenticating(bssid), Ieee80211Frame::Deauthentication(header, _)) |
+			(StationState::Associating(bssid), Ieee80211Frame::Deauthentication(header, _)) => {
+				if header.sa == bssid {
+					self.scan(now, out);
+				}
+			},
+			_ => ()
+		}
+	}
+
+	// See 11.1.4.
+	fn scan(&mut self, now: f64, out: &mut Vec<MlmeAction>)
+	{
+		self.state = StationState::Scanning;
+		self.candidates.clear();
+		if self.settings.active_scan {
+			let mut header = self.header(&BROADCAST_MAC);
+			header.bssid = BROADCAST_MAC;
+			let body = ProbeRequest {ssid: self.settings.ssid.clone(), rates: OFDM_RATES.to_vec()};
+			out.push(MlmeAction::Send(Ieee80211Frame::ProbeRequest(header, body)));
+		}
+		self.timer = Some(now + self.settings.scan_time);
+	}
+
+	fn send_beacon(&mut self, now: f64, out: &mut Vec<MlmeAction>)
+	{
+		let header = self.header(&BROADCAST_MAC);
+		let mut body = self.beacon_body();
+		body.timestamp = ((now - self.start_time)*1.0e6) as u64;
+		out.push(MlmeAction::Send(Ieee80211Frame::Beacon(header, body)));
+		self.timer = Some(now + self.settings.beacon_secs());
+	}
+
+	fn beacon_body(&self) -> Beacon
+	{
+		Beacon {
+			timestamp: 0,
+			beacon_interval: self.settings.beacon_interval,
+			capability: CAPABILITY_ESS | CAPABILITY_SHORT_SLOT_TIME,
+			ssid: self.settings.ssid.clone(),
+			rates: OFDM_RATES.to_vec(),
+			channel: self.settings.channel,
+		}
+	}
+
+	fn header(&mut self, dst_addr: &MacAddress) -> Mac80211ManagementHeader
+	{
+		let bssid = match self.mode {
+			Mac80211Mode::AccessPoint => self.mac_addr,
+			_ => match self.state {
+				StationState::Authenticating(bssid) | StationState::Associating(bssid) | StationState::Associated(bssid) => bssid,
+				StationState::Scanning => *dst_addr,
+			}
+		};
+		let header = Mac80211ManagementHeader::new(&self.mac_addr, dst_addr, &bssid, self.seq_num);
+		self.seq_num = (self.seq_num + 1) % 4096;
+		header
+	}
+
+	fn ssid_matches(&self, ssid: &str) -> bool
+	{
+		self.settings.ssid.is_empty() || ssid.is_empty() || self.settings.ssid == ssid
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[cfg(test)]
+	fn settings() -> MlmeSettings
+	{
+		MlmeSettings {ssid: "test".to_string(), beacon_interval: 100, channel: 36, scan_time: 0.2, active_scan: true, auth_timeout: 0.1}
+	}
+
+	// Delivers the frames one side sent to the other side.
+    #[cfg(test)]
+	fn deliver(from: &mut Vec<MlmeAction>, to: &mut Mlme, now: f64, out: &mut Vec<MlmeAction>) -> Vec<MacAddress>
+	{
+		let mut associated = Vec::new();
+		for action in from.drain(..) {
+			match action {
+				MlmeAction::Send(frame) => to.received(frame, now, out),
+				MlmeAction::Associated(addr) => associated.push(addr),
+				MlmeAction::Disassociated(_) => (),
+			}
+		}
+		associated
+	}
+
+    #[test]
+    fn association()
+	{
+		let ap_addr = [2, 0, 0, 0, 0, 1];
+		let sta_addr = [2, 0, 0, 0, 0, 2];
+		let mut ap = Mlme::new(Mac80211Mode::AccessPoint, ap_addr, settings());
+		let mut sta = Mlme::new(Mac80211Mode::Station, sta_addr, settings());
+		let mut ap_out = Vec::new();
+		let mut sta_out = Vec::new();
+
+		// The station's probe request is answered with a probe response (and the AP's first
+		// beacon also arrives).
+		ap.start(0.0, &mut ap_out);
+		sta.start(0.0, &mut sta_out);
+		deliver(&mut sta_out, &mut ap, 0.0, &mut ap_out);
+		assert_eq!(2, ap_out.len());
+		deliver(&mut ap_out, &mut sta, 0.0, &mut sta_out);
+		assert!(sta_out.is_empty());
+
+		// Once the scan finishes the station authenticates and associates.
+		let mut now = sta.timer.unwrap();
+		sta.timeout(now, &mut sta_out);
+		assert_eq!(StationState::Authenticating(ap_addr), sta.state);
+		let mut request = None;
+		for _ in 0..2 {
+			now += 0.001;
+			for action in sta_out.iter() {
+				if let MlmeAction::Send(ref frame @ Ieee80211Frame::AssociationRequest(..)) = *action {
+					request = Some(frame.clone());
+				}
+			}
+			let joined = deliver(&mut sta_out, &mut ap, now, &mut ap_out);
+			assert!(joined.is_empty());
+			let joined = deliver(&mut ap_out, &mut sta, now, &mut sta_out);
+			if !joined.is_empty() {
+				assert_eq!(vec![sta_addr], joined);
+			}
+		}
+		assert_eq!(StationState::Associated(ap_addr), sta.state);
+		assert_eq!(Some(ap_addr), sta.bssid());
+		assert!(ap.is_associated(&sta_addr));
+
+		// If the station re-sends its request the AP responds again but it's not a new association.
+		ap_out.clear();
+		ap.received(request.expect("station should have sent an association request"), now, &mut ap_out);
+		assert_eq!(1, ap_out.len());
+		match ap_out[0] {
+			MlmeAction::Send(Ieee80211Frame::AssociationResponse(_, ref body)) => assert_eq!(STATUS_SUCCESS, body.status),
+			ref action => panic!("expected an association response not {:?}", action)
+		}
+		ap_out.clear();
+
+		// Stations that aren't authenticated are told to go away.
+		let other = [2, 0, 0, 0, 0, 3];
+		assert!(!ap.is_associated(&other));
+		ap.not_associated(&other, &mut ap_out);
+		match ap_out[0] {
+			MlmeAction::Send(Ieee80211Frame::Deauthentication(ref header, reason)) => {
+				assert_eq!(other, header.da);
+				assert_eq!(REASON_NOT_AUTHENTICATED, reason);
+			},
+			ref action => panic!("expected a deauthentication not {:?}", action)
+		}
+    }
+}
@@ -15,19 +15,37 @@
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 
 //! This is the layer responsible for routing frames towards an endpoint.
+pub use self::arp::*;
+pub use self::dcf::*;
+pub use self::edca::*;
 pub use self::ether_type::*;
+pub use self::ethernet::*;
+pub use self::frame80211::*;
 pub use self::ideal_mac::*;
 pub use self::link::*;
 pub use self::link_helpers::*;
 pub use self::llc::*;
 pub use self::mac80211::*;
+pub use self::mlme::*;
 pub use self::pcap::*;
+pub use self::pcap_reader::*;
+pub use self::pcapng::*;
+pub use self::replay::*;
 
+mod arp;
+mod dcf;
+mod edca;
 mod ether_type;
+mod ethernet;
+mod frame80211;
 mod ideal_mac;
 mod link;
 mod link_helpers;
 mod llc;
 mod mac80211;
+mod mlme;
 mod pcap;
+mod pcap_reader;
+mod pcapng;
+mod replay;
 
@@ -14,6 +14,7 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 use common::*;
+use physical::*;
 // use internet::*;
 // use link::link::*;
 // use link::link_helpers::*;
@@ -27,21 +28,25 @@ use time;
 /// Component used to write our pcap files for use by tools such as Wireshark or tshark.
 /// There are several component settings that affect how this is done:
 /// - **count** is the maximum number of packets to write out. Defaults to 100_000.
+/// - **epoch** is the Unix time, in seconds, of simulation time zero. If this is negative the wall clock time when the simulation starts is used. Defaults to 0 so that runs with the same seed produce identical pcaps.
+/// - **nanoseconds** if 1 timestamps have nanosecond resolution. Otherwise they have microsecond resolution. Defaults to 0.
 /// - **path** is the file in which the pcap is stored. If this is empty then no pcap is generated. Defaults to empty.
-/// - **promiscuous** if 1 save all frames that were seen. Otherwise only save frames addressed to this device. Defaults to 0. TODO: not implemented
+/// - **radiotap** if 1 frames are prefixed with a radiotap header containing the channel,
+/// rate, signal, and noise (LINKTYPE_IEEE802_11_RADIOTAP). Otherwise the raw frames are
+/// written (LINKTYPE_IEEE802_11). Defaults to 0.
+/// - **promiscuous** if 1 save all frames the radio heard, including frames for other stations and frames with a bad FCS (which are flagged in radiotap headers). Otherwise only save frames sent by or addressed to this device. Defaults to 0.
 /// - **snap_length** is the maximum number of packet bytes to write out. Defaults to 65536.
 /// 
 pub struct PcapComponent	
 {
 	data: ThreadData,
 
-	/// Packet should start with a Mac80211DataFrame header. This is similar to a monitor mode tcpdump.
-	/// Listens for send_ieee80211,
-	pub ieee80211_in: InPort<Packet>,	
+	/// Packet should be an 802.11 frame, see [`Ieee80211Frame`]. This is similar to a monitor mode tcpdump.
+	/// Listens for "send_ieee80211", "recv_ieee80211", and "monitor_ieee80211" events.
+	pub ieee80211_in: InPort<(RadioInfo, Packet)>,	
 }
 
-// TODO: add direction setting? tcpdump uses in, out, and inout for the values
-// TODO: add support for radiotap headers?
+// TODO: add direction setting? tcpdump uses in, out, and inout for the values (see PcapngComponent)
 impl PcapComponent
 {
 	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
@@ -58,6 +63,10 @@ impl PcapComponent
 		let mut file = Err(io::Error::new(io::ErrorKind::Other, "no path"));
 		let mut result = Ok(());
 		let mut snap_length = 65535;
+		let mut radiotap = false;
+		let mut promiscuous = false;
+		let mut nanoseconds = false;
+		let mut epoch = 0;
 
 		thread::spawn(move || {
 			process_events!(self.data, event, state, effector,
@@ -66,13 +75,22 @@ impl PcapComponent
 						effector.set_int("count", 100_000);	// we really only need to do all this if we have a path but we always do it so that our state looks nicer in GUIs
 					}
 					if !state.contains(self.data.id, "promiscuous") {
-						effector.set_int("promiscuous", 0);	// TODO: support this (on the rx path)
+						effector.set_int("promiscuous", 0);
+					} else {
+						promiscuous = state.get_int(self.data.id, "promiscuous") != 0;
 					}
 					if !state.contains(self.data.id, "snap_length") {
 						effector.set_int("snap_length", snap_length as i64);
 					} else {
 						snap_length = state.get_int(self.data.id, "snap_length") as u32;
 					}
+					if !state.contains(self.data.id, "radiotap") {
+						effector.set_int("radiotap", 0);
+					} else {
+						radiotap = state.get_int(self.data.id, "radiotap") != 0;
+					}
+					epoch = epoch_setting(self.data.id, &state, &mut effector);
+					nanoseconds = int_setting(self.data.id, &state, &mut effector, "nanoseconds", 0) != 0;
 					if !state.contains(self.data.id, "path") {
 						effector.set_string("path", "");
 					} else {
@@ -80,7 +98,8 @@ impl PcapComponent
 						file = fs::File::create(&path);
 						match file {
 							Ok(ref mut f) => {
-								result = write_global_header(f, snap_length);
+								let link_type = if radiotap {LINKTYPE_IEEE802_11_RADIOTAP} else {LINKTYPE_IEEE802_11};
+								result = write_global_header(f, snap_length, link_type, nanoseconds);
 								if let Err(ref e) = result {
 									log_error!(effector, "failed to write the global header: {:?}", *e);
 								}
@@ -91,60 +110,200 @@ impl PcapComponent
 					effector.set_int("frame", 0);
 				},
 				"send_ieee80211" => {
-					if let Ok(ref mut f) = file {
-						if result.is_ok() {
-							let packet = event.take_payload::<Packet>();
-							result = self.write_frame(f, &mut effector, &state, &packet, snap_length);
-							if let Err(ref e) = result {
-								log_error!(effector, "failed to write a frame: {:?}", *e);
-							}
-						}
+					let (info, packet) = event.take_payload::<(RadioInfo, Packet)>();
+					let header = if radiotap {radiotap_header(&info)} else {Vec::new()};
+					self.capture(&mut file, &mut result, &mut effector, &state, &header, &packet, snap_length, epoch, nanoseconds);
+				},
+				"recv_ieee80211" => {
+					let (info, packet) = event.take_payload::<(RadioInfo, Packet)>();
+					let header = if radiotap {radiotap_header(&info)} else {Vec::new()};
+					self.capture(&mut file, &mut result, &mut effector, &state, &header, &packet, snap_length, epoch, nanoseconds);
+				},
+				"monitor_ieee80211" => {
+					if promiscuous {
+						let (info, packet) = event.take_payload::<(RadioInfo, Packet)>();
+						let header = if radiotap {radiotap_header(&info)} else {Vec::new()};
+						self.capture(&mut file, &mut result, &mut effector, &state, &header, &packet, snap_length, epoch, nanoseconds);
 					}
 				}
 			);
 		});
 	}
 
-	fn write_frame(&self, file: &mut fs::File, effector: &mut Effector, state: &SimState, packet: &Packet, snap_length: u32) -> io::Result<()>
+	fn capture(&self, file: &mut io::Result<fs::File>, result: &mut io::Result<()>, effector: &mut Effector, state: &SimState, header: &[u8], packet: &Packet, snap_length: u32, epoch: u64, nanoseconds: bool)
+	{
+		if let Ok(ref mut f) = *file {
+			if result.is_ok() {
+				let timestamp = capture_timestamp(epoch, state.time);
+				*result = self.write_frame(f, effector, state, header, packet, snap_length, timestamp, nanoseconds);
+				if let Err(ref e) = *result {
+					log_error!(effector, "failed to write a frame: {:?}", *e);
+				}
+			}
+		}
+	}
+
+	fn write_frame(&self, file: &mut fs::File, effector: &mut Effector, state: &SimState, header: &[u8], packet: &Packet, snap_length: u32, timestamp: u64, nanoseconds: bool) -> io::Result<()>
 	{
 		let frame = state.get_int(self.data.id, "frame") + 1;
 		let count = state.get_int(self.data.id, "count");
 		if frame <= count {
-			try!(write_frame_header(file, state.time, snap_length, packet.len() as u32));
-			try!(write_frame_body(file, packet, snap_length));
+			try!(write_frame_header(file, timestamp, nanoseconds, snap_length, (header.len() + packet.len()) as u32));
+			try!(write_frame_body(file, header, packet, snap_length));
 			effector.set_int("frame", frame);
 		}
 		Ok(())
 	}
 }
 
-const LINKTYPE_IEEE802_11: u32 = 105;
+/// Reads the "epoch" setting, see [`PcapComponent`], and returns the Unix time in seconds
+/// of simulation time zero.
+pub fn epoch_setting(id: ComponentID, state: &SimState, effector: &mut Effector) -> u64
+{
+	let epoch = int_setting(id, state, effector, "epoch", 0);
+	if epoch < 0 {
+		time::now_utc().to_timespec().sec as u64
+	} else {
+		epoch as u64
+	}
+}
+
+/// Converts a simulation time into nanoseconds since the Unix epoch. This depends only
+/// on its arguments so that captures are reproducible.
+pub fn capture_timestamp(epoch: u64, time: f64) -> u64
+{
+	epoch*1_000_000_000 + (time*1_000_000_000.0).round() as u64
+}
+
+/// Link types, see http://www.tcpdump.org/linktypes.html.
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_IEEE802_11: u32 = 105;
+pub const LINKTYPE_IEEE802_11_RADIOTAP: u32 = 127;
+
+// Radiotap fields, see http://www.radiotap.org/fields/defined.
+const RADIOTAP_FLAGS: u32 = 1;
+const RADIOTAP_RATE: u32 = 2;
+const RADIOTAP_CHANNEL: u32 = 3;
+const RADIOTAP_DBM_ANTSIGNAL: u32 = 5;
+const RADIOTAP_DBM_ANTNOISE: u32 = 6;
+const RADIOTAP_MCS: u32 = 19;
+const RADIOTAP_VHT: u32 = 21;
+
+const FLAGS_FCS: u8 = 0x10;				// frames include the FCS
+const FLAGS_BAD_FCS: u8 = 0x40;
+const CHANNEL_OFDM: u16 = 0x0040;
+const CHANNEL_2GHZ: u16 = 0x0080;
+const CHANNEL_5GHZ: u16 = 0x0100;
+
+/// Returns a radiotap header for a frame, see http://www.radiotap.org. Fields are little
+/// endian, aligned to their natural size, and appear in the order of their bit numbers.
+pub fn radiotap_header(info: &RadioInfo) -> Vec<u8>
+{
+	let mut present = 1 << RADIOTAP_FLAGS | 1 << RADIOTAP_CHANNEL;
+	let mut fields = Vec::with_capacity(32);
+
+	fields.push(if info.bad_fcs {FLAGS_FCS | FLAGS_BAD_FCS} else {FLAGS_FCS});
+	if info.phy.standard == PhyStandard::Dot11a || info.phy.standard == PhyStandard::Dot11g {
+		present |= 1 << RADIOTAP_RATE;
+		fields.push((info.phy.data_rate()/500_000.0).round() as u8);	// 500 kbps units
+	}
+
+	align(&mut fields, 2);
+	let band = if info.frequency < 3000 {CHANNEL_2GHZ} else {CHANNEL_5GHZ};
+	push_u16(&mut fields, info.frequency as u16);
+	push_u16(&mut fields, band | CHANNEL_OFDM);
+
+	if let Some(signal) = info.signal {
+		present |= 1 << RADIOTAP_DBM_ANTSIGNAL;
+		fields.push(dbm(signal));
+	}
+	if let Some(noise) = info.noise {
+		present |= 1 << RADIOTAP_DBM_ANTNOISE;
+		fields.push(dbm(noise));
+	}
+
+	let short_gi = if info.phy.short_gi {0x04} else {0x00};
+	match info.phy.standard {
+		PhyStandard::Dot11a | PhyStandard::Dot11g => (),
+		PhyStandard::Dot11n => {
+			present |= 1 << RADIOTAP_MCS;
+			fields.push(0x01 | 0x02 | 0x04);	// known: bandwidth, MCS, and guard interval
+			fields.push((if info.phy.bandwidth == 40 {0x01} else {0x00}) | short_gi);
+			fields.push(info.phy.mcs + 8*(info.phy.spatial_streams - 1));	// HT MCS indexes include the stream count
+		},
+		PhyStandard::Dot11ac => {
+			present |= 1 << RADIOTAP_VHT;
+			align(&mut fields, 2);
+			push_u16(&mut fields, 0x0004 | 0x0040);	// known: guard interval and bandwidth
+			fields.push(short_gi);
+			fields.push(match info.phy.bandwidth {20 => 0, 40 => 1, 80 => 4, _ => 11});
+			fields.push(info.phy.mcs << 4 | info.phy.spatial_streams);
+			fields.extend_from_slice(&[0, 0, 0]);	// other users
+			fields.push(0);							// coding (BCC)
+			fields.push(0);							// group id
+			push_u16(&mut fields, 0);				// partial AID
+		}
+	}
+
+	let mut header = Vec::with_capacity(8 + fields.len());
+	header.push(0);					// version
+	header.push(0);					// pad
+	push_u16(&mut header, (8 + fields.len()) as u16);
+	push_u16(&mut header, (present & 0xFFFF) as u16);
+	push_u16(&mut header, (present >> 16) as u16);
+	header.extend(fields);
+	header
+}
+
+// Offsets are relative to the start of the radiotap header which is 8 bytes.
+fn align(fields: &mut Vec<u8>, alignment: usize)
+{
+	while fields.len() % alignment != 0 {
+		fields.push(0);
+	}
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16)
+{
+	bytes.push((value & 0xFF) as u8);
+	bytes.push((value >> 8) as u8);
+}
+
+fn dbm(power: f64) -> u8
+{
+	power.round().max(-128.0).min(127.0) as i8 as u8
+}
 
 // It doesn't matter what byte order we write this stuff out as, it only matters that we are consistent.
 // So we'll just write them out as little endian because that seems to be what most hardware uses nowadays.
 // See https://wiki.wireshark.org/Development/LibpcapFileFormat for more.
-fn write_global_header<W>(writer: &mut W, snap_length: u32) -> io::Result<()>
+fn write_global_header<W>(writer: &mut W, snap_length: u32, link_type: u32, nanoseconds: bool) -> io::Result<()>
 	where W: io::Write
 {
-	try!(write_u32(writer, 0xa1b2c3d4));			// magic number
+	try!(write_u32(writer, if nanoseconds {0xa1b23c4d} else {0xa1b2c3d4}));	// magic number
 	try!(write_u16(writer, 2));						// major version number
 	try!(write_u16(writer, 4));						// minor version number
 	try!(write_u32(writer, 0));						// timezone correction, 0 because our timestamps are GMT
 	try!(write_u32(writer, 0));						// timestamp accuracy, all tools set this to zero
 	try!(write_u32(writer, snap_length));			// max bytes to record within a packet
-	try!(write_u32(writer, LINKTYPE_IEEE802_11));	// link layer header type, see http://www.tcpdump.org/linktypes.html for more
+	try!(write_u32(writer, link_type));				// link layer header type, see http://www.tcpdump.org/linktypes.html for more
 	Ok(())
 }
 
-fn write_frame_header<W>(writer: &mut W, timestamp: f64, snap_length: u32, len: u32) -> io::Result<()>
+// Timestamp is in nanoseconds, see capture_timestamp.
+fn write_frame_header<W>(writer: &mut W, timestamp: u64, nanoseconds: bool, snap_length: u32, len: u32) -> io::Result<()>
 	where W: io::Write
 {
-	let current = time::now_utc().to_timespec();
-	let secs = timestamp.floor() + current.sec as f64;
-	let usecs = (timestamp - timestamp.floor())*1_000_000.0;
+	let secs = timestamp/1_000_000_000;
+	let nsecs = timestamp % 1_000_000_000;
 
 	try!(write_u32(writer, secs as u32));		// seconds
-	try!(write_u32(writer, usecs as u32));		// microseconds
+	if nanoseconds {
+		try!(write_u32(writer, nsecs as u32));	// nanoseconds
+	} else {
+		try!(write_u32(writer, (nsecs/1_000) as u32));	// microseconds
+	}
 	if len < snap_length {						// recorded bytes
 		try!(write_u32(writer, len));
 	} else {
@@ -154,13 +313,15 @@ fn write_frame_header<W>(writer: &mut W, timestamp: f64, snap_length: u32, len:
 	Ok(())
 }
 
-fn write_frame_body<W>(writer: &mut W, packet: &Packet, snap_length: u32) -> io::Result<()>
+fn write_frame_body<W>(writer: &mut W, header: &[u8], packet: &Packet, snap_length: u32) -> io::Result<()>
 	where W: io::Write
 {
-	let len = if packet.len() < snap_length as usize {packet.len()} else {snap_length as usize};
+	let total = header.len() + packet.len();
+	let len = if total < snap_length as usize {total} else {snap_length as usize};
 	let mut buffer = io::BufWriter::with_capacity(len, writer);
 	for i in 0..len {
-		try!(buffer.write_all(&[packet.get(i)]));
+		let byte = if i < header.len() {header[i]} else {packet.get(i - header.len())};
+		try!(buffer.write_all(&[byte]));
 	}
 	Ok(())
 }
@@ -178,3 +339,73 @@ fn write_u32<W>(writer: &mut W, value: u32) -> io::Result<()>
 	let bytes = [(value & 0xFF) as u8, ((value >> 8) & 0xFF) as u8, ((value >> 16) & 0xFF) as u8, ((value >> 24) & 0xFF) as u8];
 	writer.write_all(&bytes)
 }
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn radiotap()
+	{
+		let phy = Phy80211::legacy(PhyStandard::Dot11a, 7);
+		let info = RadioInfo {phy, frequency: 5180, signal: Some(-60.2), noise: Some(-94.0), bad_fcs: false};
+		let header = radiotap_header(&info);
+		assert_eq!(header, vec![
+			0, 0, 16, 0,				// version, pad, and length
+			0x6E, 0, 0, 0,				// flags, rate, channel, signal, and noise
+			FLAGS_FCS, 108,				// 54 Mbps
+			0x3C, 0x14, 0x40, 0x01,		// 5180 MHz, 5 GHz OFDM
+			(-60i8) as u8, (-94i8) as u8]);
+
+		// No rate and unaligned MCS.
+		let phy = Phy80211::new(PhyStandard::Dot11n, 3, 40, 2, true).unwrap();
+		let header = radiotap_header(&RadioInfo::with_tx(phy, 2412));
+		assert_eq!(header.len(), 8 + 1 + 1 + 4 + 3);
+		assert_eq!(header[4..8], [0x0A, 0x00, 0x08, 0x00]);
+		assert_eq!(header[10..14], [0x6C, 0x09, 0xC0, 0x00]);
+		assert_eq!(header[14..17], [0x07, 0x05, 11]);
+
+		let phy = Phy80211::new(PhyStandard::Dot11ac, 9, 80, 1, false).unwrap();
+		let header = radiotap_header(&RadioInfo::with_tx(phy, 5180));
+		assert_eq!(header.len(), 8 + 2 + 4 + 12);
+		assert_eq!(header[14..20], [0x44, 0x00, 0x00, 4, 0x91, 0]);
+
+		let mut info = RadioInfo::with_tx(Phy80211::legacy(PhyStandard::Dot11a, 0), 5180);
+		info.bad_fcs = true;
+		assert_eq!(radiotap_header(&info)[8], FLAGS_FCS | FLAGS_BAD_FCS);
+	}
+
+	#[cfg(test)]
+	fn write_pcap(nanoseconds: bool) -> Vec<u8>
+	{
+		let mut packet = Packet::new("frame", "#>1.1");
+		packet.push_back_bytes(&[1, 2, 3]);
+
+		let mut bytes = Vec::new();
+		write_global_header(&mut bytes, 65535, LINKTYPE_IEEE802_11, nanoseconds).unwrap();
+		for &time in [0.0, 1.25, 2.000_000_123].iter() {
+			write_frame_header(&mut bytes, capture_timestamp(10, time), nanoseconds, 65535, 3).unwrap();
+			write_frame_body(&mut bytes, &[], &packet, 65535).unwrap();
+		}
+		bytes
+	}
+
+    #[test]
+    fn timestamps()
+	{
+		assert_eq!(capture_timestamp(0, 1.5), 1_500_000_000);
+		assert_eq!(capture_timestamp(10, 0.000_000_001), 10_000_000_001);
+
+		// Captures only depend on the simulation so they can be compared byte for byte.
+		let bytes = write_pcap(false);
+		assert_eq!(bytes, write_pcap(false));
+		assert_eq!(&bytes[0..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+		assert_eq!(&bytes[24 + 19..24 + 27], &[11, 0, 0, 0, 0x90, 0xD0, 0x03, 0]);	// 11.25 s
+
+		let bytes = write_pcap(true);
+		assert_eq!(&bytes[0..4], &[0x4d, 0x3c, 0xb2, 0xa1]);
+		assert_eq!(&bytes[24..32], &[10, 0, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(&bytes[24 + 38..24 + 46], &[12, 0, 0, 0, 123, 0, 0, 0]);
+	}
+}
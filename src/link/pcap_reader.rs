@@ -0,0 +1,523 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use link::ether_type::*;
+use link::ethernet::*;
+use link::frame80211::*;
+use link::link::*;
+use link::link_helpers::*;
+use link::llc::*;
+use link::pcap::*;
+use link::pcapng::*;
+use std::fs::File;
+use std::io::Read;
+
+/// A frame read from a pcap or pcapng file.
+pub struct CaptureRecord
+{
+	/// Nanoseconds since the Unix epoch, see [`capture_timestamp`].
+	pub timestamp: u64,
+
+	/// See http://www.tcpdump.org/linktypes.html.
+	pub link_type: u32,
+
+	/// Only pcapng files record this.
+	pub direction: Option<Direction>,
+
+	/// True if the frame (after any radiotap header) ends with an FCS. This comes from the
+	/// radiotap flags or the pcapng if_fcslen option. Otherwise 802.11 frames are assumed to
+	/// have an FCS (as [`PcapComponent`] writes them) and other frames are assumed not to.
+	pub has_fcs: bool,
+
+	/// True if the capture flagged the frame as having a bad FCS (using radiotap flags or the
+	/// pcapng CRC error flag) or if the frame's FCS doesn't match its contents.
+	pub bad_fcs: bool,
+
+	/// The name and id come from the pcapng comment if it was written by [`PcapngComponent`].
+	/// Otherwise the name is "captured frame" and the id is "#>capture.N".
+	pub packet: Packet,
+}
+
+impl CaptureRecord
+{
+	/// Returns the frame in the form MACs expect: radiotap headers are removed and an FCS is
+	/// added if the capture didn't include one. Frames with a bad FCS are errors.
+	pub fn link_frame(&self) -> Result<Packet, String>
+	{
+		if self.bad_fcs {
+			return Err("frame has a bad FCS".to_string())
+		}
+
+		let mut packet = try!(self.frame());
+		if !self.has_fcs {
+			let crc = crc32(&packet);		// see frame80211 push_fcs
+			packet.push_back_bytes(&[(crc & 0xFF) as u8, (crc >> 8 & 0xFF) as u8, (crc >> 16 & 0xFF) as u8, (crc >> 24 & 0xFF) as u8]);
+		}
+		Ok(packet)
+	}
+
+	/// Removes the link layer headers and returns the network layer packet (e.g. IPv4)
+	/// along with the link info. For raw IP captures the addresses are all zeros.
+	pub fn network_packet(&self) -> Result<(LinkInfo, Packet), String>
+	{
+		match self.link_type {
+			LINKTYPE_RAW => {
+				if self.bad_fcs {
+					return Err("packet has a CRC error".to_string())
+				}
+				let packet = self.packet.clone();
+				if packet.len() == 0 {
+					return Err("empty IP packet".to_string())
+				}
+				let ether_type = match packet.get(0) >> 4 {
+					4 => EtherType::IPv4,
+					6 => EtherType::IPv6,
+					version => return Err(format!("IP version {} isn't supported", version))
+				};
+				Ok((LinkInfo::new(ether_type, &[0; 6], &[0; 6]), packet))
+			},
+			LINKTYPE_ETHERNET => {
+				let mut packet = try!(self.link_frame());
+				let header = try!(EthernetFrame::pop(&mut packet));
+				Ok((LinkInfo::new(header.ether_type, &header.src_addr, &header.dst_addr), packet))
+			},
+			LINKTYPE_IEEE802_11 | LINKTYPE_IEEE802_11_RADIOTAP => {
+				let mut packet = try!(self.link_frame());
+				match try!(Ieee80211Frame::pop(&mut packet)) {
+					Ieee80211Frame::Data(header) => {
+						let llc = try!(LlcHeader::pop(&mut packet));
+						Ok((LinkInfo::new(llc.ether_type, &header.sa, &header.da), packet))
+					},
+					frame => Err(format!("{} frames don't have a network packet", frame.name()))
+				}
+			},
+			link_type => Err(format!("link type {} isn't supported", link_type))
+		}
+	}
+
+	// The captured frame without its radiotap header.
+	fn frame(&self) -> Result<Packet, String>
+	{
+		let mut packet = self.packet.clone();
+		if self.link_type == LINKTYPE_IEEE802_11_RADIOTAP {
+			let len = try!(radiotap_len(&packet));
+			let _ = packet.pop_bytes(len);
+		}
+		Ok(packet)
+	}
+}
+
+/// Reads a pcap or pcapng file.
+pub fn open_capture(path: &str) -> Result<Vec<CaptureRecord>, String>
+{
+	let mut bytes = Vec::new();
+	let mut file = try!(File::open(path).map_err(|e| format!("couldn't open {}: {}", path, e)));
+	try!(file.read_to_end(&mut bytes).map_err(|e| format!("couldn't read {}: {}", path, e)));
+	read_capture(&bytes)
+}
+
+/// Parses the contents of a pcap or pcapng file. Classic pcaps may use either byte order
+/// and either microsecond or nanosecond timestamps. For pcapng files Enhanced and Simple
+/// Packet Blocks are returned (the latter with zero timestamps) and other blocks are
+/// skipped.
+pub fn read_capture(bytes: &[u8]) -> Result<Vec<CaptureRecord>, String>
+{
+	if bytes.len() < 4 {
+		return Err("capture is too small".to_string())
+	}
+	let mut reader = Reader {bytes, offset: 0, big_endian: false};
+	let magic = try!(reader.u32());
+	match magic {
+		0x0A0D0D0A => read_pcapng(bytes),
+		0xa1b2c3d4 => read_pcap(bytes, false, 6),
+		0xa1b23c4d => read_pcap(bytes, false, 9),
+		0xd4c3b2a1 => read_pcap(bytes, true, 6),
+		0x4d3cb2a1 => read_pcap(bytes, true, 9),
+		_ => Err(format!("{:#x} isn't a pcap or pcapng magic number", magic))
+	}
+}
+
+// Details from a pcapng Interface Description Block (or a classic pcap header).
+struct Interface
+{
+	link_type: u32,
+	tsresol: u8,			// see if_tsresol
+	fcs_len: Option<u8>,
+}
+
+fn read_pcap(bytes: &[u8], big_endian: bool, tsresol: u8) -> Result<Vec<CaptureRecord>, String>
+{
+	let mut reader = Reader {bytes, offset: 20, big_endian};
+	let interface = Interface {link_type: try!(reader.u32()), tsresol, fcs_len: None};
+
+	let mut records = Vec::new();
+	while !reader.at_end() {
+		let secs = try!(reader.u32()) as u64;
+		let fraction = try!(reader.u32()) as u64;
+		let captured = try!(reader.u32()) as usize;
+		let _original = try!(reader.u32());
+		let data = try!(reader.take(captured));
+
+		let timestamp = secs*1_000_000_000 + to_nanoseconds(fraction, tsresol);
+		let packet = new_packet(data, None, records.len());
+		records.push(new_record(&interface, timestamp, None, false, packet));
+	}
+	Ok(records)
+}
+
+fn read_pcapng(bytes: &[u8]) -> Result<Vec<CaptureRecord>, String>
+{
+	let mut interfaces: Vec<Interface> = Vec::new();
+	let mut records = Vec::new();
+	let mut big_endian = false;
+	let mut offset = 0;
+	while offset < bytes.len() {
+		let mut reader = Reader {bytes, offset, big_endian};
+		let kind = try!(reader.u32());
+		if kind == SECTION_HEADER_BLOCK {
+			// The byte order magic tells us how to read the rest of the section.
+			let mut magic = Reader {bytes, offset: offset + 8, big_endian: false};
+			big_endian = try!(magic.u32()) != 0x1A2B3C4D;
+			reader.big_endian = big_endian;
+			interfaces.clear();
+		}
+		let len = try!(reader.u32()) as usize;
+		if len < 12 || len % 4 != 0 || offset + len > bytes.len() {
+			return Err(format!("block at offset {} has a bad length: {}", offset, len))
+		}
+		let end = offset + len - 4;		// skip the trailing length
+
+		match kind {
+			INTERFACE_DESCRIPTION_BLOCK => {
+				let link_type = try!(reader.u16()) as u32;
+				let _reserved = try!(reader.u16());
+				let _snap_length = try!(reader.u32());
+				let mut interface = Interface {link_type, tsresol: 6, fcs_len: None};
+				for (code, value) in try!(reader.options(end)) {
+					if code == IF_TSRESOL && value.len() == 1 {
+						interface.tsresol = value[0];
+					} else if code == IF_FCSLEN && value.len() == 1 {
+						interface.fcs_len = Some(value[0]);
+					}
+				}
+				interfaces.push(interface);
+			},
+			ENHANCED_PACKET_BLOCK => {
+				let index = try!(reader.u32()) as usize;
+				let high = try!(reader.u32()) as u64;
+				let low = try!(reader.u32()) as u64;
+				let captured = try!(reader.u32()) as usize;
+				let _original = try!(reader.u32());
+				let data = try!(reader.take(captured));
+				reader.align();
+
+				let mut comment = None;
+				let mut flags = 0;
+				for (code, value) in try!(reader.options(end)) {
+					if code == OPT_COMMENT {
+						comment = Some(String::from_utf8_lossy(value).into_owned());
+					} else if code == EPB_FLAGS && value.len() == 4 {
+						flags = try!(Reader {bytes: value, offset: 0, big_endian}.u32());
+					}
+				}
+				let direction = match flags & 0b11 {
+					0b01 => Some(Direction::Inbound),
+					0b10 => Some(Direction::Outbound),
+					_ => None
+				};
+				let crc_error = flags & EPB_CRC_ERROR != 0;
+
+				let interface = try!(interfaces.get(index).ok_or_else(|| format!("interface {} wasn't described", index)));
+				let timestamp = to_nanoseconds(high << 32 | low, interface.tsresol);
+				let packet = new_packet(data, comment, records.len());
+				records.push(new_record(interface, timestamp, direction, crc_error, packet));
+			},
+			SIMPLE_PACKET_BLOCK => {
+				let original = try!(reader.u32()) as usize;
+				let captured = original.min(end - reader.offset);
+				let data = try!(reader.take(captured));
+
+				let interface = try!(interfaces.get(0).ok_or_else(|| "interface 0 wasn't described".to_string()));
+				let packet = new_packet(data, None, records.len());
+				records.push(new_record(interface, 0, None, false, packet));
+			},
+			_ => ()
+		}
+		offset += len;
+	}
+	Ok(records)
+}
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const SIMPLE_PACKET_BLOCK: u32 = 3;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+
+const OPT_COMMENT: u16 = 1;
+const IF_TSRESOL: u16 = 9;
+const IF_FCSLEN: u16 = 13;
+const EPB_FLAGS: u16 = 2;
+const EPB_CRC_ERROR: u32 = 1 << 24;
+
+// Radiotap flags, see http://www.radiotap.org/fields/Flags.
+const RADIOTAP_FLAGS_FCS: u8 = 0x10;
+const RADIOTAP_FLAGS_BAD_FCS: u8 = 0x40;
+
+fn new_record(interface: &Interface, timestamp: u64, direction: Option<Direction>, crc_error: bool, packet: Packet) -> CaptureRecord
+{
+	let link_type = interface.link_type;
+	let (has_fcs, flagged) = if link_type == LINKTYPE_IEEE802_11_RADIOTAP {
+		match radiotap_flags(&packet) {
+			Some(flags) => (flags & RADIOTAP_FLAGS_FCS != 0, flags & RADIOTAP_FLAGS_BAD_FCS != 0),
+			None => (false, false)
+		}
+	} else {
+		match interface.fcs_len {
+			Some(len) => (len > 0, false),
+			None => (link_type == LINKTYPE_IEEE802_11, false)
+		}
+	};
+
+	let mut record = CaptureRecord {timestamp, link_type, direction, has_fcs, bad_fcs: crc_error || flagged, packet};
+	if has_fcs && !record.bad_fcs {
+		record.bad_fcs = match record.frame() {
+			Ok(frame) => !valid_fcs(&frame),
+			Err(_) => true,
+		};
+	}
+	record
+}
+
+// Comments written by PcapngComponent are "name id".
+fn new_packet(data: &[u8], comment: Option<String>, index: usize) -> Packet
+{
+	let (name, id) = match comment {
+		Some(ref text) if text.contains(" #>") => {
+			let split = text.rfind(" #>").unwrap();
+			(text[..split].to_string(), text[split + 1..].to_string())
+		},
+		_ => ("captured frame".to_string(), format!("#>capture.{}", index + 1))
+	};
+	let mut packet = Packet::new(&name, &id);
+	packet.push_back_bytes(data);
+	packet
+}
+
+// Converts a timestamp in if_tsresol units into nanoseconds.
+fn to_nanoseconds(ticks: u64, tsresol: u8) -> u64
+{
+	let exp = (tsresol & 0x7F) as u32;
+	if tsresol & 0x80 == 0 {
+		if exp <= 9 {
+			ticks*10u64.pow(9 - exp)
+		} else if exp - 9 <= 19 {
+			ticks/10u64.pow(exp - 9)
+		} else {
+			0
+		}
+	} else {
+		let exp = exp.min(63);
+		let secs = ticks >> exp;
+		let fraction = ticks & ((1 << exp) - 1);
+		secs*1_000_000_000 + (fraction as f64*1.0e9/2.0f64.powi(exp as i32)).round() as u64
+	}
+}
+
+fn radiotap_len(packet: &Packet) -> Result<usize, String>
+{
+	if packet.len() < 8 {
+		return Err("radiotap header is too small".to_string())
+	}
+	let len = packet.get(2) as usize | (packet.get(3) as usize) << 8;
+	if len > packet.len() {
+		return Err(format!("radiotap length is {} but the frame is only {} bytes", len, packet.len()))
+	}
+	Ok(len)
+}
+
+// Returns the radiotap Flags field if it's present.
+fn radiotap_flags(packet: &Packet) -> Option<u8>
+{
+	let len = match radiotap_len(packet) {
+		Ok(len) => len,
+		Err(_) => return None
+	};
+	let present = |offset: usize| (0..4).fold(0u32, |word, i| word | (packet.get(offset + i) as u32) << (8*i));
+
+	// Skip extended presence bitmaps.
+	let mut offset = 4;
+	while offset + 4 <= len && present(offset) & 0x8000_0000 != 0 {
+		offset += 4;
+	}
+	offset += 4;
+
+	let first = present(4);
+	if first & 0b10 == 0 {
+		return None
+	}
+	if first & 0b01 != 0 {
+		offset = (offset + 7) & !7;		// TSFT is 8 bytes and 8 byte aligned
+		offset += 8;
+	}
+	if offset < len {Some(packet.get(offset))} else {None}
+}
+
+struct Reader<'a>
+{
+	bytes: &'a [u8],
+	offset: usize,
+	big_endian: bool,
+}
+
+impl<'a> Reader<'a>
+{
+	fn at_end(&self) -> bool
+	{
+		self.offset >= self.bytes.len()
+	}
+
+	fn take(&mut self, len: usize) -> Result<&'a [u8], String>
+	{
+		if self.offset + len > self.bytes.len() {
+			return Err(format!("capture is truncated at offset {}", self.offset))
+		}
+		let result = &self.bytes[self.offset..self.offset + len];
+		self.offset += len;
+		Ok(result)
+	}
+
+	fn u16(&mut self) -> Result<u16, String>
+	{
+		let b = try!(self.take(2));
+		Ok(if self.big_endian {(b[0] as u16) << 8 | b[1] as u16} else {(b[1] as u16) << 8 | b[0] as u16})
+	}
+
+	fn u32(&mut self) -> Result<u32, String>
+	{
+		let high_first = self.big_endian;
+		let a = try!(self.u16()) as u32;
+		let b = try!(self.u16()) as u32;
+		Ok(if high_first {a << 16 | b} else {b << 16 | a})
+	}
+
+	fn align(&mut self)
+	{
+		self.offset = (self.offset + 3) & !3;
+	}
+
+	// Returns (code, value) for each option up to end.
+	fn options(&mut self, end: usize) -> Result<Vec<(u16, &'a [u8])>, String>
+	{
+		let mut options = Vec::new();
+		while self.offset + 4 <= end {
+			let code = try!(self.u16());
+			let len = try!(self.u16()) as usize;
+			if code == 0 {
+				break;
+			}
+			let value = try!(self.take(len));
+			self.align();
+			options.push((code, value));
+		}
+		Ok(options)
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+	#[cfg(test)]
+	fn data_frame() -> Vec<u8>
+	{
+		let mut packet = Packet::new("data", "#>5.1");
+		LlcHeader::with_ipv4().push(&mut packet);
+		let header = Mac80211DataFrame::new(&[2, 0, 0, 0, 0, 1], &[2, 0, 0, 0, 0, 2], 3);
+		header.push(&mut packet);
+		packet.iter().cloned().collect()
+	}
+
+    #[test]
+    fn round_trip()
+	{
+		// Classic big endian pcap with nanosecond timestamps and a raw IPv4 packet.
+		let mut pcap = vec![0xa1, 0xb2, 0x3c, 0x4d, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0, 0, 0, 101];
+		pcap.extend_from_slice(&[0, 0, 0, 10, 0, 0, 0x01, 0xF5, 0, 0, 0, 3, 0, 0, 0, 3, 0x45, 1, 2]);
+		let records = read_capture(&pcap).unwrap();
+		assert_eq!(records.len(), 1);
+		assert_eq!(records[0].timestamp, 10_000_000_501);
+		assert_eq!(records[0].link_type, LINKTYPE_RAW);
+		assert_eq!(records[0].packet.id(), "#>capture.1");
+		assert!(!records[0].has_fcs);
+		let (linfo, packet) = records[0].network_packet().unwrap();
+		assert_eq!(linfo.ether_type.as_u16(), 0x0800);
+		assert_eq!(packet.len(), 3);
+
+		// The 802.11 frame after the radiotap header gets an FCS.
+		let bytes = data_frame();
+		let mut frame = vec![0, 0, 8, 0, 0, 0, 0, 0];
+		frame.extend_from_slice(&bytes[..bytes.len() - 4]);
+		let interface = Interface {link_type: LINKTYPE_IEEE802_11_RADIOTAP, tsresol: 9, fcs_len: None};
+		let record = new_record(&interface, 1, None, false, new_packet(&frame, Some("data #>5.1".to_string()), 0));
+		assert_eq!(record.packet.name(), "data");
+		assert_eq!(record.packet.id(), "#>5.1");
+		assert!(!record.has_fcs);
+		assert!(!record.bad_fcs);
+		let link = record.link_frame().unwrap();
+		assert_eq!(link.iter().cloned().collect::<Vec<u8>>(), bytes);
+		let (linfo, packet) = record.network_packet().unwrap();
+		assert_eq!(linfo.src_addr, [2, 0, 0, 0, 0, 1]);
+		assert_eq!(linfo.dst_addr, [2, 0, 0, 0, 0, 2]);
+		assert_eq!(packet.len(), 0);
+
+		assert!(read_capture(&[1, 2, 3, 4]).is_err());
+		assert_eq!(to_nanoseconds(3, 6), 3_000);
+		assert_eq!(to_nanoseconds(1 << 30 | 1 << 29, 0x80 | 30), 1_500_000_000);
+	}
+
+    #[test]
+    fn bad_fcs()
+	{
+		// The radiotap flags say the frame has an FCS.
+		let bytes = data_frame();
+		let mut frame = vec![0, 0, 9, 0, 0x02, 0, 0, 0, RADIOTAP_FLAGS_FCS];
+		frame.extend_from_slice(&bytes);
+		let interface = Interface {link_type: LINKTYPE_IEEE802_11_RADIOTAP, tsresol: 9, fcs_len: None};
+		let record = new_record(&interface, 0, None, false, new_packet(&frame, None, 0));
+		assert!(record.has_fcs);
+		assert!(!record.bad_fcs);
+		assert_eq!(record.link_frame().unwrap().iter().cloned().collect::<Vec<u8>>(), bytes);
+
+		// Flagged as bad by radiotap.
+		frame[8] = RADIOTAP_FLAGS_FCS | RADIOTAP_FLAGS_BAD_FCS;
+		let record = new_record(&interface, 0, None, false, new_packet(&frame, None, 0));
+		assert!(record.bad_fcs);
+		assert!(record.link_frame().is_err());
+		assert!(record.network_packet().is_err());
+
+		// Flagged as bad by pcapng or corrupted.
+		let interface = Interface {link_type: LINKTYPE_IEEE802_11, tsresol: 9, fcs_len: Some(4)};
+		let record = new_record(&interface, 0, Some(Direction::Inbound), true, new_packet(&bytes, None, 0));
+		assert!(record.bad_fcs);
+		let mut corrupted = bytes.clone();
+		corrupted[10] ^= 0x01;
+		let record = new_record(&interface, 0, Some(Direction::Inbound), false, new_packet(&corrupted, None, 0));
+		assert!(record.has_fcs);
+		assert!(record.bad_fcs);
+		assert!(record.link_frame().is_err());
+	}
+}
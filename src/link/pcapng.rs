@@ -0,0 +1,373 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use link::pcap::*;
+use physical::*;
+use score::*;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::thread;
+
+/// The kinds of interfaces a [`PcapngComponent`] can capture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureKind
+{
+	/// Frames from a [`Mac80211Component`].
+	Ieee80211,
+
+	/// Frames from an [`EthernetMacComponent`].
+	Ethernet,
+
+	/// IPv4 or IPv6 packets with no link layer header.
+	RawIP,
+}
+
+/// Whether a frame was sent or received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction
+{
+	Inbound,
+	Outbound,
+}
+
+struct CaptureInterface
+{
+	name: String,
+	kind: CaptureKind,
+	port_name: String,
+}
+
+/// Component used to write pcapng files for use by tools such as Wireshark or tshark.
+/// Unlike [`PcapComponent`] a single file can hold frames from any number of interfaces
+/// (with different link types), each frame records whether it was sent or received, and
+/// timestamps have nanosecond resolution. Frames also have a comment with the packet's
+/// name and id so that packets can be followed through a device (e.g. search for
+/// `frame.comment contains "#>12.56"`). Component settings are:
+/// - **count** is the maximum number of packets to write out. Defaults to 100_000.
+/// - **epoch** is the Unix time, in seconds, of simulation time zero, see [`PcapComponent`]. Defaults to 0.
+/// - **path** is the file in which the pcapng is stored. If this is empty then no file is generated. Defaults to empty.
+/// - **promiscuous** if 1 802.11 interfaces also save frames for other stations and frames with a bad FCS (which are flagged as CRC errors). Defaults to 0.
+/// - **radiotap** if 1 802.11 frames are prefixed with a radiotap header, see [`PcapComponent`]. Defaults to 0.
+/// - **snap_length** is the maximum number of packet bytes to write out. Defaults to 65536.
+pub struct PcapngComponent
+{
+	data: ThreadData,
+	interfaces: Vec<CaptureInterface>,
+
+	ieee80211_ins: Vec<InPort<(RadioInfo, Packet)>>,
+	ethernet_ins: Vec<InPort<Packet>>,
+	ip_ins: Vec<InPort<Packet>>,
+}
+
+impl PcapngComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (_, data) = sim.add_active_component("pcapng", parent_id);
+		PcapngComponent {
+			data: data,
+			interfaces: Vec::new(),
+			ieee80211_ins: Vec::new(),
+			ethernet_ins: Vec::new(),
+			ip_ins: Vec::new(),
+		}
+	}
+
+	/// Adds an interface for a [`Mac80211Component`]'s pcap_out. The port listens for
+	/// "send_ieee80211", "recv_ieee80211", and "monitor_ieee80211" events.
+	pub fn add_ieee80211(&mut self, name: &str) -> &InPort<(RadioInfo, Packet)>
+	{
+		let port_name = self.add_interface(name, CaptureKind::Ieee80211);
+		self.ieee80211_ins.push(InPort::with_port_name(self.data.id, &port_name));
+		self.ieee80211_ins.last().unwrap()
+	}
+
+	/// Adds an interface for an [`EthernetMacComponent`]'s pcap_out. The port listens for
+	/// "send_ethernet" and "recv_ethernet" events.
+	pub fn add_ethernet(&mut self, name: &str) -> &InPort<Packet>
+	{
+		let port_name = self.add_interface(name, CaptureKind::Ethernet);
+		self.ethernet_ins.push(InPort::with_port_name(self.data.id, &port_name));
+		self.ethernet_ins.last().unwrap()
+	}
+
+	/// Adds an interface for IP packets. The port listens for "send_ip" and "recv_ip" events.
+	pub fn add_raw_ip(&mut self, name: &str) -> &InPort<Packet>
+	{
+		let port_name = self.add_interface(name, CaptureKind::RawIP);
+		self.ip_ins.push(InPort::with_port_name(self.data.id, &port_name));
+		self.ip_ins.last().unwrap()
+	}
+
+	fn add_interface(&mut self, name: &str, kind: CaptureKind) -> String
+	{
+		let port_name = format!("if{}", self.interfaces.len());
+		self.interfaces.push(CaptureInterface {name: name.to_string(), kind, port_name: port_name.clone()});
+		port_name
+	}
+
+	pub fn start(self)
+	{
+		let mut file = Err(io::Error::new(io::ErrorKind::Other, "no path"));
+		let mut result = Ok(());
+		let mut snap_length = 65535;
+		let mut radiotap = false;
+		let mut promiscuous = false;
+		let mut epoch = 0;
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					effector.set_int("frame", 0);
+					int_setting(self.data.id, &state, &mut effector, "count", 100_000);
+					snap_length = int_setting(self.data.id, &state, &mut effector, "snap_length", snap_length as i64) as u32;
+					radiotap = int_setting(self.data.id, &state, &mut effector, "radiotap", 0) != 0;
+					promiscuous = int_setting(self.data.id, &state, &mut effector, "promiscuous", 0) != 0;
+					let path = string_setting(self.data.id, &state, &mut effector, "path", "");
+
+					epoch = epoch_setting(self.data.id, &state, &mut effector);
+					if !path.is_empty() {
+						file = fs::File::create(&path);
+						match file {
+							Ok(ref mut f) => {
+								result = self.write_headers(f, snap_length, radiotap);
+								if let Err(ref e) = result {
+									log_error!(effector, "failed to write the pcapng headers: {:?}", *e);
+								}
+							},
+							Err(ref e) => log_error!(effector, "failed to create pcapng: {:?}", *e)
+						}
+					}
+				},
+				"send_ieee80211" => {
+					let (info, packet) = event.take_payload::<(RadioInfo, Packet)>();
+					let header = if radiotap {radiotap_header(&info)} else {Vec::new()};
+					self.capture(&mut file, &mut result, &mut effector, &state, &event.port_name, Direction::Outbound, false, &header, &packet, snap_length, epoch);
+				},
+				"recv_ieee80211" => {
+					let (info, packet) = event.take_payload::<(RadioInfo, Packet)>();
+					let header = if radiotap {radiotap_header(&info)} else {Vec::new()};
+					self.capture(&mut file, &mut result, &mut effector, &state, &event.port_name, Direction::Inbound, info.bad_fcs, &header, &packet, snap_length, epoch);
+				},
+				"monitor_ieee80211" => {
+					if promiscuous {
+						let (info, packet) = event.take_payload::<(RadioInfo, Packet)>();
+						let header = if radiotap {radiotap_header(&info)} else {Vec::new()};
+						self.capture(&mut file, &mut result, &mut effector, &state, &event.port_name, Direction::Inbound, info.bad_fcs, &header, &packet, snap_length, epoch);
+					}
+				},
+				"send_ethernet" => {
+					let packet = event.take_payload::<Packet>();
+					self.capture(&mut file, &mut result, &mut effector, &state, &event.port_name, Direction::Outbound, false, &[], &packet, snap_length, epoch);
+				},
+				"recv_ethernet" => {
+					let packet = event.take_payload::<Packet>();
+					self.capture(&mut file, &mut result, &mut effector, &state, &event.port_name, Direction::Inbound, false, &[], &packet, snap_length, epoch);
+				},
+				"send_ip" => {
+					let packet = event.take_payload::<Packet>();
+					self.capture(&mut file, &mut result, &mut effector, &state, &event.port_name, Direction::Outbound, false, &[], &packet, snap_length, epoch);
+				},
+				"recv_ip" => {
+					let packet = event.take_payload::<Packet>();
+					self.capture(&mut file, &mut result, &mut effector, &state, &event.port_name, Direction::Inbound, false, &[], &packet, snap_length, epoch);
+				}
+			);
+		});
+	}
+
+	fn write_headers(&self, file: &mut fs::File, snap_length: u32, radiotap: bool) -> io::Result<()>
+	{
+		try!(file.write_all(&section_header_block()));
+		for interface in self.interfaces.iter() {
+			let link_type = match interface.kind {
+				CaptureKind::Ieee80211 if radiotap => LINKTYPE_IEEE802_11_RADIOTAP,
+				CaptureKind::Ieee80211 => LINKTYPE_IEEE802_11,
+				CaptureKind::Ethernet => LINKTYPE_ETHERNET,
+				CaptureKind::RawIP => LINKTYPE_RAW,
+			};
+			let fcs_len = if interface.kind == CaptureKind::RawIP {0} else {4};
+			try!(file.write_all(&interface_description_block(link_type, snap_length, &interface.name, fcs_len)));
+		}
+		Ok(())
+	}
+
+	fn capture(&self, file: &mut io::Result<fs::File>, result: &mut io::Result<()>, effector: &mut Effector, state: &SimState, port_name: &str, direction: Direction, bad_fcs: bool, header: &[u8], packet: &Packet, snap_length: u32, epoch: u64)
+	{
+		if let Ok(ref mut f) = *file {
+			if result.is_ok() {
+				let frame = state.get_int(self.data.id, "frame") + 1;
+				let count = state.get_int(self.data.id, "count");
+				if frame <= count {
+					let interface = self.interfaces.iter().position(|i| i.port_name == port_name).expect("event should have come from an interface port");
+					let timestamp = capture_timestamp(epoch, state.time);
+					let block = enhanced_packet_block(interface as u32, timestamp, direction, bad_fcs, header, packet, snap_length);
+					*result = f.write_all(&block);
+					effector.set_int("frame", frame);
+				}
+				if let Err(ref e) = *result {
+					log_error!(effector, "failed to write a frame: {:?}", *e);
+				}
+			}
+		}
+	}
+}
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const IF_FCSLEN: u16 = 13;
+const EPB_FLAGS: u16 = 2;
+
+// Like classic pcaps we use little endian. See https://github.com/pcapng/pcapng for the
+// format. Every block starts with its type and total length and ends with the total length.
+fn block(kind: u32, body: Vec<u8>) -> Vec<u8>
+{
+	let len = (12 + body.len()) as u32;
+	let mut block = Vec::with_capacity(len as usize);
+	push_u32(&mut block, kind);
+	push_u32(&mut block, len);
+	block.extend(body);
+	push_u32(&mut block, len);
+	block
+}
+
+fn section_header_block() -> Vec<u8>
+{
+	let mut body = Vec::new();
+	push_u32(&mut body, 0x1A2B3C4D);		// byte order magic
+	push_u16(&mut body, 1);					// major version
+	push_u16(&mut body, 0);					// minor version
+	push_u32(&mut body, 0xFFFF_FFFF);		// section length is unspecified (-1)
+	push_u32(&mut body, 0xFFFF_FFFF);
+	push_option(&mut body, SHB_USERAPPL, b"snet");
+	push_option(&mut body, OPT_END, &[]);
+	block(SECTION_HEADER_BLOCK, body)
+}
+
+fn interface_description_block(link_type: u32, snap_length: u32, name: &str, fcs_len: u8) -> Vec<u8>
+{
+	let mut body = Vec::new();
+	push_u16(&mut body, link_type as u16);
+	push_u16(&mut body, 0);					// reserved
+	push_u32(&mut body, snap_length);
+	push_option(&mut body, IF_NAME, name.as_bytes());
+	push_option(&mut body, IF_TSRESOL, &[9]);	// nanoseconds
+	push_option(&mut body, IF_FCSLEN, &[fcs_len]);
+	push_option(&mut body, OPT_END, &[]);
+	block(INTERFACE_DESCRIPTION_BLOCK, body)
+}
+
+fn enhanced_packet_block(interface: u32, timestamp: u64, direction: Direction, bad_fcs: bool, header: &[u8], packet: &Packet, snap_length: u32) -> Vec<u8>
+{
+	let total = header.len() + packet.len();
+	let len = if total < snap_length as usize {total} else {snap_length as usize};
+
+	let mut body = Vec::with_capacity(len + 64);
+	push_u32(&mut body, interface);
+	push_u32(&mut body, (timestamp >> 32) as u32);
+	push_u32(&mut body, (timestamp & 0xFFFF_FFFF) as u32);
+	push_u32(&mut body, len as u32);		// captured length
+	push_u32(&mut body, total as u32);		// original length
+	for i in 0..len {
+		body.push(if i < header.len() {header[i]} else {packet.get(i - header.len())});
+	}
+	pad(&mut body);
+
+	let mut flags: u32 = match direction {
+		Direction::Inbound => 0b01,
+		Direction::Outbound => 0b10,
+	};
+	if bad_fcs {
+		flags |= 1 << 24;		// CRC error
+	}
+	let comment = format!("{} {}", packet.name(), packet.id());
+	push_option(&mut body, EPB_FLAGS, &[flags as u8, (flags >> 8) as u8, (flags >> 16) as u8, (flags >> 24) as u8]);
+	push_option(&mut body, OPT_COMMENT, comment.as_bytes());
+	push_option(&mut body, OPT_END, &[]);
+	block(ENHANCED_PACKET_BLOCK, body)
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8])
+{
+	push_u16(body, code);
+	push_u16(body, value.len() as u16);
+	body.extend_from_slice(value);
+	pad(body);
+}
+
+fn pad(body: &mut Vec<u8>)
+{
+	while body.len() % 4 != 0 {
+		body.push(0);
+	}
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16)
+{
+	bytes.push((value & 0xFF) as u8);
+	bytes.push((value >> 8) as u8);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32)
+{
+	push_u16(bytes, (value & 0xFFFF) as u16);
+	push_u16(bytes, (value >> 16) as u16);
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn pcapng_blocks()
+	{
+		let shb = section_header_block();
+		assert_eq!(shb.len() % 4, 0);
+		assert_eq!(&shb[0..4], &[0x0A, 0x0D, 0x0D, 0x0A]);
+		assert_eq!(&shb[8..12], &[0x4D, 0x3C, 0x2B, 0x1A]);
+		assert_eq!(&shb[shb.len() - 4..], &shb[4..8]);
+
+		let idb = interface_description_block(LINKTYPE_ETHERNET, 65535, "eth0", 4);
+		assert_eq!(idb.len(), 12 + 8 + 8 + 8 + 8 + 4);
+		assert_eq!(&idb[16..24], &[2, 0, 4, 0, b'e', b't', b'h', b'0']);
+
+		let mut packet = Packet::new("ping", "#>3.1");
+		packet.push_back_bytes(&[1, 2, 3, 4, 5]);
+		let epb = enhanced_packet_block(1, 1_500_000_000_123_456_789, Direction::Inbound, false, &[9, 9], &packet, 4);
+		assert_eq!(&epb[8..12], &[1, 0, 0, 0]);
+		let timestamp = (epb[12] as u64 | (epb[13] as u64) << 8 | (epb[14] as u64) << 16 | (epb[15] as u64) << 24) << 32 |
+			(epb[16] as u64 | (epb[17] as u64) << 8 | (epb[18] as u64) << 16 | (epb[19] as u64) << 24);
+		assert_eq!(timestamp, 1_500_000_000_123_456_789);
+		assert_eq!(&epb[20..28], &[4, 0, 0, 0, 7, 0, 0, 0]);
+		assert_eq!(&epb[28..32], &[9, 9, 1, 2]);
+		assert_eq!(&epb[32..40], &[2, 0, 4, 0, 0b01, 0, 0, 0]);
+		assert_eq!(&epb[40..44], &[1, 0, 10, 0]);
+		assert_eq!(&epb[44..54], b"ping #>3.1");
+		assert_eq!(epb.len(), 12 + 20 + 4 + 8 + 4 + 12 + 4);
+
+		let epb = enhanced_packet_block(1, 0, Direction::Inbound, true, &[], &packet, 8);
+		assert_eq!(&epb[36..44], &[2, 0, 4, 0, 0b01, 0, 0, 1]);
+	}
+}
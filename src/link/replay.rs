@@ -0,0 +1,227 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use link::ether_type::*;
+use link::link::*;
+use link::pcap_reader::*;
+use link::pcapng::*;
+use physical::*;
+use score::*;
+use std::thread;
+
+/// Where a [`ReplayComponent`] injects packets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayLayer
+{
+	/// Frames are sent to a [`Mac80211Component`]'s lower_in.
+	Ieee80211,
+
+	/// Frames are sent to an [`EthernetMacComponent`]'s lower_in.
+	Ethernet,
+
+	/// Link headers are removed and the packets are sent to an IPv4 or IPv6 component's
+	/// lower_ins (depending on their ether type).
+	Network,
+}
+
+/// Component that reads a pcap or pcapng file and injects its frames into a device's
+/// stack using the original schedule. Replayed frames are sent as "send_up" events
+/// (so they look like they were received) using the out port for the layer. Component
+/// settings are:
+/// - **direction** is "all", "inbound", or "outbound". Frames without a direction (e.g. from classic pcaps) are always replayed. Defaults to "all".
+/// - **path** is the pcap or pcapng file to replay. If this is empty nothing is replayed. Defaults to empty.
+/// - **start** is the time, in seconds, at which the first frame is replayed. Defaults to 0.0.
+///
+/// The component also sets "replayed" and "skipped" (frames that couldn't be converted
+/// to the layer's format, including frames with a bad FCS).
+pub struct ReplayComponent
+{
+	data: ThreadData,
+
+	/// Defaults to Network.
+	pub layer: ReplayLayer,
+
+	/// Used for Ieee80211. The [`RadioInfo`] is for a legacy 54 Mbps 5 GHz frame.
+	pub ieee80211_out: OutPort<(RadioInfo, Packet)>,
+
+	/// Used for Ethernet.
+	pub ethernet_out: OutPort<Packet>,
+
+	/// Used for Network IPv4 packets.
+	pub ipv4_out: OutPort<(LinkInfo, Packet)>,
+
+	/// Used for Network IPv6 packets.
+	pub ipv6_out: OutPort<(LinkInfo, Packet)>,
+}
+
+// A record converted into the payload for one of the out ports.
+enum Replayed
+{
+	Ieee80211(RadioInfo, Packet),
+	Ethernet(Packet),
+	IPv4(LinkInfo, Packet),
+	IPv6(LinkInfo, Packet),
+}
+
+impl ReplayComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (_, data) = sim.add_active_component("replay", parent_id);
+		ReplayComponent {
+			data: data,
+			layer: ReplayLayer::Network,
+			ieee80211_out: OutPort::new(),
+			ethernet_out: OutPort::new(),
+			ipv4_out: OutPort::new(),
+			ipv6_out: OutPort::new(),
+		}
+	}
+
+	pub fn start(self)
+	{
+		let mut records = Vec::new();
+		let mut next = 0;
+		let mut replayed = 0;
+		let mut skipped = 0;
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					effector.set_int("replayed", 0);
+					effector.set_int("skipped", 0);
+					let path = string_setting(self.data.id, &state, &mut effector, "path", "");
+					let start = float_setting(self.data.id, &state, &mut effector, "start", 0.0);
+					let direction = string_setting(self.data.id, &state, &mut effector, "direction", "all");
+					let direction = match direction.as_ref() {
+						"all" => None,
+						"inbound" => Some(Direction::Inbound),
+						"outbound" => Some(Direction::Outbound),
+						_ => panic!("direction should be all, inbound, or outbound not {}", direction)
+					};
+
+					if !path.is_empty() {
+						match open_capture(&path) {
+							Ok(all) => {
+								records = all.into_iter().filter(|r| direction.is_none() || r.direction.is_none() || r.direction == direction).collect();
+								if !records.is_empty() {
+									effector.schedule_after_secs(Event::new("replay"), self.data.id, start);
+								}
+							},
+							Err(mesg) => log_error!(effector, "couldn't read {}: {}", path, mesg)
+						}
+					}
+				},
+				"replay" => {
+					// Replay every record due now (pcaps are usually, but not always, in order).
+					let now = records[next].timestamp;
+					while next < records.len() && records[next].timestamp <= now {
+						if self.replay(&records[next], &mut effector) {
+							replayed += 1;
+						} else {
+							skipped += 1;
+						}
+						next += 1;
+					}
+					effector.set_int("replayed", replayed);
+					effector.set_int("skipped", skipped);
+
+					if next < records.len() {
+						let delay = (records[next].timestamp - now) as f64/1_000_000_000.0;
+						effector.schedule_after_secs(Event::new("replay"), self.data.id, delay);
+					}
+				}
+			);
+		});
+	}
+
+	fn replay(&self, record: &CaptureRecord, effector: &mut Effector) -> bool
+	{
+		match convert(self.layer, record) {
+			Ok(Replayed::Ieee80211(info, packet)) => self.ieee80211_out.send_payload(effector, "send_up", (info, packet)),
+			Ok(Replayed::Ethernet(packet)) => self.ethernet_out.send_payload(effector, "send_up", packet),
+			Ok(Replayed::IPv4(linfo, packet)) => self.ipv4_out.send_payload(effector, "send_up", (linfo, packet)),
+			Ok(Replayed::IPv6(linfo, packet)) => self.ipv6_out.send_payload(effector, "send_up", (linfo, packet)),
+			Err(mesg) => {
+				log_warning!(effector, "skipping {}: {}", record.packet.id(), mesg);
+				return false
+			}
+		}
+		true
+	}
+}
+
+fn convert(layer: ReplayLayer, record: &CaptureRecord) -> Result<Replayed, String>
+{
+	match layer {
+		ReplayLayer::Ieee80211 => {
+			let packet = try!(record.link_frame());
+			let info = RadioInfo::with_tx(Phy80211::legacy(PhyStandard::Dot11a, 7), 5180);
+			Ok(Replayed::Ieee80211(info, packet))
+		},
+		ReplayLayer::Ethernet => record.link_frame().map(Replayed::Ethernet),
+		ReplayLayer::Network => {
+			let (linfo, packet) = try!(record.network_packet());
+			match linfo.ether_type {
+				EtherType::IPv4 => Ok(Replayed::IPv4(linfo, packet)),
+				EtherType::IPv6 => Ok(Replayed::IPv6(linfo, packet)),
+				ether_type => Err(format!("{:?} packets can't be replayed", ether_type))
+			}
+		}
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+    #[cfg(test)]
+	use link::frame80211::*;
+    #[cfg(test)]
+	use link::llc::*;
+    #[cfg(test)]
+	use link::pcap::*;
+
+    #[test]
+    fn conversion()
+	{
+		let mut packet = Packet::new("ping", "#>7.1");
+		packet.push_back_bytes(&[0x60, 0, 0, 0]);
+		let record = CaptureRecord {timestamp: 0, link_type: LINKTYPE_RAW, direction: None, has_fcs: false, bad_fcs: false, packet};
+		match convert(ReplayLayer::Network, &record) {
+			Ok(Replayed::IPv6(_, packet)) => assert_eq!(packet.len(), 4),
+			_ => panic!("expected an IPv6 packet")
+		}
+
+		// Frames with a bad FCS are skipped instead of getting a second FCS.
+		let mut packet = Packet::new("data", "#>7.2");
+		LlcHeader::with_ipv4().push(&mut packet);
+		Mac80211DataFrame::new(&[2, 0, 0, 0, 0, 1], &[2, 0, 0, 0, 0, 2], 1).push(&mut packet);
+		let mut record = CaptureRecord {timestamp: 0, link_type: LINKTYPE_IEEE802_11, direction: None, has_fcs: true, bad_fcs: false, packet};
+		match convert(ReplayLayer::Ieee80211, &record) {
+			Ok(Replayed::Ieee80211(_, packet)) => assert_eq!(packet.len(), record.packet.len()),
+			_ => panic!("expected an 802.11 frame")
+		}
+		match convert(ReplayLayer::Network, &record) {
+			Ok(Replayed::IPv4(linfo, _)) => assert_eq!(linfo.src_addr, [2, 0, 0, 0, 0, 1]),
+			_ => panic!("expected an IPv4 packet")
+		}
+
+		record.bad_fcs = true;
+		assert!(convert(ReplayLayer::Ieee80211, &record).is_err());
+		assert!(convert(ReplayLayer::Network, &record).is_err());
+	}
+}
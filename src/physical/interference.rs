@@ -0,0 +1,111 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use physical::phy80211::*;
+
+/// A signal as seen by one radio: it arrives at start, ends at end and has power dBm.
+#[derive(Clone, Copy, Debug)]
+pub struct Signal
+{
+	pub start: f64,
+	pub end: f64,
+	pub power: f64,
+}
+
+pub fn dbm_to_mw(dbm: f64) -> f64
+{
+	10.0f64.powf(dbm/10.0)
+}
+
+pub fn mw_to_dbm(mw: f64) -> f64
+{
+	10.0*mw.log10()
+}
+
+/// Minimum SINR in dB needed to decode a frame sent using phy. These are the margins
+/// above the noise floor implied by the 802.11 minimum sensitivities (e.g. Table 17-18 and
+/// Table 19-23). Wider channels collect more noise so they need 3 dB more per doubling.
+pub fn min_sinr(phy: &Phy80211) -> f64
+{
+	const LEGACY: [f64; 8] = [4.0, 5.0, 7.0, 9.0, 12.0, 16.0, 20.0, 21.0];
+	const HT: [f64; 10] = [4.0, 7.0, 9.0, 12.0, 16.0, 20.0, 21.0, 23.0, 28.0, 30.0];
+	let sinr = match phy.standard {
+		PhyStandard::Dot11a | PhyStandard::Dot11g => LEGACY[phy.mcs as usize],
+		PhyStandard::Dot11n | PhyStandard::Dot11ac => HT[phy.mcs as usize],
+	};
+	sinr + 10.0*(phy.bandwidth as f64/20.0).log10()
+}
+
+/// Returns the lowest SINR (in dB) that signal has within [from, to) along with the time
+/// at which that SINR starts. Interferers are summed (in mW) with the noise floor, so
+/// interference only counts while it overlaps signal.
+pub fn lowest_sinr(signal: &Signal, interferers: &[Signal], noise_floor: f64, from: f64, to: f64) -> (f64, f64)
+{
+	// The interference only changes when an interferer starts or stops so those are the
+	// only times we need to check.
+	let mut times = vec![from];
+	for i in interferers.iter() {
+		if i.start > from && i.start < to {
+			times.push(i.start);
+		}
+		if i.end > from && i.end < to {
+			times.push(i.end);
+		}
+	}
+
+	let signal_mw = dbm_to_mw(signal.power);
+	let noise_mw = dbm_to_mw(noise_floor);
+	times.iter().fold((f64::INFINITY, from), |(lowest, when), &t| {
+		let interference: f64 = interferers.iter().filter(|i| i.start <= t && i.end > t).map(|i| dbm_to_mw(i.power)).sum();
+		let sinr = mw_to_dbm(signal_mw/(noise_mw + interference));
+		if sinr < lowest {(sinr, t)} else {(lowest, when)}
+	})
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn sinr()
+	{
+		let signal = Signal {start: 0.0, end: 10.0, power: -60.0};
+		let noise = -94.0;
+
+		let (sinr, when) = lowest_sinr(&signal, &[], noise, 0.0, 10.0);
+		assert!((sinr - 34.0).abs() < 0.001);
+		assert_eq!(when, 0.0);
+
+		// Interferers outside the window don't matter.
+		let before = Signal {start: -5.0, end: 0.0, power: -50.0};
+		let after = Signal {start: 10.0, end: 12.0, power: -50.0};
+		let (sinr, _) = lowest_sinr(&signal, &[before, after], noise, 0.0, 10.0);
+		assert!((sinr - 34.0).abs() < 0.001);
+
+		// Overlapping interferers add up.
+		let weak = Signal {start: 2.0, end: 6.0, power: -70.0};
+		let strong = Signal {start: 5.0, end: 8.0, power: -70.0};
+		let (sinr, when) = lowest_sinr(&signal, &[weak, strong], noise, 0.0, 10.0);
+		assert!((sinr - 6.99).abs() < 0.01, "sinr was {}", sinr);
+		assert_eq!(when, 5.0);
+
+		let (sinr, when) = lowest_sinr(&signal, &[weak, strong], noise, 0.0, 4.0);
+		assert!((sinr - 9.98).abs() < 0.01, "sinr was {}", sinr);
+		assert_eq!(when, 2.0);
+
+		assert!(min_sinr(&Phy80211::legacy(PhyStandard::Dot11a, 0)) < min_sinr(&Phy80211::legacy(PhyStandard::Dot11a, 7)));
+	}
+}
@@ -0,0 +1,36 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use physical::phy80211::*;
+use score::*;
+
+/// Implemented by components that carry frames between MACs.
+pub trait Medium
+{
+	/// Wires up the ports a MAC uses to talk to the medium.
+	fn connect(&mut self, above_out: &mut OutPort<(ComponentID, Packet)>, above_in: &InPort<Packet>);
+
+	/// Wires up the ports an 802.11 MAC uses to talk to the medium. This is used instead of
+	/// connect so that the medium can pass along the [`RadioInfo`] for each frame it delivers.
+	/// device_id is the device containing the radio: its "display-location-x" and
+	/// "display-location-y" floats are used as the radio's position (in meters). cca_in is used to tell the MAC when the medium is in use: "medium_busy"
+	/// events are sent when other MACs transmit and "transmitting" events when the MAC itself
+	/// does. The payload is the time at which the medium becomes idle.
+	fn connect_radio(&mut self, _device_id: ComponentID, _above_out: &mut OutPort<(ComponentID, Packet)>, _above_in: &InPort<(RadioInfo, Packet)>, _cca_in: &InPort<f64>)
+	{
+		panic!("wired mediums don't support radios");
+	}
+}
@@ -19,6 +19,10 @@ use common::*;
 // use internet::upper_internet::*;
 // use link::link::*;
 // use link::link_helpers::*;
+use physical::interference::*;
+use physical::medium::*;
+use physical::phy80211::*;
+use physical::propagation::*;
 use score::*;
 // use std::str;
 use std::thread;
@@ -28,53 +32,240 @@ use std::thread;
 // Unless otherwise indicated all references are to the 2016 version of "Part 11: Wireless LAN Medium Access Control (MAC) and Physical Layer (PHY) Specifications"
 // (i.e. the 802.11 spec).
 
-/// Wireless medium for 802.11 wireless radios.
+/// Wireless medium for 802.11 wireless radios. Frames take time to transmit (see
+/// [`Phy80211::airtime`]) and are delivered when the
+/// transmission ends to every other radio that receives them at or above the receiver
+/// sensitivity. Received power depends upon the distance between the radios (see
+/// [`Medium::connect_radio`]) and the propagation model. The PHY used for each frame comes
+/// from the sending MAC's settings (see [`phy_settings`]) except that control frames
+/// use the control rate. Radios are told when the medium is
+/// busy (i.e. when they can hear a transmission) so that they can do carrier sensing.
+///
+/// Every transmission, including ones too weak to be received, interferes with the
+/// transmissions it overlaps. Receivers compute the signal to interference plus noise
+/// ratio (SINR) over the airtime of each frame. Frames whose preamble is below the
+/// [`min_sinr`] for the lowest rate aren't detected and are dropped. Frames whose payload
+/// dips below the [`min_sinr`] for their PHY are delivered with a corrupted byte so that
+/// the MAC's FCS check fails. The stronger of two overlapping frames can therefore still
+/// be received (the capture effect). Radios are half duplex so frames that arrive while a
+/// radio is transmitting are dropped.
+/// Component settings are:
+/// - **tx_power** is the transmit power in dBm. Defaults to 20.0.
+/// - **sensitivity** is the minimum received power in dBm needed to receive a frame. Defaults to -82.0.
+/// - **noise_floor** is the thermal noise plus receiver noise figure in dBm. Defaults to -94.0
+/// (20 MHz channel with a 7 dB noise figure).
+/// - **frequency** is the carrier frequency in Hz. Defaults to 5_180_000_000 (channel 36).
+/// - **propagation** is the path loss model: "free_space", "log_distance", "two_ray_ground",
+/// "shadowing" (log distance with log-normal shadowing) or "fading" (shadowing plus Rayleigh
+/// fading). Defaults to "log_distance".
+/// - **path_loss_exponent** is used by the log distance models. Defaults to 3.0.
+/// - **antenna_height** is used by two_ray_ground, in meters. Defaults to 1.5.
+/// - **shadowing_std_dev** is the deviation of the shadowing in dB. Defaults to 4.0.
+/// - **seed** is used for the shadowing and fading random number generator. Defaults to 1.
 pub struct Medium80211Component
 {
 	data: ThreadData,
 
 	pub upper_ins: Vec<InPort<(ComponentID, Packet)>>,
-	pub upper_outs: Vec<OutPort<Packet>>,
+	pub upper_outs: Vec<OutPort<(RadioInfo, Packet)>>,
+
+	/// Sends "medium_busy" and "transmitting" events.
+	pub cca_outs: Vec<OutPort<f64>>,
+
+	/// The devices the radios are in, used to find their positions.
+	pub device_ids: Vec<ComponentID>,
 }
 
-impl Medium80211Component
+impl Medium for Medium80211Component
 {
-	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	fn connect(&mut self, _above_out: &mut OutPort<(ComponentID, Packet)>, _above_in: &InPort<Packet>)
 	{
-		let (_, data) = sim.add_active_component("Medium80211", parent_id);
-		Medium80211Component {data: data, upper_ins: Vec::new(), upper_outs: Vec::new()}
+		panic!("802.11 radios should use connect_radio");
 	}
-	
-	pub fn connect(&mut self, above_out: &mut OutPort<(ComponentID, Packet)>, above_in: &InPort<Packet>)
+
+	fn connect_radio(&mut self, device_id: ComponentID, above_out: &mut OutPort<(ComponentID, Packet)>, above_in: &InPort<(RadioInfo, Packet)>, cca_in: &InPort<f64>)
 	{
 		let upper_in = InPort::with_port_name(self.data.id, &format!("upper_in_{}", self.upper_ins.len()));
 		let mut upper_out = OutPort::new();
+		let mut cca_out = OutPort::new();
 
 		above_out.connect_to(&upper_in);
 		upper_out.connect_to(&above_in);
+		cca_out.connect_to(&cca_in);
 
 		self.upper_ins.push(upper_in);
 		self.upper_outs.push(upper_out);
+		self.cca_outs.push(cca_out);
+		self.device_ids.push(device_id);
+	}
+}
+
+struct Transmission
+{
+	id: u64,
+	sender: usize,
+	phy: Phy80211,
+	start: f64,
+	end: f64,
+	powers: Vec<f64>,	// received power in dBm at each radio
+	delivered: bool,
+}
+
+impl Medium80211Component
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (_, data) = sim.add_active_component("Medium80211", parent_id);
+		Medium80211Component {data: data, upper_ins: Vec::new(), upper_outs: Vec::new(), cca_outs: Vec::new(), device_ids: Vec::new()}
 	}
 	
 	pub fn start(self)
 	{		
+		let mut tx_power = 20.0;
+		let mut sensitivity = -82.0;
+		let mut noise_floor = -94.0;
+		let mut frequency = 5_180_000_000.0;
+		let mut model: Option<Box<PropagationModel>> = None;
+		let mut transmissions: Vec<Transmission> = Vec::new();
+		let mut next_id = 0;
+		let mut collisions = 0;
+		let mut below_sensitivity = 0;
+		let mut corrupted = 0;
+
 		thread::spawn(move || {
 			process_events!(self.data, event, state, effector,
 				"init 0" => {
+					tx_power = float_setting(self.data.id, &state, &mut effector, "tx_power", tx_power);
+					sensitivity = float_setting(self.data.id, &state, &mut effector, "sensitivity", sensitivity);
+					noise_floor = float_setting(self.data.id, &state, &mut effector, "noise_floor", noise_floor);
+					frequency = float_setting(self.data.id, &state, &mut effector, "frequency", frequency);
+					model = Some(new_model(self.data.id, &state, &mut effector, frequency));
+					effector.set_int("collisions", 0);
+					effector.set_int("below_sensitivity", 0);
+					effector.set_int("corrupted", 0);
 				},
 				"send_down" => {
 					assert!(!event.port_name.is_empty());
 
-					let (_, packet) = event.take_payload::<(ComponentID, Packet)>();	// TODO: from_id should be used to compute bit errors and whether the frame is below the noise floor
-					for i in 0..self.upper_outs.len() {
-						if self.upper_ins[i].target_port != event.port_name {
-							let port = &self.upper_outs[i];
-							port.send_payload(&mut effector, "send_up", packet.clone());
+					let (mac_id, packet) = event.take_payload::<(ComponentID, Packet)>();
+					let sender = self.upper_ins.iter().position(|p| p.target_port == event.port_name).expect("event should have come from an upper_in");
+					let phy = configured_phy(mac_id, &state);
+					let phy = if is_control_frame(&packet) {phy.control_rate()} else {phy};
+					let airtime = phy.airtime(packet.len());
+					let end = state.time + airtime;
+
+					let origin = position(&state, self.device_ids[sender]);
+					let model = model.as_mut().unwrap();
+					let powers: Vec<f64> = self.device_ids.iter().enumerate().map(|(i, &device_id)| {
+						if i == sender {
+							tx_power
+						} else {
+							let (x, y) = position(&state, device_id);
+							let distance = ((x - origin.0).powi(2) + (y - origin.1).powi(2)).sqrt();
+							model.rx_power(tx_power, distance)
+						}
+					}).collect();
+
+					for i in 0..self.cca_outs.len() {
+						if i == sender {
+							self.cca_outs[i].send_payload(&mut effector, "transmitting", end);
+						} else if powers[i] >= sensitivity {
+							self.cca_outs[i].send_payload(&mut effector, "medium_busy", end);
+						}
+					}
+
+					next_id += 1;
+					transmissions.push(Transmission {id: next_id, sender, phy, start: state.time, end, powers, delivered: false});
+
+					let event = Event::with_payload("deliver", (next_id, packet));
+					effector.schedule_after_secs(event, self.data.id, airtime);
+				},
+				"deliver" => {
+					let (id, packet) = event.take_payload::<(u64, Packet)>();
+					let index = transmissions.iter().position(|t| t.id == id).expect("transmission should be pending");
+					{
+						let tx = &transmissions[index];
+						for i in 0..self.upper_outs.len() {
+							if i == tx.sender {
+								continue;
+							}
+							if tx.powers[i] < sensitivity {
+								log_debug!(effector, "{} was too weak for radio {} ({:.1} dBm)", packet.name(), i, tx.powers[i]);
+								below_sensitivity += 1;
+								continue;
+							}
+							let overlapping: Vec<&Transmission> = transmissions.iter().filter(|t| t.id != id && t.start < tx.end && t.end > tx.start).collect();
+							if overlapping.iter().any(|t| t.sender == i) {
+								log_debug!(effector, "{} arrived while radio {} was transmitting", packet.name(), i);
+								collisions += 1;
+								continue;
+							}
+
+							let signal = Signal {start: tx.start, end: tx.end, power: tx.powers[i]};
+							let interferers: Vec<Signal> = overlapping.iter().map(|t| Signal {start: t.start, end: t.end, power: t.powers[i]}).collect();
+							let payload_start = tx.start + tx.phy.preamble_time();
+							let (sinr, _) = lowest_sinr(&signal, &interferers, noise_floor, tx.start, payload_start);
+							if sinr < min_sinr(&Phy80211::legacy(PhyStandard::Dot11a, 0)) {		// L-SIG uses BPSK 1/2
+								log_debug!(effector, "{} preamble collided at radio {} (SINR {:.1} dB)", packet.name(), i, sinr);
+								collisions += 1;
+								continue;
+							}
+
+							let (sinr, when) = lowest_sinr(&signal, &interferers, noise_floor, payload_start, tx.end);
+							let info = RadioInfo {phy: tx.phy, frequency: (frequency/1_000_000.0).round() as u32, signal: Some(tx.powers[i]), noise: Some(noise_floor), bad_fcs: false};
+							if sinr < min_sinr(&tx.phy) && packet.len() > 0 {
+								// Corrupt the byte that was on the air when the SINR dropped.
+								let offset = ((when - payload_start)*tx.phy.data_rate()/8.0) as usize;
+								let mut packet = packet.clone();
+								packet.corrupt(offset.min(packet.len() - 1), 0x01);
+								log_debug!(effector, "{} was corrupted at radio {} (SINR {:.1} dB)", packet.name(), i, sinr);
+								corrupted += 1;
+								self.upper_outs[i].send_payload(&mut effector, "send_up", (info, packet));
+							} else {
+								self.upper_outs[i].send_payload(&mut effector, "send_up", (info, packet.clone()));
+							}
 						}
 					}
+					transmissions[index].delivered = true;
+					effector.set_int("collisions", collisions);
+					effector.set_int("below_sensitivity", below_sensitivity);
+					effector.set_int("corrupted", corrupted);
+
+					// Delivered transmissions are only needed while they can still overlap pending ones.
+					let oldest = transmissions.iter().filter(|t| !t.delivered).map(|t| t.start).fold(state.time, f64::min);
+					transmissions.retain(|t| !t.delivered || t.end > oldest);
 				}
 			);
 		});
 	}
 }
+
+fn new_model(id: ComponentID, state: &SimState, effector: &mut Effector, frequency: f64) -> Box<PropagationModel>
+{
+	let exponent = float_setting(id, state, effector, "path_loss_exponent", 3.0);
+	let height = float_setting(id, state, effector, "antenna_height", 1.5);
+	let std_dev = float_setting(id, state, effector, "shadowing_std_dev", 4.0);
+	let seed = int_setting(id, state, effector, "seed", 1);
+	let name = string_setting(id, state, effector, "propagation", "log_distance");
+	match name.as_ref() {
+		"free_space" => Box::new(FreeSpace {frequency}),
+		"log_distance" => Box::new(LogDistance::new(frequency, exponent)),
+		"two_ray_ground" => Box::new(TwoRayGround {frequency, tx_height: height, rx_height: height}),
+		"shadowing" => Box::new(Shadowing::new(Box::new(LogDistance::new(frequency, exponent)), std_dev, false, Rng::with_component(seed, id))),
+		"fading" => Box::new(Shadowing::new(Box::new(LogDistance::new(frequency, exponent)), std_dev, true, Rng::with_component(seed, id))),
+		_ => panic!("propagation should be free_space, log_distance, two_ray_ground, shadowing, or fading, not {}", name)
+	}
+}
+
+fn position(state: &SimState, device_id: ComponentID) -> (f64, f64)
+{
+	let x = if state.contains(device_id, "display-location-x") {state.get_float(device_id, "display-location-x")} else {0.0};
+	let y = if state.contains(device_id, "display-location-y") {state.get_float(device_id, "display-location-y")} else {0.0};
+	(x, y)
+}
+
+// Control frames (e.g. ACKs) are sent at the control rate, see 10.6.6.5.
+fn is_control_frame(packet: &Packet) -> bool
+{
+	packet.len() > 0 && (packet.get(0) >> 2) & 0b11 == 0b01		// type field of the frame control, see 9.2.4.1.3
+}
@@ -0,0 +1,366 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use score::*;
+use std::f64::consts::PI;
+use std::f64;
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+
+/// Computes where a device is. Positions are in meters (the same units as the
+/// "display-location-x" and "display-location-y" state).
+pub trait MobilityModel: Send
+{
+	/// Returns the position at time now. Calls are made with non-decreasing times.
+	fn position(&mut self, now: f64) -> (f64, f64);
+}
+
+/// Moves in a straight line forever.
+pub struct ConstantVelocity
+{
+	pub start: (f64, f64),
+
+	/// Meters per second along x and y.
+	pub velocity: (f64, f64),
+}
+
+impl MobilityModel for ConstantVelocity
+{
+	fn position(&mut self, now: f64) -> (f64, f64)
+	{
+		(self.start.0 + self.velocity.0*now, self.start.1 + self.velocity.1*now)
+	}
+}
+
+/// Rectangle devices are confined to: (min x, min y, max x, max y).
+pub type Bounds = (f64, f64, f64, f64);
+
+// Straight line movement from one point to another.
+#[derive(Clone, Copy, Debug)]
+struct Leg
+{
+	from: (f64, f64),
+	to: (f64, f64),
+	depart: f64,
+	arrive: f64,
+}
+
+impl Leg
+{
+	fn stationary(at: (f64, f64), until: f64) -> Leg
+	{
+		Leg {from: at, to: at, depart: 0.0, arrive: until}
+	}
+
+	fn position(&self, now: f64) -> (f64, f64)
+	{
+		if now >= self.arrive {
+			self.to
+		} else if now <= self.depart {
+			self.from
+		} else {
+			let t = (now - self.depart)/(self.arrive - self.depart);
+			(self.from.0 + t*(self.to.0 - self.from.0), self.from.1 + t*(self.to.1 - self.from.1))
+		}
+	}
+}
+
+/// Picks a random point within bounds, moves to it at a random speed, pauses, and then
+/// does it all again.
+pub struct RandomWaypoint
+{
+	pub bounds: Bounds,
+	pub min_speed: f64,
+	pub max_speed: f64,
+	pub pause: f64,
+	rng: Rng,
+	leg: Leg,
+}
+
+impl RandomWaypoint
+{
+	pub fn new(start: (f64, f64), bounds: Bounds, min_speed: f64, max_speed: f64, pause: f64, rng: Rng) -> Self
+	{
+		assert!(min_speed > 0.0 && min_speed <= max_speed, "speeds should be positive");
+		RandomWaypoint {bounds, min_speed, max_speed, pause, rng, leg: Leg::stationary(start, 0.0)}
+	}
+}
+
+impl MobilityModel for RandomWaypoint
+{
+	fn position(&mut self, now: f64) -> (f64, f64)
+	{
+		while now > self.leg.arrive + self.pause {
+			let from = self.leg.to;
+			let to = (uniform(&mut self.rng, self.bounds.0, self.bounds.2), uniform(&mut self.rng, self.bounds.1, self.bounds.3));
+			let speed = uniform(&mut self.rng, self.min_speed, self.max_speed);
+			let depart = self.leg.arrive + self.pause;
+			self.leg = Leg {from, to, depart, arrive: depart + distance(from, to)/speed};
+		}
+		self.leg.position(now)
+	}
+}
+
+/// Moves at a constant speed in a random direction, picking a new direction every
+/// interval seconds. Devices bounce off the edges of bounds.
+pub struct RandomWalk
+{
+	pub bounds: Bounds,
+	pub speed: f64,
+	pub interval: f64,
+	rng: Rng,
+	leg: Leg,
+	heading: f64,
+	remaining: f64,		// seconds left before we change heading
+}
+
+impl RandomWalk
+{
+	pub fn new(start: (f64, f64), bounds: Bounds, speed: f64, interval: f64, rng: Rng) -> Self
+	{
+		assert!(interval > 0.0, "interval should be positive");
+		RandomWalk {bounds, speed, interval, rng, leg: Leg::stationary(start, 0.0), heading: 0.0, remaining: 0.0}
+	}
+}
+
+impl MobilityModel for RandomWalk
+{
+	fn position(&mut self, now: f64) -> (f64, f64)
+	{
+		while now > self.leg.arrive {
+			if self.remaining <= 1.0e-9 {
+				self.heading = uniform(&mut self.rng, 0.0, 2.0*PI);
+				self.remaining = self.interval;
+			}
+			self.leg = walk(self.leg.to, self.leg.arrive, self.heading, self.speed, self.remaining, self.bounds);
+			self.remaining -= self.leg.arrive - self.leg.depart;
+
+			if self.remaining > 1.0e-9 {
+				// We hit an edge so bounce off it.
+				let (x, y) = self.leg.to;
+				let (mut dx, mut dy) = (self.heading.cos(), self.heading.sin());
+				if (x <= self.bounds.0 && dx < 0.0) || (x >= self.bounds.2 && dx > 0.0) {
+					dx = -dx;
+				}
+				if (y <= self.bounds.1 && dy < 0.0) || (y >= self.bounds.3 && dy > 0.0) {
+					dy = -dy;
+				}
+				self.heading = dy.atan2(dx);
+			}
+		}
+		self.leg.position(now)
+	}
+}
+
+// Moves from start along heading until interval elapses or bounds are hit.
+fn walk(start: (f64, f64), depart: f64, heading: f64, speed: f64, interval: f64, bounds: Bounds) -> Leg
+{
+	let (dx, dy) = (speed*heading.cos(), speed*heading.sin());
+	let limit = |p: f64, d: f64, min: f64, max: f64| {
+		if d > 0.0 {(max - p)/d} else if d < 0.0 {(min - p)/d} else {f64::INFINITY}
+	};
+	let secs = interval.min(limit(start.0, dx, bounds.0, bounds.2)).min(limit(start.1, dy, bounds.1, bounds.3)).max(1.0e-6);
+	let to = ((start.0 + dx*secs).max(bounds.0).min(bounds.2), (start.1 + dy*secs).max(bounds.1).min(bounds.3));
+	Leg {from: start, to, depart, arrive: depart + secs}
+}
+
+/// Follows a list of (time, x, y) points, moving in straight lines between them. Devices
+/// stay at the first point until its time and at the last point after its time.
+pub struct Trace
+{
+	pub points: Vec<(f64, f64, f64)>,
+	index: usize,
+}
+
+impl Trace
+{
+	pub fn new(points: Vec<(f64, f64, f64)>) -> Result<Trace, String>
+	{
+		if points.is_empty() {
+			return Err("trace should have at least one point".to_string())
+		}
+		if points.windows(2).any(|w| w[1].0 < w[0].0) {
+			return Err("trace times should be non-decreasing".to_string())
+		}
+		Ok(Trace {points, index: 0})
+	}
+
+	/// Parses lines of "time,x,y". Blank lines and lines starting with '#' are skipped.
+	/// If the first remaining line isn't a point it's treated as a header and skipped.
+	pub fn parse(text: &str) -> Result<Trace, String>
+	{
+		let mut points = Vec::new();
+		let mut first = true;
+		for (i, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let fields: Vec<f64> = line.split(',').filter_map(|f| f.trim().parse::<f64>().ok()).collect();
+			if fields.len() == 3 && line.split(',').count() == 3 {
+				points.push((fields[0], fields[1], fields[2]));
+			} else if !first {
+				return Err(format!("line {} should be time,x,y not '{}'", i + 1, line))
+			}	// else it's a header
+			first = false;
+		}
+		Trace::new(points)
+	}
+
+	pub fn open(path: &str) -> Result<Trace, String>
+	{
+		let mut text = String::new();
+		let mut file = try!(File::open(path).map_err(|e| format!("couldn't open {}: {}", path, e)));
+		try!(file.read_to_string(&mut text).map_err(|e| format!("couldn't read {}: {}", path, e)));
+		Trace::parse(&text)
+	}
+}
+
+impl MobilityModel for Trace
+{
+	fn position(&mut self, now: f64) -> (f64, f64)
+	{
+		while self.index + 1 < self.points.len() && self.points[self.index + 1].0 <= now {
+			self.index += 1;
+		}
+
+		let (t0, x0, y0) = self.points[self.index];
+		if self.index + 1 == self.points.len() || now <= t0 {
+			(x0, y0)
+		} else {
+			let (t1, x1, y1) = self.points[self.index + 1];
+			let leg = Leg {from: (x0, y0), to: (x1, y1), depart: t0, arrive: t1};
+			leg.position(now)
+		}
+	}
+}
+
+fn uniform(rng: &mut Rng, min: f64, max: f64) -> f64
+{
+	min + rng.next_f64()*(max - min)
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64
+{
+	((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+/// Moves a device around using a [`MobilityModel`]. This runs on the device's own thread
+/// (so the device has to be created with add_active_component) and writes the current
+/// position to the device's "display-location-x" and "display-location-y" state so that
+/// GUIs and [`Medium80211Component`] see it move, see [`Endpoint`]. There is one setting
+/// (on the device):
+/// - **update_interval** is the number of seconds between position updates. Defaults to 0.1.
+pub struct MobilityComponent
+{
+	data: ThreadData,
+	model: Box<MobilityModel>,
+}
+
+impl MobilityComponent
+{
+	/// Data is the device's thread data.
+	pub fn new(data: ThreadData, model: Box<MobilityModel>) -> Self
+	{
+		MobilityComponent {data, model}
+	}
+
+	pub fn start(self)
+	{
+		let mut model = self.model;
+		let mut interval = 0.1;
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					interval = float_setting(self.data.id, &state, &mut effector, "update_interval", interval);
+					assert!(interval > 0.0, "update_interval should be positive");
+
+					let (x, y) = model.position(state.time);
+					effector.set_float("display-location-x", x);
+					effector.set_float("display-location-y", y);
+					effector.schedule_after_secs(Event::new("move"), self.data.id, interval);
+				},
+				"move" => {
+					let (x, y) = model.position(state.time);
+					effector.set_float("display-location-x", x);
+					effector.set_float("display-location-y", y);
+					effector.schedule_after_secs(Event::new("move"), self.data.id, interval);
+				}
+			);
+		});
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+	#[cfg(test)]
+	fn close(actual: (f64, f64), expected: (f64, f64)) -> bool
+	{
+		distance(actual, expected) < 1.0e-6
+	}
+
+	#[cfg(test)]
+	fn inside(p: (f64, f64), bounds: Bounds) -> bool
+	{
+		p.0 >= bounds.0 - 1.0e-6 && p.0 <= bounds.2 + 1.0e-6 && p.1 >= bounds.1 - 1.0e-6 && p.1 <= bounds.3 + 1.0e-6
+	}
+
+    #[test]
+    fn models()
+	{
+		let mut model = ConstantVelocity {start: (1.0, 2.0), velocity: (0.5, -1.0)};
+		assert!(close(model.position(0.0), (1.0, 2.0)));
+		assert!(close(model.position(4.0), (3.0, -2.0)));
+
+		let mut trace = Trace::parse("time,x,y\n# comment\n1.0, 0.0, 0.0\n3.0, 10.0, 20.0\n\n4.0, 10.0, 0.0\n").unwrap();
+		assert!(close(trace.position(0.0), (0.0, 0.0)));
+		assert!(close(trace.position(2.0), (5.0, 10.0)));
+		assert!(close(trace.position(3.5), (10.0, 10.0)));
+		assert!(close(trace.position(9.0), (10.0, 0.0)));
+		assert!(Trace::parse("0,1,2\n1,2\n").is_err());
+		assert!(Trace::parse("# recorded trace\n\ntime,x,y\n0,1,2\n").is_ok());
+		assert!(Trace::parse("time,x,y\nt,x,y\n0,1,2\n").is_err());
+		assert!(Trace::parse("2,1,2\n1,2,3\n").is_err());
+
+		let bounds = (0.0, 0.0, 100.0, 50.0);
+		let mut waypoint = RandomWaypoint::new((10.0, 10.0), bounds, 1.0, 5.0, 2.0, Rng::new(3));
+		assert!(close(waypoint.position(1.0), (10.0, 10.0)));	// pausing
+		let mut last = waypoint.position(2.0);
+		let mut moved = false;
+		for i in 1..1000 {
+			let p = waypoint.position(2.0 + i as f64*0.1);
+			assert!(inside(p, bounds));
+			assert!(distance(p, last) <= 5.0*0.1 + 1.0e-6);
+			moved = moved || p != last;
+			last = p;
+		}
+		assert!(moved);
+
+		let mut walk = RandomWalk::new((1.0, 1.0), bounds, 3.0, 2.0, Rng::new(4));
+		let mut last = walk.position(0.0);
+		for i in 1..1000 {
+			let p = walk.position(i as f64*0.1);
+			assert!(inside(p, bounds), "{:?} isn't inside", p);
+			assert!(distance(p, last) <= 3.0*0.1 + 1.0e-6);
+			last = p;
+		}
+	}
+}
@@ -15,7 +15,19 @@
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 
 //! This is the layer responsible for endpoint to endpoint communication.
+pub use self::interference::*;
+pub use self::medium::*;
 pub use self::medium80211::*;
+pub use self::mobility::*;
+pub use self::phy80211::*;
+pub use self::propagation::*;
+pub use self::wired::*;
 
+mod interference;
+mod medium;
 mod medium80211;
+mod mobility;
+mod phy80211;
+mod propagation;
+mod wired;
 
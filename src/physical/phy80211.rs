@@ -0,0 +1,311 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use score::*;
+use std::fmt;
+
+// Unless otherwise indicated all references are to the 2016 version of "Part 11: Wireless LAN Medium Access Control (MAC) and Physical Layer (PHY) Specifications"
+// (i.e. the 802.11 spec).
+
+/// The 802.11 PHYs we know how to time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PhyStandard
+{
+	/// OFDM at 5 GHz, see clause 17.
+	Dot11a,
+
+	/// ERP-OFDM at 2.4 GHz, see clause 18. This is 802.11a timing plus a signal extension.
+	Dot11g,
+
+	/// HT mixed format, see clause 19.
+	Dot11n,
+
+	/// VHT, see clause 21.
+	Dot11ac,
+}
+
+impl PhyStandard
+{
+	/// Parses "a", "g", "n", or "ac".
+	pub fn from_name(name: &str) -> Result<PhyStandard, String>
+	{
+		match name {
+			"a" => Ok(PhyStandard::Dot11a),
+			"g" => Ok(PhyStandard::Dot11g),
+			"n" => Ok(PhyStandard::Dot11n),
+			"ac" => Ok(PhyStandard::Dot11ac),
+			_ => Err(format!("standard should be a, g, n, or ac not {}", name))
+		}
+	}
+
+	fn is_legacy(self) -> bool
+	{
+		self == PhyStandard::Dot11a || self == PhyStandard::Dot11g
+	}
+}
+
+/// The rate frames are sent at. For legacy (a and g) PHYs mcs is an index into the rates
+/// 6, 9, 12, 18, 24, 36, 48, and 54 Mbps. For n and ac it's the per stream MCS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Phy80211
+{
+	pub standard: PhyStandard,
+	pub mcs: u8,
+
+	/// Channel width in MHz: 20 for legacy PHYs, 20 or 40 for n, and 20, 40, 80, or 160 for ac.
+	pub bandwidth: u32,
+	pub spatial_streams: u8,
+	pub short_gi: bool,
+}
+
+// (bits per subcarrier, coding rate numerator, coding rate denominator)
+const LEGACY_MCS: [(u32, u32, u32); 8] = [(1, 1, 2), (1, 3, 4), (2, 1, 2), (2, 3, 4), (4, 1, 2), (4, 3, 4), (6, 2, 3), (6, 3, 4)];
+const HT_MCS: [(u32, u32, u32); 10] = [(1, 1, 2), (2, 1, 2), (2, 3, 4), (4, 1, 2), (4, 3, 4), (6, 2, 3), (6, 3, 4), (6, 5, 6), (8, 3, 4), (8, 5, 6)];
+
+const SERVICE_BITS: usize = 16;
+const TAIL_BITS: usize = 6;
+const SIGNAL_EXTENSION: f64 = 0.000_006;	// see 18.3.2.4
+
+impl Phy80211
+{
+	pub fn new(standard: PhyStandard, mcs: u8, bandwidth: u32, spatial_streams: u8, short_gi: bool) -> Result<Phy80211, String>
+	{
+		let (max_mcs, widths, max_streams): (u8, &[u32], u8) = match standard {
+			PhyStandard::Dot11a | PhyStandard::Dot11g => (7, &[20], 1),
+			PhyStandard::Dot11n => (7, &[20, 40], 4),
+			PhyStandard::Dot11ac => (9, &[20, 40, 80, 160], 8),
+		};
+		if mcs > max_mcs {
+			return Err(format!("mcs should be at most {} for {:?} not {}", max_mcs, standard, mcs))
+		}
+		if !widths.contains(&bandwidth) {
+			return Err(format!("bandwidth should be one of {:?} for {:?} not {}", widths, standard, bandwidth))
+		}
+		if spatial_streams < 1 || spatial_streams > max_streams {
+			return Err(format!("spatial_streams should be 1 to {} for {:?} not {}", max_streams, standard, spatial_streams))
+		}
+		Ok(Phy80211 {standard, mcs, bandwidth, spatial_streams, short_gi: short_gi && !standard.is_legacy()})
+	}
+
+	/// The legacy PHY running at one of the eight OFDM rates.
+	pub fn legacy(standard: PhyStandard, mcs: u8) -> Phy80211
+	{
+		assert!(standard.is_legacy());
+		assert!(mcs < 8);
+		Phy80211 {standard, mcs, bandwidth: 20, spatial_streams: 1, short_gi: false}
+	}
+
+	/// Control responses (e.g. ACKs and CTSs) are sent using the highest mandatory rate (6,
+	/// 12, or 24 Mbps) that is no faster than the rate of the frame they respond to, see
+	/// 10.6.6.5.2. The legacy format is used even by n and ac PHYs so that everyone can
+	/// receive them.
+	pub fn control_rate(&self) -> Phy80211
+	{
+		let standard = if self.standard == PhyStandard::Dot11g {PhyStandard::Dot11g} else {PhyStandard::Dot11a};
+		let rate = self.data_rate();
+		let mcs = [4, 2, 0].iter().cloned().find(|&m| Phy80211::legacy(standard, m).data_rate() <= rate).unwrap_or(0);
+		Phy80211::legacy(standard, mcs)
+	}
+
+	/// Data bits carried by each OFDM symbol (N_DBPS).
+	pub fn bits_per_symbol(&self) -> u32
+	{
+		let (bits, num, den) = if self.standard.is_legacy() {LEGACY_MCS[self.mcs as usize]} else {HT_MCS[self.mcs as usize]};
+		let subcarriers = match (self.standard.is_legacy(), self.bandwidth) {
+			(true, _) => 48,
+			(false, 20) => 52,
+			(false, 40) => 108,
+			(false, 80) => 234,
+			(false, _) => 468,
+		};
+		subcarriers*bits*num/den*self.spatial_streams as u32
+	}
+
+	/// Duration of an OFDM symbol in seconds.
+	pub fn symbol_time(&self) -> f64
+	{
+		if self.short_gi {0.000_003_6} else {0.000_004}
+	}
+
+	/// Bits per second.
+	pub fn data_rate(&self) -> f64
+	{
+		self.bits_per_symbol() as f64/self.symbol_time()
+	}
+
+	/// Seconds needed for the preamble and PLCP headers.
+	pub fn preamble_time(&self) -> f64
+	{
+		let legacy = 0.000_016 + 0.000_004;		// L-STF + L-LTF and L-SIG, see 17.3.2.4
+		let ltfs = match self.spatial_streams {1 => 1, 2 => 2, 3 | 4 => 4, 5 | 6 => 6, _ => 8} as f64;
+		match self.standard {
+			PhyStandard::Dot11a | PhyStandard::Dot11g => legacy,
+			PhyStandard::Dot11n => legacy + 0.000_008 + 0.000_004 + ltfs*0.000_004,					// HT-SIG, HT-STF, and HT-LTFs, see 19.3.7
+			PhyStandard::Dot11ac => legacy + 0.000_008 + 0.000_004 + ltfs*0.000_004 + 0.000_004,	// VHT-SIG-A, VHT-STF, VHT-LTFs, and VHT-SIG-B, see 21.3.7
+		}
+	}
+
+	/// Seconds needed to send a PSDU (i.e. an entire MAC frame) of len bytes.
+	pub fn airtime(&self, len: usize) -> f64
+	{
+		// Each BCC encoder handles up to 300 Mbps (for n) or 600 Mbps (for ac) and needs its own
+		// tail bits, see 19.3.11.3 and 21.3.10.5.
+		let encoders = match self.standard {
+			PhyStandard::Dot11a | PhyStandard::Dot11g => 1.0,
+			PhyStandard::Dot11n => (self.data_rate()/300_000_000.0).ceil(),
+			PhyStandard::Dot11ac => (self.data_rate()/600_000_000.0).ceil(),
+		} as usize;
+		let bits = SERVICE_BITS + 8*len + TAIL_BITS*encoders;
+		let bps = self.bits_per_symbol() as usize;
+		let symbols = (bits + bps - 1)/bps;
+
+		let extension = if self.standard == PhyStandard::Dot11g {SIGNAL_EXTENSION} else {0.0};
+		self.preamble_time() + symbols as f64*self.symbol_time() + extension
+	}
+}
+
+/// PHY details for a frame that was sent or received, e.g. for radiotap headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RadioInfo
+{
+	pub phy: Phy80211,
+
+	/// Center frequency of the channel in MHz.
+	pub frequency: u32,
+
+	/// Received power in dBm (None for frames we sent).
+	pub signal: Option<f64>,
+
+	/// Noise floor in dBm (None for frames we sent).
+	pub noise: Option<f64>,
+
+	/// True for received frames that failed their FCS check.
+	pub bad_fcs: bool,
+}
+
+impl RadioInfo
+{
+	/// Info for frames we're sending.
+	pub fn with_tx(phy: Phy80211, frequency: u32) -> Self
+	{
+		RadioInfo {phy, frequency, signal: None, noise: None, bad_fcs: false}
+	}
+}
+
+/// Returns the center frequency in MHz for a 20 MHz channel number, see 17.3.8.4.2 and
+/// 18.4.6.3.
+pub fn channel_frequency(channel: u8) -> u32
+{
+	if channel == 14 {
+		2484
+	} else if channel < 14 {
+		2407 + 5*channel as u32
+	} else {
+		5000 + 5*channel as u32
+	}
+}
+
+/// Returns the PHY component id is configured to use. This is normally called from "init 0"
+/// and uses the **standard** ("a", "g", "n", or "ac"), **mcs**, **bandwidth** (MHz),
+/// **spatial_streams**, and **short_gi** (0 or 1) settings. These default to "a", 7, 20, 1,
+/// and 0 (i.e. 54 Mbps).
+pub fn phy_settings(id: ComponentID, state: &SimState, effector: &mut Effector) -> Phy80211
+{
+	let standard = string_setting(id, state, effector, "standard", "a");
+	let mcs = int_setting(id, state, effector, "mcs", 7);
+	let bandwidth = int_setting(id, state, effector, "bandwidth", 20);
+	let streams = int_setting(id, state, effector, "spatial_streams", 1);
+	let short_gi = int_setting(id, state, effector, "short_gi", 0);
+	match new_phy(&standard, mcs, bandwidth, streams, short_gi) {
+		Ok(phy) => phy,
+		Err(mesg) => panic!("bad PHY settings: {}", mesg)
+	}
+}
+
+/// Like [`phy_settings`] except that it can be used for other components (missing settings
+/// use the defaults but aren't added to the store).
+pub fn configured_phy(id: ComponentID, state: &SimState) -> Phy80211
+{
+	let get_int = |name, default| if state.contains(id, name) {state.get_int(id, name)} else {default};
+	let standard = if state.contains(id, "standard") {state.get_string(id, "standard")} else {"a".to_string()};
+	new_phy(&standard, get_int("mcs", 7), get_int("bandwidth", 20), get_int("spatial_streams", 1), get_int("short_gi", 0))
+		.unwrap_or_else(|_| Phy80211::legacy(PhyStandard::Dot11a, 7))
+}
+
+fn new_phy(standard: &str, mcs: i64, bandwidth: i64, streams: i64, short_gi: i64) -> Result<Phy80211, String>
+{
+	let standard = try!(PhyStandard::from_name(standard));
+	if mcs < 0 || mcs > 255 || bandwidth < 0 || streams < 0 || streams > 255 {
+		return Err(format!("mcs {}, bandwidth {}, or spatial_streams {} is out of range", mcs, bandwidth, streams))
+	}
+	Phy80211::new(standard, mcs as u8, bandwidth as u32, streams as u8, short_gi != 0)
+}
+
+impl fmt::Display for Phy80211
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		write!(f, "{:?} MCS {} ({:.1} Mbps)", self.standard, self.mcs, self.data_rate()/1_000_000.0)
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+	#[cfg(test)]
+	fn us(secs: f64) -> u32
+	{
+		(secs*1_000_000.0).round() as u32
+	}
+
+    #[test]
+    fn airtimes()
+	{
+		// See the examples in 17.3.2.3 and 17.4.3.
+		let a54 = Phy80211::legacy(PhyStandard::Dot11a, 7);
+		assert_eq!(a54.data_rate(), 54_000_000.0);
+		assert_eq!(us(a54.airtime(14)), 24);
+		assert_eq!(us(a54.airtime(1500)), 244);
+
+		let a6 = Phy80211::legacy(PhyStandard::Dot11a, 0);
+		assert_eq!(a6.data_rate(), 6_000_000.0);
+		assert_eq!(us(a6.airtime(14)), 44);
+		assert_eq!(a54.control_rate().mcs, 4);
+		assert_eq!(a6.control_rate().mcs, 0);
+
+		let g54 = Phy80211::legacy(PhyStandard::Dot11g, 7);
+		assert_eq!(us(g54.airtime(14)), 30);
+
+		let n = Phy80211::new(PhyStandard::Dot11n, 7, 40, 2, true).unwrap();
+		assert_eq!((n.data_rate()/1_000_000.0).round(), 300.0);
+		assert_eq!(us(n.preamble_time()), 40);
+		assert_eq!(n.control_rate(), Phy80211::legacy(PhyStandard::Dot11a, 4));
+
+		let ac = Phy80211::new(PhyStandard::Dot11ac, 9, 80, 1, false).unwrap();
+		assert_eq!((ac.data_rate()/1_000_000.0).round(), 390.0);
+		assert!(ac.airtime(1500) < a54.airtime(1500));
+
+		assert!(Phy80211::new(PhyStandard::Dot11n, 8, 20, 1, false).is_err());
+		assert!(Phy80211::new(PhyStandard::Dot11a, 0, 40, 1, false).is_err());
+		assert!(PhyStandard::from_name("b").is_err());
+
+		assert_eq!(channel_frequency(1), 2412);
+		assert_eq!(channel_frequency(14), 2484);
+		assert_eq!(channel_frequency(36), 5180);
+	}
+}
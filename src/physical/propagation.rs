@@ -0,0 +1,211 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use std::f64::consts::PI;
+
+/// Speed of light in meters per second.
+pub const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+/// Used by [`Medium80211Component`] to compute how strong a frame is when it reaches a radio.
+pub trait PropagationModel: Send
+{
+	/// Returns the received power in dBm for a frame transmitted with tx_power dBm that
+	/// travelled distance meters.
+	fn rx_power(&mut self, tx_power: f64, distance: f64) -> f64;
+}
+
+/// Friis free space model: loss grows with the square of the distance. This is only
+/// accurate with line of sight and no nearby reflectors.
+pub struct FreeSpace
+{
+	/// Carrier frequency in Hz.
+	pub frequency: f64,
+}
+
+impl FreeSpace
+{
+	/// Returns the loss in dB at distance meters.
+	pub fn path_loss(&self, distance: f64) -> f64
+	{
+		let lambda = SPEED_OF_LIGHT/self.frequency;
+		let distance = distance.max(lambda/(4.0*PI));	// don't amplify anything close to the antenna
+		20.0*(4.0*PI*distance/lambda).log10()
+	}
+}
+
+impl PropagationModel for FreeSpace
+{
+	fn rx_power(&mut self, tx_power: f64, distance: f64) -> f64
+	{
+		tx_power - self.path_loss(distance)
+	}
+}
+
+/// Log-distance model: reference_loss dB of loss at reference_distance meters plus
+/// 10*exponent dB per decade after that. Exponents are typically 2 for free space, 2.7 to 3.5
+/// for urban areas and 4 to 6 indoors with obstructions.
+pub struct LogDistance
+{
+	pub exponent: f64,
+	pub reference_distance: f64,
+	pub reference_loss: f64,
+}
+
+impl LogDistance
+{
+	/// Uses free space loss out to one meter.
+	pub fn new(frequency: f64, exponent: f64) -> Self
+	{
+		let reference_loss = FreeSpace {frequency}.path_loss(1.0);
+		LogDistance {exponent, reference_distance: 1.0, reference_loss}
+	}
+
+	pub fn path_loss(&self, distance: f64) -> f64
+	{
+		let distance = distance.max(self.reference_distance);
+		self.reference_loss + 10.0*self.exponent*(distance/self.reference_distance).log10()
+	}
+}
+
+impl PropagationModel for LogDistance
+{
+	fn rx_power(&mut self, tx_power: f64, distance: f64) -> f64
+	{
+		tx_power - self.path_loss(distance)
+	}
+}
+
+/// Two-ray ground reflection model: free space loss up to the crossover distance and then
+/// loss that grows with the fourth power of the distance (because the ground reflection
+/// starts to cancel out the direct ray). Antenna heights are in meters.
+pub struct TwoRayGround
+{
+	pub frequency: f64,
+	pub tx_height: f64,
+	pub rx_height: f64,
+}
+
+impl TwoRayGround
+{
+	/// Distance in meters after which the ground reflection matters.
+	pub fn crossover(&self) -> f64
+	{
+		let lambda = SPEED_OF_LIGHT/self.frequency;
+		4.0*PI*self.tx_height*self.rx_height/lambda
+	}
+
+	pub fn path_loss(&self, distance: f64) -> f64
+	{
+		if distance < self.crossover() {
+			FreeSpace {frequency: self.frequency}.path_loss(distance)
+		} else {
+			40.0*distance.log10() - 20.0*(self.tx_height*self.rx_height).log10()
+		}
+	}
+}
+
+impl PropagationModel for TwoRayGround
+{
+	fn rx_power(&mut self, tx_power: f64, distance: f64) -> f64
+	{
+		tx_power - self.path_loss(distance)
+	}
+}
+
+/// Wraps another model and adds log-normal shadowing (a zero mean normally distributed
+/// number of dB with std_dev deviation) and, optionally, Rayleigh fading. A new sample is
+/// drawn for every call so each frame sees a different channel.
+pub struct Shadowing
+{
+	pub model: Box<PropagationModel>,
+	pub std_dev: f64,
+	pub rayleigh: bool,
+	rng: Rng,
+}
+
+impl Shadowing
+{
+	pub fn new(model: Box<PropagationModel>, std_dev: f64, rayleigh: bool, rng: Rng) -> Self
+	{
+		Shadowing {model, std_dev, rayleigh, rng}
+	}
+
+	// Box-Muller transform.
+	fn normal(&mut self) -> f64
+	{
+		let u1 = 1.0 - self.rng.next_f64();		// (0, 1] so that ln is finite
+		let u2 = self.rng.next_f64();
+		(-2.0*u1.ln()).sqrt()*(2.0*PI*u2).cos()
+	}
+
+	// The power gain of a Rayleigh faded channel is exponentially distributed with mean 1.
+	fn fading(&mut self) -> f64
+	{
+		let u = 1.0 - self.rng.next_f64();
+		10.0*(-u.ln()).log10()
+	}
+}
+
+impl PropagationModel for Shadowing
+{
+	fn rx_power(&mut self, tx_power: f64, distance: f64) -> f64
+	{
+		let mut power = self.model.rx_power(tx_power, distance) + self.std_dev*self.normal();
+		if self.rayleigh {
+			power += self.fading();
+		}
+		power
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+	#[cfg(test)]
+	fn close(actual: f64, expected: f64) -> bool
+	{
+		(actual - expected).abs() < 0.1
+	}
+
+    #[test]
+    fn path_loss()
+	{
+		let mut free = FreeSpace {frequency: 2_400_000_000.0};
+		assert!(close(free.path_loss(1.0), 40.05));
+		assert!(close(free.path_loss(100.0), 80.05));
+		assert!(close(free.rx_power(20.0, 10.0), -40.05));
+
+		let log = LogDistance::new(2_400_000_000.0, 3.0);
+		assert!(close(log.path_loss(1.0), 40.05));
+		assert!(close(log.path_loss(100.0), 100.05));
+		assert!(close(log.path_loss(0.1), 40.05));
+
+		let two = TwoRayGround {frequency: 2_400_000_000.0, tx_height: 1.5, rx_height: 1.5};
+		assert!(close(two.crossover(), 226.4));
+		assert!(close(two.path_loss(100.0), free.path_loss(100.0)));
+		assert!(close(two.path_loss(1000.0), 120.0 - 20.0*2.25f64.log10()));
+
+		let mut shadow = Shadowing::new(Box::new(LogDistance::new(2_400_000_000.0, 3.0)), 4.0, false, Rng::new(1));
+		let n = 10_000;
+		let samples: Vec<f64> = (0..n).map(|_| shadow.rx_power(20.0, 100.0)).collect();
+		let mean = samples.iter().sum::<f64>()/n as f64;
+		let var = samples.iter().map(|s| (s - mean)*(s - mean)).sum::<f64>()/n as f64;
+		assert!((mean - -80.05).abs() < 0.2, "mean was {}", mean);
+		assert!((var.sqrt() - 4.0).abs() < 0.2, "std dev was {}", var.sqrt());
+	}
+}
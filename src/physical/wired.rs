@@ -0,0 +1,156 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use physical::medium::*;
+use score::*;
+use std::thread;
+
+/// A full duplex cable connecting exactly two wired MACs, e.g. [`EthernetMacComponent`].
+/// Frames are delivered after their serialization time plus the propagation delay and
+/// each direction is independent. There are two component settings:
+/// - **bit_rate** is the link speed in bits per second. Defaults to 100_000_000.
+/// - **delay** is the propagation delay in seconds. Defaults to 0.000_001.
+pub struct PointToPointComponent
+{
+	data: ThreadData,
+
+	pub upper_ins: Vec<InPort<(ComponentID, Packet)>>,
+	pub upper_outs: Vec<OutPort<Packet>>,
+}
+
+impl Medium for PointToPointComponent
+{
+	fn connect(&mut self, above_out: &mut OutPort<(ComponentID, Packet)>, above_in: &InPort<Packet>)
+	{
+		assert!(self.upper_ins.len() < 2, "point to point links can only connect two MACs");
+		connect_wire(self.data.id, &mut self.upper_ins, &mut self.upper_outs, above_out, above_in);
+	}
+}
+
+impl PointToPointComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (_, data) = sim.add_active_component("PointToPoint", parent_id);
+		PointToPointComponent {data: data, upper_ins: Vec::new(), upper_outs: Vec::new()}
+	}
+
+	pub fn start(self)
+	{
+		let mut bit_rate = 100_000_000.0;
+		let mut delay = 0.000_001;
+		let mut busy_until = [0.0, 0.0];	// each direction has its own wire
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					bit_rate = float_setting(self.data.id, &state, &mut effector, "bit_rate", bit_rate);
+					delay = float_setting(self.data.id, &state, &mut effector, "delay", delay);
+				},
+				"send_down" => {
+					assert!(!event.port_name.is_empty());
+
+					let (_, packet) = event.take_payload::<(ComponentID, Packet)>();
+					for i in 0..self.upper_outs.len() {
+						if self.upper_ins[i].target_port == event.port_name {
+							let start = if busy_until[i] > state.time {busy_until[i]} else {state.time};
+							busy_until[i] = start + (packet.len()*8) as f64/bit_rate;
+
+							let j = 1 - i;
+							if j < self.upper_outs.len() {
+								let port = &self.upper_outs[j];
+								port.send_payload_after_secs(&mut effector, "send_up", busy_until[i] + delay - state.time, packet);
+							}
+							break;
+						}
+					}
+				}
+			);
+		});
+	}
+}
+
+/// A half duplex wire shared by any number of wired MACs, e.g. an old 10BASE2 segment or
+/// a hub. Frames are serialized one at a time: a frame sent while the bus is in use waits
+/// until the bus is idle (which approximates CSMA/CD without modeling collisions). Every
+/// frame is delivered to every MAC except the sender. Settings are the same as
+/// [`PointToPointComponent`] except that **bit_rate** defaults to 10_000_000.
+pub struct EthernetBusComponent
+{
+	data: ThreadData,
+
+	pub upper_ins: Vec<InPort<(ComponentID, Packet)>>,
+	pub upper_outs: Vec<OutPort<Packet>>,
+}
+
+impl Medium for EthernetBusComponent
+{
+	fn connect(&mut self, above_out: &mut OutPort<(ComponentID, Packet)>, above_in: &InPort<Packet>)
+	{
+		connect_wire(self.data.id, &mut self.upper_ins, &mut self.upper_outs, above_out, above_in);
+	}
+}
+
+impl EthernetBusComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (_, data) = sim.add_active_component("EthernetBus", parent_id);
+		EthernetBusComponent {data: data, upper_ins: Vec::new(), upper_outs: Vec::new()}
+	}
+
+	pub fn start(self)
+	{
+		let mut bit_rate = 10_000_000.0;
+		let mut delay = 0.000_001;
+		let mut busy_until = 0.0;
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					bit_rate = float_setting(self.data.id, &state, &mut effector, "bit_rate", bit_rate);
+					delay = float_setting(self.data.id, &state, &mut effector, "delay", delay);
+				},
+				"send_down" => {
+					assert!(!event.port_name.is_empty());
+
+					let (_, packet) = event.take_payload::<(ComponentID, Packet)>();
+					let start = if busy_until > state.time {busy_until} else {state.time};
+					busy_until = start + (packet.len()*8) as f64/bit_rate;
+
+					for i in 0..self.upper_outs.len() {
+						if self.upper_ins[i].target_port != event.port_name {
+							let port = &self.upper_outs[i];
+							port.send_payload_after_secs(&mut effector, "send_up", busy_until + delay - state.time, packet.clone());
+						}
+					}
+				}
+			);
+		});
+	}
+}
+
+fn connect_wire(id: ComponentID, upper_ins: &mut Vec<InPort<(ComponentID, Packet)>>, upper_outs: &mut Vec<OutPort<Packet>>, above_out: &mut OutPort<(ComponentID, Packet)>, above_in: &InPort<Packet>)
+{
+	let upper_in = InPort::with_port_name(id, &format!("upper_in_{}", upper_ins.len()));
+	let mut upper_out = OutPort::new();
+
+	above_out.connect_to(&upper_in);
+	upper_out.connect_to(&above_in);
+
+	upper_ins.push(upper_in);
+	upper_outs.push(upper_out);
+}
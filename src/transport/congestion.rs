@@ -0,0 +1,687 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use std::cmp;
+
+/// The congestion control algorithms [`TcpComponent`] supports. Set using [`SocketOptions`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CongestionControl
+{
+	/// RFC 5681: slow start, congestion avoidance, fast retransmit, and fast recovery.
+	Reno,
+
+	/// RFC 6582: Reno but partial acks don't end fast recovery.
+	NewReno,
+
+	/// RFC 8312: window growth is a cubic function of the time since the last loss.
+	Cubic,
+
+	/// Model based algorithm loosely based on BBR v1: the window tracks the estimated
+	/// bandwidth delay product instead of reacting to losses.
+	Bbr,
+}
+
+/// Used by [`TcpComponent`] to decide how much unacknowledged data may be outstanding.
+/// All windows are in bytes.
+pub trait CongestionAlgorithm: Send
+{
+	/// Called when the connection is established.
+	fn init(&mut self, mss: usize, now: f64);
+
+	fn cwnd(&self) -> usize;
+
+	/// Algorithms that don't use a slow start threshold return INFINITE_SSTHRESH.
+	fn ssthresh(&self) -> usize;
+
+	/// New data was acknowledged (outside of fast recovery). Rtt is set if the ack produced an RTT sample.
+	fn on_ack(&mut self, acked: usize, in_flight: usize, rtt: Option<f64>, now: f64);
+
+	/// The third duplicate ack arrived and the missing segment is being retransmitted.
+	fn on_fast_retransmit(&mut self, in_flight: usize, now: f64);
+
+	/// An additional duplicate ack arrived during fast recovery.
+	fn on_dup_ack(&mut self);
+
+	/// An ack arrived that covers some, but not all, of the data that was outstanding when
+	/// fast recovery started. Returns true if recovery should continue (and the next missing
+	/// segment be retransmitted).
+	fn on_partial_ack(&mut self, acked: usize) -> bool;
+
+	/// Fast recovery finished.
+	fn on_recovery_end(&mut self, now: f64);
+
+	/// The retransmission timer expired.
+	fn on_timeout(&mut self, in_flight: usize, now: f64);
+}
+
+pub const INFINITE_SSTHRESH: usize = 0x7FFF_FFFF;
+
+pub fn new_congestion_algorithm(kind: CongestionControl) -> Box<CongestionAlgorithm>
+{
+	match kind {
+		CongestionControl::Reno => Box::new(Reno::new()),
+		CongestionControl::NewReno => Box::new(NewReno::new()),
+		CongestionControl::Cubic => Box::new(Cubic::new()),
+		CongestionControl::Bbr => Box::new(Bbr::new()),
+	}
+}
+
+// RFC 5681 section 3.1
+fn initial_window(mss: usize) -> usize
+{
+	if mss > 2190 {
+		2*mss
+	} else if mss > 1095 {
+		3*mss
+	} else {
+		4*mss
+	}
+}
+
+// RFC 5681 equation 4
+fn loss_ssthresh(in_flight: usize, mss: usize) -> usize
+{
+	cmp::max(in_flight/2, 2*mss)
+}
+
+pub struct Reno
+{
+	mss: usize,
+	cwnd: usize,
+	ssthresh: usize,
+}
+
+impl Reno
+{
+	pub fn new() -> Self
+	{
+		Reno {mss: 536, cwnd: initial_window(536), ssthresh: INFINITE_SSTHRESH}
+	}
+}
+
+impl CongestionAlgorithm for Reno
+{
+	fn init(&mut self, mss: usize, _now: f64)
+	{
+		self.mss = mss;
+		self.cwnd = initial_window(mss);
+	}
+
+	fn cwnd(&self) -> usize
+	{
+		self.cwnd
+	}
+
+	fn ssthresh(&self) -> usize
+	{
+		self.ssthresh
+	}
+
+	fn on_ack(&mut self, acked: usize, _in_flight: usize, _rtt: Option<f64>, _now: f64)
+	{
+		if self.cwnd < self.ssthresh {
+			self.cwnd += cmp::min(acked, self.mss);								// slow start
+		} else {
+			self.cwnd += cmp::max(self.mss*self.mss/self.cwnd, 1);				// congestion avoidance
+		}
+	}
+
+	fn on_fast_retransmit(&mut self, in_flight: usize, _now: f64)
+	{
+		self.ssthresh = loss_ssthresh(in_flight, self.mss);
+		self.cwnd = self.ssthresh + 3*self.mss;
+	}
+
+	fn on_dup_ack(&mut self)
+	{
+		self.cwnd += self.mss;
+	}
+
+	fn on_partial_ack(&mut self, _acked: usize) -> bool
+	{
+		false
+	}
+
+	fn on_recovery_end(&mut self, _now: f64)
+	{
+		self.cwnd = self.ssthresh;
+	}
+
+	fn on_timeout(&mut self, in_flight: usize, _now: f64)
+	{
+		self.ssthresh = loss_ssthresh(in_flight, self.mss);
+		self.cwnd = self.mss;
+	}
+}
+
+pub struct NewReno
+{
+	reno: Reno,
+}
+
+impl NewReno
+{
+	pub fn new() -> Self
+	{
+		NewReno {reno: Reno::new()}
+	}
+}
+
+impl CongestionAlgorithm for NewReno
+{
+	fn init(&mut self, mss: usize, now: f64)
+	{
+		self.reno.init(mss, now);
+	}
+
+	fn cwnd(&self) -> usize
+	{
+		self.reno.cwnd
+	}
+
+	fn ssthresh(&self) -> usize
+	{
+		self.reno.ssthresh
+	}
+
+	fn on_ack(&mut self, acked: usize, in_flight: usize, rtt: Option<f64>, now: f64)
+	{
+		self.reno.on_ack(acked, in_flight, rtt, now);
+	}
+
+	fn on_fast_retransmit(&mut self, in_flight: usize, now: f64)
+	{
+		self.reno.on_fast_retransmit(in_flight, now);
+	}
+
+	fn on_dup_ack(&mut self)
+	{
+		self.reno.on_dup_ack();
+	}
+
+	// RFC 6582 section 3.2 step 5
+	fn on_partial_ack(&mut self, acked: usize) -> bool
+	{
+		let reno = &mut self.reno;
+		reno.cwnd = reno.cwnd.saturating_sub(acked);
+		if acked >= reno.mss {
+			reno.cwnd += reno.mss;
+		}
+		reno.cwnd = cmp::max(reno.cwnd, reno.mss);
+		true
+	}
+
+	fn on_recovery_end(&mut self, now: f64)
+	{
+		self.reno.on_recovery_end(now);
+	}
+
+	fn on_timeout(&mut self, in_flight: usize, now: f64)
+	{
+		self.reno.on_timeout(in_flight, now);
+	}
+}
+
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+
+pub struct Cubic
+{
+	mss: usize,
+	cwnd: usize,
+	ssthresh: usize,
+	w_max: f64,					// window (in segments) just before the last reduction
+	k: f64,						// time it takes to grow back to w_max
+	w_est: f64,					// Reno friendly window (in segments)
+	epoch_start: Option<f64>,	// start of the current congestion avoidance period
+}
+
+impl Cubic
+{
+	pub fn new() -> Self
+	{
+		Cubic {mss: 536, cwnd: initial_window(536), ssthresh: INFINITE_SSTHRESH, w_max: 0.0, k: 0.0, w_est: 0.0, epoch_start: None}
+	}
+
+	fn reduce(&mut self)
+	{
+		let cwnd = self.cwnd as f64/self.mss as f64;
+		self.w_max = if cwnd < self.w_max {cwnd*(1.0 + CUBIC_BETA)/2.0} else {cwnd};	// fast convergence
+		self.ssthresh = cmp::max((self.cwnd as f64*CUBIC_BETA) as usize, 2*self.mss);
+		self.epoch_start = None;
+	}
+}
+
+// See RFC 8312 section 4.
+impl CongestionAlgorithm for Cubic
+{
+	fn init(&mut self, mss: usize, _now: f64)
+	{
+		self.mss = mss;
+		self.cwnd = initial_window(mss);
+	}
+
+	fn cwnd(&self) -> usize
+	{
+		self.cwnd
+	}
+
+	fn ssthresh(&self) -> usize
+	{
+		self.ssthresh
+	}
+
+	fn on_ack(&mut self, acked: usize, _in_flight: usize, rtt: Option<f64>, now: f64)
+	{
+		if self.cwnd < self.ssthresh {
+			self.cwnd += cmp::min(acked, self.mss);
+			return
+		}
+
+		let mss = self.mss as f64;
+		let cwnd = self.cwnd as f64/mss;
+		if self.epoch_start.is_none() {
+			self.epoch_start = Some(now);
+			if cwnd < self.w_max {
+				self.k = ((self.w_max - cwnd)/CUBIC_C).cbrt();
+			} else {
+				self.k = 0.0;
+				self.w_max = cwnd;
+			}
+			self.w_est = cwnd;
+		}
+
+		let t = now - self.epoch_start.unwrap() + rtt.unwrap_or(0.0);
+		let target = CUBIC_C*(t - self.k).powi(3) + self.w_max;
+		let segments = acked as f64/mss;
+		self.w_est += 3.0*(1.0 - CUBIC_BETA)/(1.0 + CUBIC_BETA)*segments/cwnd;
+
+		let increment = if target > cwnd {
+			(target - cwnd).min(cwnd/2.0)*segments/cwnd
+		} else {
+			0.01*segments/cwnd
+		};
+		let next = (cwnd + increment).max(self.w_est);
+		self.cwnd = cmp::max((next*mss) as usize, self.cwnd);
+	}
+
+	fn on_fast_retransmit(&mut self, _in_flight: usize, _now: f64)
+	{
+		self.reduce();
+		self.cwnd = self.ssthresh + 3*self.mss;
+	}
+
+	fn on_dup_ack(&mut self)
+	{
+		self.cwnd += self.mss;
+	}
+
+	fn on_partial_ack(&mut self, acked: usize) -> bool
+	{
+		self.cwnd = cmp::max(self.cwnd.saturating_sub(acked) + self.mss, self.mss);
+		true
+	}
+
+	fn on_recovery_end(&mut self, _now: f64)
+	{
+		self.cwnd = self.ssthresh;
+	}
+
+	fn on_timeout(&mut self, _in_flight: usize, _now: f64)
+	{
+		self.reduce();
+		self.cwnd = self.mss;
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BbrMode
+{
+	Startup,
+	Drain,
+	ProbeBandwidth,
+}
+
+const BBR_BANDWIDTH_ROUNDS: usize = 10;
+const BBR_MIN_RTT_LIFETIME: f64 = 10.0;
+const BBR_CWND_GAIN: f64 = 2.0;
+const BBR_CYCLE_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+/// We don't model pacing so the pacing gains are applied to the window.
+pub struct Bbr
+{
+	mss: usize,
+	cwnd: usize,
+	mode: BbrMode,
+
+	bandwidths: Vec<f64>,		// bytes/sec for the most recent rounds
+	min_rtt: Option<(f64, f64)>,	// RTT and when it was measured
+
+	delivered: usize,
+	round_start: f64,
+	round_delivered: usize,
+
+	full_bandwidth: f64,
+	full_bandwidth_count: usize,
+	cycle_index: usize,
+}
+
+impl Bbr
+{
+	pub fn new() -> Self
+	{
+		Bbr {
+			mss: 536,
+			cwnd: initial_window(536),
+			mode: BbrMode::Startup,
+			bandwidths: Vec::new(),
+			min_rtt: None,
+			delivered: 0,
+			round_start: 0.0,
+			round_delivered: 0,
+			full_bandwidth: 0.0,
+			full_bandwidth_count: 0,
+			cycle_index: 0,
+		}
+	}
+
+	fn max_bandwidth(&self) -> f64
+	{
+		self.bandwidths.iter().cloned().fold(0.0, f64::max)
+	}
+
+	// Bandwidth delay product in bytes.
+	fn bdp(&self) -> Option<f64>
+	{
+		match self.min_rtt {
+			Some((rtt, _)) if !self.bandwidths.is_empty() => Some(self.max_bandwidth()*rtt),
+			_ => None
+		}
+	}
+
+	fn end_round(&mut self, now: f64)
+	{
+		let elapsed = now - self.round_start;
+		let bandwidth = (self.delivered - self.round_delivered) as f64/elapsed;
+		self.bandwidths.push(bandwidth);
+		if self.bandwidths.len() > BBR_BANDWIDTH_ROUNDS {
+			self.bandwidths.remove(0);
+		}
+		self.round_start = now;
+		self.round_delivered = self.delivered;
+
+		match self.mode {
+			BbrMode::Startup => {
+				// The pipe is full once three rounds fail to increase bandwidth by 25%.
+				let max = self.max_bandwidth();
+				if max >= 1.25*self.full_bandwidth {
+					self.full_bandwidth = max;
+					self.full_bandwidth_count = 0;
+				} else {
+					self.full_bandwidth_count += 1;
+					if self.full_bandwidth_count >= 3 {
+						self.mode = BbrMode::Drain;
+					}
+				}
+			},
+			BbrMode::Drain => (),
+			BbrMode::ProbeBandwidth => self.cycle_index = (self.cycle_index + 1) % BBR_CYCLE_GAINS.len(),
+		}
+	}
+}
+
+impl CongestionAlgorithm for Bbr
+{
+	fn init(&mut self, mss: usize, now: f64)
+	{
+		self.mss = mss;
+		self.cwnd = initial_window(mss);
+		self.round_start = now;
+	}
+
+	fn cwnd(&self) -> usize
+	{
+		self.cwnd
+	}
+
+	fn ssthresh(&self) -> usize
+	{
+		INFINITE_SSTHRESH
+	}
+
+	fn on_ack(&mut self, acked: usize, in_flight: usize, rtt: Option<f64>, now: f64)
+	{
+		self.delivered += acked;
+		if let Some(sample) = rtt {
+			let expired = match self.min_rtt {
+				Some((min, time)) => sample <= min || now - time > BBR_MIN_RTT_LIFETIME,
+				None => true
+			};
+			if expired {
+				self.min_rtt = Some((sample, now));
+			}
+		}
+
+		if let Some((min_rtt, _)) = self.min_rtt {
+			if now - self.round_start >= min_rtt && now > self.round_start {
+				self.end_round(now);
+			}
+		}
+
+		let floor = 4*self.mss;
+		match (self.mode, self.bdp()) {
+			(BbrMode::Startup, _) | (_, None) => self.cwnd += acked,
+			(BbrMode::Drain, Some(bdp)) => {
+				self.cwnd = cmp::max(bdp as usize, floor);
+				if in_flight as f64 <= bdp {
+					self.mode = BbrMode::ProbeBandwidth;
+					self.cycle_index = 0;
+				}
+			},
+			(BbrMode::ProbeBandwidth, Some(bdp)) => {
+				let gain = BBR_CWND_GAIN*BBR_CYCLE_GAINS[self.cycle_index];
+				self.cwnd = cmp::max((gain*bdp) as usize, floor);
+			},
+		}
+	}
+
+	// BBR doesn't treat loss as a congestion signal.
+	fn on_fast_retransmit(&mut self, _in_flight: usize, _now: f64)
+	{
+	}
+
+	fn on_dup_ack(&mut self)
+	{
+	}
+
+	fn on_partial_ack(&mut self, _acked: usize) -> bool
+	{
+		true
+	}
+
+	fn on_recovery_end(&mut self, _now: f64)
+	{
+	}
+
+	fn on_timeout(&mut self, _in_flight: usize, _now: f64)
+	{
+		self.cwnd = self.mss;		// the window is restored from the model as acks arrive
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn reno_window()
+	{
+		let mut cc = Reno::new();
+		cc.init(1000, 0.0);
+		assert_eq!(3000, cc.cwnd());
+
+		cc.on_ack(1000, 3000, Some(0.1), 0.1);
+		assert_eq!(4000, cc.cwnd());		// slow start
+
+		cc.on_fast_retransmit(4000, 0.2);
+		assert_eq!(2000, cc.ssthresh());
+		assert_eq!(5000, cc.cwnd());		// inflated by the three duplicate acks
+		assert!(!cc.on_partial_ack(1000));
+		cc.on_recovery_end(0.3);
+		assert_eq!(2000, cc.cwnd());
+
+		cc.on_ack(1000, 2000, None, 0.4);
+		assert_eq!(2500, cc.cwnd());		// congestion avoidance
+
+		cc.on_timeout(2000, 0.5);
+		assert_eq!(1000, cc.cwnd());
+    }
+
+    #[test]
+    fn new_reno_partial_acks()
+	{
+		let mut cc = NewReno::new();
+		cc.init(1000, 0.0);
+		for i in 0..5 {
+			cc.on_ack(1000, 3000, Some(0.1), 0.1*i as f64);
+		}
+		assert_eq!(8000, cc.cwnd());
+
+		cc.on_fast_retransmit(8000, 1.0);
+		assert_eq!(4000, cc.ssthresh());
+		assert_eq!(7000, cc.cwnd());
+		cc.on_dup_ack();
+		assert_eq!(8000, cc.cwnd());
+
+		// Partial acks deflate the window by the amount acked and add back an MSS if
+		// at least an MSS was acked. Unlike Reno recovery continues.
+		assert!(cc.on_partial_ack(2000));
+		assert_eq!(7000, cc.cwnd());
+		assert!(cc.on_partial_ack(500));
+		assert_eq!(6500, cc.cwnd());
+
+		cc.on_recovery_end(1.2);
+		assert_eq!(4000, cc.cwnd());
+		assert_eq!(4000, cc.ssthresh());
+    }
+
+	// Acks a window's worth of segments each RTT (of 100 ms) for the specified number of RTTs.
+	#[cfg(test)]
+	fn ack_rounds(cc: &mut CongestionAlgorithm, rounds: usize, now: &mut f64)
+	{
+		for _ in 0..rounds {
+			let cwnd = cc.cwnd();
+			for _ in 0..cwnd/1000 {
+				cc.on_ack(1000, cwnd, Some(0.1), *now);
+			}
+			*now += 0.1;
+		}
+	}
+
+    #[test]
+    fn cubic_window()
+	{
+		let mut cc = Cubic::new();
+		cc.init(1000, 0.0);
+		while cc.cwnd() < 100_000 {
+			let cwnd = cc.cwnd();
+			cc.on_ack(1000, cwnd, Some(0.1), 0.0);		// slow start
+		}
+		assert_eq!(100_000, cc.cwnd());
+
+		// Loss reduces the window by beta.
+		cc.on_fast_retransmit(100_000, 1.0);
+		cc.on_recovery_end(1.0);
+		assert_eq!(70_000, cc.ssthresh());
+		assert_eq!(70_000, cc.cwnd());
+
+		// Growth is concave until the window gets back to where it was when the loss
+		// happened (which takes K = cbrt(30/0.4) = 4.2s).
+		let mut now = 1.0;
+		ack_rounds(&mut cc, 10, &mut now);
+		let first = cc.cwnd();
+		assert!(first > 80_000, "cwnd = {}", first);
+
+		ack_rounds(&mut cc, 30, &mut now);
+		let plateau = cc.cwnd();
+		assert!(plateau > 97_000 && plateau <= 100_000, "cwnd = {}", plateau);
+
+		ack_rounds(&mut cc, 10, &mut now);
+		assert!(cc.cwnd() - plateau < 2000, "cwnd = {}", cc.cwnd());
+
+		// And then convex as it probes for more bandwidth.
+		ack_rounds(&mut cc, 30, &mut now);
+		assert!(cc.cwnd() > 110_000, "cwnd = {}", cc.cwnd());
+
+		// A loss before the window regains the old maximum releases bandwidth
+		// for other flows (fast convergence).
+		let mut cc = Cubic::new();
+		cc.init(1000, 0.0);
+		cc.w_max = 100.0;
+		cc.cwnd = 80_000;
+		cc.ssthresh = 70_000;
+		cc.on_timeout(80_000, 1.0);
+		assert_eq!(56_000, cc.ssthresh());
+		assert_eq!(1000, cc.cwnd());
+		assert!((cc.w_max - 68.0).abs() < 1.0e-6, "w_max = {}", cc.w_max);
+    }
+
+    #[test]
+    fn bbr_modes()
+	{
+		// Acks arrive every 10 ms with an RTT of 100 ms so the bandwidth is 100 KB/s
+		// and the BDP is 10 KB.
+		let mut cc = Bbr::new();
+		cc.init(1000, 0.0);
+		let mut now = 0.0;
+		while cc.mode == BbrMode::Startup && now < 1.0 {
+			now += 0.01;
+			let cwnd = cc.cwnd();
+			cc.on_ack(1000, cwnd, Some(0.1), now);
+		}
+
+		// Startup ends after three rounds without a bandwidth increase.
+		assert_eq!(BbrMode::Drain, cc.mode);
+		assert!(now > 0.35 && now < 0.45, "now = {}", now);
+		assert!(cc.cwnd() >= 9900 && cc.cwnd() <= 10_000, "cwnd = {}", cc.cwnd());
+
+		// Drain lasts until the data in flight drops to the BDP.
+		now += 0.01;
+		cc.on_ack(1000, 20_000, Some(0.1), now);
+		assert_eq!(BbrMode::Drain, cc.mode);
+		now += 0.01;
+		cc.on_ack(1000, 9000, Some(0.1), now);
+		assert_eq!(BbrMode::ProbeBandwidth, cc.mode);
+
+		// Probe bandwidth cycles through the gains, one per round.
+		now += 0.01;
+		cc.on_ack(1000, 9000, Some(0.1), now);
+		assert_eq!(0, cc.cycle_index);
+		assert!(cc.cwnd() >= 24_900 && cc.cwnd() <= 25_000, "cwnd = {}", cc.cwnd());
+
+		let cycle_start = now;
+		while cc.cycle_index == 0 && now < cycle_start + 1.0 {
+			now += 0.01;
+			cc.on_ack(1000, 9000, Some(0.1), now);
+		}
+		assert_eq!(1, cc.cycle_index);
+		assert!(cc.cwnd() >= 14_900 && cc.cwnd() <= 15_000, "cwnd = {}", cc.cwnd());
+
+		// Loss doesn't shrink the window.
+		cc.on_fast_retransmit(15_000, now);
+		assert!(cc.cwnd() >= 14_900, "cwnd = {}", cc.cwnd());
+    }
+}
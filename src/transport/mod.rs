@@ -15,9 +15,19 @@
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 
 //! This is the layer responsible for endpoint to endpoint communication.
+pub use self::congestion::*;
+pub use self::pseudo_header::*;
 pub use self::socket::*;
+pub use self::tcb::*;
+pub use self::tcp::*;
+pub use self::tcp_header::*;
 pub use self::udp::*;
 
+mod congestion;
+mod pseudo_header;
 mod socket;
+mod tcb;
+mod tcp;
+mod tcp_header;
 mod udp;
 
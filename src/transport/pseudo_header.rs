@@ -0,0 +1,69 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::*;
+
+/// Returns the partial checksum for the pseudo-header UDP and TCP include in their
+/// checksums. Len is the length of the transport header plus its payload. For IPv6 this
+/// is the RFC 8200 pseudo-header.
+pub fn pseudo_header_checksum(len: usize, info: &InternetInfo) -> u32
+{
+	match info.dst_addr {
+		IPAddress::IPv4(dst_addr) => {
+			match info.src_addr {
+				IPAddress::IPv4(src_addr) => {
+					let mut header = Header::with_capacity(12);
+					header.push8(src_addr[0]);		// source IP
+					header.push8(src_addr[1]);
+					header.push8(src_addr[2]);
+					header.push8(src_addr[3]);
+
+					header.push8(dst_addr[0]);		// destination IP
+					header.push8(dst_addr[1]);
+					header.push8(dst_addr[2]);
+					header.push8(dst_addr[3]);
+
+					header.push8(0);				// zeros
+					header.push8(info.protocol.as_u8());
+
+					header.push16(len as u16);		// header len + data len
+
+					header.start_checksum(0)
+				},
+				IPAddress::IPv6(_) => panic!("InternetInfo has mixed IPv4 and IPv6 addresses")
+			}
+		},
+		IPAddress::IPv6(dst_addr) => {
+			match info.src_addr {
+				IPAddress::IPv6(src_addr) => {
+					let mut header = Header::with_capacity(40);
+					header.push_bytes(&src_addr);	// source IP
+					header.push_bytes(&dst_addr);	// destination IP
+
+					header.push32(len as u32);		// upper-layer packet length
+
+					header.push8(0);				// zeros
+					header.push8(0);
+					header.push8(0);
+					header.push8(info.protocol.as_u8());
+
+					header.start_checksum(0)
+				},
+				IPAddress::IPv4(_) => panic!("InternetInfo has mixed IPv4 and IPv6 addresses")
+			}
+		}
+	}
+}
@@ -14,6 +14,26 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
 use common::*;
+use transport::*;
+
+/// Ports at or above this are used when an app doesn't specify a port. See RFC 6335.
+pub const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Returns the next ephemeral port for which in_use returns false (starting at next_port
+/// and wrapping around) or None if every port in the ephemeral range is in use.
+pub fn ephemeral_port<F>(next_port: &mut u16, in_use: F) -> Option<u16>
+	where F: Fn(u16) -> bool
+{
+	let count = (u16::max_value() - EPHEMERAL_PORT_START) as usize + 1;
+	for _ in 0..count {
+		let port = *next_port;
+		*next_port = if port == u16::max_value() {EPHEMERAL_PORT_START} else {port + 1};
+		if !in_use(port) {
+			return Some(port)
+		}
+	}
+	None
+}
 
 /// QoS is a big mess and the semantics have changed from ToS to QoS to DSCP. We follow
 /// mac82011's lead (see https://wireless.wiki.kernel.org/en/developers/documentation/mac80211/queues)
@@ -35,6 +55,7 @@ pub enum QoS	// See 8.4.2.31 EDCA Parameter Set element
 }
 
 // See http://man7.org/linux/man-pages/man7/ip.7.html and https://linux.die.net/man/7/socket
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct SocketOptions
 {
 	/// This controls packet precedence when the MAC queues start backing up. Equivalent to 
@@ -50,43 +71,75 @@ pub struct SocketOptions
 	/// ICMP Fragmentation Needed packet will be sent back. Equivalent to the IP_DONTFRAG (BSD) 
 	/// and IP_MTU_DISCOVER (linux) options.
 	pub dont_fragment: bool,
+
+	/// Algorithm used by TCP connections. Defaults to Cubic (which is what Linux uses).
+	/// Equivalent to Linux's TCP_CONGESTION option.
+	pub congestion_control: CongestionControl,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocketKind
+{
+	/// UDP
+	Datagram,
+
+	/// TCP
+	Stream,
+}
+
+/// One end of a conversation. These are created using [`AppComponent`]'s open method and
+/// the transport events apps receive can be converted into a Socket using its recv method.
 // See http://elixir.free-electrons.com/linux/latest/source/include/net/sock.h#L118
-// pub struct Socket
-// {
-// 	/// TCP, UDP, IGMP, OSPF, etc.
-// 	pub protocol: u8,
-	
-// 	/// Address of an interface on the local machine.
-// 	pub source_addr: IPAddress,
-	
-// 	/// The destination of the packet.
-// 	pub dest_addr: IPAddress,
-
-// 	pub options: SocketOptions,
-// }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Socket
+{
+	pub kind: SocketKind,
+
+	/// Address of an interface on the local machine. This is the unspecified address,
+	/// e.g. 0.0.0.0, until the socket is connected.
+	pub local_addr: IPAddress,
+
+	/// Zero means an ephemeral port will be used.
+	pub local_port: u16,
+
+	/// Address the port was bound to. Unlike local_addr this isn't changed by connect so
+	/// it's what close uses to unbind the port.
+	pub bound_addr: IPAddress,
+
+	/// Set by connect.
+	pub remote_addr: IPAddress,
+	pub remote_port: u16,
+
+	pub options: SocketOptions,
+}
 
 impl SocketOptions
 {
 	pub fn with_addr(_: IPAddress) -> Self
 	{	
-		SocketOptions{qos: QoS::BestEffort, ttl: 255, dont_fragment: false}	// TODO: set ttl to 1 for multicast
+		SocketOptions{qos: QoS::BestEffort, ttl: 255, dont_fragment: false, congestion_control: CongestionControl::Cubic}	// TODO: set ttl to 1 for multicast
 	}
 }
 
-// impl Socket
-// {
-// 	pub fn new(protocol: u8, dest_addr: IPAddress) -> Socket
-// 	{	
-// 		assert!(protocol != RESERVED);
-
-// 		let options = SocketOptions::with_addr(dest_addr);
-// 		Socket {
-// 			protocol, 
-// 			source_addr: IPAddress::IPv4([10, 0, 0, 1]),	// TODO: set this for real
-// 			dest_addr,
-// 			options,
-// 		}
-// 	}
-// }
+impl Socket
+{
+	pub fn new(kind: SocketKind) -> Self
+	{
+		let any = IPAddress::IPv4([0, 0, 0, 0]);
+		Socket {
+			kind,
+			local_addr: any,
+			local_port: 0,
+			bound_addr: any,
+			remote_addr: any,
+			remote_port: 0,
+			options: SocketOptions::with_addr(any),
+		}
+	}
+
+	/// Used with [`TcpComponent`].
+	pub fn connection(&self) -> TcpConnection
+	{
+		TcpConnection::new(self.local_addr, self.local_port, self.remote_addr, self.remote_port)
+	}
+}
@@ -0,0 +1,857 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use std::cmp;
+use std::collections::VecDeque;
+use transport::*;
+
+/// Identifies a TCP connection from the point of view of one of its endpoints. When
+/// listening the remote address and port are ignored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TcpConnection
+{
+	pub local_addr: IPAddress,
+	pub local_port: u16,
+	pub remote_addr: IPAddress,
+	pub remote_port: u16,
+}
+
+impl TcpConnection
+{
+	pub fn new(local_addr: IPAddress, local_port: u16, remote_addr: IPAddress, remote_port: u16) -> Self
+	{
+		TcpConnection {local_addr, local_port, remote_addr, remote_port}
+	}
+}
+
+/// See the state diagram in section 3.2 of RFC 793.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TcpState
+{
+	Closed,
+	Listen,
+	SynSent,
+	SynReceived,
+	Established,
+	FinWait1,
+	FinWait2,
+	CloseWait,
+	Closing,
+	LastAck,
+	TimeWait,
+}
+
+/// Values from [`TcpComponent`]'s settings.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpSettings
+{
+	pub mss: u16,
+	pub receive_window: u16,
+	pub initial_rto: f64,
+	pub min_rto: f64,
+	pub max_rto: f64,
+	pub max_retries: i64,
+	pub msl: f64,
+}
+
+/// Things a [`Tcb`] asks [`TcpComponent`] to do.
+#[derive(Debug, PartialEq)]
+pub enum TcpAction
+{
+	/// Send a segment with the payload to the remote endpoint.
+	Send(TCPHeader, Vec<u8>),
+
+	/// Send an event with the payload to the app, e.g. "connected" or "send_up".
+	Notify(&'static str, Vec<u8>),
+}
+
+/// Transmission control block: the state for a single connection. This handles the protocol
+/// logic, [`TcpComponent`] handles the plumbing.
+pub struct Tcb
+{
+	pub connection: TcpConnection,
+	pub options: SocketOptions,
+	pub state: TcpState,
+
+	/// If set the retransmission (or 2MSL) timer fires at this time.
+	pub timer: Option<f64>,
+
+	settings: TcpSettings,
+
+	iss: u32,			// initial send sequence number
+	snd_una: u32,		// oldest unacknowledged sequence number
+	snd_nxt: u32,		// next sequence number to send
+	snd_max: u32,		// highest sequence number sent (snd_nxt goes backwards on retransmits)
+	snd_wnd: u32,
+	snd_wl1: u32,		// segment sequence number used for the last window update
+	snd_wl2: u32,		// segment acknowledgment number used for the last window update
+	snd_mss: usize,
+
+	rcv_nxt: u32,
+	rcv_wnd: u32,
+
+	send_buffer: VecDeque<u8>,			// starts at snd_una once the SYN has been acked
+	out_of_order: Vec<(u32, Vec<u8>, bool)>,	// sequence number, data, FIN
+	close_requested: bool,
+	fin_seq: Option<u32>,
+
+	rto: f64,
+	srtt: Option<f64>,
+	rttvar: f64,
+	rtt_sample: Option<(u32, f64)>,		// sequence number and time it was sent
+	retries: i64,
+
+	cc: Box<CongestionAlgorithm>,
+	dup_acks: usize,
+	recover: Option<u32>,		// snd_max when fast recovery started
+}
+
+impl Tcb
+{
+	/// Active open.
+	pub fn connect(connection: TcpConnection, options: SocketOptions, settings: TcpSettings, iss: u32, now: f64, out: &mut Vec<TcpAction>) -> Self
+	{
+		let mut tcb = Tcb::new(connection, options, settings, iss, TcpState::SynSent);
+		tcb.send_syn(now, out);
+		tcb
+	}
+
+	/// Passive open: syn arrived for a listening socket.
+	pub fn accept(connection: TcpConnection, options: SocketOptions, settings: TcpSettings, iss: u32, syn: &TCPHeader, now: f64, out: &mut Vec<TcpAction>) -> Self
+	{
+		let mut tcb = Tcb::new(connection, options, settings, iss, TcpState::SynReceived);
+		tcb.rcv_nxt = syn.seq_num.wrapping_add(1);
+		tcb.snd_mss = tcb.negotiate_mss(syn);
+		tcb.send_syn(now, out);
+		tcb
+	}
+
+	/// Queues data to be sent once the connection is established and the window allows it.
+	pub fn send(&mut self, data: &[u8], now: f64, out: &mut Vec<TcpAction>)
+	{
+		if self.close_requested {
+			return		// RFC 793 says this is an error
+		}
+
+		self.send_buffer.extend(data.iter());
+		self.output(now, out);
+	}
+
+	/// Sends a FIN after the queued data has been sent.
+	pub fn close(&mut self, now: f64, out: &mut Vec<TcpAction>)
+	{
+		self.close_requested = true;
+		match self.state {
+			TcpState::SynSent => self.enter_closed(out, None),
+			TcpState::SynReceived => (),		// FIN is sent after we're established
+			TcpState::Established => self.state = TcpState::FinWait1,
+			TcpState::CloseWait => self.state = TcpState::LastAck,
+			_ => (),
+		}
+		self.output(now, out);
+	}
+
+	/// Discards any queued data and resets the connection.
+	pub fn abort(&mut self, out: &mut Vec<TcpAction>)
+	{
+		match self.state {
+			TcpState::SynReceived | TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 | TcpState::CloseWait => {
+				let mut header = self.header(self.snd_nxt);
+				header.rst = true;
+				out.push(TcpAction::Send(header, Vec::new()));
+			},
+			_ => ()
+		}
+		self.send_buffer.clear();
+		self.enter_closed(out, None);
+	}
+
+	pub fn cwnd(&self) -> usize
+	{
+		self.cc.cwnd()
+	}
+
+	pub fn ssthresh(&self) -> usize
+	{
+		self.cc.ssthresh()
+	}
+
+	/// Smoothed round trip time. This is not set until an ack is timed.
+	pub fn srtt(&self) -> Option<f64>
+	{
+		self.srtt
+	}
+
+	/// Current retransmission timeout.
+	pub fn rto(&self) -> f64
+	{
+		self.rto
+	}
+
+	/// Called when the timer expires.
+	pub fn timeout(&mut self, now: f64, out: &mut Vec<TcpAction>)
+	{
+		self.timer = None;
+		if self.state == TcpState::TimeWait {
+			self.enter_closed(out, Some("closed"));
+			return
+		}
+
+		self.retries += 1;
+		if self.retries > self.settings.max_retries {
+			self.abort(out);
+			out.push(TcpAction::Notify("reset", Vec::new()));
+			return
+		}
+
+		// Exponential backoff and, per Karn's algorithm, don't time retransmitted segments.
+		self.rto = (2.0*self.rto).min(self.settings.max_rto);
+		self.rtt_sample = None;
+
+		match self.state {
+			TcpState::SynSent | TcpState::SynReceived => self.send_syn(now, out),
+			_ => {
+				let in_flight = self.snd_max.wrapping_sub(self.snd_una) as usize;
+				self.cc.on_timeout(in_flight, now);
+				self.recover = None;
+				self.dup_acks = 0;
+
+				self.snd_nxt = self.snd_una;	// go back N
+				self.fin_seq = None;			// so output will resend the FIN
+				let sent = out.len();
+				self.output(now, out);
+
+				if out.len() == sent && !self.send_buffer.is_empty() && self.sending() {
+					// The window is closed so probe it with one byte.
+					let header = self.header(self.snd_una);
+					let byte = vec![self.send_buffer[0]];
+					self.snd_nxt = self.snd_una.wrapping_add(1);
+					self.snd_max = max_seq(self.snd_max, self.snd_nxt);
+					out.push(TcpAction::Send(header, byte));
+				}
+				self.start_timer(now);
+			}
+		}
+	}
+
+	/// Implements the SEGMENT ARRIVES section of RFC 793 for the non-listening states.
+	pub fn segment_arrives(&mut self, header: &TCPHeader, data: Vec<u8>, now: f64, out: &mut Vec<TcpAction>)
+	{
+		match self.state {
+			TcpState::Closed | TcpState::Listen => (),
+			TcpState::SynSent => self.syn_sent_arrives(header, now, out),
+			_ => self.synchronized_arrives(header, data, now, out),
+		}
+	}
+
+	fn syn_sent_arrives(&mut self, header: &TCPHeader, now: f64, out: &mut Vec<TcpAction>)
+	{
+		if header.ack && (seq_le(header.ack_num, self.iss) || seq_gt(header.ack_num, self.snd_max)) {
+			if !header.rst {
+				out.push(TcpAction::Send(reset_for(header, 0), Vec::new()));
+			}
+			return
+		}
+
+		if header.rst {
+			if header.ack {
+				self.enter_closed(out, Some("reset"));
+			}
+			return
+		}
+
+		if header.syn {
+			self.rcv_nxt = header.seq_num.wrapping_add(1);
+			self.snd_mss = self.negotiate_mss(header);
+
+			if header.ack {
+				self.ack_arrives(header, 0, now, out);
+			}
+
+			if seq_gt(self.snd_una, self.iss) {
+				self.establish(header, now, out);
+				self.send_ack(out);
+				self.output(now, out);
+			} else {
+				self.state = TcpState::SynReceived;		// simultaneous open
+				self.send_syn(now, out);
+			}
+		}
+	}
+
+	fn synchronized_arrives(&mut self, header: &TCPHeader, mut data: Vec<u8>, now: f64, out: &mut Vec<TcpAction>)
+	{
+		// Check the sequence number.
+		if !self.acceptable(header, data.len()) {
+			if !header.rst {
+				self.send_ack(out);
+			}
+			return
+		}
+
+		// Check the RST bit.
+		if header.rst {
+			match self.state {
+				TcpState::SynReceived => self.enter_closed(out, None),		// the listener is still around
+				TcpState::Closing | TcpState::LastAck | TcpState::TimeWait => self.enter_closed(out, Some("closed")),
+				_ => self.enter_closed(out, Some("reset")),
+			}
+			return
+		}
+
+		// Check the SYN bit (this is an error since the SYN is in the window).
+		if header.syn {
+			out.push(TcpAction::Send(reset_for(header, data.len()), Vec::new()));
+			self.enter_closed(out, Some("reset"));
+			return
+		}
+
+		// Check the ACK field.
+		if !header.ack {
+			return
+		}
+
+		if self.state == TcpState::SynReceived {
+			if seq_lt(self.snd_una, header.ack_num) && seq_le(header.ack_num, self.snd_max) {
+				self.establish(header, now, out);
+				if self.close_requested {
+					self.state = TcpState::FinWait1;
+				}
+			} else {
+				out.push(TcpAction::Send(reset_for(header, data.len()), Vec::new()));
+				return
+			}
+		}
+
+		if seq_gt(header.ack_num, self.snd_max) {
+			self.send_ack(out);		// acks something we haven't sent
+			return
+		}
+		self.ack_arrives(header, data.len(), now, out);
+
+		if self.fin_acked() {
+			match self.state {
+				TcpState::FinWait1 => self.state = TcpState::FinWait2,
+				TcpState::Closing => self.enter_time_wait(now),
+				TcpState::LastAck => {
+					self.enter_closed(out, Some("closed"));
+					return
+				},
+				_ => ()
+			}
+		}
+
+		// Process the segment text.
+		let mut fin = header.fin;
+		let mut seq = header.seq_num;
+		match self.state {
+			TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => {
+				// Trim off anything we've already received or that is past our window.
+				if seq_lt(seq, self.rcv_nxt) {
+					let old = cmp::min(self.rcv_nxt.wrapping_sub(seq) as usize, data.len());
+					data.drain(..old);
+					seq = seq.wrapping_add(old as u32);
+				}
+				if data.len() > self.rcv_wnd as usize {
+					data.truncate(self.rcv_wnd as usize);
+					fin = false;
+				}
+
+				if !data.is_empty() || fin {
+					if seq == self.rcv_nxt {
+						self.deliver(data, fin, now, out);
+					} else if seq_gt(seq, self.rcv_nxt) {
+						self.out_of_order.push((seq, data, fin));
+					}
+					self.send_ack(out);		// only ack segments that use sequence space so we don't ack acks
+				}
+			},
+			TcpState::TimeWait if fin => {
+				self.send_ack(out);			// our ACK of their FIN was lost
+				self.enter_time_wait(now);
+			},
+			_ => ()
+		}
+
+		self.output(now, out);
+	}
+
+	// Delivers in order data and then any out of order data that is now in order.
+	fn deliver(&mut self, data: Vec<u8>, fin: bool, now: f64, out: &mut Vec<TcpAction>)
+	{
+		let mut data = data;
+		let mut fin = fin;
+		loop {
+			if !data.is_empty() {
+				self.rcv_nxt = self.rcv_nxt.wrapping_add(data.len() as u32);
+				out.push(TcpAction::Notify("send_up", data));
+			}
+			if fin {
+				self.fin_arrives(now, out);
+				self.out_of_order.clear();
+				return
+			}
+
+			let rcv_nxt = self.rcv_nxt;
+			match self.out_of_order.iter().position(|s| seq_le(s.0, rcv_nxt)) {
+				Some(index) => {
+					let (seq, mut next, next_fin) = self.out_of_order.remove(index);
+					let old = cmp::min(rcv_nxt.wrapping_sub(seq) as usize, next.len());
+					next.drain(..old);
+					data = next;
+					fin = next_fin;
+				},
+				None => return
+			}
+		}
+	}
+
+	fn fin_arrives(&mut self, now: f64, out: &mut Vec<TcpAction>)
+	{
+		self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+		out.push(TcpAction::Notify("remote_closed", Vec::new()));
+
+		match self.state {
+			TcpState::SynReceived | TcpState::Established => self.state = TcpState::CloseWait,
+			TcpState::FinWait1 if self.fin_acked() => self.enter_time_wait(now),
+			TcpState::FinWait1 => self.state = TcpState::Closing,
+			TcpState::FinWait2 => self.enter_time_wait(now),
+			_ => ()
+		}
+	}
+
+	fn ack_arrives(&mut self, header: &TCPHeader, data_len: usize, now: f64, out: &mut Vec<TcpAction>)
+	{
+		let ack = header.ack_num;
+		let in_flight = self.snd_max.wrapping_sub(self.snd_una) as usize;
+		if seq_lt(self.snd_una, ack) {
+			let acked = ack.wrapping_sub(self.snd_una) as usize;
+			let syn_acked = self.snd_una == self.iss;
+			let data_acked = if syn_acked {acked - 1} else {acked};
+			let len = cmp::min(data_acked, self.send_buffer.len());
+			self.send_buffer.drain(..len);
+			self.snd_una = ack;
+			if seq_lt(self.snd_nxt, self.snd_una) {
+				self.snd_nxt = self.snd_una;
+			}
+
+			let mut rtt = None;
+			if let Some((seq, sent)) = self.rtt_sample {
+				if seq_le(seq, ack) {
+					rtt = Some(now - sent);
+					self.update_rto(now - sent);
+					self.rtt_sample = None;
+				}
+			}
+			self.retries = 0;
+			self.dup_acks = 0;
+
+			if !syn_acked {
+				match self.recover {
+					Some(recover) if seq_lt(ack, recover) => {
+						if self.cc.on_partial_ack(acked) {
+							self.retransmit_first(out);
+						} else {
+							self.recover = None;
+							self.cc.on_recovery_end(now);
+						}
+					},
+					Some(_) => {
+						self.recover = None;
+						self.cc.on_recovery_end(now);
+					},
+					None => self.cc.on_ack(acked, in_flight, rtt, now),
+				}
+			}
+
+			self.timer = None;
+			if self.snd_una != self.snd_max {
+				self.start_timer(now);
+			}
+
+		} else if ack == self.snd_una && data_len == 0 && !header.syn && !header.fin && header.window as u32 == self.snd_wnd && in_flight > 0 {
+			// Duplicate ack, see RFC 5681 section 3.2.
+			self.dup_acks += 1;
+			if self.dup_acks == 3 && self.recover.is_none() {
+				self.recover = Some(self.snd_max);
+				self.cc.on_fast_retransmit(in_flight, now);
+				self.retransmit_first(out);
+			} else if self.dup_acks > 3 && self.recover.is_some() {
+				self.cc.on_dup_ack();
+			}
+		}
+
+		// Update the send window using the most recent segment.
+		if seq_lt(self.snd_wl1, header.seq_num) || (self.snd_wl1 == header.seq_num && seq_le(self.snd_wl2, ack)) {
+			self.snd_wnd = header.window as u32;
+			self.snd_wl1 = header.seq_num;
+			self.snd_wl2 = ack;
+		}
+	}
+
+	// See RFC 6298.
+	fn update_rto(&mut self, rtt: f64)
+	{
+		match self.srtt {
+			Some(srtt) => {
+				self.rttvar = 0.75*self.rttvar + 0.25*(srtt - rtt).abs();
+				self.srtt = Some(0.875*srtt + 0.125*rtt);
+			},
+			None => {
+				self.srtt = Some(rtt);
+				self.rttvar = rtt/2.0;
+			}
+		}
+		let rto = self.srtt.unwrap() + 4.0*self.rttvar;
+		self.rto = rto.max(self.settings.min_rto).min(self.settings.max_rto);
+	}
+
+	// Sends as much queued data as the window allows followed by a FIN if the app has closed.
+	fn output(&mut self, now: f64, out: &mut Vec<TcpAction>)
+	{
+		if !self.sending() {
+			return
+		}
+
+		loop {
+			let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+			if offset >= self.send_buffer.len() {
+				break
+			}
+
+			let window = cmp::min(self.snd_wnd as usize, self.cc.cwnd());
+			let usable = window.saturating_sub(offset);
+			let len = cmp::min(cmp::min(self.send_buffer.len() - offset, usable), self.snd_mss);
+			if len == 0 {
+				break
+			}
+
+			let mut header = self.header(self.snd_nxt);
+			header.psh = offset + len == self.send_buffer.len();
+			let data: Vec<u8> = self.send_buffer.iter().skip(offset).take(len).cloned().collect();
+			out.push(TcpAction::Send(header, data));
+
+			if self.rtt_sample.is_none() && !seq_lt(self.snd_nxt, self.snd_max) {
+				self.rtt_sample = Some((self.snd_nxt.wrapping_add(len as u32), now));
+			}
+			self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+			self.snd_max = max_seq(self.snd_max, self.snd_nxt);
+		}
+
+		let data_end = self.snd_una.wrapping_add(self.send_buffer.len() as u32);
+		if self.close_requested && self.fin_seq.is_none() && self.snd_nxt == data_end {
+			let mut header = self.header(data_end);
+			header.fin = true;
+			out.push(TcpAction::Send(header, Vec::new()));
+
+			self.fin_seq = Some(data_end);
+			self.snd_nxt = data_end.wrapping_add(1);
+			self.snd_max = max_seq(self.snd_max, self.snd_nxt);
+		}
+
+		if self.snd_una != self.snd_max && self.timer.is_none() {
+			self.start_timer(now);
+		}
+	}
+
+	// True if we're in a state where data (or our FIN) may be sent.
+	fn sending(&self) -> bool
+	{
+		match self.state {
+			TcpState::Established | TcpState::CloseWait | TcpState::FinWait1 | TcpState::Closing | TcpState::LastAck => true,
+			_ => false
+		}
+	}
+
+	fn acceptable(&self, header: &TCPHeader, data_len: usize) -> bool
+	{
+		let len = data_len as u32 + header.syn as u32 + header.fin as u32;
+		let end = self.rcv_nxt.wrapping_add(self.rcv_wnd);
+		let in_window = |seq: u32| seq_le(self.rcv_nxt, seq) && seq_lt(seq, end);
+
+		match (len, self.rcv_wnd) {
+			(0, 0) => header.seq_num == self.rcv_nxt,
+			(0, _) => in_window(header.seq_num),
+			(_, 0) => false,
+			(_, _) => in_window(header.seq_num) || in_window(header.seq_num.wrapping_add(len - 1)),
+		}
+	}
+
+	fn establish(&mut self, header: &TCPHeader, now: f64, out: &mut Vec<TcpAction>)
+	{
+		self.cc.init(self.snd_mss, now);
+		self.state = TcpState::Established;
+		self.snd_wnd = header.window as u32;
+		self.snd_wl1 = header.seq_num;
+		self.snd_wl2 = header.ack_num;
+		out.push(TcpAction::Notify("connected", Vec::new()));
+	}
+
+	fn enter_time_wait(&mut self, now: f64)
+	{
+		self.state = TcpState::TimeWait;
+		self.timer = Some(now + 2.0*self.settings.msl);
+	}
+
+	fn enter_closed(&mut self, out: &mut Vec<TcpAction>, event: Option<&'static str>)
+	{
+		self.state = TcpState::Closed;
+		self.timer = None;
+		if let Some(name) = event {
+			out.push(TcpAction::Notify(name, Vec::new()));
+		}
+	}
+
+	fn fin_acked(&self) -> bool
+	{
+		match self.fin_seq {
+			Some(fin) => seq_gt(self.snd_una, fin),
+			None => false
+		}
+	}
+
+	fn negotiate_mss(&self, syn: &TCPHeader) -> usize
+	{
+		let mss = syn.mss.unwrap_or(536);		// RFC 1122 default
+		cmp::min(mss, self.settings.mss) as usize
+	}
+
+	fn start_timer(&mut self, now: f64)
+	{
+		self.timer = Some(now + self.rto);
+	}
+
+	fn send_syn(&mut self, now: f64, out: &mut Vec<TcpAction>)
+	{
+		let mut header = self.header(self.iss);
+		header.syn = true;
+		header.ack = self.state == TcpState::SynReceived;
+		header.mss = Some(self.settings.mss);
+		out.push(TcpAction::Send(header, Vec::new()));
+
+		self.snd_nxt = self.iss.wrapping_add(1);
+		self.snd_max = self.snd_nxt;
+		if self.rtt_sample.is_none() && self.retries == 0 {
+			self.rtt_sample = Some((self.snd_nxt, now));
+		}
+		self.start_timer(now);
+	}
+
+	// Resends the oldest unacknowledged segment.
+	fn retransmit_first(&mut self, out: &mut Vec<TcpAction>)
+	{
+		let len = cmp::min(self.send_buffer.len(), self.snd_mss);
+		if len > 0 {
+			let data = self.send_buffer.iter().take(len).cloned().collect();
+			out.push(TcpAction::Send(self.header(self.snd_una), data));
+		} else if let Some(fin) = self.fin_seq {
+			let mut header = self.header(fin);
+			header.fin = true;
+			out.push(TcpAction::Send(header, Vec::new()));
+		}
+		self.rtt_sample = None;		// Karn's algorithm
+	}
+
+	fn send_ack(&self, out: &mut Vec<TcpAction>)
+	{
+		out.push(TcpAction::Send(self.header(self.snd_nxt), Vec::new()));
+	}
+
+	fn header(&self, seq: u32) -> TCPHeader
+	{
+		let mut header = TCPHeader::new(self.connection.local_port, self.connection.remote_port, seq);
+		if self.state != TcpState::SynSent {
+			header.ack = true;
+			header.ack_num = self.rcv_nxt;
+		}
+		header.window = self.rcv_wnd as u16;
+		header
+	}
+
+	fn new(connection: TcpConnection, options: SocketOptions, settings: TcpSettings, iss: u32, state: TcpState) -> Self
+	{
+		Tcb {
+			connection,
+			options,
+			state,
+			timer: None,
+			settings,
+
+			iss,
+			snd_una: iss,
+			snd_nxt: iss,
+			snd_max: iss,
+			snd_wnd: 0,
+			snd_wl1: 0,
+			snd_wl2: 0,
+			snd_mss: 536,
+
+			rcv_nxt: 0,
+			rcv_wnd: settings.receive_window as u32,
+
+			send_buffer: VecDeque::new(),
+			out_of_order: Vec::new(),
+			close_requested: false,
+			fin_seq: None,
+
+			rto: settings.initial_rto,
+			srtt: None,
+			rttvar: 0.0,
+			rtt_sample: None,
+			retries: 0,
+
+			cc: new_congestion_algorithm(options.congestion_control),
+			dup_acks: 0,
+			recover: None,
+		}
+	}
+}
+
+/// Returns the RST to send in response to a segment that doesn't belong to a connection.
+pub fn reset_for(header: &TCPHeader, data_len: usize) -> TCPHeader
+{
+	if header.ack {
+		let mut reset = TCPHeader::new(header.dst_port, header.src_port, header.ack_num);
+		reset.rst = true;
+		reset
+	} else {
+		let len = data_len as u32 + header.syn as u32 + header.fin as u32;
+		let mut reset = TCPHeader::new(header.dst_port, header.src_port, 0);
+		reset.ack_num = header.seq_num.wrapping_add(len);
+		reset.rst = true;
+		reset.ack = true;
+		reset
+	}
+}
+
+// Sequence numbers wrap so they have to be compared using modular arithmetic.
+fn seq_lt(a: u32, b: u32) -> bool
+{
+	(a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool
+{
+	a == b || seq_lt(a, b)
+}
+
+fn seq_gt(a: u32, b: u32) -> bool
+{
+	seq_lt(b, a)
+}
+
+fn max_seq(a: u32, b: u32) -> u32
+{
+	if seq_lt(a, b) {b} else {a}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+	#[cfg(test)]
+	fn settings() -> TcpSettings
+	{
+		TcpSettings {mss: 1460, receive_window: 65535, initial_rto: 1.0, min_rto: 0.2, max_rto: 60.0, max_retries: 8, msl: 30.0}
+	}
+
+	// Delivers segments back and forth until both sides are quiet. Returns the notifications
+	// for the client and the server.
+	#[cfg(test)]
+	fn pump(client: &mut Tcb, server: &mut Tcb, mut to_server: Vec<TcpAction>, mut to_client: Vec<TcpAction>) -> (Vec<TcpAction>, Vec<TcpAction>)
+	{
+		let mut client_notes = Vec::new();
+		let mut server_notes = Vec::new();
+		while !to_server.is_empty() || !to_client.is_empty() {
+			let mut out = Vec::new();
+			for action in to_server.drain(..) {
+				match action {
+					TcpAction::Send(header, data) => server.segment_arrives(&header, data, 0.0, &mut out),
+					note => client_notes.push(note),
+				}
+			}
+			to_client.extend(out);
+
+			let mut out = Vec::new();
+			for action in to_client.drain(..) {
+				match action {
+					TcpAction::Send(header, data) => client.segment_arrives(&header, data, 0.0, &mut out),
+					note => server_notes.push(note),
+				}
+			}
+			to_server = out;
+		}
+		(client_notes, server_notes)
+	}
+
+    #[test]
+    fn connection_lifetime()
+	{
+		let a = IPAddress::IPv4([10, 0, 0, 1]);
+		let b = IPAddress::IPv4([10, 0, 0, 2]);
+		let options = SocketOptions::with_addr(b);
+
+		// three-way handshake
+		let mut out = Vec::new();
+		let mut client = Tcb::connect(TcpConnection::new(a, 49152, b, 80), options, settings(), 1000, 0.0, &mut out);
+		let syn = match out.remove(0) {
+			TcpAction::Send(header, _) => header,
+			action => panic!("expected a SYN not {:?}", action)
+		};
+		assert!(syn.syn && !syn.ack);
+		assert_eq!(Some(1460), syn.mss);
+
+		let mut server_out = Vec::new();
+		let mut server = Tcb::accept(TcpConnection::new(b, 80, a, 49152), options, settings(), 5000, &syn, 0.0, &mut server_out);
+		let (c, s) = pump(&mut client, &mut server, Vec::new(), server_out);
+		assert_eq!(vec![TcpAction::Notify("connected", Vec::new())], c);
+		assert_eq!(vec![TcpAction::Notify("connected", Vec::new())], s);
+		assert_eq!(TcpState::Established, client.state);
+		assert_eq!(TcpState::Established, server.state);
+
+		// data is split into MSS sized segments
+		let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+		let mut out = Vec::new();
+		client.send(&data, 0.0, &mut out);
+		assert_eq!(3, out.len());
+
+		let (c, s) = pump(&mut client, &mut server, out, Vec::new());
+		assert!(c.is_empty());
+		let received: Vec<u8> = s.into_iter().flat_map(|n| match n {
+			TcpAction::Notify("send_up", data) => data,
+			_ => Vec::new()
+		}).collect();
+		assert_eq!(data, received);
+		assert_eq!(None, client.timer);		// everything has been acked
+
+		// client closes first so it winds up in TIME-WAIT
+		let mut out = Vec::new();
+		client.close(0.0, &mut out);
+		let (_, s) = pump(&mut client, &mut server, out, Vec::new());
+		assert_eq!(vec![TcpAction::Notify("remote_closed", Vec::new())], s);
+		assert_eq!(TcpState::FinWait2, client.state);
+		assert_eq!(TcpState::CloseWait, server.state);
+
+		let mut out = Vec::new();
+		server.close(0.0, &mut out);
+		let (c, s) = pump(&mut client, &mut server, Vec::new(), out);
+		assert_eq!(vec![TcpAction::Notify("remote_closed", Vec::new())], c);
+		assert_eq!(vec![TcpAction::Notify("closed", Vec::new())], s);
+		assert_eq!(TcpState::TimeWait, client.state);
+		assert_eq!(TcpState::Closed, server.state);
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::*;
+use score::*;
+use std::thread;
+use transport::*;
+
+/// Implements RFC 793 TCP (with the RFC 1122 and RFC 6298 fixes). Apps send "listen",
+/// "connect", "send_down", "close", and "abort" events to upper_in and receive "connected",
+/// "send_up", "remote_closed", "closed", and "reset" events from upper_out. For "connect"
+/// the local port may be zero in which case an unused ephemeral port is picked (if they
+/// are all in use the app gets a "reset" event). The SocketOptions
+/// passed to "listen" and "connect" are used for the lifetime of the connection (and are
+/// included in the events sent to upper_out). There
+/// are several component settings:
+/// - **mss** is the maximum segment size advertised to peers. Defaults to 1460.
+/// - **receive_window** is the window advertised to peers. Defaults to 65535.
+/// - **initial_rto** is the retransmission timeout used before RTT has been measured. Defaults to 1.0.
+/// - **min_rto** is the smallest retransmission timeout. Defaults to 0.2.
+/// - **max_rto** is the largest retransmission timeout. Defaults to 60.0.
+/// - **max_retries** is the number of retransmissions before the connection is reset. Defaults to 8.
+/// - **msl** is the maximum segment lifetime in seconds, TIME-WAIT lasts twice this. Defaults to 30.0.
+///
+/// The congestion control algorithm is selected using [`SocketOptions`]. The state of each
+/// connection is written to the store using names like "cwnd_49152_80" where the numbers
+/// are the local and remote ports: cwnd and ssthresh (in bytes) and srtt and rto (in seconds).
+pub struct TcpComponent
+{
+	data: ThreadData,
+
+	/// Listens for "listen", "connect", "send_down", "close", and "abort" events.
+	pub upper_in: InPort<(TcpConnection, SocketOptions, Packet)>,
+	pub upper_out: OutPort<(TcpConnection, SocketOptions, Packet)>,
+
+	/// Listens for "send_up" events.
+	pub lower_in: InPort<(InternetInfo, Packet)>,
+	pub lower_out: OutPort<(InternetInfo, SocketOptions, Packet)>,
+
+	/// Listens for "icmp_error" events for segments we sent.
+	pub icmp_in: InPort<(InternetInfo, IcmpMessage, Packet)>,
+}
+
+impl TcpComponent
+{
+	pub fn new(sim: &mut Simulation, parent_id: ComponentID) -> Self
+	{
+		let (id, data) = sim.add_active_component("TCP", parent_id);
+		TcpComponent {
+			data: data,
+
+			upper_in: InPort::with_port_name(id, "upper_in"),
+			lower_out: OutPort::new(),
+
+			lower_in: InPort::with_port_name(id, "lower_in"),
+			upper_out: OutPort::new(),
+
+			icmp_in: InPort::with_port_name(id, "icmp_in"),
+		}
+	}
+
+	pub fn start(self)
+	{
+		let mut settings = TcpSettings {
+			mss: 1460,
+			receive_window: 65535,
+			initial_rto: 1.0,
+			min_rto: 0.2,
+			max_rto: 60.0,
+			max_retries: 8,
+			msl: 30.0,
+		};
+		let mut listeners: Vec<(TcpConnection, SocketOptions)> = Vec::new();
+		let mut tcbs: Vec<Tcb> = Vec::new();
+		let mut next_port = EPHEMERAL_PORT_START;
+		let mut counters = Counters {next_id: 0, segments_sent: 0};
+
+		thread::spawn(move || {
+			process_events!(self.data, event, state, effector,
+				"init 0" => {
+					settings.mss = int_setting(self.data.id, &state, &mut effector, "mss", settings.mss as i64) as u16;
+					settings.receive_window = int_setting(self.data.id, &state, &mut effector, "receive_window", settings.receive_window as i64) as u16;
+					settings.initial_rto = float_setting(self.data.id, &state, &mut effector, "initial_rto", settings.initial_rto);
+					settings.min_rto = float_setting(self.data.id, &state, &mut effector, "min_rto", settings.min_rto);
+					settings.max_rto = float_setting(self.data.id, &state, &mut effector, "max_rto", settings.max_rto);
+					settings.max_retries = int_setting(self.data.id, &state, &mut effector, "max_retries", settings.max_retries);
+					settings.msl = float_setting(self.data.id, &state, &mut effector, "msl", settings.msl);
+					effector.set_int("segments_sent", 0);
+					effector.set_int("segments_received", 0);
+					effector.set_int("retransmits", 0);
+				},
+				"listen" => {
+					let (connection, options, _) = event.take_payload::<(TcpConnection, SocketOptions, Packet)>();
+					listeners.push((connection, options));
+				},
+				"connect" => {
+					let (mut connection, options, _) = event.take_payload::<(TcpConnection, SocketOptions, Packet)>();
+					if connection.local_port == 0 {
+						let in_use = |port| tcbs.iter().any(|t| t.connection.local_port == port) || listeners.iter().any(|l| l.0.local_port == port);
+						connection.local_port = ephemeral_port(&mut next_port, in_use).unwrap_or(0);
+					}
+
+					if connection.local_port != 0 {
+						let mut actions = Vec::new();
+						let tcb = Tcb::connect(connection, options, settings, initial_seq(&state), state.time, &mut actions);
+						tcbs.push(tcb);
+						let index = tcbs.len() - 1;
+						self.process(&mut effector, &state, &mut tcbs, index, None, actions, &mut counters);
+					} else {
+						log_warning!(effector, "can't connect to {:?} (all ephemeral ports are in use)", connection.remote_addr);
+						counters.next_id += 1;
+						let packet = Packet::new("TCP data", &format!("#>{}.{}", self.data.id, counters.next_id));
+						self.upper_out.send_payload(&mut effector, "reset", (connection, options, packet));
+					}
+				},
+				"send_down" => {
+					let (connection, _, packet) = event.take_payload::<(TcpConnection, SocketOptions, Packet)>();
+					let data: Vec<u8> = packet.iter().cloned().collect();
+					self.request(&mut effector, &state, &mut tcbs, &connection, &mut counters, |tcb, actions| tcb.send(&data, state.time, actions));
+				},
+				"close" => {
+					let (connection, _, _) = event.take_payload::<(TcpConnection, SocketOptions, Packet)>();
+					if tcbs.iter().any(|t| t.connection == connection) {
+						self.request(&mut effector, &state, &mut tcbs, &connection, &mut counters, |tcb, actions| tcb.close(state.time, actions));
+					} else {
+						listeners.retain(|l| l.0 != connection);
+					}
+				},
+				"abort" => {
+					let (connection, _, _) = event.take_payload::<(TcpConnection, SocketOptions, Packet)>();
+					self.request(&mut effector, &state, &mut tcbs, &connection, &mut counters, |tcb, actions| tcb.abort(actions));
+				},
+				"timer" => {
+					let connection = event.take_payload::<TcpConnection>();
+					if let Some(index) = tcbs.iter().position(|t| t.connection == connection) {
+						let expired = match tcbs[index].timer {
+							Some(time) => time <= state.time + TIMER_SLOP,
+							None => false
+						};
+						if expired {
+							let mut actions = Vec::new();
+							if tcbs[index].state != TcpState::TimeWait {
+								let count = state.get_int(self.data.id, "retransmits");
+								effector.set_int("retransmits", count + 1);
+							}
+							tcbs[index].timeout(state.time, &mut actions);
+							self.process(&mut effector, &state, &mut tcbs, index, None, actions, &mut counters);
+						}
+					}
+				},
+				"send_up" => {
+					let (info, mut packet) = event.take_payload::<(InternetInfo, Packet)>();
+					match TCPHeader::pop(&mut packet, &info) {
+						Ok(header) => {
+							let count = state.get_int(self.data.id, "segments_received");
+							effector.set_int("segments_received", count + 1);
+
+							let len = packet.len();
+							let data = packet.pop_bytes(len);
+							let connection = TcpConnection::new(info.dst_addr, header.dst_port, info.src_addr, header.src_port);
+							if let Some(index) = tcbs.iter().position(|t| t.connection == connection) {
+								let mut actions = Vec::new();
+								let timer = tcbs[index].timer;
+								tcbs[index].segment_arrives(&header, data, state.time, &mut actions);
+								self.process(&mut effector, &state, &mut tcbs, index, timer, actions, &mut counters);
+
+							} else if let Some(&(_, options)) = listeners.iter().find(|l| listening(&l.0, &connection)) {
+								if header.rst {
+									// nothing to do
+								} else if header.ack {
+									self.send_segment(&mut effector, &connection, &options, reset_for(&header, len), Vec::new(), &mut counters);
+								} else if header.syn {
+									let mut actions = Vec::new();
+									let tcb = Tcb::accept(connection, options, settings, initial_seq(&state), &header, state.time, &mut actions);
+									tcbs.push(tcb);
+									let index = tcbs.len() - 1;
+									self.process(&mut effector, &state, &mut tcbs, index, None, actions, &mut counters);
+								}
+
+							} else if !header.rst {
+								let options = SocketOptions::with_addr(connection.remote_addr);
+								self.send_segment(&mut effector, &connection, &options, reset_for(&header, len), Vec::new(), &mut counters);
+							}
+						},
+						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
+					}
+				},
+				"icmp_error" => {
+					let (info, message, mut packet) = event.take_payload::<(InternetInfo, IcmpMessage, Packet)>();
+					if packet.len() >= 4 {
+						let src_port = packet.pop16();
+						let dst_port = packet.pop16();
+						let connection = TcpConnection::new(info.src_addr, src_port, info.dst_addr, dst_port);
+						if let Some(index) = tcbs.iter().position(|t| t.connection == connection) {
+							// RFC 1122 says that only hard errors should abort connections and that
+							// even those can be ignored once the connection is established.
+							let hard = match message {
+								IcmpMessage::DestinationUnreachable(Unreachable::Protocol) => true,
+								IcmpMessage::DestinationUnreachable(Unreachable::Port) => true,
+								_ => false
+							};
+							if hard && tcbs[index].state == TcpState::SynSent {
+								log_info!(effector, "aborting {:?} ({:?})", connection, message);
+								let mut actions = Vec::new();
+								tcbs[index].abort(&mut actions);
+								actions.push(TcpAction::Notify("reset", Vec::new()));
+								self.process(&mut effector, &state, &mut tcbs, index, None, actions, &mut counters);
+							} else {
+								log_info!(effector, "ignoring {:?} for {:?}", message, connection);
+							}
+						}
+					}
+				}
+			);
+		});
+	}
+
+	// Handles an app request for an existing connection.
+	fn request<F>(&self, effector: &mut Effector, state: &SimState, tcbs: &mut Vec<Tcb>, connection: &TcpConnection, counters: &mut Counters, f: F)
+		where F: FnOnce(&mut Tcb, &mut Vec<TcpAction>)
+	{
+		match tcbs.iter().position(|t| t.connection == *connection) {
+			Some(index) => {
+				let mut actions = Vec::new();
+				let timer = tcbs[index].timer;
+				f(&mut tcbs[index], &mut actions);
+				self.process(effector, state, tcbs, index, timer, actions, counters);
+			},
+			None => log_warning!(effector, "ignoring request for unknown connection {:?}", connection)
+		}
+	}
+
+	// Executes the actions a Tcb asked for, schedules its timer if it changed, and removes
+	// the Tcb if it has closed.
+	fn process(&self, effector: &mut Effector, state: &SimState, tcbs: &mut Vec<Tcb>, index: usize, old_timer: Option<f64>, actions: Vec<TcpAction>, counters: &mut Counters)
+	{
+		for action in actions {
+			match action {
+				TcpAction::Send(header, data) => {
+					let tcb = &tcbs[index];
+					self.send_segment(effector, &tcb.connection, &tcb.options, header, data, counters);
+				},
+				TcpAction::Notify(name, data) => {
+					counters.next_id += 1;
+					let mut packet = Packet::new("TCP data", &format!("#>{}.{}", self.data.id, counters.next_id));
+					packet.push_back_bytes(&data);
+					self.upper_out.send_payload(effector, name, (tcbs[index].connection, tcbs[index].options, packet));
+				}
+			}
+		}
+
+		{
+			let tcb = &tcbs[index];
+			if tcb.state != TcpState::Closed {
+				let suffix = format!("{}_{}", tcb.connection.local_port, tcb.connection.remote_port);
+				effector.set_int(&format!("cwnd_{}", suffix), tcb.cwnd() as i64);
+				effector.set_int(&format!("ssthresh_{}", suffix), tcb.ssthresh() as i64);
+				if let Some(srtt) = tcb.srtt() {
+					effector.set_float(&format!("srtt_{}", suffix), srtt);
+				}
+				effector.set_float(&format!("rto_{}", suffix), tcb.rto());
+			}
+		}
+
+		if tcbs[index].timer != old_timer {
+			if let Some(time) = tcbs[index].timer {
+				let event = Event::with_payload("timer", tcbs[index].connection);
+				effector.schedule_after_secs(event, self.data.id, time - state.time);
+			}
+		}
+
+		if tcbs[index].state == TcpState::Closed {
+			tcbs.remove(index);
+		}
+	}
+
+	fn send_segment(&self, effector: &mut Effector, connection: &TcpConnection, options: &SocketOptions, header: TCPHeader, data: Vec<u8>, counters: &mut Counters)
+	{
+		counters.next_id += 1;
+		let name = if data.is_empty() {format!("TCP {}", header.flags())} else {format!("TCP {} bytes", data.len())};
+		let mut packet = Packet::new(&name, &format!("#>{}.{}", self.data.id, counters.next_id));
+		packet.push_back_bytes(&data);
+
+		let info = InternetInfo::new(Protocol::Standard(StandardProtocol::TCP), connection.local_addr, connection.remote_addr);
+		header.push(&mut packet, &info);
+		self.lower_out.send_payload(effector, "send_down", (info, *options, packet));
+
+		counters.segments_sent += 1;
+		effector.set_int("segments_sent", counters.segments_sent);
+	}
+}
+
+struct Counters
+{
+	next_id: i64,
+	segments_sent: i64,
+}
+
+// Timers are scheduled relative to the current time so allow for rounding error.
+const TIMER_SLOP: f64 = 1.0e-9;
+
+// RFC 793 suggests a clock that increments every 4 microseconds. Using the simulation
+// time keeps runs deterministic.
+fn initial_seq(state: &SimState) -> u32
+{
+	((state.time*250_000.0) as u64 & 0xFFFF_FFFF) as u32
+}
+
+fn listening(listener: &TcpConnection, connection: &TcpConnection) -> bool
+{
+	if listener.local_port != connection.local_port {
+		return false
+	}
+
+	listener.local_addr.is_unspecified() || listener.local_addr == connection.local_addr
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn listeners()
+	{
+		let ipv4 = IPAddress::IPv4([10, 0, 0, 1]);
+		let ipv6 = IPAddress::IPv6([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+		let remote = IPAddress::IPv6([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+		let to_ipv4 = TcpConnection::new(ipv4, 80, IPAddress::IPv4([10, 0, 0, 2]), 50000);
+		let to_ipv6 = TcpConnection::new(ipv6, 80, remote, 50000);
+
+		for &any in [IPAddress::IPv4([0, 0, 0, 0]), IPAddress::IPv6([0; 16])].iter() {
+			let listener = TcpConnection::new(any, 80, any, 0);
+			assert!(listening(&listener, &to_ipv4));
+			assert!(listening(&listener, &to_ipv6));
+		}
+
+		let listener = TcpConnection::new(ipv6, 80, ipv6, 0);
+		assert!(!listening(&listener, &to_ipv4));
+		assert!(listening(&listener, &to_ipv6));
+
+		let listener = TcpConnection::new(ipv6, 81, ipv6, 0);
+		assert!(!listening(&listener, &to_ipv6));
+	}
+
+    #[test]
+    fn ephemeral_ports()
+	{
+		// Ports used by listeners and live connections are skipped.
+		let in_use = [EPHEMERAL_PORT_START, EPHEMERAL_PORT_START + 1];
+		let mut next_port = EPHEMERAL_PORT_START;
+		assert_eq!(ephemeral_port(&mut next_port, |p| in_use.contains(&p)), Some(EPHEMERAL_PORT_START + 2));
+
+		// Including after wrapping around.
+		let mut next_port = u16::max_value();
+		assert_eq!(ephemeral_port(&mut next_port, |p| p == u16::max_value() || in_use.contains(&p)), Some(EPHEMERAL_PORT_START + 2));
+		assert_eq!(ephemeral_port(&mut next_port, |_| true), None);
+	}
+}
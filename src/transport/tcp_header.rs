@@ -0,0 +1,207 @@
+// Copyright (C) 2017 Jesse Jones
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301, USA.
+use common::*;
+use internet::*;
+use transport::*;
+
+/// In memory version of the TCP header. When serialized to a [`Packet`] it's packed according to the spec.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TCPHeader
+{
+	/// Usually an ephemeral or well-known port.
+	pub src_port: u16,
+
+	/// An ephemeral or well-known port.
+	pub dst_port: u16,
+
+	/// Sequence number of the first data byte (or of the SYN).
+	pub seq_num: u32,
+
+	/// If ack is set this is the next sequence number the sender expects to receive.
+	pub ack_num: u32,
+
+	pub urg: bool,
+	pub ack: bool,
+	pub psh: bool,
+	pub rst: bool,
+	pub syn: bool,
+	pub fin: bool,
+
+	/// Number of bytes the sender is willing to receive starting at ack_num.
+	pub window: u16,
+
+	pub urgent_ptr: u16,
+
+	/// Maximum segment size option. This should only be set for SYN segments.
+	pub mss: Option<u16>,
+}
+
+// See https://en.wikipedia.org/wiki/Transmission_Control_Protocol#TCP_segment_structure
+impl TCPHeader
+{
+	pub fn new(src_port: u16, dst_port: u16, seq_num: u32) -> Self
+	{
+		TCPHeader {
+			src_port,
+			dst_port,
+			seq_num,
+			ack_num: 0,
+			urg: false,
+			ack: false,
+			psh: false,
+			rst: false,
+			syn: false,
+			fin: false,
+			window: 0,
+			urgent_ptr: 0,
+			mss: None,
+		}
+	}
+
+	/// Returns something like "SYN ACK".
+	pub fn flags(&self) -> String
+	{
+		let names = [(self.syn, "SYN"), (self.fin, "FIN"), (self.rst, "RST"), (self.psh, "PSH"), (self.ack, "ACK"), (self.urg, "URG")];
+		let set: Vec<&str> = names.iter().filter(|n| n.0).map(|n| n.1).collect();
+		set.join(" ")
+	}
+
+	/// Adds a TCP header to the packet.
+	pub fn push(&self, packet: &mut Packet, info: &InternetInfo)
+	{
+		let header_len = if self.mss.is_some() {24} else {20};
+		let total_len = header_len + packet.len();
+
+		let mut header = Header::with_capacity(header_len);
+		header.push16(self.src_port);
+		header.push16(self.dst_port);
+		header.push32(self.seq_num);
+		header.push32(self.ack_num);
+
+		let flags = (self.urg as u16) << 5 | (self.ack as u16) << 4 | (self.psh as u16) << 3 |
+			(self.rst as u16) << 2 | (self.syn as u16) << 1 | self.fin as u16;
+		header.push16(((header_len/4) as u16) << 12 | flags);
+		header.push16(self.window);
+		header.push16(0);				// checksum (this is set for real after we've pushed the header)
+		header.push16(self.urgent_ptr);
+
+		if let Some(mss) = self.mss {
+			header.push8(2);			// kind
+			header.push8(4);			// length
+			header.push16(mss);
+		}
+
+		let crc1 = pseudo_header_checksum(total_len, info);
+		let crc2 = header.start_checksum(crc1);
+		let crc = packet.finish_checksum(packet.len(), crc2);
+		header.data[16] = (crc >> 8) as u8;
+		header.data[17] = (crc & 0xFF) as u8;
+
+		packet.push_header(&header);
+	}
+
+	/// Removes a TCP header (including options) from the packet.
+	pub fn pop(packet: &mut Packet, info: &InternetInfo) -> Result<TCPHeader, String>
+	{
+		let in_len = packet.len();
+		if in_len < 20 {
+			return Err(format!("TCP segment is only {} bytes", in_len))
+		}
+
+		let crc1 = pseudo_header_checksum(in_len, info);
+		let crc = packet.finish_checksum(in_len, crc1);
+		if crc != 0 {
+			return Err("Checksum error".to_string())
+		}
+
+		let src_port = packet.pop16();
+		let dst_port = packet.pop16();
+		let mut header = TCPHeader::new(src_port, dst_port, packet.pop32());
+		header.ack_num = packet.pop32();
+
+		let hw = packet.pop16();
+		let header_len = 4*(hw >> 12) as usize;
+		header.urg = hw & 0x20 != 0;
+		header.ack = hw & 0x10 != 0;
+		header.psh = hw & 0x08 != 0;
+		header.rst = hw & 0x04 != 0;
+		header.syn = hw & 0x02 != 0;
+		header.fin = hw & 0x01 != 0;
+
+		header.window = packet.pop16();
+		let _ = packet.pop16();
+		header.urgent_ptr = packet.pop16();
+
+		if header_len < 20 || header_len > in_len {
+			return Err(format!("TCPHeader.data_offset is {} bytes but the segment is {} bytes", header_len, in_len))
+		}
+
+		let mut options = packet.pop_bytes(header_len - 20);
+		while !options.is_empty() {
+			match options[0] {
+				0 => break,								// end of option list
+				1 => {options.remove(0);},				// no-operation
+				kind => {
+					if options.len() < 2 || options[1] < 2 || options[1] as usize > options.len() {
+						return Err(format!("TCP option {} has a bad length", kind))
+					}
+					if kind == 2 && options[1] == 4 {
+						header.mss = Some((options[2] as u16) << 8 | options[3] as u16);
+					}
+					let len = options[1] as usize;
+					options.drain(..len);				// ignore options we don't support
+				}
+			}
+		}
+
+		Ok(header)
+	}
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn tcp_header_pushing()
+	{
+		let info = InternetInfo::new(Protocol::Standard(StandardProtocol::TCP), IPAddress::IPv4([10, 0, 0, 1]), IPAddress::IPv4([10, 0, 0, 2]));
+
+		let mut header = TCPHeader::new(49152, 80, 0xFFFF_FFF0);
+		header.ack_num = 1000;
+		header.ack = true;
+		header.syn = true;
+		header.window = 65535;
+		header.mss = Some(1460);
+
+		let mut packet = Packet::new("test packet", "1");
+		packet.push_back_bytes(&[1, 2, 3]);
+		header.push(&mut packet, &info);
+		assert_eq!(24 + 3, packet.len());
+
+		let popped = TCPHeader::pop(&mut packet, &info).unwrap();
+		assert_eq!(header, popped);
+		assert_eq!("SYN ACK", popped.flags());
+		assert_eq!(3, packet.len());
+		assert_eq!(1, packet.pop8());
+
+		let mut packet = Packet::new("test packet", "1");
+		header.push(&mut packet, &info);
+		let other = InternetInfo::new(Protocol::Standard(StandardProtocol::TCP), IPAddress::IPv4([10, 0, 0, 1]), IPAddress::IPv4([10, 0, 0, 3]));
+		assert!(TCPHeader::pop(&mut packet, &other).is_err());
+    }
+}
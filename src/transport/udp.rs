@@ -23,6 +23,7 @@ use std::u16;
 use transport::*;
 
 /// In memory version of the UDP header. When serialized to a [`Packet`] it's packed according to the spec.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct UDPHeader
 {
 	/// Usually an ephemeral or well-known port.
@@ -56,7 +57,7 @@ impl UDPHeader
 		header.push16(total_len as u16);
 		header.push16(0);
 
-		let crc1 = UDPHeader::psuedo_header_checksum(packet.len() as u16, info);
+		let crc1 = pseudo_header_checksum(total_len, info);
 		let crc2 = header.start_checksum(crc1);
 		let crc = packet.finish_checksum(packet.len(), crc2);
 		header.data[6] = (crc >> 8) as u8;
@@ -70,7 +71,7 @@ impl UDPHeader
 	{
 		let in_len = packet.len();
 
-		let crc1 = UDPHeader::psuedo_header_checksum((packet.len() - 8) as u16, info);
+		let crc1 = pseudo_header_checksum(in_len, info);
 		let crc = packet.finish_checksum(packet.len(), crc1);
 		if crc != 0 {
 			return Err("Checksum error".to_string())
@@ -88,52 +89,46 @@ impl UDPHeader
 		let header = UDPHeader {src_port, dst_port};
 		Ok(header)
 	}
-
-	fn psuedo_header_checksum(payload_len: u16, info: &InternetInfo) -> u32
-	{
-		match info.dst_addr {
-			IPAddress::IPv4(dst_addr) => {
-				match info.src_addr {
-					IPAddress::IPv4(src_addr) => {
-						let mut header = Header::with_capacity(12);
-						header.push8(src_addr[0]);		// source IP
-						header.push8(src_addr[1]);
-						header.push8(src_addr[2]);
-						header.push8(src_addr[3]);
-
-						header.push8(dst_addr[0]);		// destination IP
-						header.push8(dst_addr[1]);
-						header.push8(dst_addr[2]);
-						header.push8(dst_addr[3]);
-
-						header.push8(0);				// zeros
-						header.push8(17);				// protocol
-
-						header.push16(8 + payload_len);	// header len + data len
-
-						header.start_checksum(0)
-					},
-					IPAddress::IPv6(_) => panic!("InternetInfo has mixed IPv4 and IPv6 addresses")
-				}
-			},
-			IPAddress::IPv6(_) => panic!("IPv6 isn't supported yet")
-		}
-	}
 }
 
 /// Pushes an UDPHeader onto packets moving down the network stack.
 /// Pops off an UDPHeader header for packets moving up the stack.
+/// Apps bind to ports using "bind" events: the header's src_port is the port to bind to
+/// (zero picks an ephemeral port which is returned with a "bound" event) and the info's
+/// src_addr is the local address (0.0.0.0 or :: binds to all IPv4 and IPv6 addresses). Datagrams are delivered
+/// to the app bound to their destination port and datagrams for unbound ports are answered
+/// with ICMP Port Unreachable. Sending from port zero uses an ephemeral port which is
+/// bound to the app the first time it sends that way.
 pub struct UdpComponent
 {
 	data: ThreadData,
 
-	/// Listens for "send_down" events.
-	pub upper_in: InPort<(InternetInfo, SocketOptions, Packet)>,	// TODO: need a port from somewhere, take a Socket?
-	pub upper_out: OutPort<(InternetInfo, Packet)>,	
+	/// Listens for "bind", "unbind", and "send_down" events. Use add_upper to add these.
+	pub upper_ins: Vec<InPort<(InternetInfo, UDPHeader, SocketOptions, Packet)>>,
+
+	/// Sends "bound" and "send_up" events.
+	pub upper_outs: Vec<OutPort<(InternetInfo, UDPHeader, Packet)>>,
+
+	/// Errors for datagrams an app sent are forwarded here using "icmp_error" events.
+	pub icmp_outs: Vec<OutPort<(InternetInfo, IcmpMessage, Packet)>>,
 
 	/// Listens for "send_up" events.
 	pub lower_in: InPort<(InternetInfo, Packet)>,
 	pub lower_out: OutPort<(InternetInfo, SocketOptions, Packet)>,
+
+	/// Listens for "icmp_error" events for datagrams we sent.
+	pub icmp_in: InPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Sends "send_error" events to [`IcmpComponent`].
+	pub error_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+}
+
+struct Binding
+{
+	addr: IPAddress,
+	port: u16,
+	upper: usize,
+	implicit: bool,		// bound by sending from port zero
 }
 
 impl UdpComponent
@@ -144,36 +139,206 @@ impl UdpComponent
 		UdpComponent {
 			data: data,
 
-			upper_in: InPort::with_port_name(id, "upper_in"),
+			upper_ins: Vec::new(),
 			lower_out: OutPort::new(),
 
 			lower_in: InPort::with_port_name(id, "lower_in"),
-			upper_out: OutPort::new(),
+			upper_outs: Vec::new(),
+			icmp_outs: Vec::new(),
+
+			icmp_in: InPort::with_port_name(id, "icmp_in"),
+			error_out: OutPort::new(),
 		}
 	}
+
+	/// Adds ports for a new app and returns their index.
+	pub fn add_upper(&mut self) -> usize
+	{
+		let index = self.upper_ins.len();
+		self.upper_ins.push(InPort::with_port_name(self.data.id, &format!("upper_in_{}", index)));
+		self.upper_outs.push(OutPort::new());
+		self.icmp_outs.push(OutPort::new());
+		index
+	}
 	
 	pub fn start(self)
 	{		
+		let mut bindings: Vec<Binding> = Vec::new();
+		let mut next_port = EPHEMERAL_PORT_START;
+
 		thread::spawn(move || {
 			process_events!(self.data, event, state, effector,
 				"init 0" => {
 				},
+				"bind" => {
+					let upper = self.upper_index(&event.port_name);
+					let (info, mut header, _, _) = event.take_payload::<(InternetInfo, UDPHeader, SocketOptions, Packet)>();
+					if header.src_port == 0 {
+						match ephemeral_port(&mut next_port, |port| is_bound(&bindings, port)) {
+							Some(port) => {
+								header.src_port = port;
+								bindings.push(Binding {addr: info.src_addr, port, upper, implicit: false});
+								let packet = Packet::new("bound", &format!("#>{}", self.data.id));
+								self.upper_outs[upper].send_payload(&mut effector, "bound", (info, header, packet));
+							},
+							None => log_warning!(effector, "all ephemeral ports are bound")
+						}
+
+					} else if find_binding(&bindings, &info.src_addr, header.src_port).is_some() {
+						log_warning!(effector, "port {} is already bound", header.src_port);
+
+					} else {
+						bindings.push(Binding {addr: info.src_addr, port: header.src_port, upper, implicit: false});
+					}
+				},
+				"unbind" => {
+					let upper = self.upper_index(&event.port_name);
+					let (info, header, _, _) = event.take_payload::<(InternetInfo, UDPHeader, SocketOptions, Packet)>();
+					bindings.retain(|b| !(b.port == header.src_port && b.upper == upper && (b.addr == info.src_addr || b.implicit)));
+				},
 				"send_down" => {
-					let (info, options, mut packet) = event.take_payload::<(InternetInfo, SocketOptions, Packet)>();
-					let src_port = 1;	// TODO: use an epheremal port
-					let header = UDPHeader::new(src_port, 19);
-					header.push(&mut packet, &info);
+					let upper = self.upper_index(&event.port_name);
+					let (info, mut header, options, mut packet) = event.take_payload::<(InternetInfo, UDPHeader, SocketOptions, Packet)>();
+					if header.src_port == 0 {
+						let existing = bindings.iter().find(|b| b.upper == upper && b.implicit).map(|b| b.port);
+						if let Some(port) = existing {
+							header.src_port = port;
+						} else if let Some(port) = ephemeral_port(&mut next_port, |port| is_bound(&bindings, port)) {
+							header.src_port = port;
+							bindings.push(Binding {addr: any_addr(&info.src_addr), port, upper, implicit: true});
+						}
+					}
 
-					self.lower_out.send_payload(&mut effector, &event.name, (info, options, packet));
+					if header.src_port != 0 {
+						header.push(&mut packet, &info);
+						self.lower_out.send_payload(&mut effector, &event.name, (info, options, packet));
+					} else {
+						log_warning!(effector, "dropping {} (all ephemeral ports are bound)", packet.name());
+					}
 				},
 				"send_up" => {
 					let (info, mut packet) = event.take_payload::<(InternetInfo, Packet)>();
+					let dst_port = if packet.len() >= 4 {(packet.get(2) as u16) << 8 | packet.get(3) as u16} else {0};
+					let upper = find_binding(&bindings, &info.dst_addr, dst_port);
+					let original = if upper.is_none() {Some(offending_datagram(&info, &packet))} else {None};	// errors need the IP and UDP headers
+
 					match UDPHeader::pop(&mut packet, &info) {
-						Ok(_header) => self.upper_out.send_payload(&mut effector, &event.name, (info, packet)),
+						Ok(header) => match upper {
+							Some(index) => self.upper_outs[index].send_payload(&mut effector, &event.name, (info, header, packet)),
+							None => {
+								log_info!(effector, "dropping {} (port {} isn't bound)", packet.name(), dst_port);
+								match original.unwrap() {
+									Some(original) => {
+										let message = IcmpMessage::DestinationUnreachable(Unreachable::Port);
+										self.error_out.send_payload(&mut effector, "send_error", (info, message, original));
+									},
+									None => log_warning!(effector, "can't send port unreachable for {} (its IP header is missing)", packet.name())
+								}
+							}
+						},
 						Err(mesg) => log_warning!(effector, "pop failed: {}", mesg)
 					}
+				},
+				"icmp_error" => {
+					let (info, message, packet) = event.take_payload::<(InternetInfo, IcmpMessage, Packet)>();
+					let src_port = if packet.len() >= 2 {(packet.get(0) as u16) << 8 | packet.get(1) as u16} else {0};
+					match find_binding(&bindings, &info.src_addr, src_port) {
+						Some(index) => self.icmp_outs[index].send_payload(&mut effector, &event.name, (info, message, packet)),
+						None => log_info!(effector, "ignoring {:?} for unbound port {}", message, src_port)
+					}
 				}
 			);
 		});
 	}
+
+	fn upper_index(&self, port_name: &str) -> usize
+	{
+		assert!(!port_name.is_empty());
+		self.upper_ins.iter().position(|p| p.target_port == port_name).expect("event should have come from an upper_in")
+	}
+}
+
+// Errors quote the IP header which the internet layer leaves in the packet (before the
+// current position), see IcmpComponent and Icmpv6Component.
+fn offending_datagram(info: &InternetInfo, packet: &Packet) -> Option<Packet>
+{
+	let header = match info.src_addr {
+		IPAddress::IPv4(_) => packet.popped_bytes(20),
+		IPAddress::IPv6(_) => popped_ipv6_header(packet),
+	};
+	header.map(|mut bytes| {
+		bytes.extend(packet.iter());
+		let mut original = Packet::new(packet.name(), packet.id());
+		original.push_back_bytes(&bytes);
+		original
+	})
+}
+
+fn any_addr(addr: &IPAddress) -> IPAddress
+{
+	match *addr {
+		IPAddress::IPv4(_) => IPAddress::IPv4([0, 0, 0, 0]),
+		IPAddress::IPv6(_) => IPAddress::IPv6([0; 16]),
+	}
+}
+
+// Bindings to an unspecified address match both IPv4 and IPv6 (endpoints are dual-stack).
+fn find_binding(bindings: &Vec<Binding>, addr: &IPAddress, port: u16) -> Option<usize>
+{
+	bindings.iter()
+		.find(|b| b.port == port && (b.addr.is_unspecified() || b.addr == *addr))
+		.map(|b| b.upper)
+}
+
+fn is_bound(bindings: &Vec<Binding>, port: u16) -> bool
+{
+	bindings.iter().any(|b| b.port == port)
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn ephemeral_ports()
+	{
+		let mut bindings = Vec::new();
+		let mut next_port = u16::MAX;
+		assert_eq!(ephemeral_port(&mut next_port, |port| is_bound(&bindings, port)), Some(u16::MAX));
+		assert_eq!(next_port, EPHEMERAL_PORT_START);
+
+		let addr = IPAddress::IPv4([0, 0, 0, 0]);
+		bindings.push(Binding {addr, port: EPHEMERAL_PORT_START, upper: 0, implicit: true});
+		assert_eq!(ephemeral_port(&mut next_port, |port| is_bound(&bindings, port)), Some(EPHEMERAL_PORT_START + 1));
+
+		for port in EPHEMERAL_PORT_START + 1..u16::MAX {
+			bindings.push(Binding {addr, port, upper: 0, implicit: false});
+		}
+		bindings.push(Binding {addr, port: u16::MAX, upper: 1, implicit: false});
+		assert_eq!(ephemeral_port(&mut next_port, |port| is_bound(&bindings, port)), None);
+	}
+
+    #[test]
+    fn dual_stack_bindings()
+	{
+		let ipv4 = IPAddress::IPv4([10, 0, 0, 1]);
+		let ipv6 = IPAddress::IPv6([0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+		let bindings = vec![
+			Binding {addr: IPAddress::IPv4([0, 0, 0, 0]), port: 7, upper: 0, implicit: false},
+			Binding {addr: IPAddress::IPv6([0; 16]), port: 9, upper: 1, implicit: false},
+			Binding {addr: ipv4, port: 11, upper: 2, implicit: false},
+		];
+
+		// Unspecified addresses of either family receive both IPv4 and IPv6 datagrams.
+		assert_eq!(find_binding(&bindings, &ipv4, 7), Some(0));
+		assert_eq!(find_binding(&bindings, &ipv6, 7), Some(0));
+		assert_eq!(find_binding(&bindings, &ipv4, 9), Some(1));
+		assert_eq!(find_binding(&bindings, &ipv6, 9), Some(1));
+
+		// But specific addresses only match themselves.
+		assert_eq!(find_binding(&bindings, &ipv4, 11), Some(2));
+		assert_eq!(find_binding(&bindings, &ipv6, 11), None);
+		assert_eq!(find_binding(&bindings, &ipv4, 13), None);
+	}
 }
@@ -27,13 +27,46 @@ use transport::*;
 pub type AppCallback = fn (app: &AppComponent, event: &mut Event, state: &SimState, effector: &mut Effector);
 
 /// Component that makes it easy to instal custom code at the top of the network stack.
+/// Callbacks will normally use the socket methods (open, bind, connect, send, etc) instead
+/// of sending events to the transport layer directly. "configured" events are handled
+/// by the component itself (they add to ipv6_interfaces) and aren't passed to the callback.
 pub struct AppComponent
 {
 	pub data: ThreadData,
 	pub callback: Option<AppCallback>,
 
-	pub lower_in: InPort<(InternetInfo, Packet)>,	
-	pub lower_out: OutPort<(InternetInfo, SocketOptions, Packet)>,
+	/// The device's interfaces. Used to pick the source address for sockets.
+	pub interfaces: Vec<IPv4Interface>,
+	pub ipv6_interfaces: Vec<IPv6Interface>,
+
+	/// Receives "bound" and "send_up" events from [`UdpComponent`].
+	pub lower_in: InPort<(InternetInfo, UDPHeader, Packet)>,
+
+	/// Used to send "bind", "unbind", and "send_down" events to [`UdpComponent`].
+	pub lower_out: OutPort<(InternetInfo, UDPHeader, SocketOptions, Packet)>,
+
+	/// Receives "connected", "send_up", "remote_closed", "closed", and "reset" events
+	/// from [`TcpComponent`].
+	pub tcp_in: InPort<(TcpConnection, SocketOptions, Packet)>,
+
+	/// Used to send "listen", "connect", "send_down", "close", and "abort" events.
+	pub tcp_out: OutPort<(TcpConnection, SocketOptions, Packet)>,
+
+	/// Receives "send_up" events for ICMP replies and "icmp_error" events for errors
+	/// caused by datagrams this app sent.
+	pub icmp_in: InPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Used to send ICMP queries, e.g. echo requests.
+	pub icmp_out: OutPort<(InternetInfo, IcmpMessage, Packet)>,
+
+	/// Receives "send_up" events for ICMPv6 replies.
+	pub icmpv6_in: InPort<(InternetInfo, Icmpv6Message, Packet)>,
+
+	/// Used to send ICMPv6 queries.
+	pub icmpv6_out: OutPort<(InternetInfo, Icmpv6Message, Packet)>,
+
+	/// Receives "configured" events from [`IPv6Component`].
+	pub config_in: InPort<AutoConfig>,
 }
 
 impl AppComponent
@@ -44,24 +77,245 @@ impl AppComponent
 		AppComponent {
 			data,
 			callback: None,
+			interfaces: Vec::new(),
+			ipv6_interfaces: Vec::new(),
 
 			lower_in: InPort::with_port_name(id, "lower_in"),
 			lower_out: OutPort::new(),
+
+			tcp_in: InPort::with_port_name(id, "tcp_in"),
+			tcp_out: OutPort::new(),
+
+			icmp_in: InPort::with_port_name(id, "icmp_in"),
+			icmp_out: OutPort::new(),
+
+			icmpv6_in: InPort::with_port_name(id, "icmpv6_in"),
+			icmpv6_out: OutPort::new(),
+
+			config_in: InPort::with_port_name(id, "config_in"),
 		}
 	}
 	
-	pub fn start(self)
+	pub fn start(mut self)
 	{		
 		thread::spawn(move || {
 			for (mut event, state) in self.data.rx.iter() {
 				let mut effector = Effector::new();
-				match self.callback {
-					Some(f) => f(&self, &mut event, &state, &mut effector),
-					None => log_warning!(effector, "dropping {} event", event.name),
+				if event.name == "configured" {
+					if let AutoConfig::Address(interface) = event.take_payload::<AutoConfig>() {
+						self.ipv6_interfaces.push(interface);
+					}
+				} else {
+					match self.callback {
+						Some(f) => f(&self, &mut event, &state, &mut effector),
+						None => log_warning!(effector, "dropping {} event", event.name),
+					}
 				}				
 				drop(state);
 				let _ = self.data.tx.send(effector);
 			}
 		});
 	}
+
+	/// Returns a new unbound and unconnected socket.
+	pub fn open(&self, kind: SocketKind) -> Socket
+	{
+		Socket::new(kind)
+	}
+
+	/// For datagram sockets this binds the port (zero picks an ephemeral port which is
+	/// returned using a "bound" event). For stream sockets this listens for connections
+	/// which are reported using "connected" events.
+	pub fn bind(&self, effector: &mut Effector, socket: &mut Socket, port: u16)
+	{
+		socket.local_port = port;
+		socket.bound_addr = socket.local_addr;
+		match socket.kind {
+			SocketKind::Datagram => {
+				let info = InternetInfo::new(UDP, socket.local_addr, socket.remote_addr);
+				let header = UDPHeader::new(port, 0);
+				self.lower_out.send_payload(effector, "bind", (info, header, socket.options, self.control_packet("bind")));
+			},
+			SocketKind::Stream => {
+				assert!(port != 0, "stream sockets can't listen on an ephemeral port");
+				self.tcp_out.send_payload(effector, "listen", (socket.connection(), socket.options, self.control_packet("listen")));
+			}
+		}
+	}
+
+	/// Sets the remote end of the socket and fills in the local address. For stream
+	/// sockets this also opens the connection: a "connected" event (or a "reset" event)
+	/// is sent once the handshake finishes. Note that, for stream sockets with an ephemeral
+	/// port, the socket from the "connected" event should be used to send.
+	pub fn connect(&self, effector: &mut Effector, socket: &mut Socket, addr: IPAddress, port: u16)
+	{
+		socket.local_addr = self.source_addr(addr);
+		socket.remote_addr = addr;
+		socket.remote_port = port;
+
+		if socket.kind == SocketKind::Stream {
+			self.tcp_out.send_payload(effector, "connect", (socket.connection(), socket.options, self.control_packet("connect")));
+		}
+	}
+
+	/// Sends the packet to the socket's remote end.
+	pub fn send(&self, effector: &mut Effector, socket: &Socket, packet: Packet)
+	{
+		match socket.kind {
+			SocketKind::Datagram => self.send_to(effector, socket, socket.remote_addr, socket.remote_port, packet),
+			SocketKind::Stream => self.tcp_out.send_payload(effector, "send_down", (socket.connection(), socket.options, packet)),
+		}
+	}
+
+	/// Like send except that the packet is sent after a delay.
+	pub fn send_after_secs(&self, effector: &mut Effector, socket: &Socket, secs: f64, packet: Packet)
+	{
+		match socket.kind {
+			SocketKind::Datagram => {
+				let info = InternetInfo::new(UDP, self.source_addr(socket.remote_addr), socket.remote_addr);
+				let header = datagram_header(socket, socket.remote_port);
+				self.lower_out.send_payload_after_secs(effector, "send_down", secs, (info, header, socket.options, packet));
+			},
+			SocketKind::Stream => self.tcp_out.send_payload_after_secs(effector, "send_down", secs, (socket.connection(), socket.options, packet)),
+		}
+	}
+
+	/// Sends a datagram to an arbitrary address and port.
+	pub fn send_to(&self, effector: &mut Effector, socket: &Socket, addr: IPAddress, port: u16, packet: Packet)
+	{
+		assert!(socket.kind == SocketKind::Datagram, "send_to is only supported for datagram sockets");
+
+		let info = InternetInfo::new(UDP, self.source_addr(addr), addr);
+		let header = datagram_header(socket, port);
+		self.lower_out.send_payload(effector, "send_down", (info, header, socket.options, packet));
+	}
+
+	/// Unbinds datagram sockets. For stream sockets this closes the connection (or stops
+	/// listening). A "closed" event is sent once the connection has been torn down.
+	pub fn close(&self, effector: &mut Effector, socket: &Socket)
+	{
+		match socket.kind {
+			SocketKind::Datagram => {
+				let info = InternetInfo::new(UDP, socket.bound_addr, socket.remote_addr);
+				let header = UDPHeader::new(socket.local_port, socket.remote_port);
+				self.lower_out.send_payload(effector, "unbind", (info, header, socket.options, self.control_packet("unbind")));
+			},
+			SocketKind::Stream => {
+				self.tcp_out.send_payload(effector, "close", (socket.connection(), socket.options, self.control_packet("close")));
+			}
+		}
+	}
+
+	/// Converts events from the transport layer ("bound", "send_up", "connected",
+	/// "remote_closed", "closed", and "reset") into the socket they are for (with the
+	/// remote end set to the sender) and the packet. Sockets from "bound" events have
+	/// the port that was bound and no remote end.
+	pub fn recv(&self, event: &mut Event) -> (Socket, Packet)
+	{
+		if event.port_name == self.tcp_in.target_port {
+			let (connection, options, packet) = event.take_payload::<(TcpConnection, SocketOptions, Packet)>();
+			let mut socket = Socket::new(SocketKind::Stream);
+			socket.local_addr = connection.local_addr;
+			socket.local_port = connection.local_port;
+			socket.remote_addr = connection.remote_addr;
+			socket.remote_port = connection.remote_port;
+			socket.options = options;
+			(socket, packet)
+
+		} else {
+			assert!(event.port_name == self.lower_in.target_port, "{} isn't a transport event", event.name);
+			let (info, header, packet) = event.take_payload::<(InternetInfo, UDPHeader, Packet)>();
+			(datagram_socket(&event.name, &info, &header), packet)
+		}
+	}
+
+	/// Returns the address of the interface used to reach addr.
+	pub fn source_addr(&self, addr: IPAddress) -> IPAddress
+	{
+		match addr {
+			IPAddress::IPv4(remote) => {
+				let interface = self.interfaces.iter().find(|i| i.is_local(remote)).or(self.interfaces.first());
+				match interface {
+					Some(i) => IPAddress::IPv4(i.addr),
+					None => IPAddress::IPv4([0, 0, 0, 0])
+				}
+			},
+			IPAddress::IPv6(remote) => {
+				// Prefer global addresses for off-link destinations.
+				let interface = self.ipv6_interfaces.iter().find(|i| i.is_local(remote))
+					.or(self.ipv6_interfaces.iter().find(|i| !(i.addr[0] == 0xfe && i.addr[1] & 0xC0 == 0x80)))
+					.or(self.ipv6_interfaces.first());
+				match interface {
+					Some(i) => IPAddress::IPv6(i.addr),
+					None => IPAddress::IPv6([0; 16])
+				}
+			},
+		}
+	}
+
+	fn control_packet(&self, name: &str) -> Packet
+	{
+		Packet::new(name, &format!("#>{}", self.data.id))
+	}
+}
+
+const UDP: Protocol = Protocol::Standard(StandardProtocol::UDP);
+
+// "bound" events use the info and header from the "bind" event (with the port filled in).
+// Everything else is a datagram that was received.
+fn datagram_socket(event_name: &str, info: &InternetInfo, header: &UDPHeader) -> Socket
+{
+	let mut socket = Socket::new(SocketKind::Datagram);
+	if event_name == "bound" {
+		socket.local_addr = info.src_addr;
+		socket.local_port = header.src_port;
+		socket.bound_addr = info.src_addr;
+	} else {
+		socket.local_addr = info.dst_addr;
+		socket.local_port = header.dst_port;
+		socket.remote_addr = info.src_addr;
+		socket.remote_port = header.src_port;
+		socket.options = SocketOptions::with_addr(info.src_addr);
+	}
+	socket
+}
+
+fn datagram_header(socket: &Socket, dst_port: u16) -> UDPHeader
+{
+	UDPHeader::new(socket.local_port, dst_port)
+}
+
+mod tests
+{
+    #[cfg(test)]
+	use super::*;
+
+    #[test]
+    fn datagram_sockets()
+	{
+		// bind(0) sends the socket's address and port zero, UDP fills in the port and
+		// sends it back using a "bound" event.
+		let socket = Socket::new(SocketKind::Datagram);
+		let info = InternetInfo::new(UDP, socket.local_addr, socket.remote_addr);
+		let header = UDPHeader::new(EPHEMERAL_PORT_START + 3, 0);
+		let bound = datagram_socket("bound", &info, &header);
+		assert_eq!(IPAddress::IPv4([0, 0, 0, 0]), bound.local_addr);
+		assert_eq!(EPHEMERAL_PORT_START + 3, bound.local_port);
+		assert_eq!(0, bound.remote_port);
+		assert_eq!(IPAddress::IPv4([0, 0, 0, 0]), bound.bound_addr);
+
+		// Sends use the bound port.
+		let header = datagram_header(&bound, 7);
+		assert_eq!(EPHEMERAL_PORT_START + 3, header.src_port);
+		assert_eq!(7, header.dst_port);
+
+		// Received datagrams have the remote end set to the sender.
+		let info = InternetInfo::new(UDP, IPAddress::IPv4([10, 0, 0, 2]), IPAddress::IPv4([10, 0, 0, 1]));
+		let header = UDPHeader::new(7, EPHEMERAL_PORT_START + 3);
+		let received = datagram_socket("send_up", &info, &header);
+		assert_eq!(IPAddress::IPv4([10, 0, 0, 1]), received.local_addr);
+		assert_eq!(EPHEMERAL_PORT_START + 3, received.local_port);
+		assert_eq!(IPAddress::IPv4([10, 0, 0, 2]), received.remote_addr);
+		assert_eq!(7, received.remote_port);
+	}
 }